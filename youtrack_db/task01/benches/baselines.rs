@@ -0,0 +1,195 @@
+//! Runs the same workloads on a `TSIMTree`, a `BTreeMap` and a `HashMap`, to tell whether the design pays off.
+//!
+//! Run with `cargo bench --features bench-utils --bench baselines`.
+//! Unlike the other benches, it uses Criterion, which compares each run with the previous one
+//! and keeps the reports in `target/criterion`.
+
+use criterion::measurement::WallTime;
+use criterion::{criterion_group, criterion_main, BenchmarkGroup, Criterion};
+use quick_start::bench_utils::{long_keys, missing_keys, sequential_keys, shuffled_keys, value_of};
+use quick_start::TSIMTree;
+use std::collections::{BTreeMap, HashMap};
+
+/// The number of short keys stored before each workload.
+const ENTRIES: usize = 1_000_000;
+/// The number of keys looked up in each sample.
+const LOOKUPS: usize = 100_000;
+/// Fewer long keys are stored, so the maps of all three types fit into memory at once.
+const LONG_ENTRIES: usize = 100_000;
+const LONG_KEY_LEN: usize = 256;
+const SEED: u64 = 42;
+
+type BTree = BTreeMap<Vec<u8>, Vec<u8>>;
+type Hash = HashMap<Vec<u8>, Vec<u8>>;
+type Group<'c> = BenchmarkGroup<'c, WallTime>;
+
+criterion_group! {
+    name = baselines;
+    // Filling a map with a million keys takes long, so fewer samples than the default 100 are taken.
+    config = Criterion::default().sample_size(10);
+    targets = insert_sequential, insert_random, get_hit, get_miss, scan_prefix, insert_long_keys, get_long_keys
+}
+criterion_main!(baselines);
+
+/// Runs the workload on each map type in one group, so Criterion reports them next to each other.
+macro_rules! compare {
+    ($c:expr, $workload:ident) => {{
+        let mut group = $c.benchmark_group(stringify!($workload));
+        $workload::<TSIMTree>(&mut group, "TSIMTree");
+        $workload::<BTree>(&mut group, "BTreeMap");
+        $workload::<Hash>(&mut group, "HashMap");
+        group.finish();
+    }};
+}
+
+/// The operations the workloads run, the maps return cloned values like the tree does.
+trait Map: Default + Sync {
+    fn insert(&mut self, key: &[u8], value: Vec<u8>);
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+    fn count_prefix(&self, prefix: &[u8]) -> usize;
+
+    fn filled<K: AsRef<[u8]>>(keys: &[K]) -> Self {
+        let mut map = Self::default();
+        for key in keys {
+            map.insert(key.as_ref(), value_of(key.as_ref()));
+        }
+        map
+    }
+}
+
+impl Map for TSIMTree {
+    fn insert(&mut self, key: &[u8], value: Vec<u8>) {
+        self.put(key, value);
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        TSIMTree::get(self, key)
+    }
+
+    fn count_prefix(&self, prefix: &[u8]) -> usize {
+        TSIMTree::count_prefix(self, prefix)
+    }
+}
+
+impl Map for BTree {
+    fn insert(&mut self, key: &[u8], value: Vec<u8>) {
+        BTreeMap::insert(self, key.to_vec(), value);
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        BTreeMap::get(self, key).cloned()
+    }
+
+    fn count_prefix(&self, prefix: &[u8]) -> usize {
+        self.range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .count()
+    }
+}
+
+/// A hash map has no order, so counting a prefix has to look at every key.
+impl Map for Hash {
+    fn insert(&mut self, key: &[u8], value: Vec<u8>) {
+        HashMap::insert(self, key.to_vec(), value);
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        HashMap::get(self, key).cloned()
+    }
+
+    fn count_prefix(&self, prefix: &[u8]) -> usize {
+        self.keys().filter(|key| key.starts_with(prefix)).count()
+    }
+}
+
+fn insert_sequential(c: &mut Criterion) {
+    fn insert_sequential<M: Map>(group: &mut Group, name: &str) {
+        let keys = sequential_keys(ENTRIES);
+        group.bench_function(name, |b| b.iter(|| M::filled(&keys)));
+    }
+    compare!(c, insert_sequential);
+}
+
+fn insert_random(c: &mut Criterion) {
+    fn insert_random<M: Map>(group: &mut Group, name: &str) {
+        let keys = shuffled_keys(ENTRIES, SEED);
+        group.bench_function(name, |b| b.iter(|| M::filled(&keys)));
+    }
+    compare!(c, insert_random);
+}
+
+fn get_hit(c: &mut Criterion) {
+    fn get_hit<M: Map>(group: &mut Group, name: &str) {
+        let map = M::filled(&sequential_keys(ENTRIES));
+        let keys = &shuffled_keys(ENTRIES, SEED)[..LOOKUPS];
+
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for key in keys {
+                    assert!(map.get(key).is_some());
+                }
+            })
+        });
+    }
+    compare!(c, get_hit);
+}
+
+fn get_miss(c: &mut Criterion) {
+    fn get_miss<M: Map>(group: &mut Group, name: &str) {
+        let map = M::filled(&sequential_keys(ENTRIES));
+        let keys = missing_keys(LOOKUPS, SEED);
+
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for key in &keys {
+                    assert!(map.get(key).is_none());
+                }
+            })
+        });
+    }
+    compare!(c, get_miss);
+}
+
+/// Counts the keys under prefixes that each hold 256 of the sequential keys.
+fn scan_prefix(c: &mut Criterion) {
+    fn scan_prefix<M: Map>(group: &mut Group, name: &str) {
+        let map = M::filled(&sequential_keys(ENTRIES));
+        let prefixes: Vec<[u8; 7]> = shuffled_keys(ENTRIES, SEED)[..10]
+            .iter()
+            .map(|key| key[..7].try_into().unwrap())
+            .collect();
+
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for prefix in &prefixes {
+                    assert_eq!(map.count_prefix(prefix), 256);
+                }
+            })
+        });
+    }
+    compare!(c, scan_prefix);
+}
+
+fn insert_long_keys(c: &mut Criterion) {
+    fn insert_long_keys<M: Map>(group: &mut Group, name: &str) {
+        let keys = long_keys(LONG_ENTRIES, LONG_KEY_LEN, SEED);
+        group.bench_function(name, |b| b.iter(|| M::filled(&keys)));
+    }
+    compare!(c, insert_long_keys);
+}
+
+fn get_long_keys(c: &mut Criterion) {
+    fn get_long_keys<M: Map>(group: &mut Group, name: &str) {
+        let keys = long_keys(LONG_ENTRIES, LONG_KEY_LEN, SEED);
+        let map = M::filled(&keys);
+
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for key in &keys {
+                    assert_eq!(map.get(key).as_deref(), Some(&key[LONG_KEY_LEN - 8..]));
+                }
+            })
+        });
+    }
+    compare!(c, get_long_keys);
+}
@@ -0,0 +1,57 @@
+//! Stores and reads values of a few sizes around the longest value that is stored within its node.
+//!
+//! Run with `cargo bench --bench small_values`, the allocations of each sample are shown next to the timings.
+
+use divan::{AllocProfiler, Bencher};
+use quick_start::TSIMTree;
+
+#[global_allocator]
+static ALLOC: AllocProfiler = AllocProfiler::system();
+
+const ENTRIES: u32 = 100_000;
+const VALUE_LENS: [usize; 4] = [8, 16, 17, 64];
+
+fn main() {
+    divan::main();
+}
+
+/// Visits every number below `ENTRIES` exactly once in a scattered order, as 7919 and `ENTRIES` are coprime.
+fn shuffled_keys() -> Vec<[u8; 4]> {
+    (0..ENTRIES)
+        .map(|i| (i * 7919 % ENTRIES).to_be_bytes())
+        .collect()
+}
+
+fn filled_tree(keys: &[[u8; 4]], value_len: usize) -> TSIMTree {
+    let tree = TSIMTree::new();
+    for key in keys {
+        tree.put(key, vec![key[3]; value_len]);
+    }
+    tree
+}
+
+/// The values are allocated by the caller, the tree keeps them or copies them into its nodes.
+#[divan::bench(args = VALUE_LENS, sample_count = 10)]
+fn insert(bencher: Bencher, value_len: usize) {
+    let keys = shuffled_keys();
+    bencher.bench(|| filled_tree(&keys, value_len));
+}
+
+/// Cloning the tree copies every value, values stored within their nodes are copied with them.
+#[divan::bench(args = VALUE_LENS, sample_count = 10)]
+fn clone(bencher: Bencher, value_len: usize) {
+    let tree = filled_tree(&shuffled_keys(), value_len);
+    bencher.bench(|| tree.clone());
+}
+
+/// Reads the last byte of every value without cloning it, so the lookups differ only in where the value is stored.
+#[divan::bench(args = VALUE_LENS, sample_count = 10)]
+fn get_with(bencher: Bencher, value_len: usize) {
+    let keys = shuffled_keys();
+    let tree = filled_tree(&keys, value_len);
+    bencher.bench(|| {
+        for key in &keys {
+            divan::black_box(tree.get_with(key, |value| value[value_len - 1]));
+        }
+    });
+}
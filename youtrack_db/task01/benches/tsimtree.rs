@@ -0,0 +1,305 @@
+use divan::Bencher;
+use quick_start::{ShardedTSIMTree, TSIMTree, TSIMTreeBuilder, TSIMTreeLocal};
+
+const ENTRIES: u32 = 100_000;
+const WRITERS: usize = 8;
+
+fn main() {
+    divan::main();
+}
+
+/// Visits every number below `ENTRIES` exactly once in a scattered order, as 7919 and `ENTRIES` are coprime.
+fn shuffled_keys() -> Vec<[u8; 4]> {
+    (0..ENTRIES)
+        .map(|i| (i * 7919 % ENTRIES).to_be_bytes())
+        .collect()
+}
+
+#[divan::bench]
+fn build_with_builder(bencher: Bencher) {
+    let keys = shuffled_keys();
+    bencher.bench(|| {
+        let mut builder = TSIMTreeBuilder::new();
+        for key in &keys {
+            builder.push(key, key.to_vec());
+        }
+        builder.build()
+    });
+}
+
+/// One million keys, so each range of `build_parallel` holds enough entries to be worth a thread.
+#[cfg(feature = "rayon")]
+fn many_shuffled_keys() -> Vec<[u8; 4]> {
+    const MANY_ENTRIES: u64 = 1_000_000;
+    (0..MANY_ENTRIES)
+        .map(|i| ((i * 7919 % MANY_ENTRIES) as u32).to_be_bytes())
+        .collect()
+}
+
+#[cfg(feature = "rayon")]
+#[divan::bench(args = [false, true])]
+fn build_many_with_builder(bencher: Bencher, parallel: bool) {
+    let keys = many_shuffled_keys();
+    bencher.bench(|| {
+        let mut builder = TSIMTreeBuilder::new();
+        for key in &keys {
+            builder.push(key, key.to_vec());
+        }
+        if parallel {
+            builder.build_parallel()
+        } else {
+            builder.build()
+        }
+    });
+}
+
+#[divan::bench]
+fn build_with_random_puts(bencher: Bencher) {
+    let keys = shuffled_keys();
+    bencher.bench(|| {
+        let tree = TSIMTree::new();
+        for key in &keys {
+            tree.put(key, key.to_vec());
+        }
+        tree
+    });
+}
+
+/// Ascending keys, either loaded in a single pass or put one by one.
+#[divan::bench(args = [false, true])]
+fn build_from_sorted_keys(bencher: Bencher, bulk_load: bool) {
+    let keys: Vec<[u8; 4]> = (0..ENTRIES).map(u32::to_be_bytes).collect();
+    bencher.bench(|| {
+        if bulk_load {
+            let entries = keys.iter().map(|key| (key.to_vec(), key.to_vec()));
+            return TSIMTree::from_sorted_iter(entries).unwrap();
+        }
+        let tree = TSIMTree::new();
+        for key in &keys {
+            tree.put(key, key.to_vec());
+        }
+        tree
+    });
+}
+
+/// The same puts as `build_with_random_puts` without acquiring a lock for each of them.
+#[divan::bench]
+fn build_local_with_random_puts(bencher: Bencher) {
+    let keys = shuffled_keys();
+    bencher.bench(|| {
+        let mut tree = TSIMTreeLocal::new();
+        for key in &keys {
+            tree.put(key, key.to_vec());
+        }
+        tree
+    });
+}
+
+/// Overwrites a key of trees of growing size. With the `lockfree` feature, each put copies the chunks of slots
+/// and the nodes on the path to the key, so the time per put must grow with the depth, not with the number of keys.
+/// Compare `cargo bench --bench tsimtree put_into_tree_of_size` with and without `--features lockfree`.
+#[divan::bench(args = [10_000, 40_000, 160_000])]
+fn put_into_tree_of_size(bencher: Bencher, entries: u32) {
+    let tree = TSIMTree::new();
+    for i in 0..entries {
+        let key = (i * 7919 % entries).to_be_bytes();
+        tree.put(key, key.to_vec());
+    }
+
+    let mut i = 0_u32;
+    bencher.bench_local(|| {
+        let key = (i % entries).to_be_bytes();
+        i = i.wrapping_add(7919);
+        tree.put(key, key.to_vec());
+    });
+}
+
+/// Puts the keys from `WRITERS` threads at once, each thread storing its own slice of the keys.
+fn put_concurrently(keys: &[[u8; 4]], put: impl Fn(&[u8; 4]) + Sync) {
+    std::thread::scope(|s| {
+        for chunk in keys.chunks(keys.len().div_ceil(WRITERS)) {
+            let put = &put;
+            s.spawn(move || chunk.iter().for_each(put));
+        }
+    });
+}
+
+/// The same puts as `build_with_random_puts`, but the writers wait for the single root lock.
+#[divan::bench]
+fn build_with_concurrent_puts(bencher: Bencher) {
+    let keys = shuffled_keys();
+    bencher.bench(|| {
+        let tree = TSIMTree::new();
+        put_concurrently(&keys, |key| tree.put(key, key.to_vec()));
+        tree
+    });
+}
+
+/// The same puts as `build_with_concurrent_puts` into one shard per writer, so they mostly lock different shards.
+#[divan::bench]
+fn build_sharded_with_concurrent_puts(bencher: Bencher) {
+    let keys = shuffled_keys();
+    bencher.bench(|| {
+        let tree = ShardedTSIMTree::with_shards(WRITERS);
+        put_concurrently(&keys, |key| tree.put(key, key.to_vec()));
+        tree
+    });
+}
+
+/// Looks up every key while a writer keeps putting keys, 90% of which overwrite an existing key.
+/// `put` finds the position of the key under the read lock, so the readers wait less for the writer.
+#[divan::bench]
+fn get_while_mostly_overwriting(bencher: Bencher) {
+    get_while_writing(bencher, |tree, key| tree.put(key, key.to_vec()));
+}
+
+/// The same workload with the writer holding the write lock for the whole insertion path,
+/// as `put` did before it looked the key up under the read lock. Requires the `bench-utils` feature.
+#[cfg(feature = "bench-utils")]
+#[divan::bench]
+fn get_while_mostly_overwriting_write_locked(bencher: Bencher) {
+    get_while_writing(bencher, |tree, key| {
+        tree.put_write_locked(key, key.to_vec())
+    });
+}
+
+/// Looks up every key while a writer keeps calling `put` with keys, 90% of which are already stored.
+fn get_while_writing(bencher: Bencher, put: impl Fn(&TSIMTree, [u8; 4]) + Sync) {
+    let keys = shuffled_keys();
+    let tree = TSIMTree::new();
+    for key in &keys {
+        tree.put(key, key.to_vec());
+    }
+    let done = std::sync::atomic::AtomicBool::new(false);
+
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            let mut i = 0_u32;
+            while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                let key = match i % 10 {
+                    0 => (ENTRIES + i % ENTRIES).to_be_bytes(),
+                    _ => (i % ENTRIES).to_be_bytes(),
+                };
+                put(&tree, key);
+                i = i.wrapping_add(7919);
+            }
+        });
+
+        bencher.bench(|| {
+            for key in &keys {
+                divan::black_box(tree.get(key));
+            }
+        });
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+    });
+}
+
+/// The number of keys the read-heavy benchmarks read over and over.
+const HOT_KEYS: usize = 64;
+
+/// Reads a few hot keys over and over from several threads, which all share the read lock.
+#[divan::bench(threads = [1, 4])]
+fn get_hot_keys(bencher: Bencher) {
+    let keys = shuffled_keys();
+    let tree = TSIMTree::new();
+    for key in &keys {
+        tree.put(key, key.to_vec());
+    }
+
+    bencher.bench(|| {
+        for key in &keys[..HOT_KEYS] {
+            divan::black_box(tree.get(key));
+        }
+    });
+}
+
+/// The same reads as `get_hot_keys`, served from the thread-local caches without touching the lock.
+#[cfg(feature = "thread-local-cache")]
+#[divan::bench(threads = [1, 4])]
+fn get_hot_keys_cached(bencher: Bencher) {
+    let keys = shuffled_keys();
+    let tree = quick_start::CachedTSIMTree::new(HOT_KEYS);
+    for key in &keys {
+        tree.put(key, key.to_vec());
+    }
+
+    bencher.bench(|| {
+        for key in &keys[..HOT_KEYS] {
+            divan::black_box(tree.get(key));
+        }
+    });
+}
+
+/// A tree with `ENTRIES` keys that are spread over many nodes and values that are cheap to clone.
+fn filled_tree() -> TSIMTree {
+    let mut builder = TSIMTreeBuilder::new();
+    for key in shuffled_keys() {
+        builder.push(key.repeat(4), key.to_vec());
+    }
+    builder.build()
+}
+
+/// Looks up every key of a tree that was filled in random order, so the descents rarely hit cached nodes.
+#[divan::bench]
+fn get_from_random_tree(bencher: Bencher) {
+    let keys = shuffled_keys();
+    let tree = TSIMTree::new();
+    for key in &keys {
+        tree.put(key, key.to_vec());
+    }
+
+    bencher.bench(|| {
+        for key in &keys {
+            // Checked on every lookup, so prefetching can not change the results unnoticed.
+            assert_eq!(tree.get(key).as_deref(), Some(key.as_slice()));
+        }
+    });
+}
+
+/// The same lookups as `get_from_random_tree` without acquiring a lock or cloning the values.
+#[divan::bench]
+fn get_from_random_local_tree(bencher: Bencher) {
+    let keys = shuffled_keys();
+    let mut tree = TSIMTreeLocal::new();
+    for key in &keys {
+        tree.put(key, key.to_vec());
+    }
+
+    bencher.bench(|| {
+        for key in &keys {
+            assert_eq!(tree.get(key), Some(key.as_slice()));
+        }
+    });
+}
+
+/// Resolves keys against a full root whose segments hold 7 bytes, the most a segment of the default layout holds.
+/// Compare the runs with and without `--features simd`, and with `RUSTFLAGS="-C target-feature=+sse4.2"`.
+#[divan::bench]
+fn resolve_seven_byte_segments(bencher: Bencher) {
+    let keys: Vec<[u8; 7]> = (0..16_u8).map(|i| [i * 16, 1, 2, 3, 4, 5, i]).collect();
+    let tree = TSIMTree::new();
+    for key in &keys {
+        tree.put(key, vec![]);
+    }
+    let root = tree.root_node();
+    assert_eq!(root.children_count(), keys.len());
+
+    // The root guard is not Sync with the lockfree feature, as it pins the current thread.
+    bencher.bench_local(|| {
+        for key in &keys {
+            divan::black_box(root.resolve_child(divan::black_box(key)));
+        }
+    });
+}
+
+#[divan::bench]
+fn iterate_keys(bencher: Bencher) {
+    let tree = filled_tree();
+    bencher.bench(|| tree.keys().count());
+}
+
+#[divan::bench]
+fn iterate_values(bencher: Bencher) {
+    let tree = filled_tree();
+    bencher.bench(|| tree.values().count());
+}
@@ -0,0 +1,96 @@
+//! Replays arbitrary operations on a tree and on a `BTreeMap`, which serves as the oracle.
+//!
+//! Run with `cargo +nightly fuzz run btreemap_oracle` from the crate directory.
+//! Keys and values are not bounded, besides the size of the input.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use quick_start::TSIMTree;
+use std::collections::BTreeMap;
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Put(Vec<u8>, Vec<u8>),
+    Get(Vec<u8>),
+    Remove(Vec<u8>),
+    CountPrefix(Vec<u8>),
+    GetNext(Vec<u8>),
+    GetPrev(Vec<u8>),
+    Successor(Vec<u8>),
+    Predecessor(Vec<u8>),
+    ForEachPrefix(Vec<u8>),
+    Iterate,
+}
+
+/// Applies the operations to both maps and checks the results and the invariants of the tree after each one.
+fn replay<const RADIX: usize, const LINE: usize>(ops: &[Op]) {
+    let tree = TSIMTree::<RADIX, LINE>::default();
+    let mut oracle = BTreeMap::new();
+    for op in ops {
+        match op {
+            Op::Put(key, value) => {
+                tree.put(key, value.clone());
+                oracle.insert(key.clone(), value.clone());
+            }
+            Op::Get(key) => assert_eq!(tree.get(key), oracle.get(key).cloned()),
+            Op::Remove(key) => assert_eq!(tree.remove(key), oracle.remove(key)),
+            Op::CountPrefix(prefix) => {
+                let count = oracle
+                    .range(prefix.clone()..)
+                    .take_while(|(key, _)| key.starts_with(prefix))
+                    .count();
+                assert_eq!(tree.count_prefix(prefix), count);
+            }
+            Op::GetNext(key) => {
+                let next = oracle.range(key.clone()..).next();
+                assert_eq!(
+                    tree.get_next(key),
+                    next.map(|(k, v)| (k.clone(), v.clone()))
+                );
+            }
+            Op::GetPrev(key) => {
+                let prev = oracle.range(..=key.clone()).next_back();
+                assert_eq!(
+                    tree.get_prev(key),
+                    prev.map(|(k, v)| (k.clone(), v.clone()))
+                );
+            }
+            Op::Successor(key) => {
+                let next = oracle.range(key.clone()..).find(|(k, _)| *k != key);
+                assert_eq!(
+                    tree.successor(key),
+                    next.map(|(k, v)| (k.clone(), v.clone()))
+                );
+            }
+            Op::Predecessor(key) => {
+                let prev = oracle.range(..key.clone()).next_back();
+                assert_eq!(
+                    tree.predecessor(key),
+                    prev.map(|(k, v)| (k.clone(), v.clone()))
+                );
+            }
+            Op::ForEachPrefix(key) => {
+                let expected: Vec<_> = (0..=key.len())
+                    .filter_map(|len| oracle.get_key_value(&key[..len]))
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect();
+                let mut prefixes = Vec::new();
+                tree.for_each_prefix(key, |k, v| prefixes.push((k.to_vec(), v.to_vec())));
+                assert_eq!(prefixes, expected);
+            }
+            Op::Iterate => {
+                let entries = oracle.iter().map(|(k, v)| (k.clone(), v.clone()));
+                assert!(tree.iter().eq(entries));
+            }
+        }
+        assert_eq!(tree.check_integrity(), Ok(()));
+    }
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    // The default layout and a small one, whose nodes fill up and split after a few keys.
+    replay::<16, 128>(&ops);
+    replay::<8, 64>(&ops);
+});
@@ -0,0 +1,489 @@
+//! Stores the nodes of a tree, nodes refer to their children by their index in the arena.
+//!
+//! Boxing every node separately scatters the nodes over the heap and costs one allocation per node.
+//! The arena keeps all nodes of a tree in a single growing buffer and reuses the slots of removed nodes.
+//!
+//! By default the arena is a vector with a free list.
+//! With the `slab` feature, the nodes are stored in a [`slab::Slab`] instead.
+//!
+//! Descents prefetch the next node as soon as it is chosen, so it is loaded while the current node is still processed.
+//! Prefetching uses SSE on x86_64, it is a no-op on other targets and with the `slab` and `lockfree` features.
+//!
+//! With the `lockfree` feature, every write copies the arena of the published root, so a buffer of slots would be
+//! copied as a whole. The slots are instead split into chunks of 32, which are the leaves of a tree of chunks,
+//! and each node is kept behind an [`Arc`](alloc::sync::Arc). Copies of the arena share their chunks and nodes,
+//! a chunk or a node is only copied once it is modified. A write therefore copies the nodes on the paths to the keys
+//! it modifies and the chunks on the paths to their slots, which grows logarithmically with the number of nodes.
+//! In exchange, looking up a node descends through the chunks, and the `slab` feature has no effect.
+//!
+//! The free list arena allocates its slots with the allocator of the tree. The slab and the chunks of the
+//! `lockfree` feature do not support custom allocators, they always allocate from the global allocator.
+
+#[cfg(not(any(feature = "slab", feature = "lockfree")))]
+pub(crate) use free_list::Arena;
+
+#[cfg(all(feature = "slab", not(feature = "lockfree")))]
+pub(crate) use slab_arena::Arena;
+
+#[cfg(feature = "lockfree")]
+pub(crate) use shared_arena::Arena;
+
+/// The index of a node in its arena.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct NodeId(usize);
+
+#[cfg(not(any(feature = "slab", feature = "lockfree")))]
+mod free_list {
+    use super::NodeId;
+    use allocator_api2::alloc::Allocator;
+    use allocator_api2::vec::Vec;
+    use core::ops::{Index, IndexMut};
+
+    #[derive(Clone)]
+    pub(crate) struct Arena<T, A: Allocator> {
+        slots: Vec<Option<T>, A>,
+        /// The indices of the vacant slots, which are filled before the arena grows.
+        free: Vec<usize, A>,
+    }
+
+    impl<T: Clone, A: Allocator + Clone> Arena<T, A> {
+        pub(crate) fn new_in(alloc: A) -> Arena<T, A> {
+            Arena {
+                slots: Vec::new_in(alloc.clone()),
+                free: Vec::new_in(alloc),
+            }
+        }
+
+        pub(crate) fn insert(&mut self, value: T) -> NodeId {
+            match self.free.pop() {
+                Some(idx) => {
+                    self.slots[idx] = Some(value);
+                    NodeId(idx)
+                }
+                None => {
+                    self.slots.push(Some(value));
+                    NodeId(self.slots.len() - 1)
+                }
+            }
+        }
+
+        pub(crate) fn remove(&mut self, id: NodeId) -> T {
+            let value = self.slots[id.0]
+                .take()
+                .expect("NodeId must refer to a stored node");
+            self.free.push(id.0);
+            value
+        }
+
+        /// Returns the node, or `None` if the id does not refer to a stored node.
+        pub(crate) fn get(&self, id: NodeId) -> Option<&T> {
+            self.slots.get(id.0)?.as_ref()
+        }
+
+        /// Starts loading the node into the cache without accessing it.
+        pub(crate) fn prefetch(&self, id: NodeId) {
+            // The slot is only addressed, reading it would wait for the very load that is prefetched.
+            prefetch(self.slots.as_ptr().wrapping_add(id.0));
+        }
+
+        /// Returns the number of stored nodes.
+        #[cfg(test)]
+        pub(crate) fn len(&self) -> usize {
+            self.slots.len() - self.free.len()
+        }
+
+        /// Returns the number of bytes allocated for the slots and the free list, vacant slots included.
+        pub(crate) fn allocated_bytes(&self) -> usize {
+            self.slots.capacity() * size_of::<Option<T>>()
+                + self.free.capacity() * size_of::<usize>()
+        }
+
+        /// Releases the capacity that is not used by a stored node.
+        pub(crate) fn shrink_to_fit(&mut self) {
+            while self.slots.last().is_some_and(Option::is_none) {
+                self.slots.pop();
+            }
+            let slots = self.slots.len();
+            self.free.retain(|&idx| idx < slots);
+            self.slots.shrink_to_fit();
+            self.free.shrink_to_fit();
+        }
+
+        /// Returns the number of slots, including the vacant ones.
+        #[cfg(test)]
+        pub(crate) fn slots(&self) -> usize {
+            self.slots.len()
+        }
+    }
+
+    /// Hints the CPU to load all cache lines of the value, the value is neither read nor required to be valid.
+    #[cfg(all(target_arch = "x86_64", target_feature = "sse"))]
+    fn prefetch<T>(value: *const T) {
+        use core::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+
+        const CACHE_LINE: usize = 64;
+        for offset in (0..size_of::<T>()).step_by(CACHE_LINE) {
+            // SAFETY: Prefetching never faults, even for addresses that are not mapped.
+            unsafe { _mm_prefetch::<_MM_HINT_T0>(value.cast::<i8>().wrapping_add(offset)) };
+        }
+    }
+
+    #[cfg(not(all(target_arch = "x86_64", target_feature = "sse")))]
+    fn prefetch<T>(_value: *const T) {}
+
+    impl<T, A: Allocator> Index<NodeId> for Arena<T, A> {
+        type Output = T;
+
+        fn index(&self, id: NodeId) -> &T {
+            self.slots[id.0]
+                .as_ref()
+                .expect("NodeId must refer to a stored node")
+        }
+    }
+
+    impl<T: Clone, A: Allocator> IndexMut<NodeId> for Arena<T, A> {
+        fn index_mut(&mut self, id: NodeId) -> &mut T {
+            self.slots[id.0]
+                .as_mut()
+                .expect("NodeId must refer to a stored node")
+        }
+    }
+}
+
+#[cfg(all(feature = "slab", not(feature = "lockfree")))]
+mod slab_arena {
+    use super::NodeId;
+    use core::marker::PhantomData;
+    use core::ops::{Index, IndexMut};
+    use slab::Slab;
+
+    /// The allocator is only a marker, the slab allocates from the global allocator.
+    #[derive(Clone)]
+    pub(crate) struct Arena<T, A>(Slab<T>, PhantomData<A>);
+
+    impl<T: Clone, A> Arena<T, A> {
+        pub(crate) fn new_in(_alloc: A) -> Arena<T, A> {
+            Arena(Slab::new(), PhantomData)
+        }
+
+        pub(crate) fn insert(&mut self, value: T) -> NodeId {
+            NodeId(self.0.insert(value))
+        }
+
+        pub(crate) fn remove(&mut self, id: NodeId) -> T {
+            self.0.remove(id.0)
+        }
+
+        /// Returns the node, or `None` if the id does not refer to a stored node.
+        pub(crate) fn get(&self, id: NodeId) -> Option<&T> {
+            self.0.get(id.0)
+        }
+
+        /// Does nothing, as a slab can only locate an entry by reading whether it is occupied.
+        pub(crate) fn prefetch(&self, _id: NodeId) {}
+
+        /// Returns the number of stored nodes.
+        #[cfg(test)]
+        pub(crate) fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        /// Returns the number of bytes allocated for the entries of the slab, vacant entries included.
+        ///
+        /// An entry is assumed to be as large as its slot, as the type of the entries is private to the slab.
+        pub(crate) fn allocated_bytes(&self) -> usize {
+            self.0.capacity() * size_of::<T>()
+        }
+
+        /// Releases the capacity behind the last stored node.
+        pub(crate) fn shrink_to_fit(&mut self) {
+            self.0.shrink_to_fit();
+        }
+
+        /// Returns the number of slots the slab has allocated, including the vacant ones.
+        #[cfg(test)]
+        pub(crate) fn slots(&self) -> usize {
+            self.0.capacity()
+        }
+    }
+
+    impl<T, A> Index<NodeId> for Arena<T, A> {
+        type Output = T;
+
+        fn index(&self, id: NodeId) -> &T {
+            &self.0[id.0]
+        }
+    }
+
+    impl<T: Clone, A> IndexMut<NodeId> for Arena<T, A> {
+        fn index_mut(&mut self, id: NodeId) -> &mut T {
+            &mut self.0[id.0]
+        }
+    }
+}
+
+#[cfg(feature = "lockfree")]
+mod shared_arena {
+    use super::NodeId;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::marker::PhantomData;
+    use core::ops::{Index, IndexMut};
+
+    /// The number of bits of a slot index that select the child within a chunk.
+    const CHUNK_BITS: usize = 5;
+    /// The number of slots of a leaf chunk, and of children of an inner chunk.
+    const CHUNK_SIZE: usize = 1 << CHUNK_BITS;
+
+    #[derive(Clone)]
+    enum Slot<T> {
+        Occupied(Arc<T>),
+        /// Links to the next vacant slot of the free list.
+        Vacant(Option<usize>),
+    }
+
+    #[derive(Clone)]
+    enum Chunk<T> {
+        Leaf(Vec<Slot<T>>),
+        Inner(Vec<Arc<Chunk<T>>>),
+    }
+
+    impl<T> Chunk<T> {
+        /// Returns an empty chunk for the slot indices that are shifted by `shift` bits to select its children.
+        fn empty(shift: usize) -> Chunk<T> {
+            match shift {
+                0 => Chunk::Leaf(Vec::with_capacity(CHUNK_SIZE)),
+                _ => Chunk::Inner(Vec::with_capacity(CHUNK_SIZE)),
+            }
+        }
+    }
+
+    /// The allocator is only a marker, the chunks and nodes are allocated from the global allocator.
+    #[derive(Clone)]
+    pub(crate) struct Arena<T, A> {
+        root: Arc<Chunk<T>>,
+        /// The number of levels of inner chunks above the leaves.
+        height: usize,
+        /// The number of slots, including the vacant ones.
+        slots: usize,
+        len: usize,
+        /// The most recently vacated slot, which is filled before the arena grows.
+        free: Option<usize>,
+        alloc: PhantomData<A>,
+    }
+
+    impl<T, A> Arena<T, A> {
+        fn empty() -> Arena<T, A> {
+            Arena {
+                root: Arc::new(Chunk::empty(0)),
+                height: 0,
+                slots: 0,
+                len: 0,
+                free: None,
+                alloc: PhantomData,
+            }
+        }
+
+        /// Returns the slot, or `None` if the arena has fewer slots.
+        fn slot(&self, idx: usize) -> Option<&Slot<T>> {
+            if idx >= self.slots {
+                return None;
+            }
+            let mut chunk = &*self.root;
+            let mut shift = self.height * CHUNK_BITS;
+            loop {
+                let child = (idx >> shift) % CHUNK_SIZE;
+                match chunk {
+                    Chunk::Inner(children) => {
+                        chunk = &children[child];
+                        shift -= CHUNK_BITS;
+                    }
+                    Chunk::Leaf(slots) => return slots.get(child),
+                }
+            }
+        }
+
+        /// Returns the node, or `None` if the id does not refer to a stored node.
+        pub(crate) fn get(&self, id: NodeId) -> Option<&T> {
+            match self.slot(id.0)? {
+                Slot::Occupied(node) => Some(node),
+                Slot::Vacant(_) => None,
+            }
+        }
+
+        /// Does nothing, as locating the slot already reads the chunks on the path to it.
+        pub(crate) fn prefetch(&self, _id: NodeId) {}
+
+        /// Returns the number of stored nodes.
+        #[cfg(test)]
+        pub(crate) fn len(&self) -> usize {
+            self.len
+        }
+
+        /// Returns the number of bytes allocated for the chunks and the nodes, including those shared with other copies.
+        pub(crate) fn allocated_bytes(&self) -> usize {
+            // The chunks and nodes are allocated behind an Arc, next to the strong and the weak count.
+            let counts = 2 * size_of::<usize>();
+            let mut chunks = self.slots.div_ceil(CHUNK_SIZE);
+            let mut allocated = chunks * (counts + CHUNK_SIZE * size_of::<Slot<T>>());
+            for _ in 0..self.height {
+                chunks = chunks.div_ceil(CHUNK_SIZE);
+                allocated += chunks * (counts + CHUNK_SIZE * size_of::<Arc<Chunk<T>>>());
+            }
+            allocated + self.len * (counts + size_of::<T>())
+        }
+
+        /// Returns the number of slots, including the vacant ones.
+        #[cfg(test)]
+        pub(crate) fn slots(&self) -> usize {
+            self.slots
+        }
+
+        /// Returns the number of chunks of the arena that are not shared with the other arena.
+        #[cfg(test)]
+        pub(crate) fn unshared_chunks(&self, other: &Arena<T, A>) -> usize {
+            fn count<T>(chunk: &Arc<Chunk<T>>, other: Option<&Arc<Chunk<T>>>) -> usize {
+                if other.is_some_and(|other| Arc::ptr_eq(chunk, other)) {
+                    return 0;
+                }
+                let Chunk::Inner(children) = &**chunk else {
+                    return 1;
+                };
+                let others = match other.map(|other| &**other) {
+                    Some(Chunk::Inner(others)) => &others[..],
+                    _ => &[],
+                };
+                let unshared = children.iter().enumerate();
+                1 + unshared
+                    .map(|(idx, child)| count(child, others.get(idx)))
+                    .sum::<usize>()
+            }
+            match self.height == other.height {
+                true => count(&self.root, Some(&other.root)),
+                false => count(&self.root, None),
+            }
+        }
+    }
+
+    impl<T: Clone, A> Arena<T, A> {
+        pub(crate) fn new_in(_alloc: A) -> Arena<T, A> {
+            Arena::empty()
+        }
+
+        pub(crate) fn insert(&mut self, value: T) -> NodeId {
+            let occupied = Slot::Occupied(Arc::new(value));
+            self.len += 1;
+            let Some(idx) = self.free else {
+                self.push(occupied);
+                return NodeId(self.slots - 1);
+            };
+            match core::mem::replace(self.slot_mut(idx), occupied) {
+                Slot::Vacant(next) => self.free = next,
+                Slot::Occupied(_) => unreachable!("the free list only links vacant slots"),
+            }
+            NodeId(idx)
+        }
+
+        pub(crate) fn remove(&mut self, id: NodeId) -> T {
+            let vacant = Slot::Vacant(self.free);
+            let Slot::Occupied(node) = core::mem::replace(self.slot_mut(id.0), vacant) else {
+                panic!("NodeId must refer to a stored node");
+            };
+            self.free = Some(id.0);
+            self.len -= 1;
+            Arc::unwrap_or_clone(node)
+        }
+
+        /// Releases the chunks behind the last stored node.
+        ///
+        /// The chunks are rebuilt, so the arena no longer shares them with its copies.
+        pub(crate) fn shrink_to_fit(&mut self) {
+            let mut nodes: Vec<Option<Arc<T>>> = (0..self.slots)
+                .map(|idx| match self.slot(idx) {
+                    Some(Slot::Occupied(node)) => Some(Arc::clone(node)),
+                    _ => None,
+                })
+                .collect();
+            while nodes.last().is_some_and(Option::is_none) {
+                nodes.pop();
+            }
+
+            let mut shrunk = Arena::empty();
+            for node in nodes {
+                match node {
+                    Some(node) => {
+                        shrunk.push(Slot::Occupied(node));
+                        shrunk.len += 1;
+                    }
+                    None => {
+                        shrunk.push(Slot::Vacant(shrunk.free));
+                        shrunk.free = Some(shrunk.slots - 1);
+                    }
+                }
+            }
+            *self = shrunk;
+        }
+
+        /// Returns the slot for modification, copying the chunks on the path to it that are shared with another arena.
+        fn slot_mut(&mut self, idx: usize) -> &mut Slot<T> {
+            assert!(idx < self.slots, "NodeId must refer to a stored node");
+            let mut chunk = Arc::make_mut(&mut self.root);
+            let mut shift = self.height * CHUNK_BITS;
+            loop {
+                let child = (idx >> shift) % CHUNK_SIZE;
+                match chunk {
+                    Chunk::Inner(children) => {
+                        chunk = Arc::make_mut(&mut children[child]);
+                        shift -= CHUNK_BITS;
+                    }
+                    Chunk::Leaf(slots) => return &mut slots[child],
+                }
+            }
+        }
+
+        /// Appends a slot, adding a level of inner chunks above the root once all slots below it are used.
+        fn push(&mut self, slot: Slot<T>) {
+            if self.slots == CHUNK_SIZE << (self.height * CHUNK_BITS) {
+                let mut root = Vec::with_capacity(CHUNK_SIZE);
+                root.push(Arc::clone(&self.root));
+                self.root = Arc::new(Chunk::Inner(root));
+                self.height += 1;
+            }
+
+            let idx = self.slots;
+            self.slots += 1;
+            let mut chunk = Arc::make_mut(&mut self.root);
+            let mut shift = self.height * CHUNK_BITS;
+            loop {
+                let child = (idx >> shift) % CHUNK_SIZE;
+                match chunk {
+                    Chunk::Inner(children) => {
+                        shift -= CHUNK_BITS;
+                        if child == children.len() {
+                            children.push(Arc::new(Chunk::empty(shift)));
+                        }
+                        chunk = Arc::make_mut(&mut children[child]);
+                    }
+                    Chunk::Leaf(slots) => return slots.push(slot),
+                }
+            }
+        }
+    }
+
+    impl<T, A> Index<NodeId> for Arena<T, A> {
+        type Output = T;
+
+        fn index(&self, id: NodeId) -> &T {
+            self.get(id).expect("NodeId must refer to a stored node")
+        }
+    }
+
+    impl<T: Clone, A> IndexMut<NodeId> for Arena<T, A> {
+        fn index_mut(&mut self, id: NodeId) -> &mut T {
+            match self.slot_mut(id.0) {
+                Slot::Occupied(node) => Arc::make_mut(node),
+                Slot::Vacant(_) => panic!("NodeId must refer to a stored node"),
+            }
+        }
+    }
+}
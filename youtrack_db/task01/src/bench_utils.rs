@@ -0,0 +1,86 @@
+//! Generates the keys the benchmarks and the proptests run on, so both see the same distributions.
+//!
+//! The keys are derived from a seed by a xorshift generator, so a run can be reproduced
+//! without depending on a random number crate.
+
+use alloc::vec::Vec;
+
+/// A xorshift64 generator, which is fast and good enough to scatter keys.
+#[derive(Debug, Clone)]
+pub struct KeyRng(u64);
+
+impl KeyRng {
+    pub fn new(seed: u64) -> KeyRng {
+        // A zero state would stay zero forever, so the lowest bit is always set.
+        KeyRng((seed ^ 0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Returns a number below the bound, the slight bias of the modulo does not matter for keys.
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+
+    /// Shuffles the slice with the Fisher-Yates shuffle.
+    pub fn shuffle<T>(&mut self, slice: &mut [T]) {
+        for i in (1..slice.len()).rev() {
+            slice.swap(i, self.below(i + 1));
+        }
+    }
+}
+
+/// Short keys, the big-endian bytes of the numbers below the count in ascending order.
+pub fn sequential_keys(count: usize) -> Vec<[u8; 8]> {
+    (0..count as u64).map(u64::to_be_bytes).collect()
+}
+
+/// The keys of [`sequential_keys`] in a random order.
+pub fn shuffled_keys(count: usize, seed: u64) -> Vec<[u8; 8]> {
+    let mut keys = sequential_keys(count);
+    KeyRng::new(seed).shuffle(&mut keys);
+    keys
+}
+
+/// Short keys that are not among the [`sequential_keys`] of the count, but sort between and after them.
+pub fn missing_keys(count: usize, seed: u64) -> Vec<[u8; 9]> {
+    let mut rng = KeyRng::new(seed);
+    (0..count)
+        .map(|_| {
+            let mut key = [0; 9];
+            key[..8].copy_from_slice(&(rng.below(count.max(1) * 2) as u64).to_be_bytes());
+            key[8] = rng.next_u64() as u8;
+            key
+        })
+        .collect()
+}
+
+/// Keys of the given length in a random order, like paths they share long prefixes.
+///
+/// Each key starts with one of 16 random prefixes and ends with the big-endian bytes of its index,
+/// so the keys are distinct and diverge only in their last 8 bytes.
+///
+/// # Panics
+/// If the length is below 8.
+pub fn long_keys(count: usize, len: usize, seed: u64) -> Vec<Vec<u8>> {
+    assert!(len >= 8, "The keys end with an 8 byte index");
+    let mut rng = KeyRng::new(seed);
+    let prefixes: Vec<Vec<u8>> = (0..16)
+        .map(|_| (0..len - 8).map(|_| rng.next_u64() as u8).collect())
+        .collect();
+    let mut keys: Vec<Vec<u8>> = (0..count as u64)
+        .map(|i| [&prefixes[i as usize % 16][..], &i.to_be_bytes()].concat())
+        .collect();
+    rng.shuffle(&mut keys);
+    keys
+}
+
+/// The value stored under a key, the last 8 bytes of the key, so it is cheap to clone and checkable.
+pub fn value_of(key: &[u8]) -> Vec<u8> {
+    key[key.len().saturating_sub(8)..].to_vec()
+}
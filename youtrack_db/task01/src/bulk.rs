@@ -0,0 +1,279 @@
+//! Builds the nodes of a tree from entries in ascending key order in a single pass, see [`TSIMTree::from_sorted_iter`].
+//! [`TSIMTree::compact`] rebuilds the nodes of a tree the same way.
+//!
+//! The loader keeps a frame for each node on the path to the previous key that may still receive children.
+//! A new key closes the frames below the byte at which it differs from the previous key and is appended
+//! to the deepest remaining frame, so no key is looked up from the root and no node is ever split.
+//!
+//! A frame fills its node up to the configured number of children. Then it moves the node into the arena,
+//! starts the next one and remembers the first segment of the new node as its lower bound.
+//! When the frame is closed, the nodes it filled are connected by routing nodes like those of
+//! `TSIMTreeNodes::split`, which are filled in the same way and stacked until a single node remains.
+//!
+//! [`TSIMTree::from_sorted_iter`]: crate::TSIMTree::from_sorted_iter
+//! [`TSIMTree::compact`]: crate::TSIMTree::compact
+
+use crate::arena::{Arena, NodeId};
+use crate::{
+    compare, BulkLoadError, TSIMTreeNode, TSIMTreeNodeChild, TSIMTreeNodes, TSIMTreeWalker,
+};
+use alloc::vec;
+use alloc::vec::Vec;
+use allocator_api2::alloc::Allocator;
+
+/// Builds nodes from the entries, each holding at most `children_per_node` children.
+///
+/// The keys must be strictly ascending, otherwise the index of the first key that is not is returned.
+pub(crate) fn load<const RADIX: usize, const LINE: usize, I>(
+    entries: I,
+    children_per_node: usize,
+) -> Result<TSIMTreeNodes<RADIX, LINE>, BulkLoadError>
+where
+    I: Iterator<Item = (Vec<u8>, Vec<u8>)>,
+{
+    let mut loader = BulkLoader::new(TSIMTreeNodes::empty(), children_per_node);
+    for (index, (key, value)) in entries.enumerate() {
+        if index > 0 && key <= loader.previous_key {
+            return Err(BulkLoadError::UnsortedKeys { index });
+        }
+        let value = TSIMTreeNodeChild::Value(loader.nodes.values.store(value), None);
+        loader.push(&key, value);
+    }
+    Ok(loader.finish())
+}
+
+/// Moves the unexpired entries of the nodes into new nodes, each holding at most `children_per_node` children.
+///
+/// The values are moved as they are, and the old nodes are removed while they are visited.
+pub(crate) fn rebuild<const RADIX: usize, const LINE: usize, A: Allocator + Clone>(
+    nodes: &mut TSIMTreeNodes<RADIX, LINE, A>,
+    children_per_node: usize,
+) {
+    let mut old_nodes = nodes.take();
+    let mut loader = BulkLoader::new(old_nodes.empty_like(), children_per_node);
+    let mut walker = TSIMTreeWalker::new(old_nodes.root);
+    while let Some((key, value, expiry)) = walker.take_next_value(&mut old_nodes) {
+        loader.push(key, TSIMTreeNodeChild::Value(value, expiry));
+    }
+    *nodes = loader.finish();
+}
+
+struct BulkLoader<const RADIX: usize, const LINE: usize, A: Allocator + Clone> {
+    nodes: TSIMTreeNodes<RADIX, LINE, A>,
+    /// The frames on the path to the previous key, starting at the root.
+    frames: Vec<Frame<RADIX, LINE, A>>,
+    previous_key: Vec<u8>,
+    children_per_node: usize,
+}
+
+/// A node that may still receive children.
+///
+/// Unless it is the last frame, its last child is the node of the next frame,
+/// which is only inserted once that frame is closed. Room for it is made when the next frame is opened.
+struct Frame<const RADIX: usize, const LINE: usize, A: Allocator + Clone> {
+    /// The number of key bytes consumed by the nodes above.
+    offset: usize,
+    /// The node that is being filled.
+    node: TSIMTreeNode<RADIX, LINE, A>,
+    /// The filled nodes by their lower bound, each level is routed to by the level above.
+    levels: Vec<Vec<(Vec<u8>, NodeId)>>,
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> BulkLoader<RADIX, LINE, A> {
+    const MAX_STORED_KEY_SEGMENT_SIZE: usize =
+        TSIMTreeNodes::<RADIX, LINE, A>::MAX_STORED_KEY_SEGMENT_SIZE;
+
+    /// Creates a loader that fills the empty nodes.
+    fn new(
+        nodes: TSIMTreeNodes<RADIX, LINE, A>,
+        children_per_node: usize,
+    ) -> BulkLoader<RADIX, LINE, A> {
+        assert!(
+            (2..=RADIX).contains(&children_per_node),
+            "A node must hold between 2 and RADIX children"
+        );
+        BulkLoader {
+            nodes,
+            frames: vec![Frame::new(0)],
+            previous_key: Vec::new(),
+            children_per_node,
+        }
+    }
+
+    /// Appends a value child for the key, which must sort after the previous key.
+    fn push(&mut self, key: &[u8], value: TSIMTreeNodeChild<RADIX, LINE, A>) {
+        let common = compare::common_prefix_len(&self.previous_key, key);
+        while self.top().offset > common {
+            self.close_top();
+        }
+
+        // The last child of the frame shares the bytes between the frame and the common prefix with the key,
+        // so it is moved below a new frame that holds these bytes. If it is the value of the previous key,
+        // which is a prefix of the key, it ends up under the empty segment of the new frame.
+        let children_per_node = self.children_per_node;
+        let frame = self.top();
+        let shared = common - frame.offset;
+        if shared > 0 {
+            let last_idx = frame.node.children_count() - 1;
+            let segment = frame.node.get_segment(last_idx).to_vec();
+            let child = frame.node.remove_child(last_idx);
+            let mut below = Frame::new(frame.offset + shared);
+            below.node.insert_child(0, &segment[shared..], child);
+            self.frames.push(below);
+        }
+
+        // Keys that do not fit into a single segment continue in a chain of frames.
+        loop {
+            let frame = self
+                .frames
+                .last_mut()
+                .expect("the frame of the root is never closed");
+            frame.make_room(&mut self.nodes.arena, children_per_node);
+            let remaining_key = &key[frame.offset..];
+            if remaining_key.len() <= Self::MAX_STORED_KEY_SEGMENT_SIZE {
+                let idx = frame.node.children_count();
+                frame.node.insert_child(idx, remaining_key, value);
+                break;
+            }
+            let offset = frame.offset + Self::MAX_STORED_KEY_SEGMENT_SIZE;
+            self.frames.push(Frame::new(offset));
+        }
+        self.previous_key.clear();
+        self.previous_key.extend_from_slice(key);
+    }
+
+    fn top(&mut self) -> &mut Frame<RADIX, LINE, A> {
+        self.frames
+            .last_mut()
+            .expect("the frame of the root is never closed")
+    }
+
+    /// Closes the last frame and inserts its node as the last child of the frame above.
+    fn close_top(&mut self) {
+        let frame = self
+            .frames
+            .pop()
+            .expect("only frames below the root are closed");
+        let offset = frame.offset;
+        let node = frame.close(&mut self.nodes.arena, self.children_per_node);
+        let node = self.nodes.arena.insert(node);
+
+        let parent = self
+            .frames
+            .last_mut()
+            .expect("the frame of the root is never closed");
+        let segment = &self.previous_key[parent.offset..offset];
+        let idx = parent.node.children_count();
+        parent
+            .node
+            .insert_child(idx, segment, TSIMTreeNodeChild::Node(node));
+    }
+
+    fn finish(mut self) -> TSIMTreeNodes<RADIX, LINE, A> {
+        while self.frames.len() > 1 {
+            self.close_top();
+        }
+        let root = self
+            .frames
+            .pop()
+            .expect("the frame of the root is never closed");
+        let root = root.close(&mut self.nodes.arena, self.children_per_node);
+        let root_id = self.nodes.root;
+        self.nodes.arena[root_id] = root;
+        self.nodes
+    }
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Frame<RADIX, LINE, A> {
+    fn new(offset: usize) -> Frame<RADIX, LINE, A> {
+        Frame {
+            offset,
+            node: TSIMTreeNode::empty(),
+            levels: Vec::new(),
+        }
+    }
+
+    /// Moves the node into the arena once it holds `children_per_node` children and starts the next one.
+    fn make_room(
+        &mut self,
+        arena: &mut Arena<TSIMTreeNode<RADIX, LINE, A>, A>,
+        children_per_node: usize,
+    ) {
+        if self.node.children_count() < children_per_node {
+            return;
+        }
+        let lower_bound = self.node.get_segment(0).to_vec();
+        let node = core::mem::replace(&mut self.node, TSIMTreeNode::empty());
+        let node = arena.insert(node);
+        self.route(arena, children_per_node, 0, lower_bound, node);
+    }
+
+    /// Adds the node to the given level, a full level is moved below a routing node on the level above first.
+    fn route(
+        &mut self,
+        arena: &mut Arena<TSIMTreeNode<RADIX, LINE, A>, A>,
+        children_per_node: usize,
+        mut level: usize,
+        mut lower_bound: Vec<u8>,
+        mut node: NodeId,
+    ) {
+        loop {
+            if level == self.levels.len() {
+                self.levels.push(Vec::new());
+            }
+            if self.levels[level].len() < children_per_node {
+                self.levels[level].push((lower_bound, node));
+                return;
+            }
+            let full_level = core::mem::replace(&mut self.levels[level], vec![(lower_bound, node)]);
+            (lower_bound, node) = Self::routing_node(arena, full_level);
+            level += 1;
+        }
+    }
+
+    /// Moves a routing node that leads to the nodes into the arena and returns it with its lower bound.
+    fn routing_node(
+        arena: &mut Arena<TSIMTreeNode<RADIX, LINE, A>, A>,
+        nodes: Vec<(Vec<u8>, NodeId)>,
+    ) -> (Vec<u8>, NodeId) {
+        let mut routing_node = TSIMTreeNode::empty();
+        routing_node.routing = true;
+        let mut nodes = nodes.into_iter();
+        let (lower_bound, first) = nodes.next().expect("a routing node leads to a node");
+        // The lower bound of the first node is the lower bound of the routing node, so its segment is empty.
+        routing_node.insert_child(0, &[], TSIMTreeNodeChild::Node(first));
+        for (idx, (segment, node)) in nodes.enumerate() {
+            routing_node.insert_child(idx + 1, &segment, TSIMTreeNodeChild::Node(node));
+        }
+        (lower_bound, arena.insert(routing_node))
+    }
+
+    /// Returns the node that leads to all children of the frame.
+    fn close(
+        mut self,
+        arena: &mut Arena<TSIMTreeNode<RADIX, LINE, A>, A>,
+        children_per_node: usize,
+    ) -> TSIMTreeNode<RADIX, LINE, A> {
+        if self.levels.is_empty() {
+            return self.node;
+        }
+        let node = core::mem::replace(&mut self.node, TSIMTreeNode::empty());
+        let lower_bound = node.get_segment(0).to_vec();
+        let node = arena.insert(node);
+        self.route(arena, children_per_node, 0, lower_bound, node);
+
+        // Route each level from the level above, a single node is moved up as it is.
+        let mut level = 0;
+        while level + 1 < self.levels.len() || self.levels[level].len() > 1 {
+            let mut nodes = core::mem::take(&mut self.levels[level]);
+            let (lower_bound, node) = match nodes.len() {
+                1 => nodes.pop().expect("the level holds a node"),
+                _ => Self::routing_node(arena, nodes),
+            };
+            self.route(arena, children_per_node, level + 1, lower_bound, node);
+            level += 1;
+        }
+        let (_, root) = self.levels[level].pop().expect("the level holds a node");
+        arena.remove(root)
+    }
+}
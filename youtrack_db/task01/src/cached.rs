@@ -0,0 +1,231 @@
+//! Caches the values a thread read from a tree, so repeated reads of hot keys do not touch the lock of the tree.
+//!
+//! Each thread keeps a single cache, which belongs to the tree the thread read from last. Every write to a tree
+//! bumps its generation, and a cache filled at an older generation is cleared before it is used again,
+//! so a thread never reads a value that another thread already replaced. Reading another tree clears the cache
+//! as well. The cache therefore pays off for keys that are read many times between two writes.
+
+use crate::{TSIMTree, CACHE_LINE_SIZE, TREE_RADIX};
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+use std::collections::HashMap;
+
+/// Hands out the ids that tell the caches of different trees apart.
+static NEXT_TREE_ID: AtomicU64 = AtomicU64::new(0);
+
+std::thread_local! {
+    static CACHE: RefCell<ThreadCache> = RefCell::new(ThreadCache::new());
+}
+
+/// A thread-safe sorted in-memory tree whose readers keep the values they read in a thread-local LRU cache.
+///
+/// A cached read neither acquires the read lock nor descends the tree. Writes go to the tree and outdate
+/// the caches of all threads, so reads that start after a write returned see the written value.
+#[derive(Debug)]
+pub struct CachedTSIMTree<const RADIX: usize = TREE_RADIX, const LINE: usize = CACHE_LINE_SIZE> {
+    tree: TSIMTree<RADIX, LINE>,
+    /// Tells the cached values of this tree apart from those of other trees.
+    id: u64,
+    /// Counts the writes, values cached at an older generation are outdated.
+    generation: AtomicU64,
+    /// The number of keys each thread caches.
+    cache_capacity: usize,
+}
+
+impl CachedTSIMTree {
+    /// Creates an empty tree whose readers each cache the results of up to `cache_capacity` keys.
+    /// No values are cached if the capacity is zero.
+    pub fn new(cache_capacity: usize) -> CachedTSIMTree {
+        CachedTSIMTree::from_tree(TSIMTree::new(), cache_capacity)
+    }
+}
+
+impl<const RADIX: usize, const LINE: usize> CachedTSIMTree<RADIX, LINE> {
+    fn from_tree(
+        tree: TSIMTree<RADIX, LINE>,
+        cache_capacity: usize,
+    ) -> CachedTSIMTree<RADIX, LINE> {
+        CachedTSIMTree {
+            tree,
+            id: NEXT_TREE_ID.fetch_add(1, Ordering::Relaxed),
+            generation: AtomicU64::new(0),
+            cache_capacity,
+        }
+    }
+
+    /// Returns the number of keys each thread caches.
+    pub fn cache_capacity(&self) -> usize {
+        self.cache_capacity
+    }
+
+    pub fn put<K>(&self, k: K, v: Vec<u8>)
+    where
+        K: AsRef<[u8]>,
+    {
+        self.tree.put(k, v);
+        self.outdate_caches();
+    }
+
+    /// Returns the value stored under the key.
+    ///
+    /// The result is taken from the cache of the current thread if it holds the key, missing keys are cached too.
+    pub fn get<K>(&self, k: K) -> Option<Vec<u8>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = k.as_ref();
+        if self.cache_capacity == 0 {
+            return self.tree.get(key);
+        }
+
+        // The generation is read before the tree, so a write the read misses bumps it afterwards
+        // and the cached value is dropped before it is read again.
+        let generation = self.generation.load(Ordering::Acquire);
+        // The cache is gone while the thread is torn down, the tree is read directly then.
+        CACHE
+            .try_with(|cache| {
+                let mut cache = cache.borrow_mut();
+                cache.claim(self.id, generation, self.cache_capacity);
+                if let Some(value) = cache.get(key) {
+                    return value.clone();
+                }
+                let value = self.tree.get(key);
+                cache.insert(key, value.clone());
+                value
+            })
+            .unwrap_or_else(|_| self.tree.get(key))
+    }
+
+    /// Removes the key from the tree and returns the value that was stored under it.
+    pub fn remove<K>(&self, k: K) -> Option<Vec<u8>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let value = self.tree.remove(k);
+        self.outdate_caches();
+        value
+    }
+
+    /// Returns the wrapped tree, the values cached from it are never read again.
+    pub fn into_inner(self) -> TSIMTree<RADIX, LINE> {
+        self.tree
+    }
+
+    /// Bumps the generation once a write is done, but before it returns.
+    fn outdate_caches(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Marks the ends of the list of slots.
+const NONE: usize = usize::MAX;
+
+/// The results of the lookups a thread made in one tree, the least recently used key is evicted first.
+#[derive(Debug)]
+pub(crate) struct ThreadCache {
+    /// The id of the tree the results were read from, together with the generation of the tree at the time.
+    owner: Option<(u64, u64)>,
+    capacity: usize,
+    slots_by_key: HashMap<Vec<u8>, usize>,
+    /// The slots form a list from the most to the least recently used key.
+    slots: Vec<Slot>,
+    most_recent: usize,
+    least_recent: usize,
+}
+
+#[derive(Debug)]
+struct Slot {
+    key: Vec<u8>,
+    value: Option<Vec<u8>>,
+    newer: usize,
+    older: usize,
+}
+
+impl ThreadCache {
+    pub(crate) fn new() -> ThreadCache {
+        ThreadCache {
+            owner: None,
+            capacity: 0,
+            slots_by_key: HashMap::new(),
+            slots: Vec::new(),
+            most_recent: NONE,
+            least_recent: NONE,
+        }
+    }
+
+    /// Makes the cache hold the results of the tree at the generation.
+    /// The results of other trees or of other generations are dropped.
+    pub(crate) fn claim(&mut self, tree: u64, generation: u64, capacity: usize) {
+        if self.owner == Some((tree, generation)) {
+            return;
+        }
+        self.owner = Some((tree, generation));
+        self.capacity = capacity;
+        self.slots_by_key.clear();
+        self.slots.clear();
+        self.most_recent = NONE;
+        self.least_recent = NONE;
+    }
+
+    /// Returns the cached result of looking up the key and marks the key as the most recently used one.
+    pub(crate) fn get(&mut self, key: &[u8]) -> Option<&Option<Vec<u8>>> {
+        let slot = *self.slots_by_key.get(key)?;
+        self.unlink(slot);
+        self.link_as_most_recent(slot);
+        Some(&self.slots[slot].value)
+    }
+
+    /// Caches the result of looking up the key, which must not be cached yet.
+    /// If the cache is full, the least recently used key is evicted.
+    pub(crate) fn insert(&mut self, key: &[u8], value: Option<Vec<u8>>) {
+        assert!(
+            self.capacity > 0,
+            "A cache without capacity cannot hold a key"
+        );
+
+        let slot = match self.slots.len() < self.capacity {
+            true => {
+                self.slots.push(Slot {
+                    key: key.to_vec(),
+                    value,
+                    newer: NONE,
+                    older: NONE,
+                });
+                self.slots.len() - 1
+            }
+            false => {
+                let slot = self.least_recent;
+                self.unlink(slot);
+                let evicted = core::mem::replace(&mut self.slots[slot].key, key.to_vec());
+                self.slots_by_key.remove(&evicted);
+                self.slots[slot].value = value;
+                slot
+            }
+        };
+        self.slots_by_key.insert(key.to_vec(), slot);
+        self.link_as_most_recent(slot);
+    }
+
+    fn unlink(&mut self, slot: usize) {
+        let (newer, older) = (self.slots[slot].newer, self.slots[slot].older);
+        match newer {
+            NONE => self.most_recent = older,
+            newer => self.slots[newer].older = older,
+        }
+        match older {
+            NONE => self.least_recent = newer,
+            older => self.slots[older].newer = newer,
+        }
+    }
+
+    fn link_as_most_recent(&mut self, slot: usize) {
+        self.slots[slot].newer = NONE;
+        self.slots[slot].older = self.most_recent;
+        match self.most_recent {
+            NONE => self.least_recent = slot,
+            most_recent => self.slots[most_recent].newer = slot,
+        }
+        self.most_recent = slot;
+    }
+}
@@ -0,0 +1,124 @@
+//! Encodes the entries of a tree into a flat byte buffer and rebuilds trees from such buffers.
+//!
+//! The buffer starts with the magic bytes `TSIM` and a version byte, the current version is 1.
+//! The entries follow in ascending key order until the end of the buffer, each entry consists of
+//!
+//! - the number of leading bytes the key shares with the previous key,
+//! - the number of remaining key bytes, followed by these bytes,
+//! - the length of the value, followed by the value.
+//!
+//! All numbers are unsigned LEB128 varints. The first key shares no bytes with a previous key.
+//!
+//! Only the entries are encoded, not the nodes, so a buffer can be decoded into a tree with a different layout.
+//! Expired entries are skipped and the expiry of the other entries is not encoded,
+//! since an instant is only meaningful within the process that created it.
+
+use crate::{TSIMTreeDecodeError, TSIMTreeNodes};
+use alloc::vec::Vec;
+use allocator_api2::alloc::Allocator;
+
+pub(crate) const MAGIC: &[u8; 4] = b"TSIM";
+pub(crate) const VERSION: u8 = 1;
+
+pub(crate) fn encode<const RADIX: usize, const LINE: usize, A: Allocator + Clone>(
+    nodes: &TSIMTreeNodes<RADIX, LINE, A>,
+) -> Vec<u8> {
+    let mut buf = Vec::from(*MAGIC);
+    buf.push(VERSION);
+
+    let mut previous_key: Vec<u8> = Vec::new();
+    nodes.for_each_entry(|key, value| {
+        let shared = previous_key
+            .iter()
+            .zip(key)
+            .take_while(|(previous_byte, key_byte)| previous_byte == key_byte)
+            .count();
+        write_varint(&mut buf, shared);
+        write_varint(&mut buf, key.len() - shared);
+        buf.extend_from_slice(&key[shared..]);
+        write_varint(&mut buf, value.len());
+        buf.extend_from_slice(value);
+
+        previous_key.truncate(shared);
+        previous_key.extend_from_slice(&key[shared..]);
+    });
+    buf
+}
+
+pub(crate) fn decode<const RADIX: usize, const LINE: usize>(
+    buf: &[u8],
+) -> Result<TSIMTreeNodes<RADIX, LINE>, TSIMTreeDecodeError> {
+    let mut reader = Reader { buf };
+    if reader.bytes(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+        return Err(TSIMTreeDecodeError::InvalidMagic);
+    }
+    let version = reader.bytes(1)?[0];
+    if version != VERSION {
+        return Err(TSIMTreeDecodeError::UnsupportedVersion { version });
+    }
+
+    let mut nodes = TSIMTreeNodes::empty();
+    let mut key: Vec<u8> = Vec::new();
+    let mut first = true;
+    while !reader.buf.is_empty() {
+        let shared = reader.varint()?;
+        if shared > key.len() {
+            return Err(TSIMTreeDecodeError::InvalidSharedPrefix { shared });
+        }
+        let suffix_len = reader.varint()?;
+        let suffix = reader.bytes(suffix_len)?;
+        // The key sorts after the previous key if the new suffix sorts after the old one.
+        if !first && suffix <= &key[shared..] {
+            return Err(TSIMTreeDecodeError::UnsortedKeys);
+        }
+        key.truncate(shared);
+        key.extend_from_slice(suffix);
+        let value_len = reader.varint()?;
+        let value = reader.bytes(value_len)?;
+
+        nodes.put(&key, value.to_vec());
+        first = false;
+    }
+    Ok(nodes)
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+/// Consumes the buffer from the front.
+struct Reader<'b> {
+    buf: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    fn bytes(&mut self, len: usize) -> Result<&'b [u8], TSIMTreeDecodeError> {
+        if len > self.buf.len() {
+            return Err(TSIMTreeDecodeError::UnexpectedEnd);
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<usize, TSIMTreeDecodeError> {
+        let mut n: usize = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.bytes(1)?[0];
+            let bits = (byte & 0x7f) as usize;
+            if shift >= usize::BITS || (bits << shift) >> shift != bits {
+                return Err(TSIMTreeDecodeError::InvalidVarint);
+            }
+            n |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+            shift += 7;
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Compares stored key segments with keys.
+//!
+//! By default the segments are compared byte by byte.
+//!
+//! With the `simd` feature, x86_64 targets compare 16 bytes at once with SSE2, which every x86_64 CPU supports.
+//! If the target enables SSE4.2 (e.g. `-C target-cpu=native`), `_mm_cmpestri` finds the first mismatch
+//! of 16 bytes in a single instruction instead. AVX2 is not used, as the segments of the default layout
+//! only hold 7 bytes and already fit into a single SSE register.
+//! For such short segments copying the bytes into the registers costs more than the comparison saves,
+//! so the feature is off by default; `resolve_seven_byte_segments` in the benches measures the difference.
+//! The bytes behind the shorter slice are never compared, so the padding of a segment buffer cannot affect the result.
+//! Other targets fall back to the byte by byte comparison.
+
+use core::cmp::Ordering;
+
+#[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+pub(crate) use scalar::common_prefix_len;
+
+#[cfg(all(
+    feature = "simd",
+    target_arch = "x86_64",
+    not(target_feature = "sse4.2")
+))]
+pub(crate) use sse2::common_prefix_len;
+
+#[cfg(all(feature = "simd", target_arch = "x86_64", target_feature = "sse4.2"))]
+pub(crate) use sse42::common_prefix_len;
+
+/// Compares the segment with the key like slices are compared, i.e. a proper prefix sorts first.
+pub(crate) fn compare(segment: &[u8], key: &[u8]) -> Ordering {
+    let common = common_prefix_len(segment, key);
+    match (segment.get(common), key.get(common)) {
+        (Some(segment_byte), Some(key_byte)) => segment_byte.cmp(key_byte),
+        _ => segment.len().cmp(&key.len()),
+    }
+}
+
+pub(crate) mod scalar {
+    /// Returns the amount of leading bytes the segment and the key have in common.
+    #[cfg_attr(all(feature = "simd", target_arch = "x86_64"), allow(dead_code))]
+    pub(crate) fn common_prefix_len(segment: &[u8], key: &[u8]) -> usize {
+        segment
+            .iter()
+            .zip(key)
+            .take_while(|(segment_byte, key_byte)| segment_byte == key_byte)
+            .count()
+    }
+}
+
+/// Copies the chunk at the offset into a zeroed buffer, so a 16 byte load never reads past the end of the slice.
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+fn load_chunk(bytes: &[u8], offset: usize, chunk_len: usize) -> [u8; 16] {
+    let mut chunk = [0; 16];
+    chunk[..chunk_len].copy_from_slice(&bytes[offset..offset + chunk_len]);
+    chunk
+}
+
+#[cfg(all(
+    feature = "simd",
+    target_arch = "x86_64",
+    not(target_feature = "sse4.2")
+))]
+mod sse2 {
+    use core::arch::x86_64::{__m128i, _mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8};
+
+    const LANES: usize = 16;
+
+    /// Returns the amount of leading bytes the segment and the key have in common.
+    pub(crate) fn common_prefix_len(segment: &[u8], key: &[u8]) -> usize {
+        let len = segment.len().min(key.len());
+        let mut offset = 0;
+        while offset < len {
+            let chunk_len = (len - offset).min(LANES);
+            let segment_chunk = super::load_chunk(segment, offset, chunk_len);
+            let key_chunk = super::load_chunk(key, offset, chunk_len);
+
+            // SAFETY: SSE2 is part of the x86_64 baseline and both loads read 16 bytes from 16 byte buffers.
+            let equal_bytes = unsafe {
+                let segment_chunk = _mm_loadu_si128(segment_chunk.as_ptr().cast::<__m128i>());
+                let key_chunk = _mm_loadu_si128(key_chunk.as_ptr().cast::<__m128i>());
+                _mm_movemask_epi8(_mm_cmpeq_epi8(segment_chunk, key_chunk)) as u32
+            };
+            // Only the lanes within the chunk are considered, the zeroed lanes behind it always match.
+            let mismatches = !equal_bytes & ((1 << chunk_len) - 1);
+            if mismatches != 0 {
+                return offset + mismatches.trailing_zeros() as usize;
+            }
+            offset += chunk_len;
+        }
+        len
+    }
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64", target_feature = "sse4.2"))]
+mod sse42 {
+    use core::arch::x86_64::{
+        __m128i, _mm_cmpestri, _mm_loadu_si128, _SIDD_CMP_EQUAL_EACH, _SIDD_NEGATIVE_POLARITY,
+        _SIDD_UBYTE_OPS,
+    };
+
+    const LANES: usize = 16;
+
+    /// Returns the amount of leading bytes the segment and the key have in common.
+    pub(crate) fn common_prefix_len(segment: &[u8], key: &[u8]) -> usize {
+        let len = segment.len().min(key.len());
+        let mut offset = 0;
+        while offset < len {
+            let chunk_len = (len - offset).min(LANES);
+            let segment_chunk = super::load_chunk(segment, offset, chunk_len);
+            let key_chunk = super::load_chunk(key, offset, chunk_len);
+
+            // SAFETY: The target enables SSE4.2 and both loads read 16 bytes from 16 byte buffers.
+            // The explicit lengths limit the comparison to the chunk, the index of the first
+            // mismatching byte is returned, or 16 if all bytes within the chunk are equal.
+            let mismatch = unsafe {
+                let segment_chunk = _mm_loadu_si128(segment_chunk.as_ptr().cast::<__m128i>());
+                let key_chunk = _mm_loadu_si128(key_chunk.as_ptr().cast::<__m128i>());
+                _mm_cmpestri::<{ _SIDD_UBYTE_OPS | _SIDD_CMP_EQUAL_EACH | _SIDD_NEGATIVE_POLARITY }>(
+                    segment_chunk,
+                    chunk_len as i32,
+                    key_chunk,
+                    chunk_len as i32,
+                ) as usize
+            };
+            if mismatch < chunk_len {
+                return offset + mismatch;
+            }
+            offset += chunk_len;
+        }
+        len
+    }
+}
@@ -1,594 +1,10235 @@
-use std::array;
-use std::cmp::Ordering;
-use std::fmt::Debug;
-use std::sync::RwLock;
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+// The tests use threads and the collections of std as references, even when the crate itself is no_std.
+#[cfg(all(test, not(feature = "std")))]
+extern crate std;
+
+use alloc::borrow::ToOwned;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use allocator_api2::alloc::{Allocator, Global};
+use core::cmp::Ordering;
+use core::fmt::{Debug, Display};
+use core::hash::{Hash, Hasher};
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use smallvec::SmallVec;
+#[cfg(feature = "std")]
+use std::thread::JoinHandle;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+mod arena;
+#[cfg(any(test, feature = "bench-utils"))]
+pub mod bench_utils;
+mod bulk;
+#[cfg(feature = "thread-local-cache")]
+mod cached;
+mod codec;
+mod compare;
+mod persistent;
+mod recency;
+mod sharded;
+mod sync;
+mod typed;
+use arena::{Arena, NodeId};
+#[cfg(feature = "thread-local-cache")]
+pub use cached::CachedTSIMTree;
+pub use persistent::{PersistentTSIMTree, PersistentTSIMTreeIter};
+use recency::Recency;
+pub use sharded::{ShardedTSIMTree, ShardedTSIMTreeIter};
+use sync::{Lock, RootLock, RootReadGuard, RootWriteGuard};
+pub use typed::{TSIMTreeKey, TypedTSIMTree, TypedTSIMTreeIter};
 
 const CACHE_LINE_SIZE: usize = 128;
 const TREE_RADIX: usize = 16;
+/// The number of children stored within a node with the `sparse-nodes` feature, more children are moved to the heap.
+/// Six children still fit into the cache lines the default node is aligned to.
+#[cfg(feature = "sparse-nodes")]
+const INLINE_CHILDREN: usize = 6;
+/// The length of the longest value stored within its node, which takes the place of the pointer and the capacity
+/// of a `Vec`, see [`StoredValue`].
+const INLINE_VALUE_LEN: usize = 16;
 
+/// A thread-safe sorted in-memory tree.
+///
+/// `RADIX` is the maximum number of children of a node and `LINE` the size of the buffer storing their key segments.
+/// Each key segment therefore takes `LINE / RADIX` bytes, one of which stores its length.
 #[derive(Debug)]
-pub struct TSIMTree {
-    root: RwLock<TSIMTreeNode>,
+pub struct TSIMTree<
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    root: RootLock<TSIMTreeNodes<RADIX, LINE, A>>,
+    /// Only trees created with [`TSIMTree::with_capacity`] track the recency of their entries.
+    /// The lock is always acquired while holding the root lock.
+    recency: Option<Lock<Recency<RADIX, LINE>>>,
+    /// Called with the entries that are evicted, see [`TSIMTree::on_evict`].
+    on_evict: Option<EvictionHook>,
 }
 
-impl TSIMTree {
-    pub fn new() -> TSIMTree {
-        TSIMTree {
-            root: RwLock::new(TSIMTreeNode::empty()),
-        }
-    }
+/// The hook of [`TSIMTree::on_evict`], clones of a tree share it.
+#[derive(Clone)]
+struct EvictionHook(Arc<dyn Fn(Vec<u8>, Vec<u8>) + Send + Sync>);
 
-    pub fn put<K>(&self, k: K, v: Vec<u8>)
-    where
-        K: AsRef<[u8]>,
-    {
-        let mut key: &[u8] = k.as_ref();
-        let mut node_guard = self
-            .root
-            .write()
-            .expect("Must be able to acquire write lock");
-        let mut node: &mut TSIMTreeNode = &mut node_guard;
+impl Debug for EvictionHook {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("EvictionHook")
+    }
+}
 
-        loop {
-            match node.resolve_child(key) {
-                ResolvedChild::Smallest => {
-                    if (node.children_count as usize) < TREE_RADIX {
-                        if let Some((key_fragment, remaining_key)) =
-                            key.split_at_checked(MAX_STORED_KEY_SEGMENT_SIZE)
-                        {
-                            node.insert_child(
-                                0,
-                                key_fragment,
-                                TSIMTreeNodeChild::with_mapping(remaining_key, v),
-                            );
-                            break;
-                        } else {
-                            node.insert_child(0, key, TSIMTreeNodeChild::Value(v));
-                            break;
-                        }
-                    }
+impl<const RADIX: usize, const LINE: usize> Default for TSIMTree<RADIX, LINE> {
+    fn default() -> Self {
+        TSIMTree::from_nodes(TSIMTreeNodes::empty())
+    }
+}
 
-                    // There is no space in this node, so we must replace the key_segment in this node with the new segment.
-                    // But what do we do with the old key? We dont know which
-                    let old_key_fragment = node.get_segment(0).to_owned();
-                    let child = node.children[0]
-                        .as_mut()
-                        .expect("node.children[0] must be Some(..)");
-                    child.pushdown_children_under_key(&old_key_fragment);
+/// Takes a snapshot of the tree, later modifications of either tree are not visible in the other.
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Clone
+    for TSIMTree<RADIX, LINE, A>
+{
+    fn clone(&self) -> Self {
+        let node_guard = self.root.read();
+        TSIMTree {
+            root: RootLock::new(node_guard.clone()),
+            recency: self
+                .recency
+                .as_ref()
+                .map(|recency| Lock::new(recency.lock().clone())),
+            on_evict: self.on_evict.clone(),
+        }
+    }
+}
 
-                    let (new_key_fragment, remaining_key) = key.split_at(old_key_fragment.len());
+/// Renders the tree as an outline with one line per child, indented by its depth.
+///
+/// Segments and values are shown as escaped ASCII, routing pivots are prefixed with `>=`.
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Display
+    for TSIMTree<RADIX, LINE, A>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let node_guard = self.root.read();
+        node_guard.fmt_outline(f)
+    }
+}
 
-                    node.set_segment(0, new_key_fragment);
-                    let child = node.children[0].as_mut();
+/// Two trees are equal if they store the same entries, regardless of how their nodes are structured.
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> PartialEq
+    for TSIMTree<RADIX, LINE, A>
+{
+    fn eq(&self, other: &Self) -> bool {
+        if core::ptr::eq(self, other) {
+            return true;
+        }
 
-                    let TSIMTreeNodeChild::Node(n) =
-                        child.expect("node.children[0] must be Some(..)")
-                    else {
-                        panic!("remaining_key is not empty, so new_node must be TSIMTreeNodeChild::Node(..)")
-                    };
-                    node = n;
-                    key = remaining_key;
-                }
+        let (node_guard, other_guard) = self.read_both(other);
+        node_guard.entries().eq(other_guard.entries())
+    }
+}
 
-                ResolvedChild::ExactMatch(segment, remaining_key) => {
-                    let borrowed_child = node.children[segment].as_mut();
-                    let child = borrowed_child.expect("children[child_idx] must be Some(..)");
-                    match child {
-                        TSIMTreeNodeChild::Value(old_val) if remaining_key.is_empty() => {
-                            *old_val = v;
-                            break;
-                        }
-                        TSIMTreeNodeChild::Value(old_val) => {
-                            // The existing value is stored under a prefix of the new value.
-                            // We must replace the value with a new Node that contains the old value AND the new one.
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Eq for TSIMTree<RADIX, LINE, A> {}
 
-                            let mut new_node = TSIMTreeNodeChild::with_mapping(remaining_key, v);
-                            let TSIMTreeNodeChild::Node(n) = &mut new_node else {
-                                panic!("remaining_key is not empty, so new_node must be TSIMTreeNodeChild::Node(..)")
-                            };
-                            n.insert_child(0, &[], TSIMTreeNodeChild::Value(old_val.to_owned()));
-                            *child = new_node;
-                            break;
-                        }
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> PartialOrd
+    for TSIMTree<RADIX, LINE, A>
+{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
 
-                        TSIMTreeNodeChild::Node(new_node) => {
-                            node = new_node;
-                            key = remaining_key;
-                        }
-                    }
-                }
-                ResolvedChild::InDomainOf(segment) => {
-                    let borrowed_child = node.children[segment].as_mut();
-                    let child = borrowed_child.expect("children[child_idx] must be Some(..)");
-                    match child {
-                        TSIMTreeNodeChild::Value(old_val) => {
-                            // We must insert a new node to house old value together with the new value.
-
-                            let mut new_node = TSIMTreeNodeChild::with_mapping(key, v);
-                            let TSIMTreeNodeChild::Node(n) = &mut new_node else {
-                                panic!("remaining_key is not empty, so new_node must be TSIMTreeNodeChild::Node(..)")
-                            };
-                            n.insert_child(0, &[], TSIMTreeNodeChild::Value(old_val.to_owned()));
-                            *child = new_node;
-                            break;
-                        }
-                        TSIMTreeNodeChild::Node(new_node) => {
-                            node = new_node;
-                        }
-                    }
-                }
-            };
+/// Compares the entries in ascending key order lexicographically, like `BTreeMap` does.
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Ord for TSIMTree<RADIX, LINE, A> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if core::ptr::eq(self, other) {
+            return Ordering::Equal;
         }
 
-        drop(node_guard)
+        let (node_guard, other_guard) = self.read_both(other);
+        node_guard.entries().cmp(other_guard.entries())
     }
+}
 
-    pub fn get<'s, K>(&'s self, k: K) -> Option<Vec<u8>>
-    where
-        K: AsRef<[u8]>,
-    {
-        let mut key: &[u8] = k.as_ref();
-        let node_guard = self.root.read().expect("Must be able to acquire read lock");
-        let mut node = &*node_guard;
-        loop {
-            match node.resolve_child(key) {
-                ResolvedChild::Smallest => return None,
-                ResolvedChild::ExactMatch(segment, remaining_key) => {
-                    match &node.children[segment]
-                        .as_ref()
-                        .expect("children[child_idx] must be Some(..)")
-                    {
-                        TSIMTreeNodeChild::Value(v) => {
-                            if remaining_key.is_empty() {
-                                return Some(v.clone());
-                            } else {
-                                return None;
-                            }
-                        }
-                        TSIMTreeNodeChild::Node(new_node) => {
-                            assert!(node != new_node.as_ref());
-                            node = new_node;
-                            key = remaining_key;
-                        }
-                    }
-                }
-                ResolvedChild::InDomainOf(segment) => {
-                    let TSIMTreeNodeChild::Node(new_node) = &node.children[segment]
-                        .as_ref()
-                        .expect("children[segment] must be Some(..)")
-                    else {
-                        // If the key is in the domain of a Value child, the actual key does not exist in the tree
-                        return None;
-                    };
-                    assert!(node != new_node.as_ref());
-                    node = new_node;
-                }
-            };
-        }
+/// Hashes the entries in ascending key order, so equal trees have the same hash regardless of their structure.
+///
+/// As the tree can be modified through a shared reference, a tree must not be modified while it is used as a key.
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Hash
+    for TSIMTree<RADIX, LINE, A>
+{
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let node_guard = self.root.read();
+        // Slices hash their length as well, so the boundaries between keys and values are unambiguous.
+        node_guard.for_each_entry(|key, value| {
+            key.hash(state);
+            value.hash(state);
+        });
     }
 }
 
-const KEY_SEGMENT_SIZE: usize = CACHE_LINE_SIZE / TREE_RADIX;
+impl<K, const RADIX: usize, const LINE: usize> FromIterator<(K, Vec<u8>)> for TSIMTree<RADIX, LINE>
+where
+    K: AsRef<[u8]>,
+{
+    fn from_iter<I: IntoIterator<Item = (K, Vec<u8>)>>(iter: I) -> Self {
+        let tree = TSIMTree::default();
+        (&tree).extend(iter);
+        tree
+    }
+}
 
-#[derive(PartialEq, Eq, Clone)]
-#[repr(C, align(128))]
-struct TSIMTreeNode {
-    key_segments: [[u8; KEY_SEGMENT_SIZE]; TREE_RADIX],
-    children: [Option<TSIMTreeNodeChild>; TREE_RADIX],
-    children_count: u8,
+/// Puts all entries under a single write lock.
+///
+/// The iterator is consumed while the lock is held, so it must not access this tree.
+impl<K, const RADIX: usize, const LINE: usize, A: Allocator + Clone> Extend<(K, Vec<u8>)>
+    for &TSIMTree<RADIX, LINE, A>
+where
+    K: AsRef<[u8]>,
+{
+    fn extend<I: IntoIterator<Item = (K, Vec<u8>)>>(&mut self, iter: I) {
+        let mut node_guard = self.write();
+        for (k, v) in iter {
+            node_guard.put(k.as_ref(), v);
+            self.touch_and_evict(&mut node_guard, k.as_ref());
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
-enum TSIMTreeNodeChild {
-    Node(Box<TSIMTreeNode>),
-    Value(Vec<u8>),
+impl<K, const RADIX: usize, const LINE: usize, A: Allocator + Clone> Extend<(K, Vec<u8>)>
+    for TSIMTree<RADIX, LINE, A>
+where
+    K: AsRef<[u8]>,
+{
+    fn extend<I: IntoIterator<Item = (K, Vec<u8>)>>(&mut self, iter: I) {
+        (&*self).extend(iter);
+    }
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
-enum TSIMTreeFault {
-    InvalidSegment {
-        len: u8,
-    },
-    ChildIsNone {
-        child_idx: usize,
-        children_count: u8,
-    },
+/// Consumes the tree and yields its entries in ascending key order, the values are moved out without cloning.
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> IntoIterator
+    for TSIMTree<RADIX, LINE, A>
+{
+    type Item = (Vec<u8>, Vec<u8>);
+    type IntoIter = TSIMTreeIntoIter<RADIX, LINE, A>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let nodes = self.root.into_inner();
+        TSIMTreeIntoIter {
+            walker: TSIMTreeWalker::new(nodes.root),
+            nodes,
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-/// Encodes the location of a child in a node.
-enum ResolvedChild<'k> {
-    /// The queried key is outside the domain of any existing child.
-    Smallest,
-    /// The queried key exactly matches the key segment at this index.
-    /// The remaining key fragment is returned as well.
-    ExactMatch(usize, &'k [u8]),
-    /// The queried key does not match directly but is in the domain of this child
-    /// In this case, no remaining key fragment is returned, the previous key must be reused in the query.
-    InDomainOf(usize),
+impl<'t, const RADIX: usize, const LINE: usize, A: Allocator + Clone> IntoIterator
+    for &'t TSIMTree<RADIX, LINE, A>
+{
+    type Item = (Vec<u8>, Vec<u8>);
+    type IntoIter = TSIMTreeIter<'t, RADIX, LINE, A>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
 }
 
-const MAX_STORED_KEY_SEGMENT_SIZE: usize = KEY_SEGMENT_SIZE - 1;
-impl TSIMTreeNode {
-    fn empty() -> TSIMTreeNode {
-        TSIMTreeNode {
-            key_segments: [[0; KEY_SEGMENT_SIZE]; TREE_RADIX],
-            children: array::from_fn(|_| None),
-            children_count: 0,
+impl TSIMTree {
+    pub fn new() -> TSIMTree {
+        TSIMTree::default()
+    }
+
+    /// Creates a tree that holds at most `max_entries` entries and is used as a cache.
+    ///
+    /// Storing a key beyond the capacity evicts the least recently used entries.
+    /// An entry is used when it is stored or read by its key, e.g. through [`TSIMTree::put`] or [`TSIMTree::get`].
+    /// Reads still share the read lock, the recency of the entries is tracked behind a separate lock.
+    ///
+    /// Entries merged into the tree, e.g. by [`TSIMTree::merge`], count as used when they are inserted,
+    /// and entries removed by bulk operations like [`TSIMTree::retain`] or [`TSIMTree::split_off`] stop counting
+    /// towards the capacity.
+    pub fn with_capacity(max_entries: usize) -> TSIMTree {
+        TSIMTree::with_capacity_limit(CacheLimit::Entries(max_entries))
+    }
+
+    /// Creates a tree that is used as a cache like [`TSIMTree::with_capacity`], but is bounded by the given limit.
+    ///
+    /// With [`CacheLimit::ValueBytes`], the least recently used entries are evicted until the values of the remaining
+    /// entries fit into the limit, so a value longer than the limit is evicted as soon as it is stored.
+    pub fn with_capacity_limit(limit: CacheLimit) -> TSIMTree {
+        TSIMTree {
+            root: RootLock::new(TSIMTreeNodes::empty()),
+            recency: Some(Lock::new(Recency::new(limit))),
+            on_evict: None,
         }
     }
 
-    /// Stores a fragment of a key at the given segment index.
-    fn set_segment(&mut self, segment_idx: usize, key_fragment: &[u8]) {
-        assert!(segment_idx < TREE_RADIX);
+    /// Sets a hook that is called with the key and the value of every entry the capacity evicts,
+    /// e.g. to write it back to a slower store.
+    ///
+    /// Entries that are removed explicitly or expire are not passed to the hook, and trees without a capacity
+    /// never call it. The hook is called while the write lock is held, so it must not access the tree.
+    pub fn on_evict<F>(mut self, f: F) -> TSIMTree
+    where
+        F: Fn(Vec<u8>, Vec<u8>) + Send + Sync + 'static,
+    {
+        self.on_evict = Some(EvictionHook(Arc::new(f)));
+        self
+    }
+}
 
-        let key_len = key_fragment.len();
-        assert!(key_len <= MAX_STORED_KEY_SEGMENT_SIZE);
+impl<const RADIX: usize, const LINE: usize> TSIMTree<RADIX, LINE> {
+    /// Rebuilds a tree from a buffer produced by [`TSIMTree::encode`].
+    ///
+    /// The buffer may have been encoded by a tree with a different layout.
+    pub fn decode(buf: &[u8]) -> Result<TSIMTree<RADIX, LINE>, TSIMTreeDecodeError> {
+        let nodes = codec::decode(buf)?;
+        Ok(TSIMTree::from_nodes(nodes))
+    }
 
-        let (length, buffer) = self.key_segments[segment_idx].split_at_mut(1);
-        length[0] = key_len as u8;
-        let (segment_buf, _unused) = buffer.split_at_mut(key_len);
-        segment_buf.copy_from_slice(key_fragment);
+    /// Builds a tree from entries in strictly ascending key order, without looking up any key.
+    ///
+    /// The nodes are filled from left to right in a single pass over the entries and hold `RADIX` children each,
+    /// so the tree is as compact as one built by [`TSIMTreeBuilder`], but neither the entries are collected
+    /// nor the keys inserted one by one. If a key does not sort after the previous key, the entries consumed so far
+    /// are dropped and the index of the key is returned.
+    pub fn from_sorted_iter<I: Iterator<Item = (Vec<u8>, Vec<u8>)>>(
+        iter: I,
+    ) -> Result<TSIMTree<RADIX, LINE>, BulkLoadError> {
+        TSIMTree::from_sorted_iter_with_fill_factor(iter, 1.0)
     }
 
-    fn get_segment(&self, segment_idx: usize) -> &[u8] {
-        assert!(segment_idx < TREE_RADIX);
-        TSIMTreeNode::stored_segment(&self.key_segments[segment_idx])
-            .expect("Segment must be valid!")
+    /// Like [`TSIMTree::from_sorted_iter`], but fills each node only up to the given fraction of its `RADIX` children,
+    /// which leaves room for keys that are put later without splitting the node. Each node holds at least two children.
+    ///
+    /// # Panics
+    /// Panics if the fill factor is not within `(0.0, 1.0]`.
+    pub fn from_sorted_iter_with_fill_factor<I: Iterator<Item = (Vec<u8>, Vec<u8>)>>(
+        iter: I,
+        fill_factor: f64,
+    ) -> Result<TSIMTree<RADIX, LINE>, BulkLoadError> {
+        assert!(
+            fill_factor > 0.0 && fill_factor <= 1.0,
+            "The fill factor {fill_factor} is not within (0.0, 1.0]"
+        );
+        let children_per_node = ((RADIX as f64 * fill_factor) as usize).max(2);
+        let nodes = bulk::load(iter, children_per_node)?;
+        Ok(TSIMTree::from_nodes(nodes))
     }
+}
 
-    /// The buffer for the segments contains length bytes and subsequently the segment.
-    /// This function reads the length byte and returns a reference to part of the buffer that represent the segment.
-    fn stored_segment<'s>(segment: &'s [u8]) -> Result<&'s [u8], TSIMTreeFault> {
-        let (len_buffer, segment_buffer) = segment.split_at(1);
-        let stored_segment_length = len_buffer[0];
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> TSIMTree<RADIX, LINE, A> {
+    /// Creates an empty tree that allocates its nodes and the values that do not fit into their nodes from `alloc`.
+    ///
+    /// The values put into the tree are copied into `alloc`, and the values taken out of it are copied back
+    /// into vectors on the global allocator. With the `slab` and `lockfree` features the nodes are still
+    /// allocated globally, as are the children that sparse nodes move to the heap.
+    pub fn new_in(alloc: A) -> TSIMTree<RADIX, LINE, A> {
+        TSIMTree::from_nodes(TSIMTreeNodes::empty_in(ValueAllocator::new_in(alloc)))
+    }
 
-        if stored_segment_length as usize > MAX_STORED_KEY_SEGMENT_SIZE {
-            return Err(TSIMTreeFault::InvalidSegment {
-                len: stored_segment_length,
-            });
+    fn from_nodes(nodes: TSIMTreeNodes<RADIX, LINE, A>) -> TSIMTree<RADIX, LINE, A> {
+        TSIMTree {
+            root: RootLock::new(nodes),
+            recency: None,
+            on_evict: None,
         }
+    }
 
-        let (stored_segment, _unused_segment) =
-            segment_buffer.split_at(stored_segment_length as usize);
-        Ok(stored_segment)
+    /// Acquires the write lock and counts the write towards the version of the tree.
+    fn write(&self) -> RootWriteGuard<'_, TSIMTreeNodes<RADIX, LINE, A>> {
+        Self::count_write(self.root.write())
     }
 
-    /// Compares two key segments and returns an ordering for the compared segment and a remaining key segment
-    fn compare_key_segment<'k>(segment: &[u8], key: &'k [u8]) -> (Ordering, &'k [u8]) {
-        let stored_segment = TSIMTreeNode::stored_segment(segment).expect("segment must be valid!");
+    /// Counts a write towards the version of the tree while its write lock is held,
+    /// whether or not the write ends up modifying the tree.
+    fn count_write(
+        mut node_guard: RootWriteGuard<'_, TSIMTreeNodes<RADIX, LINE, A>>,
+    ) -> RootWriteGuard<'_, TSIMTreeNodes<RADIX, LINE, A>> {
+        node_guard.version += 1;
+        node_guard
+    }
 
-        let key_segment_length = key.len().min(stored_segment.len());
+    /// Acquires the read locks of this and another tree.
+    ///
+    /// The locks are acquired in the same order as in merge, so comparing a with b and b with a
+    /// cannot deadlock with writers.
+    fn read_both<'t>(
+        &'t self,
+        other: &'t Self,
+    ) -> (
+        RootReadGuard<'t, TSIMTreeNodes<RADIX, LINE, A>>,
+        RootReadGuard<'t, TSIMTreeNodes<RADIX, LINE, A>>,
+    ) {
+        if (self as *const Self) < (other as *const Self) {
+            let node_guard = self.root.read();
+            (node_guard, other.root.read())
+        } else {
+            let other_guard = other.root.read();
+            (self.root.read(), other_guard)
+        }
+    }
 
-        match key.split_at_checked(key_segment_length) {
-            None => (key.cmp(stored_segment), &[]),
-            Some((expected_key_segment, remaining_key)) => {
-                let ordering = expected_key_segment.cmp(stored_segment);
-                (ordering, remaining_key)
-            }
+    /// Marks the key as the most recently used one after it was read, if the tree has a capacity.
+    ///
+    /// Only the writers count the length of the value, a reader keeps the tracked length. Without the lock,
+    /// a writer may have removed the key since it was read, so a key that is not tracked stays untracked.
+    fn refresh(&self, key: &[u8]) {
+        if let Some(recency) = &self.recency {
+            recency.lock().refresh(key);
         }
     }
 
-    /// Use binary search to figure out under what child the key could be located.
-    fn resolve_child<'k>(&self, key: &'k [u8]) -> ResolvedChild<'k> {
-        let mut left_segment_idx = 0;
-        let mut right_segment_idx = self.children_count as usize;
+    /// Marks the stored key as the most recently used one, if the tree has a capacity,
+    /// and evicts the least recently used entries that exceed the capacity.
+    fn touch_and_evict(&self, nodes: &mut TSIMTreeNodes<RADIX, LINE, A>, key: &[u8]) {
+        if let Some(recency) = &self.recency {
+            let mut recency = recency.lock();
+            recency.touch(key, nodes.get(key).map_or(0, <[u8]>::len));
+            self.evict(nodes, &mut recency);
+        }
+    }
 
-        if self.children_count == 0 {
-            return ResolvedChild::Smallest;
+    /// Marks all keys of the nodes, expired ones included, as recently used in ascending key order
+    /// with the current lengths of their values, if the tree has a capacity, and evicts the entries that exceed it.
+    fn touch_all_and_evict(&self, nodes: &mut TSIMTreeNodes<RADIX, LINE, A>) {
+        if let Some(recency) = &self.recency {
+            let mut recency = recency.lock();
+            let mut walker = TSIMTreeWalker::new(nodes.root);
+            while let Some((key, value, _)) = walker.advance(nodes, false) {
+                recency.touch(key, value.len());
+            }
+            self.evict(nodes, &mut recency);
         }
-        assert!(right_segment_idx as usize <= TREE_RADIX);
-        // Binary search in the segments for the next hop:
-        while left_segment_idx < right_segment_idx {
-            let segment = left_segment_idx + (right_segment_idx - left_segment_idx) / 2;
+    }
 
-            match TSIMTreeNode::compare_key_segment(&self.key_segments[segment], key) {
-                (Ordering::Equal, remaining_key) => {
-                    return ResolvedChild::ExactMatch(segment, remaining_key)
-                }
-                (Ordering::Greater, _) if (left_segment_idx + 1 == right_segment_idx) => {
-                    return ResolvedChild::InDomainOf(segment)
-                }
-                (Ordering::Greater, _) => left_segment_idx = segment,
-                (Ordering::Less, _) => right_segment_idx = segment,
+    /// Removes the least recently used entries that exceed the capacity and hands them to the eviction hook.
+    fn evict(&self, nodes: &mut TSIMTreeNodes<RADIX, LINE, A>, recency: &mut Recency<RADIX, LINE>) {
+        for evicted in recency.evict() {
+            let removed = nodes.remove_entry(&evicted);
+            if let (Some(on_evict), Some((value, _))) = (&self.on_evict, removed) {
+                (on_evict.0)(evicted, value);
             }
         }
-        ResolvedChild::Smallest
     }
 
-    fn insert_child(&mut self, idx: usize, key_fragment: &[u8], child: TSIMTreeNodeChild) {
-        assert!(
-            (self.children_count as usize) < TREE_RADIX,
-            "Cannot insert into full node"
-        );
+    /// Stops tracking the key after it was removed, if the tree has a capacity.
+    fn forget(&self, key: &[u8]) {
+        if let Some(recency) = &self.recency {
+            recency.lock().forget(key);
+        }
+    }
 
-        // Copy over all the key segments
-        if idx <= self.children_count as usize {
-            let (_unchanged, children) = self.children.split_at_mut(idx);
-            let (_unchanged, key_segments) = self.key_segments.split_at_mut(idx);
-            children.rotate_right(1);
-            key_segments.rotate_right(1);
+    /// Stops tracking the keys of the nodes, expired ones included, after they were moved out of the tree,
+    /// if the tree has a capacity.
+    fn forget_entries(&self, nodes: &TSIMTreeNodes<RADIX, LINE, A>) {
+        if let Some(recency) = &self.recency {
+            let mut recency = recency.lock();
+            let mut walker = TSIMTreeWalker::new(nodes.root);
+            while let Some((key, _, _)) = walker.advance(nodes, false) {
+                recency.forget(key);
+            }
         }
+    }
 
-        self.set_segment(idx, key_fragment);
-        self.children[idx] = Some(child);
-        self.children_count = self.children_count + 1;
+    /// Stops tracking all keys before the tree is emptied, if the tree has a capacity.
+    fn forget_all(&self) {
+        if let Some(recency) = &self.recency {
+            recency.lock().forget_all();
+        }
     }
-}
 
-impl TSIMTreeNodeChild {
-    /// Creates a subtree to store the value at the given key.
-    fn with_mapping(key: &[u8], value: Vec<u8>) -> TSIMTreeNodeChild {
-        key.chunks(MAX_STORED_KEY_SEGMENT_SIZE)
-            .map(|key_fragment| {
-                let mut node = TSIMTreeNode {
-                    key_segments: [[0; KEY_SEGMENT_SIZE]; TREE_RADIX],
-                    children: array::from_fn(|_| None),
-                    children_count: 1,
-                };
+    /// Stores the value under the key.
+    ///
+    /// Keys may have any length, a key longer than a segment is stored as a chain of nodes.
+    /// The key is first looked up under the read lock, which finds the value to swap or the node with room for
+    /// a new key. Unless another write came in between, the write lock is then only held to store the value there.
+    /// Only keys whose insertion changes the structure of the tree take the full insertion path under the write lock.
+    pub fn put<K>(&self, k: K, v: Vec<u8>)
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = k.as_ref();
+        let (position, version) = {
+            let node_guard = self.root.read();
+            (node_guard.find_position(key), node_guard.version)
+        };
+        let node_guard = self.root.write();
 
-                node.set_segment(0, key_fragment);
+        // Every write moves the version on, so at the same version the position still leads to the key.
+        let position = position.filter(|_| node_guard.version == version);
+        let mut node_guard = Self::count_write(node_guard);
+        match position {
+            Some(position) => node_guard.put_at(position, v),
+            None => {
+                node_guard.put(key, v);
+            }
+        }
+        self.touch_and_evict(&mut node_guard, key);
+    }
 
-                TSIMTreeNodeChild::Node(Box::new(node))
-            })
-            .rev()
-            .fold(TSIMTreeNodeChild::Value(value), |child, mut node| {
-                let TSIMTreeNodeChild::Node(n) = &mut node else {
-                    panic!("Element of the iterator are initialized as Node variants of the enum");
-                };
-                n.children[0] = Some(child);
-                return node;
-            })
+    /// Stores the value under the key like [`TSIMTree::put`] did before it looked the key up under the read lock,
+    /// by holding the write lock for the whole insertion path. Requires the `bench-utils` feature.
+    ///
+    /// Only meant for benchmarks to compare both paths.
+    #[cfg(feature = "bench-utils")]
+    pub fn put_write_locked<K>(&self, k: K, v: Vec<u8>)
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = k.as_ref();
+        let mut node_guard = self.write();
+        node_guard.put(key, v);
+        self.touch_and_evict(&mut node_guard, key);
     }
 
-    /// Will modify the current node, so that the node is effectively pushed one layer down.
-    /// the old_key_fragment should have pointed to this self node.
-    /// This effectively creates new space at the self node.
-    fn pushdown_children_under_key(&mut self, old_key_fragment: &[u8]) {
-        let mut node = TSIMTreeNode {
-            key_segments: [[0; KEY_SEGMENT_SIZE]; TREE_RADIX],
-            children: array::from_fn(|_| None),
-            children_count: 1,
-        };
-        node.set_segment(0, old_key_fragment);
+    /// Stores all entries of the batch under a single write lock, or none of them.
+    ///
+    /// The batch only inserts new keys. It is rejected before the tree is modified if a key occurs twice
+    /// in the batch or is already stored in the tree, so no rollback is needed.
+    pub fn put_batch_transactional<I, K>(&self, iter: I) -> Result<(), TransactionError>
+    where
+        I: IntoIterator<Item = (K, Vec<u8>)>,
+        K: AsRef<[u8]>,
+    {
+        let mut entries: Vec<(K, Vec<u8>)> = iter.into_iter().collect();
+        entries.sort_unstable_by(|(a, _), (b, _)| a.as_ref().cmp(b.as_ref()));
+        if let Some(pair) = entries
+            .windows(2)
+            .find(|pair| pair[0].0.as_ref() == pair[1].0.as_ref())
+        {
+            return Err(TransactionError::DuplicateKey {
+                key: pair[0].0.as_ref().to_vec(),
+            });
+        }
 
-        let mut node_child = TSIMTreeNodeChild::Node(Box::new(node));
+        let mut node_guard = self.write();
+        // The dry run uses the same lock as the insertions, so no other thread can store a key in between.
+        if let Some((key, _)) = entries
+            .iter()
+            .find(|(key, _)| node_guard.get(key.as_ref()).is_some())
+        {
+            return Err(TransactionError::KeyExists {
+                key: key.as_ref().to_vec(),
+            });
+        }
 
-        std::mem::swap(self, &mut node_child);
+        for (key, value) in entries {
+            node_guard.put(key.as_ref(), value);
+            self.touch_and_evict(&mut node_guard, key.as_ref());
+        }
+        Ok(())
+    }
 
-        let TSIMTreeNodeChild::Node(self_node) = self else {
-            panic!("self was just set to TSIMTreeNodeChild::Node(...)");
-        };
+    /// Stores the value under the key until the time to live has passed. Requires the `std` feature.
+    ///
+    /// Once expired, the key behaves as if it was removed. Its entry is only removed
+    /// by the next access to the key, by [`TSIMTree::tick`] or by an expiry thread.
+    /// Storing the key again with [`TSIMTree::put`] keeps the value until it is removed.
+    #[cfg(feature = "std")]
+    pub fn put_with_ttl<K>(&self, k: K, v: Vec<u8>, ttl: Duration)
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = k.as_ref();
+        let expiry = Instant::now() + ttl;
+        let mut node_guard = self.write();
 
-        self_node.children[0] = Some(node_child)
+        node_guard.put_expiring(key, v, Some(expiry));
+        self.touch_and_evict(&mut node_guard, key);
     }
-}
 
-impl Debug for TSIMTreeNode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut builder = &mut f.debug_map();
-
-        for child_idx in 0..self.children_count as usize {
-            let key_builder =
-                match TSIMTreeNode::stored_segment(self.key_segments[child_idx].as_slice()) {
-                    Ok(segment) => builder.key(&format!("{segment:X?}")),
-                    Err(e) => builder.key(&e),
-                };
+    /// Returns the value stored under the key.
+    ///
+    /// If the value is expired, it is removed under the write lock and `None` is returned.
+    pub fn get<K>(&self, k: K) -> Option<Vec<u8>>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.get_with(k, <[u8]>::to_vec)
+    }
 
-            builder = match &self.children[child_idx] {
-                Some(TSIMTreeNodeChild::Node(node)) => key_builder.value(&node),
-                Some(TSIMTreeNodeChild::Value(value)) => key_builder.value(&format!("{value:X?}")),
-                None => key_builder.value(&TSIMTreeFault::ChildIsNone {
-                    child_idx: child_idx,
-                    children_count: self.children_count,
-                }),
-            };
+    /// Calls `f` with the value stored under the key while the read lock is held and returns its result.
+    ///
+    /// Unlike [`TSIMTree::get`], the value is not cloned. Writers have to wait until `f` returns.
+    /// If the value is expired, it is removed under the write lock and `None` is returned.
+    pub fn get_with<K, R, F>(&self, k: K, f: F) -> Option<R>
+    where
+        K: AsRef<[u8]>,
+        F: FnOnce(&[u8]) -> R,
+    {
+        let key = k.as_ref();
+        {
+            let node_guard = self.root.read();
+            match node_guard.get_entry(key) {
+                None => return None,
+                Some((value, expiry)) if !is_expired(expiry) => {
+                    self.refresh(key);
+                    return Some(f(value));
+                }
+                Some(_) => {}
+            }
         }
 
-        builder.finish()
+        // The key may have been stored again after the read lock was released, so the expiry is checked again.
+        let mut node_guard = self.write();
+        match node_guard.get_entry(key) {
+            Some((value, expiry)) if !is_expired(expiry) => {
+                self.refresh(key);
+                Some(f(value))
+            }
+            Some(_) => {
+                node_guard.remove_entry(key);
+                self.forget(key);
+                None
+            }
+            None => None,
+        }
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    /// Returns the stored key together with its value, like the neighbor lookups such as [`TSIMTree::get_next`].
+    ///
+    /// Keys are stored byte for byte, so the returned key equals the queried one.
+    /// It lets generic code handle exact lookups like the others.
+    pub fn get_entry<K>(&self, k: K) -> Option<(Vec<u8>, Vec<u8>)>
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = k.as_ref();
+        self.get_with(key, |value| (key.to_vec(), value.to_vec()))
+    }
 
-    #[test]
-    fn test_comparison_behavior() {
-        assert_eq!(b"abc".as_slice().cmp(b"abc".as_slice()), Ordering::Equal);
-        assert_eq!(b"ab".as_slice().cmp(b"abc".as_slice()), Ordering::Less);
-        assert_eq!(b"abc".as_slice().cmp(b"ab".as_slice()), Ordering::Greater);
+    /// Returns the length of the value stored under the key without cloning the value.
+    ///
+    /// An empty value is `Some(0)`, while a key that is not stored is `None`.
+    pub fn value_len<K>(&self, k: K) -> Option<usize>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.get_with(k, <[u8]>::len)
     }
 
-    #[test]
-    fn test_pretty_printing() {
-        let tree = TSIMTree::new();
-        tree.put(b"key1", b"val1".into());
+    /// Stores the UTF-8 encoding of the value under the UTF-8 encoding of the key.
+    pub fn put_str(&self, k: &str, v: &str) {
+        self.put(k, v.as_bytes().to_vec());
+    }
 
-        dbg!(tree);
+    /// Returns the value stored under the key as a string.
+    ///
+    /// Values that are not valid UTF-8 are treated as if they were not stored and `None` is returned,
+    /// use [`TSIMTree::get`] to read them as bytes.
+    pub fn get_str(&self, k: &str) -> Option<String> {
+        self.get_with(k, |value| {
+            core::str::from_utf8(value).ok().map(String::from)
+        })
+        .flatten()
     }
 
-    #[test]
-    fn test_node_resolving() {
-        println!("Initializing Node");
-        let mut node = TSIMTreeNode {
-            key_segments: Default::default(),
-            children: array::from_fn(|i| Some(TSIMTreeNodeChild::Value(vec![i as u8]))),
-            children_count: TREE_RADIX as u8,
+    /// Returns a guard through which the value stored under the key can be modified in place.
+    ///
+    /// The guard holds the write lock until it is dropped, so it blocks all other reads and writes of the tree.
+    /// With the `lockfree` feature, readers keep seeing the old value until the guard is dropped instead.
+    /// If the value is expired, it is removed and `None` is returned.
+    ///
+    /// If the tree has a capacity, the key is marked as recently used with the length of the modified value
+    /// when the guard is dropped, which may evict other entries or the key itself.
+    pub fn get_mut<K>(&self, k: K) -> Option<TSIMTreeValueGuard<'_, RADIX, LINE, A>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = k.as_ref();
+        let mut node_guard = self.write();
+        let (node, idx) = node_guard.find_value(key)?;
+        let (_, expiry) = node_guard.entry_at(node, idx);
+        if is_expired(expiry) {
+            node_guard.remove_entry(key);
+            self.forget(key);
+            return None;
+        }
+        let TSIMTreeNodeChild::Value(value, _) = node_guard.arena[node].child_mut(idx) else {
+            panic!("find_value only returns TSIMTreeNodeChild::Value(..)")
         };
+        let value = core::mem::take(value);
+        let value = node_guard.values.release(value);
+        Some(TSIMTreeValueGuard {
+            tree: self,
+            key: self.recency.is_some().then(|| key.to_vec()),
+            node_guard,
+            node,
+            idx,
+            value,
+        })
+    }
 
-        let first_key = 1 as u8;
-        let last_key = TREE_RADIX as u8 + 1;
-
-        assert_eq!((first_key..last_key).len(), TREE_RADIX);
+    /// Looks up all keys under a single read lock and returns the values in the order of the keys.
+    ///
+    /// The lookups are performed in sorted key order, so keys sharing a prefix visit nodes that are still cached.
+    pub fn get_many<K>(&self, keys: impl IntoIterator<Item = K>) -> Vec<Option<Vec<u8>>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let keys: Vec<K> = keys.into_iter().collect();
+        let mut lookup_order: Vec<usize> = (0..keys.len()).collect();
+        lookup_order.sort_unstable_by_key(|&idx| keys[idx].as_ref());
 
-        println!("Initializing Segments");
+        let mut values = vec![None; keys.len()];
+        let node_guard = self.root.read();
+        for idx in lookup_order {
+            values[idx] = node_guard.get(keys[idx].as_ref()).map(<[u8]>::to_vec);
+            if values[idx].is_some() {
+                self.refresh(keys[idx].as_ref());
+            }
+        }
+        values
+    }
 
-        for (segment, key) in (0..TREE_RADIX).zip(first_key..last_key) {
+    /// Returns the value stored under the key.
+    /// If the key does not exist yet, the result of `f` is inserted and returned.
+    ///
+    /// The lookup and the insertion happen under the same write lock, so `f` is called at most once
+    /// and no other thread can insert the key in between.
+    pub fn get_or_insert_with<K, F>(&self, k: K, f: F) -> Vec<u8>
+    where
+        K: AsRef<[u8]>,
+        F: FnOnce() -> Vec<u8>,
+    {
+        let key = k.as_ref();
+        let mut node_guard = self.write();
+
+        let value = node_guard.get_or_insert_with(key, f).to_vec();
+        self.touch_and_evict(&mut node_guard, key);
+        value
+    }
+
+    /// Stores the new value under the key only if the current value equals `expected`,
+    /// where `None` expects the key to be absent. Otherwise the current value is returned as the error.
+    ///
+    /// The comparison and the update happen under the same write lock, so retry loops can build on it.
+    pub fn compare_and_swap<K>(
+        &self,
+        k: K,
+        expected: Option<&[u8]>,
+        new: Vec<u8>,
+    ) -> Result<(), Option<Vec<u8>>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = k.as_ref();
+        let mut node_guard = self.write();
+
+        let current = node_guard.get(key);
+        if current != expected {
+            return Err(current.map(<[u8]>::to_vec));
+        }
+        node_guard.put(key, new);
+        self.touch_and_evict(&mut node_guard, key);
+        Ok(())
+    }
+
+    /// Adds `delta` to the counter stored under the key and returns the new count.
+    ///
+    /// The counter is stored as an 8-byte little-endian `i64`, a key that is not stored counts as 0.
+    /// The addition wraps around on overflow. The counter is read and stored under the same write lock,
+    /// so concurrent increments are never lost.
+    ///
+    /// # Panics
+    /// If the stored value is not exactly 8 bytes long. The lock is released first, so the tree remains usable.
+    pub fn increment<K>(&self, k: K, delta: i64) -> i64
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = k.as_ref();
+        let counter = {
+            let mut node_guard = self.write();
+            let current = match node_guard.get(key) {
+                None => Ok(0),
+                Some(value) => value
+                    .try_into()
+                    .map(i64::from_le_bytes)
+                    .map_err(|_| value.len()),
+            };
+            current.map(|current| {
+                let counter = current.wrapping_add(delta);
+                node_guard.put(key, counter.to_le_bytes().to_vec());
+                self.touch_and_evict(&mut node_guard, key);
+                counter
+            })
+        };
+        counter.unwrap_or_else(|len| panic!("The counter is stored in {len} instead of 8 bytes"))
+    }
+
+    /// Returns the value stored under the key together with the version of the tree.
+    ///
+    /// The version counts the writes to the tree. Every write increments it, even one that leaves the tree unchanged,
+    /// e.g. removing a missing key. Pass the version to [`TSIMTree::put_if_version`] to store a value
+    /// derived from this one only if the tree was not written in between, without holding a lock meanwhile.
+    pub fn get_with_version<K>(&self, k: K) -> Option<(Vec<u8>, u64)>
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = k.as_ref();
+        let node_guard = self.root.read();
+        // The version is read from the same nodes as the value, so a write cannot fall in between.
+        match node_guard.get_entry(key) {
+            Some((value, expiry)) if !is_expired(expiry) => {
+                self.refresh(key);
+                Some((value.to_vec(), node_guard.version))
+            }
+            _ => None,
+        }
+    }
+
+    /// Stores the value under the key only if the tree is still at the version returned by
+    /// [`TSIMTree::get_with_version`], and returns whether it was stored.
+    ///
+    /// The version is compared and the value stored under the same write lock. Storing the value increments
+    /// the version, so of several threads that read the same version at most one succeeds.
+    pub fn put_if_version<K>(&self, k: K, v: Vec<u8>, expected_version: u64) -> bool
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = k.as_ref();
+        let node_guard = self.root.write();
+        if node_guard.version != expected_version {
+            return false;
+        }
+
+        let mut node_guard = Self::count_write(node_guard);
+        node_guard.put(key, v);
+        self.touch_and_evict(&mut node_guard, key);
+        true
+    }
+
+    /// Stores the value under the key only if the key does not exist yet and returns whether it was stored.
+    ///
+    /// An existing value is left untouched. The lookup and the insertion are a single descent under the write lock,
+    /// so of several threads inserting the same key exactly one succeeds. An expired value counts as absent.
+    pub fn insert_if_absent<K>(&self, k: K, v: Vec<u8>) -> bool
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = k.as_ref();
+        let mut node_guard = self.write();
+
+        let mut inserted = false;
+        node_guard.get_or_insert_with(key, || {
+            inserted = true;
+            v
+        });
+        self.touch_and_evict(&mut node_guard, key);
+        inserted
+    }
+
+    /// Removes the key from the tree and returns the value that was stored under it.
+    pub fn remove<K>(&self, k: K) -> Option<Vec<u8>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = k.as_ref();
+        let mut node_guard = self.write();
+
+        self.forget(key);
+        node_guard.remove(key)
+    }
+
+    /// Like [`TSIMTree::get`], but returns an error instead of panicking
+    /// if a node on the path to the key violates an invariant or a thread panicked while holding the lock.
+    ///
+    /// The nodes on the path are verified before they are used, which makes the lookup slower than `get`.
+    /// Like `get`, it waits for a writer that holds the lock, see [`TSIMTree::try_get`] for a lookup that does not.
+    pub fn get_checked<K>(&self, k: K) -> Result<Option<Vec<u8>>, TSIMTreeError>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.get_verified(
+            k.as_ref(),
+            || self.root.checked_read(),
+            || self.root.checked_write(),
+        )
+    }
+
+    /// Like [`TSIMTree::get_checked`], but returns [`TSIMTreeError::WouldBlock`]
+    /// instead of waiting for a writer that holds the lock.
+    pub fn try_get<K>(&self, k: K) -> Result<Option<Vec<u8>>, TSIMTreeError>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.get_verified(
+            k.as_ref(),
+            || self.root.try_read(),
+            || self.root.try_write(),
+        )
+    }
+
+    /// Like [`TSIMTree::try_get`], but waits up to the timeout for the lock before returning
+    /// [`TSIMTreeError::WouldBlock`]. Requires the `parking_lot` feature.
+    #[cfg(feature = "parking_lot")]
+    pub fn try_get_for<K>(&self, k: K, timeout: Duration) -> Result<Option<Vec<u8>>, TSIMTreeError>
+    where
+        K: AsRef<[u8]>,
+    {
+        let deadline = Instant::now() + timeout;
+        self.get_verified(
+            k.as_ref(),
+            || self.root.try_read_for(timeout),
+            || {
+                self.root
+                    .try_write_for(deadline.saturating_duration_since(Instant::now()))
+            },
+        )
+    }
+
+    /// Looks up the key with the locks acquired by `read` and, if the value is expired, by `write`.
+    fn get_verified<'t>(
+        &'t self,
+        key: &[u8],
+        read: impl FnOnce() -> Result<RootReadGuard<'t, TSIMTreeNodes<RADIX, LINE, A>>, TSIMTreeError>,
+        write: impl FnOnce() -> Result<RootWriteGuard<'t, TSIMTreeNodes<RADIX, LINE, A>>, TSIMTreeError>,
+    ) -> Result<Option<Vec<u8>>, TSIMTreeError> {
+        {
+            let node_guard = read()?;
+            let Some((node, idx)) = node_guard.verify_path(key)? else {
+                return Ok(None);
+            };
+            match node_guard.entry_at(node, idx) {
+                (value, expiry) if !is_expired(expiry) => {
+                    self.refresh(key);
+                    return Ok(Some(value.to_vec()));
+                }
+                _ => {}
+            }
+        }
+
+        // The key may have been stored again after the read lock was released, so the expiry is checked again.
+        let mut node_guard = write().map(Self::count_write)?;
+        let Some((node, idx)) = node_guard.verify_path(key)? else {
+            return Ok(None);
+        };
+        match node_guard.entry_at(node, idx) {
+            (value, expiry) if !is_expired(expiry) => {
+                self.refresh(key);
+                Ok(Some(value.to_vec()))
+            }
+            _ => {
+                node_guard.remove_entry(key);
+                self.forget(key);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Like [`TSIMTree::put`], but returns an error instead of panicking
+    /// if a node on the path to the key violates an invariant or a thread panicked while holding the lock.
+    ///
+    /// The tree is only modified once the nodes on the path are verified.
+    /// Like `put`, it waits for the lock, see [`TSIMTree::try_put`] for a write that does not.
+    pub fn put_checked<K>(&self, k: K, v: Vec<u8>) -> Result<(), TSIMTreeError>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.put_verified(
+            k.as_ref(),
+            v,
+            self.root.checked_write().map(Self::count_write)?,
+        )
+    }
+
+    /// Like [`TSIMTree::put_checked`], but returns [`TSIMTreeError::WouldBlock`]
+    /// instead of waiting for another thread that holds the lock.
+    pub fn try_put<K>(&self, k: K, v: Vec<u8>) -> Result<(), TSIMTreeError>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.put_verified(k.as_ref(), v, self.root.try_write().map(Self::count_write)?)
+    }
+
+    /// Like [`TSIMTree::try_put`], but waits up to the timeout for the lock before returning
+    /// [`TSIMTreeError::WouldBlock`]. Requires the `parking_lot` feature.
+    #[cfg(feature = "parking_lot")]
+    pub fn try_put_for<K>(&self, k: K, v: Vec<u8>, timeout: Duration) -> Result<(), TSIMTreeError>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.put_verified(
+            k.as_ref(),
+            v,
+            self.root.try_write_for(timeout).map(Self::count_write)?,
+        )
+    }
+
+    fn put_verified(
+        &self,
+        key: &[u8],
+        v: Vec<u8>,
+        mut node_guard: RootWriteGuard<'_, TSIMTreeNodes<RADIX, LINE, A>>,
+    ) -> Result<(), TSIMTreeError> {
+        node_guard.verify_path(key)?;
+
+        node_guard.put(key, v);
+        self.touch_and_evict(&mut node_guard, key);
+        Ok(())
+    }
+
+    /// Like [`TSIMTree::remove`], but returns an error instead of panicking
+    /// if a node on the path to the key violates an invariant or a thread panicked while holding the lock.
+    ///
+    /// The tree is only modified once the nodes on the path are verified.
+    /// Like `remove`, it waits for the lock, see [`TSIMTree::try_remove`] for a removal that does not.
+    pub fn remove_checked<K>(&self, k: K) -> Result<Option<Vec<u8>>, TSIMTreeError>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.remove_verified(
+            k.as_ref(),
+            self.root.checked_write().map(Self::count_write)?,
+        )
+    }
+
+    /// Like [`TSIMTree::remove_checked`], but returns [`TSIMTreeError::WouldBlock`]
+    /// instead of waiting for another thread that holds the lock.
+    pub fn try_remove<K>(&self, k: K) -> Result<Option<Vec<u8>>, TSIMTreeError>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.remove_verified(k.as_ref(), self.root.try_write().map(Self::count_write)?)
+    }
+
+    fn remove_verified(
+        &self,
+        key: &[u8],
+        mut node_guard: RootWriteGuard<'_, TSIMTreeNodes<RADIX, LINE, A>>,
+    ) -> Result<Option<Vec<u8>>, TSIMTreeError> {
+        if node_guard.verify_path(key)?.is_none() {
+            return Ok(None);
+        }
+
+        self.forget(key);
+        Ok(node_guard.remove(key))
+    }
+
+    /// Returns the entry with the smallest key.
+    pub fn first_key_value(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let node_guard = self.root.read();
+        let (_, key, value) = node_guard.extreme_entry(false)?;
+        Some((key, value.to_vec()))
+    }
+
+    /// Returns the entry with the largest key.
+    pub fn last_key_value(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let node_guard = self.root.read();
+        let (_, key, value) = node_guard.extreme_entry(true)?;
+        Some((key, value.to_vec()))
+    }
+
+    /// Returns the lexicographically smallest key, without cloning its value.
+    ///
+    /// This is the key of [`TSIMTree::first_key_value`], which is not necessarily the key with the fewest bytes.
+    pub fn shortest_key(&self) -> Option<Vec<u8>> {
+        let (_, key, _) = self.root.read().extreme_entry(false)?;
+        Some(key)
+    }
+
+    /// Returns the lexicographically largest key, without cloning its value.
+    ///
+    /// This is the key of [`TSIMTree::last_key_value`], which is not necessarily the key with the most bytes.
+    pub fn longest_key(&self) -> Option<Vec<u8>> {
+        let (_, key, _) = self.root.read().extreme_entry(true)?;
+        Some(key)
+    }
+
+    /// Returns the entry with the smallest key that is greater than or equal to the key.
+    pub fn get_next<K>(&self, k: K) -> Option<(Vec<u8>, Vec<u8>)>
+    where
+        K: AsRef<[u8]>,
+    {
+        let node_guard = self.root.read();
+        let (key, value) = node_guard.neighbour_entry(k.as_ref(), false, true)?;
+        Some((key, value.to_vec()))
+    }
+
+    /// Returns the entry with the largest key that is less than or equal to the key.
+    pub fn get_prev<K>(&self, k: K) -> Option<(Vec<u8>, Vec<u8>)>
+    where
+        K: AsRef<[u8]>,
+    {
+        let node_guard = self.root.read();
+        let (key, value) = node_guard.neighbour_entry(k.as_ref(), true, true)?;
+        Some((key, value.to_vec()))
+    }
+
+    /// Returns the entry with the smallest key that is strictly greater than the key.
+    ///
+    /// Unlike a cursor, no lock is held in between, so a range can be walked by passing the returned key back in.
+    pub fn successor<K>(&self, k: K) -> Option<(Vec<u8>, Vec<u8>)>
+    where
+        K: AsRef<[u8]>,
+    {
+        let node_guard = self.root.read();
+        let (key, value) = node_guard.neighbour_entry(k.as_ref(), false, false)?;
+        Some((key, value.to_vec()))
+    }
+
+    /// Returns the entry with the largest key that is strictly less than the key.
+    pub fn predecessor<K>(&self, k: K) -> Option<(Vec<u8>, Vec<u8>)>
+    where
+        K: AsRef<[u8]>,
+    {
+        let node_guard = self.root.read();
+        let (key, value) = node_guard.neighbour_entry(k.as_ref(), true, false)?;
+        Some((key, value.to_vec()))
+    }
+
+    /// Returns a cursor that is positioned in front of the entry with the smallest key
+    /// that is greater than or equal to the key.
+    ///
+    /// The read lock is held until the cursor is dropped.
+    pub fn cursor_at<K>(&self, k: K) -> TSIMTreeCursor<'_, RADIX, LINE, A>
+    where
+        K: AsRef<[u8]>,
+    {
+        TSIMTreeCursor {
+            node_guard: self.root.read(),
+            key: k.as_ref().to_vec(),
+            behind_key: false,
+        }
+    }
+
+    /// Removes the entry with the smallest key and returns it.
+    pub fn pop_first(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let mut node_guard = self.write();
+        let (key, value) = node_guard.pop_extreme_entry(false)?;
+        self.forget(&key);
+        Some((key, value))
+    }
+
+    /// Removes the entry with the largest key and returns it.
+    pub fn pop_last(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let mut node_guard = self.write();
+        let (key, value) = node_guard.pop_extreme_entry(true)?;
+        self.forget(&key);
+        Some((key, value))
+    }
+
+    /// Inserts all entries of `other` into this tree.
+    /// If a key is stored in both trees, `conflict(key, self_value, other_value)` decides the value that is kept.
+    ///
+    /// The write lock of this tree and the read lock of `other` are held for the whole operation.
+    pub fn merge<F>(&self, other: &TSIMTree<RADIX, LINE, A>, conflict: F)
+    where
+        F: Fn(&[u8], &[u8], &[u8]) -> Vec<u8>,
+    {
+        if core::ptr::eq(self, other) {
+            // Every key conflicts with itself, the read lock of other would deadlock with our write lock.
+            let mut node_guard = self.write();
+            let mut merged_entries = Vec::new();
+            node_guard.for_each_entry(|key, value| {
+                merged_entries.push((key.to_vec(), conflict(key, value, value)));
+            });
+            for (key, value) in merged_entries {
+                node_guard.put(&key, value);
+                self.touch_and_evict(&mut node_guard, &key);
+            }
+            return;
+        }
+
+        // The locks are always acquired in the same order, so concurrent merges in both directions cannot deadlock.
+        let (mut node_guard, other_guard) = if (self as *const Self) < (other as *const Self) {
+            let node_guard = self.write();
+            (node_guard, other.root.read())
+        } else {
+            let other_guard = other.root.read();
+            (self.write(), other_guard)
+        };
+
+        let values = node_guard.values.clone();
+        other_guard.for_each_entry(|key, other_value| {
+            let mut inserted = false;
+            let value = node_guard.get_or_insert_with(key, || {
+                inserted = true;
+                other_value.to_vec()
+            });
+            if !inserted {
+                *value = values.store(conflict(key, value, other_value));
+            }
+            self.touch_and_evict(&mut node_guard, key);
+        });
+    }
+
+    /// Moves all entries of `other` into this tree without cloning them.
+    /// If a key is stored in both trees, `resolve(key, self_value, other_value)` decides the value that is kept.
+    ///
+    /// Expired entries of `other` are dropped. If this tree is empty, the nodes of `other` are taken over as a whole.
+    /// The write lock of this tree is held for the whole operation.
+    pub fn merge_owned<F>(&self, other: TSIMTree<RADIX, LINE, A>, mut resolve: F)
+    where
+        F: FnMut(&[u8], Vec<u8>, Vec<u8>) -> Vec<u8>,
+    {
+        // Declared before the guard, so the emptied nodes of other are dropped after the write lock is released.
+        let mut other_nodes = other.root.into_inner();
+        let mut node_guard = self.write();
+        if node_guard.arena[node_guard.root].children_count == 0 {
+            core::mem::swap(&mut *node_guard, &mut other_nodes);
+            node_guard.version = other_nodes.version;
+            if self.recency.is_some() {
+                let mut keys = Vec::new();
+                node_guard.for_each_entry(|key, _| keys.push(key.to_vec()));
+                for key in keys {
+                    self.touch_and_evict(&mut node_guard, &key);
+                }
+            }
+            return;
+        }
+
+        let values = node_guard.values.clone();
+        let mut walker = TSIMTreeWalker::new(other_nodes.root);
+        while let Some((key, other_value)) = walker.take_next_entry(&mut other_nodes) {
+            let mut other_value = Some(other_value);
+            let (value, _) = node_guard.get_or_insert_entry_with(key, || {
+                other_value.take().expect("the value is only inserted once")
+            });
+            // If the value of other is still there, the key already existed.
+            if let Some(other_value) = other_value {
+                let self_value = values.release(core::mem::take(value));
+                *value = values.store(resolve(key, self_value, other_value));
+            }
+            self.touch_and_evict(&mut node_guard, key);
+        }
+    }
+
+    /// Removes all keys that start with the prefix from this tree and returns them in a new tree.
+    ///
+    /// If the keys are stored in a single subtree, the subtree is moved into the new tree as a whole.
+    pub fn split_off_prefix<K>(&self, prefix: K) -> TSIMTree<RADIX, LINE, A>
+    where
+        K: AsRef<[u8]>,
+    {
+        let mut node_guard = self.write();
+
+        let split_off = node_guard.split_off_prefix(prefix.as_ref());
+        self.forget_entries(&split_off);
+        TSIMTree::from_nodes(split_off)
+    }
+
+    /// Removes all keys that are greater than or equal to the key from this tree and returns them in a new tree.
+    ///
+    /// Only the nodes on the path to the key are divided, all other children are moved as a whole.
+    pub fn split_off<K>(&self, k: K) -> TSIMTree<RADIX, LINE, A>
+    where
+        K: AsRef<[u8]>,
+    {
+        let mut node_guard = self.write();
+
+        let split_off = node_guard.split_off_from(k.as_ref());
+        self.forget_entries(&split_off);
+        TSIMTree::from_nodes(split_off)
+    }
+
+    /// Splits the entries of this tree into two new trees and leaves this tree empty.
+    ///
+    /// The first tree holds all keys that are less than the prefix,
+    /// the second tree the prefix and all keys that are greater, which includes all keys starting with the prefix.
+    pub fn split_at_prefix<K>(
+        &self,
+        prefix: K,
+    ) -> (TSIMTree<RADIX, LINE, A>, TSIMTree<RADIX, LINE, A>)
+    where
+        K: AsRef<[u8]>,
+    {
+        let mut node_guard = self.write();
+
+        let upper = node_guard.split_off_from(prefix.as_ref());
+        let lower = node_guard.take();
+        self.forget_all();
+        (TSIMTree::from_nodes(lower), TSIMTree::from_nodes(upper))
+    }
+
+    /// Returns the number of keys that start with the prefix, the empty prefix counts all keys.
+    ///
+    /// Subtrees that only hold keys starting with the prefix are counted without reconstructing their keys.
+    pub fn count_prefix<K>(&self, prefix: K) -> usize
+    where
+        K: AsRef<[u8]>,
+    {
+        let node_guard = self.root.read();
+        node_guard.count_prefix(prefix.as_ref())
+    }
+
+    /// Calls `f(prefix, value)` for every stored key that is a prefix of the key, the key itself included,
+    /// from the shortest to the longest prefix. The values are borrowed under the read lock.
+    ///
+    /// Only the nodes on the way to the key are visited, like all policies that apply to a resource path.
+    pub fn for_each_prefix<K, F>(&self, key: K, f: F)
+    where
+        K: AsRef<[u8]>,
+        F: FnMut(&[u8], &[u8]),
+    {
+        let node_guard = self.root.read();
+        node_guard.for_each_prefix(key.as_ref(), f);
+    }
+
+    /// Verifies that all nodes of the tree are well-formed and returns the first violated invariant.
+    pub fn check_integrity(&self) -> Result<(), TSIMTreeFault> {
+        let node_guard = self.root.read();
+        node_guard.check_integrity()
+    }
+
+    /// Returns a frozen view of the tree, which later modifications of the tree do not change.
+    ///
+    /// With the `lockfree` feature, the snapshot shares the current root and is taken without copying any node.
+    /// Otherwise the nodes are copied under the read lock. Either way, reading the snapshot never locks
+    /// and cloning it only takes another reference to the same nodes.
+    pub fn snapshot(&self) -> TSIMTreeSnapshot<RADIX, LINE, A> {
+        TSIMTreeSnapshot {
+            nodes: self.root.snapshot(),
+        }
+    }
+
+    /// Returns an iterator over the entries in ascending key order as they were when it was created.
+    ///
+    /// The iterator owns a [`TSIMTree::snapshot`], so it holds no lock, can be sent to other threads
+    /// and is not affected by later modifications of the tree.
+    pub fn snapshot_iter(&self) -> TSIMTreeSnapshotIter<RADIX, LINE, A> {
+        self.snapshot().into_iter()
+    }
+
+    /// Acquires the read lock and returns a guard that dereferences to the root node, for custom traversals.
+    ///
+    /// The children of a node are reached through [`TSIMTreeNodeRef::child`]. Writers have to wait until
+    /// the guard is dropped.
+    pub fn root_node(&self) -> TSIMTreeRootGuard<'_, RADIX, LINE, A> {
+        TSIMTreeRootGuard {
+            node_guard: self.root.read(),
+        }
+    }
+
+    /// Acquires the read lock and returns a guard through which the tree is read without cloning the values.
+    ///
+    /// Several reads can be made under one acquisition of the lock, writers have to wait until the guard is dropped.
+    pub fn read(&self) -> TSIMReadGuard<'_, RADIX, LINE, A> {
+        TSIMReadGuard {
+            tree: self,
+            node_guard: self.root.read(),
+        }
+    }
+
+    /// Returns an iterator over the entries in ascending key order.
+    ///
+    /// The read lock is held until the iterator is dropped.
+    pub fn iter(&self) -> TSIMTreeIter<'_, RADIX, LINE, A> {
+        let node_guard = self.root.read();
+        TSIMTreeIter {
+            walker: TSIMTreeWalker::new(node_guard.root),
+            node_guard,
+        }
+    }
+
+    /// Calls `f` with every entry in ascending key order while the read lock is held.
+    ///
+    /// Unlike [`TSIMTree::iter`], the values are borrowed instead of cloned
+    /// and the key is rebuilt in a single buffer that is reused for every entry.
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        let node_guard = self.root.read();
+        node_guard.for_each_entry(|key, value| f(key, value));
+    }
+
+    /// Returns an iterator over the keys in ascending order, the values are not cloned.
+    ///
+    /// The read lock is held until the iterator is dropped.
+    pub fn keys(&self) -> TSIMTreeKeys<'_, RADIX, LINE, A> {
+        let node_guard = self.root.read();
+        TSIMTreeKeys {
+            walker: TSIMTreeWalker::new(node_guard.root),
+            node_guard,
+        }
+    }
+
+    /// Returns an iterator over the entries whose keys start with the prefix, in ascending key order.
+    ///
+    /// Only the nodes on the way to the first such key and the entries starting with the prefix are visited.
+    /// The read lock is held until the iterator is dropped.
+    pub fn prefix_iter<K>(&self, prefix: K) -> TSIMTreePrefixIter<'_, RADIX, LINE, A>
+    where
+        K: AsRef<[u8]>,
+    {
+        let node_guard = self.root.read();
+        TSIMTreePrefixIter {
+            walker: TSIMTreeWalker::seek(&node_guard, prefix.as_ref()),
+            prefix: prefix.as_ref().to_vec(),
+            node_guard,
+        }
+    }
+
+    /// Returns an iterator over the keys starting with the prefix in ascending order, the values are not cloned.
+    ///
+    /// Like listing a directory, if the keys are paths and the prefix ends with a separator.
+    /// The read lock is held until the iterator is dropped.
+    pub fn prefix_keys<K>(&self, prefix: K) -> TSIMTreePrefixKeys<'_, RADIX, LINE, A>
+    where
+        K: AsRef<[u8]>,
+    {
+        let node_guard = self.root.read();
+        TSIMTreePrefixKeys {
+            walker: TSIMTreeWalker::seek(&node_guard, prefix.as_ref()),
+            prefix: prefix.as_ref().to_vec(),
+            node_guard,
+        }
+    }
+
+    /// Returns a lookup that is fed the bytes of a key in chunks, so the key never has to be assembled.
+    ///
+    /// Unlike [`TSIMTree::get`], finding a key does not count as an access for the capacity of the tree,
+    /// as the whole key is never known. The read lock is held until the lookup is dropped.
+    pub fn lookup(&self) -> TSIMTreeLookup<'_, RADIX, LINE, A> {
+        let node_guard = self.root.read();
+        TSIMTreeLookup {
+            node: Some(node_guard.root),
+            node_guard,
+            rest: Vec::new(),
+        }
+    }
+
+    /// Returns an iterator over the values in ascending order of their keys, the keys are not reconstructed.
+    ///
+    /// The read lock is held until the iterator is dropped.
+    pub fn values(&self) -> TSIMTreeValues<'_, RADIX, LINE, A> {
+        let node_guard = self.root.read();
+        TSIMTreeValues {
+            walker: TSIMTreeWalker::new(node_guard.root),
+            node_guard,
+        }
+    }
+
+    /// Merges the nodes that removals left sparse and releases the memory of the removed nodes.
+    ///
+    /// Removing a key only merges the nodes on its path, so after many removals the tree may still hold nodes
+    /// with few children and chains that could be shorter. Nodes with a single child are merged into their
+    /// parents where the segments fit, routing nodes are merged into their children where they fit into one node.
+    /// The nodes are then moved into a new arena, so the slots of the removed nodes are released as well.
+    ///
+    /// Returns the number of bytes that were released. The write lock is held the whole time.
+    pub fn shrink_to_fit(&self) -> usize {
+        self.write().shrink_to_fit()
+    }
+
+    /// Rebuilds the nodes of the tree from its entries like [`TSIMTree::from_sorted_iter`], so every node is full.
+    ///
+    /// Unlike [`TSIMTree::shrink_to_fit`], which only merges nodes where they fit, the children of all nodes
+    /// are packed anew, which leaves the tree as compact as if it had been bulk loaded. The values are moved
+    /// into the new nodes without copying them, expired entries are dropped.
+    ///
+    /// Returns the stats of the tree before and after, both taken under the write lock that is held the whole time.
+    pub fn compact(&self) -> (TSIMTreeStats, TSIMTreeStats) {
+        let mut node_guard = self.write();
+        let before = node_guard.stats();
+        // The expired entries are dropped by the rebuild.
+        if self.recency.is_some() {
+            for key in node_guard.expired_keys() {
+                self.forget(&key);
+            }
+        }
+        bulk::rebuild(&mut node_guard, RADIX);
+        (before, node_guard.stats())
+    }
+
+    /// Returns an estimate of the bytes the tree occupies, computed by visiting every node.
+    ///
+    /// It counts the tree itself, the slots of the arena, vacant ones included, the children that spilled
+    /// out of their nodes, the capacity of the values that are not stored inline and, for trees created with
+    /// [`TSIMTree::with_capacity`], the nodes that track the recency of the entries.
+    /// The bookkeeping of the allocator and the memory behind the locks are not counted.
+    pub fn approximate_memory_bytes(&self) -> usize {
+        let recency = match &self.recency {
+            Some(recency) => recency.lock().memory_bytes(),
+            None => 0,
+        };
+        size_of::<Self>() + self.root.read().memory_bytes() + recency
+    }
+
+    /// Counts the nodes and entries of the tree and measures its depth.
+    pub fn stats(&self) -> TSIMTreeStats {
+        let node_guard = self.root.read();
+        node_guard.stats()
+    }
+
+    /// Hashes the entries in ascending key order, so trees with the same entries have the same hash
+    /// regardless of the order they were inserted in.
+    ///
+    /// Unlike the `Hash` implementation, the hash does not depend on a `Hasher` or on the platform,
+    /// so the hashes of trees on different machines can be compared, for example to check replicas.
+    /// As a 64-bit hash, it tells trees apart with a high probability, but it is not cryptographic.
+    pub fn content_hash(&self) -> u64 {
+        let node_guard = self.root.read();
+        let mut hash = FNV_OFFSET_BASIS;
+        node_guard.for_each_entry(|key, value| {
+            // The lengths keep the boundaries between keys and values unambiguous.
+            for bytes in [key, value] {
+                hash = fnv1a(hash, &(bytes.len() as u64).to_le_bytes());
+                hash = fnv1a(hash, bytes);
+            }
+        });
+        hash
+    }
+
+    /// Removes all entries from the tree and returns an iterator that yields them in ascending key order.
+    ///
+    /// The write lock is held until the iterator is dropped, so no other thread observes a partially drained tree.
+    /// Entries that were not yielded are removed as well when the iterator is dropped.
+    pub fn drain(&self) -> TSIMTreeDrain<'_, RADIX, LINE, A> {
+        let mut node_guard = self.write();
+        self.forget_all();
+        let nodes = node_guard.take();
+        TSIMTreeDrain {
+            _node_guard: node_guard,
+            entries: TSIMTreeIntoIter {
+                walker: TSIMTreeWalker::new(nodes.root),
+                nodes,
+            },
+        }
+    }
+
+    /// Removes all entries from the tree.
+    ///
+    /// The old entries are dropped after the write lock is released.
+    pub fn clear(&self) {
+        self.root.replace_with(|nodes| {
+            // Forgotten under the write lock, so no key can be stored in between and stay tracked.
+            self.forget_all();
+            let mut empty = nodes.empty_like();
+            empty.version += 1;
+            empty
+        });
+    }
+
+    /// Removes all expired entries and returns how many were removed.
+    ///
+    /// The expired keys are searched under the read lock, the write lock is only acquired if there are any.
+    pub fn tick(&self) -> usize {
+        let expired_keys = self.root.read().expired_keys();
+        if expired_keys.is_empty() {
+            return 0;
+        }
+
+        let mut node_guard = self.write();
+        let mut removed = 0;
+        for key in expired_keys {
+            // The key may have been stored again after the read lock was released.
+            if node_guard
+                .get_entry(&key)
+                .is_some_and(|(_, expiry)| is_expired(expiry))
+            {
+                node_guard.remove_entry(&key);
+                self.forget(&key);
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Removes all expired entries now instead of waiting for them to be accessed and returns how many were removed.
+    ///
+    /// This is the same as [`TSIMTree::tick`], under the name used for eager cleanup.
+    pub fn purge_expired(&self) -> usize {
+        self.tick()
+    }
+
+    /// Spawns a thread that calls [`TSIMTree::tick`] on the tree every interval. Requires the `std` feature.
+    ///
+    /// The thread only holds a weak reference between the ticks and stops once the tree is dropped.
+    #[cfg(feature = "std")]
+    pub fn spawn_expiry_thread(tree: &Arc<Self>, interval: Duration) -> JoinHandle<()>
+    where
+        Self: Send + Sync + 'static,
+    {
+        let tree = Arc::downgrade(tree);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let Some(tree) = tree.upgrade() else {
+                return;
+            };
+            tree.tick();
+        })
+    }
+
+    /// Applies `f` to every value in ascending order of their keys and modifies the values in place.
+    ///
+    /// The keys are not reconstructed, so no key is allocated. The write lock is held for the whole operation.
+    /// If the tree has a capacity, all keys are then marked as recently used in ascending order with the lengths
+    /// of their new values, and the least recently used entries that exceed the capacity are evicted.
+    pub fn transform_values<F>(&self, f: F)
+    where
+        F: FnMut(&mut Vec<u8>),
+    {
+        let mut node_guard = self.write();
+        node_guard.transform_values(f);
+        self.touch_all_and_evict(&mut node_guard);
+    }
+
+    /// Returns a new tree with the same keys, each storing `f(key, value)` of the value in this tree.
+    ///
+    /// The entries are inserted into the new tree in ascending key order under the read lock, so its nodes
+    /// end up full. Expired entries are skipped, and neither the expiry of the other entries nor the capacity
+    /// of this tree is carried over.
+    pub fn map_values<F>(&self, mut f: F) -> TSIMTree<RADIX, LINE, A>
+    where
+        F: FnMut(&[u8], &[u8]) -> Vec<u8>,
+    {
+        let node_guard = self.root.read();
+        let mut nodes = node_guard.empty_like();
+        node_guard.for_each_entry(|key, value| {
+            nodes.put(key, f(key, value));
+        });
+        TSIMTree::from_nodes(nodes)
+    }
+
+    /// Encodes the entries of the tree into a flat byte buffer, which [`TSIMTree::decode`] turns back into a tree.
+    ///
+    /// The buffer starts with a magic header and a format version, the entries follow in ascending key order
+    /// with the prefix they share with the previous key left out. Expired entries are skipped
+    /// and the expiry of the other entries is not encoded.
+    pub fn encode(&self) -> Vec<u8> {
+        let node_guard = self.root.read();
+        codec::encode(&node_guard)
+    }
+
+    /// Keeps only the entries for which `f(key, value)` returns `true`, expired entries are removed as well.
+    ///
+    /// The tree is traversed once and the write lock is held for the whole operation,
+    /// so no other thread observes a partially filtered tree.
+    pub fn retain<F>(&self, mut f: F)
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        let mut node_guard = self.write();
+        if self.recency.is_none() {
+            node_guard.retain(f);
+            return;
+        }
+
+        let mut removed_keys = node_guard.expired_keys();
+        node_guard.retain(|key, value| {
+            let keep = f(key, value);
+            if !keep {
+                removed_keys.push(key.to_vec());
+            }
+            keep
+        });
+        for key in removed_keys {
+            self.forget(&key);
+        }
+    }
+}
+
+/// Collects entries in any order and builds a tree from them in ascending key order.
+///
+/// Keys that arrive in ascending order are always appended behind the existing keys,
+/// so full nodes are left behind instead of half full ones and the tree ends up with fewer nodes.
+/// If a key is pushed multiple times, the value that was pushed last is kept.
+#[derive(Debug)]
+pub struct TSIMTreeBuilder<const RADIX: usize = TREE_RADIX, const LINE: usize = CACHE_LINE_SIZE> {
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl<const RADIX: usize, const LINE: usize> Default for TSIMTreeBuilder<RADIX, LINE> {
+    fn default() -> Self {
+        TSIMTreeBuilder {
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl TSIMTreeBuilder {
+    pub fn new() -> TSIMTreeBuilder {
+        TSIMTreeBuilder::default()
+    }
+}
+
+impl<const RADIX: usize, const LINE: usize> TSIMTreeBuilder<RADIX, LINE> {
+    pub fn push<K>(&mut self, k: K, v: Vec<u8>)
+    where
+        K: AsRef<[u8]>,
+    {
+        self.entries.push((k.as_ref().to_vec(), v));
+    }
+
+    /// Sorts the entries by key and inserts them into a new tree.
+    pub fn build(mut self) -> TSIMTree<RADIX, LINE> {
+        // The sort is stable, so the value that was pushed last is inserted last and replaces the others.
+        self.entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut nodes = TSIMTreeNodes::empty();
+        for (k, v) in self.entries {
+            nodes.put(&k, v);
+        }
+        TSIMTree::from_nodes(nodes)
+    }
+
+    /// Like [`TSIMTreeBuilder::build`], but sorts the entries and builds the subtrees of the root in parallel
+    /// on the rayon thread pool. Requires the `rayon` feature.
+    ///
+    /// The sorted entries are divided into up to `RADIX` ranges, each of which is inserted into a tree of its own.
+    /// Once all of them are built, their nodes are moved into one tree under a routing root.
+    #[cfg(feature = "rayon")]
+    pub fn build_parallel(mut self) -> TSIMTree<RADIX, LINE> {
+        use rayon::prelude::*;
+
+        self.entries.par_sort_by(|(a, _), (b, _)| a.cmp(b));
+        // A key must not be divided over two ranges, so only the value that was pushed last is kept.
+        self.entries.dedup_by(|(key, value), (kept_key, kept_value)| {
+            let duplicate = key == kept_key;
+            if duplicate {
+                core::mem::swap(value, kept_value);
+            }
+            duplicate
+        });
+
+        let mut ranges = Vec::new();
+        let mut entries = self.entries;
+        for start in TSIMTreeNodes::<RADIX, LINE>::range_starts(&entries).into_iter().rev() {
+            let pivot = TSIMTreeNodes::<RADIX, LINE>::range_pivot(&entries, start);
+            ranges.push((pivot, entries.split_off(start)));
+        }
+        ranges.push((Vec::new(), entries));
+        ranges.reverse();
+
+        let subtrees: Vec<_> = ranges
+            .into_par_iter()
+            .map(|(pivot, entries)| {
+                let mut nodes = TSIMTreeNodes::empty();
+                for (k, v) in entries {
+                    nodes.put(&k, v);
+                }
+                (pivot, nodes)
+            })
+            .collect();
+        TSIMTree::from_nodes(TSIMTreeNodes::route_subtrees(subtrees))
+    }
+}
+
+/// A sorted in-memory tree for a single thread.
+///
+/// It stores its nodes like [`TSIMTree`], but without a lock around them. Modifications take `&mut self` instead,
+/// which lets lookups return references into the tree instead of clones.
+/// Both trees convert into each other without copying their nodes.
+#[derive(Debug, Clone)]
+pub struct TSIMTreeLocal<
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    nodes: TSIMTreeNodes<RADIX, LINE, A>,
+}
+
+impl<const RADIX: usize, const LINE: usize> Default for TSIMTreeLocal<RADIX, LINE> {
+    fn default() -> Self {
+        TSIMTreeLocal {
+            nodes: TSIMTreeNodes::empty(),
+        }
+    }
+}
+
+/// Takes the nodes out of the lock. A capacity of the tree is not kept, the entries are no longer evicted.
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> From<TSIMTree<RADIX, LINE, A>>
+    for TSIMTreeLocal<RADIX, LINE, A>
+{
+    fn from(tree: TSIMTree<RADIX, LINE, A>) -> Self {
+        TSIMTreeLocal {
+            nodes: tree.root.into_inner(),
+        }
+    }
+}
+
+/// Puts the nodes behind a lock, so the tree can be shared between threads.
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone>
+    From<TSIMTreeLocal<RADIX, LINE, A>> for TSIMTree<RADIX, LINE, A>
+{
+    fn from(tree: TSIMTreeLocal<RADIX, LINE, A>) -> Self {
+        TSIMTree::from_nodes(tree.nodes)
+    }
+}
+
+/// Consumes the tree and yields its entries in ascending key order, the values are moved out without cloning.
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> IntoIterator
+    for TSIMTreeLocal<RADIX, LINE, A>
+{
+    type Item = (Vec<u8>, Vec<u8>);
+    type IntoIter = TSIMTreeIntoIter<RADIX, LINE, A>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        TSIMTreeIntoIter {
+            walker: TSIMTreeWalker::new(self.nodes.root),
+            nodes: self.nodes,
+        }
+    }
+}
+
+impl<'t, const RADIX: usize, const LINE: usize, A: Allocator + Clone> IntoIterator
+    for &'t TSIMTreeLocal<RADIX, LINE, A>
+{
+    type Item = (Vec<u8>, &'t [u8]);
+    type IntoIter = TSIMTreeLocalIter<'t, RADIX, LINE, A>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl TSIMTreeLocal {
+    pub fn new() -> TSIMTreeLocal {
+        TSIMTreeLocal::default()
+    }
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> TSIMTreeLocal<RADIX, LINE, A> {
+    pub fn put<K>(&mut self, k: K, v: Vec<u8>)
+    where
+        K: AsRef<[u8]>,
+    {
+        self.nodes.put(k.as_ref(), v);
+    }
+
+    /// Returns the value stored under the key without cloning it.
+    ///
+    /// Values that expired while the nodes were part of a [`TSIMTree`] are skipped, but not removed.
+    pub fn get<K>(&self, k: K) -> Option<&[u8]>
+    where
+        K: AsRef<[u8]>,
+    {
+        match self.nodes.get_entry(k.as_ref()) {
+            Some((value, expiry)) if !is_expired(expiry) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn remove<K>(&mut self, k: K) -> Option<Vec<u8>>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.nodes.remove(k.as_ref())
+    }
+
+    /// Returns an iterator over the entries in ascending key order, the values are not cloned.
+    pub fn iter(&self) -> TSIMTreeLocalIter<'_, RADIX, LINE, A> {
+        TSIMTreeLocalIter {
+            entries: self.nodes.entries(),
+        }
+    }
+}
+
+/// An immutable view of a [`TSIMTree`] at the time it was taken by [`TSIMTree::snapshot`].
+///
+/// Clones of a snapshot share the same nodes. The nodes are freed once the last snapshot is dropped.
+#[derive(Debug, Clone)]
+pub struct TSIMTreeSnapshot<
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    nodes: Arc<TSIMTreeNodes<RADIX, LINE, A>>,
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> IntoIterator
+    for TSIMTreeSnapshot<RADIX, LINE, A>
+{
+    type Item = (Vec<u8>, Vec<u8>);
+    type IntoIter = TSIMTreeSnapshotIter<RADIX, LINE, A>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        TSIMTreeSnapshotIter {
+            walker: TSIMTreeWalker::new(self.nodes.root),
+            nodes: self.nodes,
+        }
+    }
+}
+
+impl<'t, const RADIX: usize, const LINE: usize, A: Allocator + Clone> IntoIterator
+    for &'t TSIMTreeSnapshot<RADIX, LINE, A>
+{
+    type Item = (Vec<u8>, &'t [u8]);
+    type IntoIter = TSIMTreeLocalIter<'t, RADIX, LINE, A>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> TSIMTreeSnapshot<RADIX, LINE, A> {
+    /// Returns the value stored under the key without cloning it.
+    ///
+    /// Values that expired since the snapshot was taken are skipped.
+    pub fn get<K>(&self, k: K) -> Option<&[u8]>
+    where
+        K: AsRef<[u8]>,
+    {
+        match self.nodes.get_entry(k.as_ref()) {
+            Some((value, expiry)) if !is_expired(expiry) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns an iterator over the entries in ascending key order, the values are not cloned.
+    pub fn iter(&self) -> TSIMTreeLocalIter<'_, RADIX, LINE, A> {
+        TSIMTreeLocalIter {
+            entries: self.nodes.entries(),
+        }
+    }
+}
+
+/// Converts string keys into the byte keys of the tree.
+///
+/// `&str` and `String` can be passed to the tree directly, as they implement `AsRef<[u8]>`.
+/// Other string types like `Box<str>`, `Arc<str>` or `Cow<str>` only implement `AsRef<str>`,
+/// with this trait they can be passed as `tree.get(key.tree_key())`.
+pub trait TSIMTreeStrKey {
+    /// Returns the UTF-8 encoding of the key, which is how the key is stored in the tree.
+    fn tree_key(&self) -> &[u8];
+}
+
+impl<K> TSIMTreeStrKey for K
+where
+    K: AsRef<str> + ?Sized,
+{
+    fn tree_key(&self) -> &[u8] {
+        self.as_ref().as_bytes()
+    }
+}
+
+/// A node of the tree.
+///
+/// There are two kinds of nodes:
+/// - regular nodes consume the key segment of the child they descend into.
+///   The non-empty segments of a regular node all start with a different byte.
+///   An empty segment may only be stored at index 0 and always holds the value of the key that ends at this node.
+/// - routing nodes are created when a regular node runs out of space.
+///   Their children all live at the same position in the key as the routing node itself,
+///   the segments are only lower bounds that decide which child is responsible for a key.
+///   The first segment of a routing node is always empty.
+///
+/// Nodes are exposed through [`TSIMTree::root_node`] for custom traversals, they cannot be modified from outside.
+#[derive(Clone)]
+#[repr(C, align(128))]
+pub struct TSIMTreeNode<
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    /// Holds `RADIX` key segments of `KEY_SEGMENT_SIZE` bytes each.
+    key_segments: [u8; LINE],
+    /// Holds exactly `children_count` children.
+    children: Children<RADIX, LINE, A>,
+    children_count: u8,
+    routing: bool,
+}
+
+/// The number of cache lines a node of the default layout spans. The key segments fill the first line
+/// and the children the others, each child takes 40 bytes, or 32 without `std`, which leaves no room for an expiry.
+#[cfg(not(feature = "sparse-nodes"))]
+const NODE_CACHE_LINES: usize = if cfg!(feature = "std") { 7 } else { 6 };
+#[cfg(feature = "sparse-nodes")]
+const NODE_CACHE_LINES: usize = 3;
+
+// A field added to the node or the child must not silently make the node span another cache line.
+// The sizes of the children depend on the width of pointers, so they are only checked on 64-bit targets.
+#[cfg(target_pointer_width = "64")]
+const _: () = assert!(size_of::<TSIMTreeNode>() == NODE_CACHE_LINES * CACHE_LINE_SIZE);
+// An empty slot of the children is encoded in a niche of the child and takes no space of its own.
+const _: () = assert!(size_of::<Option<TSIMTreeNodeChild>>() == size_of::<TSIMTreeNodeChild>());
+
+/// The children of a node, which are all stored within the node, so a lookup reads no other memory.
+#[cfg(not(feature = "sparse-nodes"))]
+type Children<const RADIX: usize, const LINE: usize, A> =
+    SmallVec<[Option<TSIMTreeNodeChild<RADIX, LINE, A>>; RADIX]>;
+
+/// The children of a node, the first ones are stored within the node and the others on the heap.
+/// Sparse nodes take up less than half of the memory, but lookups in full nodes read another allocation.
+#[cfg(feature = "sparse-nodes")]
+type Children<const RADIX: usize, const LINE: usize, A> =
+    SmallVec<[Option<TSIMTreeNodeChild<RADIX, LINE, A>>; INLINE_CHILDREN]>;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum TSIMTreeNodeChild<
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    Node(NodeId),
+    /// A value and the instant it expires at, values without expiry are kept until they are removed.
+    Value(StoredValue<A>, Option<Expiry>),
+}
+
+/// A value as it is stored in a node, values of up to `INLINE_VALUE_LEN` bytes are stored within the child.
+/// Such values are read without following a pointer and cost no allocation of their own.
+/// Longer values are allocated by the allocator of the tree.
+///
+/// An inline value is stored in place of the pointer and the capacity of a heap value, so the value is no larger
+/// than a `Vec`. The length tells both kinds apart: a `Vec` is never longer than `isize::MAX` bytes,
+/// so the highest bit of the length marks inline values.
+struct StoredValue<A: Allocator = Global> {
+    bytes: ValueBytes,
+    /// The length of the value, with [`StoredValue::INLINE`] set for values stored within `bytes`.
+    tagged_len: usize,
+    /// Only initialized for heap values, so inline values are created without an allocator.
+    alloc: MaybeUninit<A>,
+}
+
+#[derive(Clone, Copy)]
+union ValueBytes {
+    inline: [u8; INLINE_VALUE_LEN],
+    /// The pointer to and the capacity of the allocation of a heap value.
+    heap: (*mut u8, usize),
+}
+
+// SAFETY: A value owns its bytes just like the `Vec` it was created from.
+unsafe impl<A: Allocator + Send> Send for StoredValue<A> {}
+// SAFETY: The bytes are only modified through `&mut StoredValue`.
+unsafe impl<A: Allocator + Sync> Sync for StoredValue<A> {}
+
+impl<A: Allocator> StoredValue<A> {
+    const INLINE: usize = 1 << (usize::BITS - 1);
+
+    fn new() -> StoredValue<A> {
+        StoredValue::inline(&[])
+    }
+
+    /// Copies a value of up to `INLINE_VALUE_LEN` bytes into a value stored within its child.
+    fn inline(value: &[u8]) -> StoredValue<A> {
+        let mut bytes = [0; INLINE_VALUE_LEN];
+        bytes[..value.len()].copy_from_slice(value);
+        StoredValue {
+            bytes: ValueBytes { inline: bytes },
+            tagged_len: value.len() | StoredValue::<A>::INLINE,
+            alloc: MaybeUninit::uninit(),
+        }
+    }
+
+    /// Takes over the allocation of the `Vec` as a heap value.
+    fn heap(value: allocator_api2::vec::Vec<u8, A>) -> StoredValue<A> {
+        let (ptr, len, capacity, alloc) = value.into_raw_parts_with_alloc();
+        StoredValue {
+            bytes: ValueBytes {
+                heap: (ptr, capacity),
+            },
+            tagged_len: len,
+            alloc: MaybeUninit::new(alloc),
+        }
+    }
+
+    fn is_inline(&self) -> bool {
+        self.tagged_len & StoredValue::<A>::INLINE != 0
+    }
+
+    /// Returns the capacity of the allocation of a heap value, or `None` for an inline value.
+    fn heap_capacity(&self) -> Option<usize> {
+        match self.is_inline() {
+            true => None,
+            // SAFETY: The bytes of a heap value hold its pointer and capacity.
+            false => Some(unsafe { self.bytes.heap.1 }),
+        }
+    }
+
+    /// Turns a heap value back into the `Vec` it was created from, an inline value is returned as it is.
+    fn into_heap(self) -> Result<allocator_api2::vec::Vec<u8, A>, StoredValue<A>> {
+        if self.is_inline() {
+            return Err(self);
+        }
+        let mut value = core::mem::ManuallyDrop::new(self);
+        // SAFETY: The value is a heap value, which is not dropped, so the `Vec` becomes the only owner.
+        Ok(unsafe { value.take_heap() })
+    }
+
+    /// Rebuilds the `Vec` of a heap value.
+    ///
+    /// # Safety
+    ///
+    /// The value must be a heap value, and it must neither be used nor dropped afterwards.
+    unsafe fn take_heap(&mut self) -> allocator_api2::vec::Vec<u8, A> {
+        // SAFETY: The pointer, length, capacity and allocator were taken from a `Vec` by `StoredValue::heap`.
+        unsafe {
+            let (ptr, capacity) = self.bytes.heap;
+            let alloc = self.alloc.assume_init_read();
+            allocator_api2::vec::Vec::from_raw_parts_in(ptr, self.tagged_len, capacity, alloc)
+        }
+    }
+}
+
+impl<A: Allocator> Default for StoredValue<A> {
+    fn default() -> Self {
+        StoredValue::new()
+    }
+}
+
+impl<A: Allocator> Drop for StoredValue<A> {
+    fn drop(&mut self) {
+        if !self.is_inline() {
+            // SAFETY: The value is a heap value and is not used after it is dropped.
+            drop(unsafe { self.take_heap() });
+        }
+    }
+}
+
+impl<A: Allocator + Clone> Clone for StoredValue<A> {
+    fn clone(&self) -> Self {
+        if self.is_inline() {
+            return StoredValue::inline(self);
+        }
+        // SAFETY: The allocator of a heap value is initialized.
+        let alloc = unsafe { self.alloc.assume_init_ref() }.clone();
+        let mut value = allocator_api2::vec::Vec::with_capacity_in(self.len(), alloc);
+        value.extend_from_slice(self);
+        StoredValue::heap(value)
+    }
+}
+
+impl<A: Allocator> Deref for StoredValue<A> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        let len = self.tagged_len & !StoredValue::<A>::INLINE;
+        match self.is_inline() {
+            // SAFETY: The bytes of an inline value hold the value.
+            true => unsafe { &self.bytes.inline[..len] },
+            // SAFETY: The pointer of a heap value points to its `len` initialized bytes.
+            false => unsafe { core::slice::from_raw_parts(self.bytes.heap.0, len) },
+        }
+    }
+}
+
+impl<A: Allocator> Debug for StoredValue<A> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Debug::fmt(&**self, f)
+    }
+}
+
+impl<A: Allocator> PartialEq for StoredValue<A> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<A: Allocator> Eq for StoredValue<A> {}
+
+/// Allocates the values that are too long to be stored within their node.
+#[derive(Debug, Clone)]
+struct ValueAllocator<A: Allocator = Global> {
+    alloc: A,
+    /// Whether `A` is [`Global`], whose values take over the allocations of the vectors put into the tree
+    /// and hand them back when they are taken out, instead of being copied.
+    global: bool,
+}
+
+impl ValueAllocator {
+    fn global() -> ValueAllocator {
+        ValueAllocator {
+            alloc: Global,
+            global: true,
+        }
+    }
+}
+
+impl<A: Allocator + Clone> ValueAllocator<A> {
+    fn new_in(alloc: A) -> ValueAllocator<A> {
+        ValueAllocator {
+            alloc,
+            global: false,
+        }
+    }
+
+    /// Takes ownership of a value put into the tree.
+    fn store(&self, value: Vec<u8>) -> StoredValue<A> {
+        if value.len() <= INLINE_VALUE_LEN {
+            return StoredValue::inline(&value);
+        }
+        if self.global {
+            let mut value = core::mem::ManuallyDrop::new(value);
+            // SAFETY: `A` is `Global`, which allocates from the global allocator just like `Vec`.
+            return StoredValue::heap(unsafe {
+                allocator_api2::vec::Vec::from_raw_parts_in(
+                    value.as_mut_ptr(),
+                    value.len(),
+                    value.capacity(),
+                    self.alloc.clone(),
+                )
+            });
+        }
+        let mut stored =
+            allocator_api2::vec::Vec::with_capacity_in(value.len(), self.alloc.clone());
+        stored.extend_from_slice(&value);
+        StoredValue::heap(stored)
+    }
+
+    /// Hands out a value taken out of the tree.
+    fn release(&self, value: StoredValue<A>) -> Vec<u8> {
+        match value.into_heap() {
+            Ok(value) if self.global => {
+                let (ptr, len, capacity) = value.into_raw_parts();
+                // SAFETY: `A` is `Global`, so the allocation came from the global allocator just like those of `Vec`.
+                unsafe { Vec::from_raw_parts(ptr, len, capacity) }
+            }
+            Ok(value) => value.to_vec(),
+            Err(value) => value.to_vec(),
+        }
+    }
+}
+
+/// The instant a value expires at.
+#[cfg(feature = "std")]
+type Expiry = Instant;
+
+/// Without `std` there is no clock, so values cannot expire and no expiry can be constructed.
+#[cfg(not(feature = "std"))]
+type Expiry = core::convert::Infallible;
+
+/// Checks whether a value with this expiry is expired, expired values are treated as if they were removed.
+#[cfg(feature = "std")]
+fn is_expired(expiry: Option<Expiry>) -> bool {
+    expiry.is_some_and(|expiry| expiry <= Instant::now())
+}
+
+#[cfg(not(feature = "std"))]
+fn is_expired(expiry: Option<Expiry>) -> bool {
+    match expiry {
+        None => false,
+        Some(never) => match never {},
+    }
+}
+
+/// The initial state of the FNV-1a hash.
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Continues the FNV-1a hash with the bytes, which is fast and the same on every platform.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// A violated invariant of a node, as reported by [`TSIMTree::check_integrity`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TSIMTreeFault {
+    /// The length byte of a segment exceeds the space reserved for the segment.
+    InvalidSegment { len: u8 },
+    /// A child within `children_count` is missing.
+    ChildIsNone {
+        child_idx: usize,
+        children_count: u8,
+    },
+    /// The segment at this index does not sort after the previous segment.
+    UnsortedSegments { segment_idx: usize },
+    /// The number of stored children differs from `children_count`.
+    CountMismatch { children_count: u8, children: usize },
+    /// A node other than the root has no children.
+    EmptyNode,
+    /// A child refers to a node that is not stored in the arena.
+    MissingNode { child_idx: usize },
+    /// A child of a routing node is a value instead of a node.
+    ValueInRoutingNode { child_idx: usize },
+    /// The first segment of a routing node is not empty, so the smallest keys are not covered by any child.
+    UncoveredKeys,
+    /// The empty segment of a regular node leads to a node instead of the value of the key that ends at the node.
+    NodeUnderEmptySegment,
+}
+
+/// The reason a fallible operation like [`TSIMTree::try_get`] failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TSIMTreeError {
+    /// A node on the path to the key violates an invariant.
+    Fault(TSIMTreeFault),
+    /// A thread panicked while holding the lock of the tree.
+    /// The locks of the `parking_lot` feature are not poisoned, so it is not reported there.
+    Poisoned,
+    /// Another thread holds the lock, and the operation does not wait for it.
+    WouldBlock,
+}
+
+impl From<TSIMTreeFault> for TSIMTreeError {
+    fn from(fault: TSIMTreeFault) -> Self {
+        TSIMTreeError::Fault(fault)
+    }
+}
+
+/// The reason a batch was rejected by [`TSIMTree::put_batch_transactional`], none of its entries were stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+    /// The key occurs more than once in the batch.
+    DuplicateKey { key: Vec<u8> },
+    /// The key is already stored in the tree.
+    KeyExists { key: Vec<u8> },
+}
+
+/// The reason a buffer was rejected by [`TSIMTree::decode`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum TSIMTreeDecodeError {
+    /// The buffer does not start with the magic bytes of an encoded tree.
+    InvalidMagic,
+    /// The buffer was encoded in a format version this crate does not know.
+    UnsupportedVersion { version: u8 },
+    /// The buffer ends in the middle of an entry.
+    UnexpectedEnd,
+    /// A length does not fit into a `usize`.
+    InvalidVarint,
+    /// A key claims to share more bytes with the previous key than the previous key has.
+    InvalidSharedPrefix { shared: usize },
+    /// A key does not sort after the previous key.
+    UnsortedKeys,
+}
+
+/// The reason the entries were rejected by [`TSIMTree::from_sorted_iter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BulkLoadError {
+    /// The key at this position of the entries does not sort after the previous key,
+    /// either because the keys are not ascending or because the key occurs twice.
+    UnsortedKeys { index: usize },
+}
+
+/// The bound of a tree that is used as a cache, see [`TSIMTree::with_capacity_limit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheLimit {
+    /// The maximum number of entries.
+    Entries(usize),
+    /// The maximum sum of the value lengths of the entries, the keys are not counted.
+    /// A value that is modified through [`TSIMTree::get_mut`] counts with the length it had before,
+    /// until the entry is used again.
+    ValueBytes(usize),
+}
+
+/// The shape of a tree, as reported by [`TSIMTree::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TSIMTreeStats {
+    /// The number of nodes, including the root.
+    pub nodes: usize,
+    /// The number of routing nodes, which do not consume any part of the key.
+    pub routing_nodes: usize,
+    /// The number of stored entries.
+    pub entries: usize,
+    /// The number of nodes on the longest path from the root to an entry.
+    pub depth: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+/// Encodes the location of a child in a node, as returned by [`TSIMTreeNode::resolve_child`].
+pub enum ResolvedChild<'k> {
+    /// The queried key is outside the domain of any existing child.
+    /// A child for the key would have to be inserted at this index.
+    Vacant(usize),
+    /// The key segment at this index is a prefix of the queried key.
+    /// The remaining key fragment is returned as well.
+    ExactMatch(usize, &'k [u8]),
+    /// The queried key and the key segment at this index share the first `common` bytes, but then diverge.
+    PartialMatch { idx: usize, common: usize },
+    /// The queried key is in the domain of the child of a routing node.
+    /// In this case, no remaining key fragment is returned, the previous key must be reused in the query.
+    InDomainOf(usize),
+}
+
+/// Where [`TSIMTreeNodes::find_position`] found a key, or where it would insert it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyPosition<'k> {
+    /// The value of the key is the child at this index of the node.
+    Value(NodeId, usize),
+    /// The key is missing and the node has room for the remaining key fragment.
+    Vacant(NodeId, &'k [u8]),
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> TSIMTreeNode<RADIX, LINE, A> {
+    const KEY_SEGMENT_SIZE: usize = LINE / RADIX;
+    const MAX_STORED_KEY_SEGMENT_SIZE: usize = Self::KEY_SEGMENT_SIZE - 1;
+
+    /// Evaluated when a node is created, so invalid layouts are rejected at compile time.
+    const VALID_LAYOUT: () = {
+        assert!(
+            RADIX >= 2,
+            "Nodes must be able to hold two children to be split"
+        );
+        assert!(
+            RADIX <= u8::MAX as usize,
+            "children_count must fit into a u8"
+        );
+        assert!(
+            Self::KEY_SEGMENT_SIZE >= 2,
+            "Key segments must hold the length byte and at least one byte of the key"
+        );
+        assert!(
+            Self::MAX_STORED_KEY_SEGMENT_SIZE <= u8::MAX as usize,
+            "The segment length must fit into the length byte"
+        );
+    };
+
+    fn empty() -> TSIMTreeNode<RADIX, LINE, A> {
+        let () = Self::VALID_LAYOUT;
+
+        TSIMTreeNode {
+            key_segments: [0; LINE],
+            children: SmallVec::new(),
+            children_count: 0,
+            routing: false,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.children_count as usize == RADIX
+    }
+
+    /// Returns the number of children, they are stored at the indices below it.
+    pub fn children_count(&self) -> usize {
+        self.children_count as usize
+    }
+
+    /// Returns the number of bytes the children take up on the heap, once there are too many to be stored inline.
+    fn heap_bytes(&self) -> usize {
+        match self.children.spilled() {
+            true => {
+                self.children.capacity() * size_of::<Option<TSIMTreeNodeChild<RADIX, LINE, A>>>()
+            }
+            false => 0,
+        }
+    }
+
+    /// Returns whether this is a routing node, whose segments are lower bounds that are not consumed from the key.
+    pub fn is_routing(&self) -> bool {
+        self.routing
+    }
+
+    /// Returns the buffer that stores the length byte and the key segment at the given index.
+    fn segment_buffer(&self, segment_idx: usize) -> &[u8] {
+        let start = segment_idx * Self::KEY_SEGMENT_SIZE;
+        &self.key_segments[start..start + Self::KEY_SEGMENT_SIZE]
+    }
+
+    fn segment_buffer_mut(&mut self, segment_idx: usize) -> &mut [u8] {
+        let start = segment_idx * Self::KEY_SEGMENT_SIZE;
+        &mut self.key_segments[start..start + Self::KEY_SEGMENT_SIZE]
+    }
+
+    /// Stores a fragment of a key at the given segment index.
+    fn set_segment(&mut self, segment_idx: usize, key_fragment: &[u8]) {
+        assert!(segment_idx < RADIX);
+
+        let key_len = key_fragment.len();
+        assert!(key_len <= Self::MAX_STORED_KEY_SEGMENT_SIZE);
+
+        let (length, buffer) = self.segment_buffer_mut(segment_idx).split_at_mut(1);
+        length[0] = key_len as u8;
+        let (segment_buf, unused) = buffer.split_at_mut(key_len);
+        segment_buf.copy_from_slice(key_fragment);
+        // Stale bytes of a previous segment are cleared, so a node never shows remnants of removed keys.
+        unused.fill(0);
+    }
+
+    /// Shortens the key segment at the given index to its first `len` bytes.
+    fn truncate_segment(&mut self, segment_idx: usize, len: usize) {
+        assert!(segment_idx < RADIX);
+
+        let (length, buffer) = self.segment_buffer_mut(segment_idx).split_at_mut(1);
+        assert!(len <= length[0] as usize);
+        length[0] = len as u8;
+        buffer[len..].fill(0);
+    }
+
+    /// Returns the key segment at the given index.
+    /// The segments of a routing node are lower bounds, the segments of a regular node are consumed from the key.
+    ///
+    /// # Panics
+    /// If the index is not below `RADIX`.
+    pub fn get_segment(&self, segment_idx: usize) -> &[u8] {
+        assert!(segment_idx < RADIX);
+        TSIMTreeNode::<RADIX, LINE, A>::stored_segment(self.segment_buffer(segment_idx))
+            .expect("Segment must be valid!")
+    }
+
+    /// The buffer for the segments contains length bytes and subsequently the segment.
+    /// This function reads the length byte and returns a reference to part of the buffer that represent the segment.
+    fn stored_segment(segment: &[u8]) -> Result<&[u8], TSIMTreeFault> {
+        let (len_buffer, segment_buffer) = segment.split_at(1);
+        let stored_segment_length = len_buffer[0];
+
+        if stored_segment_length as usize > Self::MAX_STORED_KEY_SEGMENT_SIZE {
+            return Err(TSIMTreeFault::InvalidSegment {
+                len: stored_segment_length,
+            });
+        }
+
+        let (stored_segment, _unused_segment) =
+            segment_buffer.split_at(stored_segment_length as usize);
+        Ok(stored_segment)
+    }
+
+    /// Use binary search to figure out under what child the key could be located.
+    pub fn resolve_child<'k>(&self, key: &'k [u8]) -> ResolvedChild<'k> {
+        let children_count = self.children_count as usize;
+        assert!(children_count <= RADIX);
+
+        if self.routing {
+            // Find the last child whose lower bound is not greater than the key.
+            // The first segment of a routing node is empty, so there always is one.
+            let mut left_segment_idx = 0;
+            let mut right_segment_idx = children_count;
+            while left_segment_idx < right_segment_idx {
+                let segment = left_segment_idx + (right_segment_idx - left_segment_idx) / 2;
+                match compare::compare(self.get_segment(segment), key) {
+                    Ordering::Greater => right_segment_idx = segment,
+                    Ordering::Less | Ordering::Equal => left_segment_idx = segment + 1,
+                }
+            }
+            assert!(left_segment_idx > 0, "Routing nodes must cover every key");
+            return ResolvedChild::InDomainOf(left_segment_idx - 1);
+        }
+
+        let Some(first_byte) = key.first() else {
+            return match self.has_empty_segment() {
+                true => ResolvedChild::ExactMatch(0, key),
+                false => ResolvedChild::Vacant(0),
+            };
+        };
+
+        // The non-empty segments all start with a different byte, so the first byte decides the child.
+        let mut left_segment_idx = 0;
+        let mut right_segment_idx = children_count;
+        while left_segment_idx < right_segment_idx {
+            let segment_idx = left_segment_idx + (right_segment_idx - left_segment_idx) / 2;
+            let segment = self.get_segment(segment_idx);
+
+            match segment.first().cmp(&Some(first_byte)) {
+                Ordering::Less => left_segment_idx = segment_idx + 1,
+                Ordering::Greater => right_segment_idx = segment_idx,
+                Ordering::Equal => {
+                    let common = compare::common_prefix_len(segment, key);
+                    if common == segment.len() {
+                        return ResolvedChild::ExactMatch(segment_idx, &key[common..]);
+                    }
+                    return ResolvedChild::PartialMatch {
+                        idx: segment_idx,
+                        common,
+                    };
+                }
+            }
+        }
+        ResolvedChild::Vacant(left_segment_idx)
+    }
+
+    fn child(&self, idx: usize) -> &TSIMTreeNodeChild<RADIX, LINE, A> {
+        self.children[idx]
+            .as_ref()
+            .expect("children[child_idx] must be Some(..)")
+    }
+
+    fn child_mut(&mut self, idx: usize) -> &mut TSIMTreeNodeChild<RADIX, LINE, A> {
+        self.children[idx]
+            .as_mut()
+            .expect("children[child_idx] must be Some(..)")
+    }
+
+    /// Inserts the child under the start of the key at the index [`TSIMTreeNode::resolve_child`] returns for it,
+    /// so the segments stay sorted without the caller computing the index. The start is as long as a segment holds,
+    /// the index of the child is returned together with the rest of the key, which has to lead from the child on.
+    ///
+    /// Only a key whose first byte no segment starts with is inserted. Otherwise the node is left unchanged
+    /// and the resolution of the key is returned, which names the child the key continues below or diverges from.
+    /// A full node is left unchanged as well, the key is then resolved to `ResolvedChild::Vacant`.
+    fn put_sorted<'k>(
+        &mut self,
+        key: &'k [u8],
+        child: TSIMTreeNodeChild<RADIX, LINE, A>,
+    ) -> Result<(usize, &'k [u8]), ResolvedChild<'k>> {
+        match self.resolve_child(key) {
+            ResolvedChild::Vacant(idx) if !self.is_full() => {
+                let (key_fragment, remaining_key) =
+                    key.split_at(key.len().min(Self::MAX_STORED_KEY_SEGMENT_SIZE));
+                self.insert_child(idx, key_fragment, child);
+                Ok((idx, remaining_key))
+            }
+            resolved => Err(resolved),
+        }
+    }
+
+    /// Inserts the child at the given index.
+    /// The index must keep the segments sorted, for new keys it is returned by `resolve_child` as `ResolvedChild::Vacant(idx)`.
+    ///
+    /// The index must not exceed `children_count`, as the children are stored without gaps.
+    fn insert_child(
+        &mut self,
+        idx: usize,
+        key_fragment: &[u8],
+        child: TSIMTreeNodeChild<RADIX, LINE, A>,
+    ) {
+        assert!(!self.is_full(), "Cannot insert into full node");
+        assert!(
+            idx <= self.children_count as usize,
+            "Cannot insert at {idx} behind the {} children of the node",
+            self.children_count
+        );
+
+        // Copy over all the key segments
+        let (_unchanged, key_segments) =
+            self.key_segments.split_at_mut(idx * Self::KEY_SEGMENT_SIZE);
+        key_segments.rotate_right(Self::KEY_SEGMENT_SIZE);
+        self.set_segment(idx, key_fragment);
+
+        if self.children.len() == self.children.capacity() {
+            // Spilled children grow up to RADIX, as a node never holds more.
+            let capacity = (self.children.capacity() * 2).min(RADIX);
+            self.children.reserve_exact(capacity - self.children.len());
+        }
+        self.children.insert(idx, Some(child));
+        self.children_count += 1;
+
+        debug_assert!(
+            self.segments_are_sorted(),
+            "Inserting {key_fragment:X?} at {idx} breaks the order of the segments: {self:?}"
+        );
+    }
+
+    /// Checks whether the node stores an empty segment, which is always stored first.
+    ///
+    /// In a regular node, the empty segment holds the value of the key that ends at this node
+    /// and is the only segment the empty remainder of a key can match.
+    /// Non-empty keys never match it, as it sorts before the segments starting with any byte.
+    fn has_empty_segment(&self) -> bool {
+        self.children_count > 0 && self.get_segment(0).is_empty()
+    }
+
+    /// Checks that the segments are strictly ascending.
+    /// In regular nodes the segments must additionally start with different bytes, so at most one of them is empty.
+    fn segments_are_sorted(&self) -> bool {
+        (1..self.children_count as usize).all(|idx| self.is_sorted_at(idx))
+    }
+
+    /// Checks that the segment at the index sorts after the previous segment.
+    fn is_sorted_at(&self, segment_idx: usize) -> bool {
+        let previous = self.get_segment(segment_idx - 1);
+        let segment = self.get_segment(segment_idx);
+        match self.routing {
+            true => previous < segment,
+            false => previous.first() < segment.first(),
+        }
+    }
+
+    /// Closes the gaps left by children that were taken out, the remaining children keep their order.
+    fn compact_children(&mut self) {
+        let children_count = self.children_count as usize;
+        let mut kept = 0;
+        for idx in 0..children_count {
+            if self.children[idx].is_none() {
+                continue;
+            }
+            if idx != kept {
+                self.children.swap(idx, kept);
+                let segment = idx * Self::KEY_SEGMENT_SIZE;
+                self.key_segments.copy_within(
+                    segment..segment + Self::KEY_SEGMENT_SIZE,
+                    kept * Self::KEY_SEGMENT_SIZE,
+                );
+            }
+            kept += 1;
+        }
+
+        self.key_segments[kept * Self::KEY_SEGMENT_SIZE..children_count * Self::KEY_SEGMENT_SIZE]
+            .fill(0);
+        self.children.truncate(kept);
+        self.children_count = kept as u8;
+    }
+
+    /// Removes the child at the given index and closes the gap it leaves behind.
+    fn remove_child(&mut self, idx: usize) -> TSIMTreeNodeChild<RADIX, LINE, A> {
+        let children_count = self.children_count as usize;
+        assert!(
+            idx < children_count,
+            "Cannot remove a child that does not exist"
+        );
+
+        let child = self
+            .children
+            .remove(idx)
+            .expect("children[child_idx] must be Some(..)");
+
+        let (_unchanged, key_segments) = self.key_segments
+            [..children_count * Self::KEY_SEGMENT_SIZE]
+            .split_at_mut(idx * Self::KEY_SEGMENT_SIZE);
+        key_segments.rotate_left(Self::KEY_SEGMENT_SIZE);
+
+        self.segment_buffer_mut(children_count - 1).fill(0);
+        self.children_count -= 1;
+        child
+    }
+
+    /// Moves the children starting at `split_idx` into a new node of the same kind.
+    /// The segments are moved unchanged, so the first segment of a new routing node still has to be reset.
+    fn split_off_children(&mut self, split_idx: usize) -> TSIMTreeNode<RADIX, LINE, A> {
+        let children_count = self.children_count as usize;
+
+        let mut split_off = TSIMTreeNode::empty();
+        split_off.routing = self.routing;
+
+        for idx in split_idx..children_count {
+            let child = self.children[idx]
+                .take()
+                .expect("children[child_idx] must be Some(..)");
+            split_off.insert_child(idx - split_idx, self.get_segment(idx), child);
+            self.segment_buffer_mut(idx).fill(0);
+        }
+        self.children.truncate(split_idx);
+        self.children_count = split_idx as u8;
+        split_off
+    }
+
+    /// Moves the upper half of the children into a new node of the same kind.
+    /// Returns the lower bound of the keys stored in the new node together with the node.
+    fn split_off_upper_half(&mut self) -> (Vec<u8>, TSIMTreeNode<RADIX, LINE, A>) {
+        let mut upper_half = self.split_off_children(self.children_count as usize / 2);
+
+        let pivot = upper_half.get_segment(0).to_owned();
+        if upper_half.routing {
+            upper_half.set_segment(0, &[]);
+        }
+        (pivot, upper_half)
+    }
+
+    /// Moves children of this full node into a new node of the same kind, so there is space for the key.
+    /// Returns the lower bound of the keys stored in the new node together with the node.
+    ///
+    /// If the key would be appended behind all children of a regular node, the new node starts out empty.
+    /// Otherwise the upper half of the children is moved.
+    /// This way keys that are inserted in ascending order leave full nodes behind instead of half full ones.
+    fn split_off_for(&mut self, key: &[u8]) -> (Vec<u8>, TSIMTreeNode<RADIX, LINE, A>) {
+        match self.resolve_child(key) {
+            ResolvedChild::Vacant(idx) if !self.routing && idx == self.children_count as usize => {
+                // The first byte of the key is greater than the first byte of every segment,
+                // so the start of the key sorts after all keys stored in this node.
+                let pivot = &key[..key.len().min(Self::MAX_STORED_KEY_SEGMENT_SIZE)];
+                (pivot.to_vec(), TSIMTreeNode::empty())
+            }
+            _ => self.split_off_upper_half(),
+        }
+    }
+}
+
+/// The nodes of a tree.
+///
+/// All nodes are stored in an arena and refer to their children by their [`NodeId`].
+/// The root node is never removed, it is empty if the tree is empty.
+#[derive(Clone)]
+struct TSIMTreeNodes<
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    arena: Arena<TSIMTreeNode<RADIX, LINE, A>, A>,
+    root: NodeId,
+    values: ValueAllocator<A>,
+    /// The number of writes to the tree, see [`TSIMTree::get_with_version`].
+    version: u64,
+}
+
+impl<const RADIX: usize, const LINE: usize> TSIMTreeNodes<RADIX, LINE> {
+    fn empty() -> TSIMTreeNodes<RADIX, LINE> {
+        TSIMTreeNodes::empty_in(ValueAllocator::global())
+    }
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> TSIMTreeNodes<RADIX, LINE, A> {
+    const MAX_STORED_KEY_SEGMENT_SIZE: usize =
+        TSIMTreeNode::<RADIX, LINE, A>::MAX_STORED_KEY_SEGMENT_SIZE;
+
+    fn empty_in(values: ValueAllocator<A>) -> TSIMTreeNodes<RADIX, LINE, A> {
+        let mut arena = Arena::new_in(values.alloc.clone());
+        let root = arena.insert(TSIMTreeNode::empty());
+        TSIMTreeNodes {
+            arena,
+            root,
+            values,
+            version: 0,
+        }
+    }
+
+    /// Returns empty nodes that allocate from the same allocator and continue the same version.
+    fn empty_like(&self) -> TSIMTreeNodes<RADIX, LINE, A> {
+        TSIMTreeNodes {
+            version: self.version,
+            ..TSIMTreeNodes::empty_in(self.values.clone())
+        }
+    }
+
+    /// Takes all nodes and leaves empty nodes behind.
+    fn take(&mut self) -> TSIMTreeNodes<RADIX, LINE, A> {
+        let empty = self.empty_like();
+        core::mem::replace(self, empty)
+    }
+
+    /// Turns the node into a routing node that distributes its children over two new nodes.
+    /// This effectively creates new space at this node for the key.
+    fn split(&mut self, node: NodeId, key: &[u8]) {
+        let (pivot, upper_half) = self.arena[node].split_off_for(key);
+        let lower_half = core::mem::replace(&mut self.arena[node], TSIMTreeNode::empty());
+        let lower_half = self.arena.insert(lower_half);
+        let upper_half = self.arena.insert(upper_half);
+
+        let node = &mut self.arena[node];
+        node.routing = true;
+        node.insert_child(0, &[], TSIMTreeNodeChild::Node(lower_half));
+        node.insert_child(1, &pivot, TSIMTreeNodeChild::Node(upper_half));
+    }
+
+    /// Returns where the ranges of sorted, unique entries start that are built in parallel, except for the first one.
+    ///
+    /// Each range holds at least `PARALLEL_RANGE_ENTRIES` entries. A range starts at the entry whose key
+    /// differs from the previous key within the first `MAX_STORED_KEY_SEGMENT_SIZE` bytes,
+    /// so its lower bound fits into a segment of a routing node.
+    #[cfg(feature = "rayon")]
+    fn range_starts(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<usize> {
+        const PARALLEL_RANGE_ENTRIES: usize = 4096;
+
+        let ranges = (entries.len() / PARALLEL_RANGE_ENTRIES).clamp(1, RADIX);
+        let mut starts = Vec::new();
+        for range in 1..ranges {
+            let previous_start = starts.last().copied().unwrap_or(0);
+            let mut start = range * entries.len() / ranges;
+            let next_start = (range + 1) * entries.len() / ranges;
+            while start < next_start
+                && Self::range_pivot(entries, start).len() > Self::MAX_STORED_KEY_SEGMENT_SIZE
+            {
+                start += 1;
+            }
+            if start > previous_start && start < next_start {
+                starts.push(start);
+            }
+        }
+        starts
+    }
+
+    /// Returns the shortest prefix of the key at `start` that sorts after the previous key.
+    #[cfg(feature = "rayon")]
+    fn range_pivot(entries: &[(Vec<u8>, Vec<u8>)], start: usize) -> Vec<u8> {
+        let key = &entries[start].0;
+        let common = compare::common_prefix_len(&entries[start - 1].0, key);
+        key[..common + 1].to_vec()
+    }
+
+    /// Moves the nodes of the subtrees into one tree, whose routing root leads to the subtrees by their lower bounds.
+    ///
+    /// The subtrees must be sorted by their lower bounds and hold at most `RADIX` non-overlapping key ranges,
+    /// the first lower bound must be empty.
+    #[cfg(feature = "rayon")]
+    fn route_subtrees(
+        subtrees: Vec<(Vec<u8>, TSIMTreeNodes<RADIX, LINE, A>)>,
+    ) -> TSIMTreeNodes<RADIX, LINE, A> {
+        assert!(
+            subtrees.len() <= RADIX,
+            "a routing node holds at most RADIX subtrees"
+        );
+        if subtrees.len() == 1 {
+            let (_, nodes) = subtrees.into_iter().next().expect("there is one subtree");
+            return nodes;
+        }
+
+        let mut nodes = subtrees[0].1.empty_like();
+        let root = nodes.root;
+        nodes.arena[root].routing = true;
+        for (idx, (lower_bound, mut subtree)) in subtrees.into_iter().enumerate() {
+            let subtree_root = subtree.arena.remove(subtree.root);
+            let subtree_root = subtree.move_node(subtree_root, &mut nodes);
+            nodes.arena[root].insert_child(
+                idx,
+                &lower_bound,
+                TSIMTreeNodeChild::Node(subtree_root),
+            );
+        }
+        nodes
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.get_entry(key)
+            .filter(|(_, expiry)| !is_expired(*expiry))
+            .map(|(value, _)| value)
+    }
+
+    /// Returns the value stored under the key together with its expiry, even if the value is expired.
+    fn get_entry(&self, key: &[u8]) -> Option<(&[u8], Option<Expiry>)> {
+        let (node, idx) = self.find_value(key)?;
+        Some(self.entry_at(node, idx))
+    }
+
+    /// Returns the value at the index of the node together with its expiry.
+    fn entry_at(&self, node: NodeId, idx: usize) -> (&[u8], Option<Expiry>) {
+        match self.arena[node].child(idx) {
+            TSIMTreeNodeChild::Value(v, expiry) => (v, *expiry),
+            TSIMTreeNodeChild::Node(_) => {
+                panic!("the location must be resolved to a TSIMTreeNodeChild::Value(..)")
+            }
+        }
+    }
+
+    /// Returns the node holding the value of the key and the index of the value in the node.
+    fn find_value(&self, key: &[u8]) -> Option<(NodeId, usize)> {
+        self.find_value_from(self.root, key)
+    }
+
+    /// Looks up the key like `find_value`, but starts at the node instead of the root.
+    /// The key must not contain the segments on the path to the node.
+    fn find_value_from(&self, node_id: NodeId, key: &[u8]) -> Option<(NodeId, usize)> {
+        let mut key = key;
+        let mut node_id = node_id;
+        loop {
+            let node = &self.arena[node_id];
+            match node.resolve_child(key) {
+                ResolvedChild::Vacant(_) | ResolvedChild::PartialMatch { .. } => return None,
+                ResolvedChild::ExactMatch(segment, remaining_key) => match node.child(segment) {
+                    TSIMTreeNodeChild::Value(..) if remaining_key.is_empty() => {
+                        return Some((node_id, segment))
+                    }
+                    TSIMTreeNodeChild::Value(..) => return None,
+                    TSIMTreeNodeChild::Node(new_node) => {
+                        self.arena.prefetch(*new_node);
+                        node_id = *new_node;
+                        key = remaining_key;
+                    }
+                },
+                ResolvedChild::InDomainOf(segment) => {
+                    let TSIMTreeNodeChild::Node(new_node) = node.child(segment) else {
+                        panic!("children of routing nodes must be TSIMTreeNodeChild::Node(..)")
+                    };
+                    self.arena.prefetch(*new_node);
+                    node_id = *new_node;
+                }
+            };
+        }
+    }
+
+    /// Returns the position of the value of the key, or the node that can take the missing key as it is.
+    ///
+    /// `None` means that the insertion path would restructure the tree, by splitting a node or pushing down a child.
+    fn find_position<'k>(&self, key: &'k [u8]) -> Option<KeyPosition<'k>> {
+        let mut key = key;
+        let mut node_id = self.root;
+        // Whether the insertion path would split a full child of a routing node on the way down.
+        let mut splits = false;
+        loop {
+            let node = &self.arena[node_id];
+            match node.resolve_child(key) {
+                ResolvedChild::Vacant(_) if !node.is_full() && !splits => {
+                    return Some(KeyPosition::Vacant(node_id, key))
+                }
+                ResolvedChild::Vacant(_) | ResolvedChild::PartialMatch { .. } => return None,
+                ResolvedChild::ExactMatch(segment, remaining_key) => match node.child(segment) {
+                    TSIMTreeNodeChild::Value(..) if remaining_key.is_empty() => {
+                        return Some(KeyPosition::Value(node_id, segment))
+                    }
+                    TSIMTreeNodeChild::Value(..) => return None,
+                    TSIMTreeNodeChild::Node(new_node) => {
+                        self.arena.prefetch(*new_node);
+                        node_id = *new_node;
+                        key = remaining_key;
+                    }
+                },
+                ResolvedChild::InDomainOf(segment) => {
+                    let TSIMTreeNodeChild::Node(new_node) = node.child(segment) else {
+                        panic!("children of routing nodes must be TSIMTreeNodeChild::Node(..)")
+                    };
+                    splits |= !node.is_full() && self.arena[*new_node].is_full();
+                    self.arena.prefetch(*new_node);
+                    node_id = *new_node;
+                }
+            };
+        }
+    }
+
+    /// Stores the value at the position found by `find_position` in the same version of the nodes.
+    fn put_at(&mut self, position: KeyPosition<'_>, v: Vec<u8>) {
+        match position {
+            KeyPosition::Value(node, idx) => {
+                let v = self.values.store(v);
+                let TSIMTreeNodeChild::Value(value, expiry) = self.arena[node].child_mut(idx)
+                else {
+                    panic!("find_position only returns the index of a TSIMTreeNodeChild::Value(..)")
+                };
+                self.values.release(core::mem::replace(value, v));
+                *expiry = None;
+            }
+            KeyPosition::Vacant(node, key) => {
+                let placeholder = TSIMTreeNodeChild::Value(StoredValue::new(), None);
+                let Ok((segment, remaining_key)) = self.arena[node].put_sorted(key, placeholder)
+                else {
+                    panic!("find_position only returns nodes with room for the key")
+                };
+                let child = self.with_mapping(remaining_key, v);
+                *self.arena[node].child_mut(segment) = child;
+            }
+        }
+    }
+
+    /// Stores the value under the key and returns the value that was previously stored there.
+    fn put(&mut self, key: &[u8], v: Vec<u8>) -> Option<Vec<u8>> {
+        self.put_expiring(key, v, None)
+    }
+
+    /// Stores the value under the key until it expires and returns the value that was previously stored there.
+    fn put_expiring(&mut self, key: &[u8], v: Vec<u8>, expiry: Option<Expiry>) -> Option<Vec<u8>> {
+        let values = self.values.clone();
+        let mut new_value = Some(v);
+        let (value, value_expiry) = self.get_or_insert_entry_with(key, || {
+            new_value
+                .take()
+                .expect("the value is only inserted once")
+        });
+        *value_expiry = expiry;
+
+        // If the value is still there, the key already existed.
+        new_value.map(|v| values.release(core::mem::replace(value, values.store(v))))
+    }
+
+    /// Returns the value stored under the key, inserting the result of `f` if the key does not exist yet.
+    fn get_or_insert_with<F>(&mut self, key: &[u8], f: F) -> &mut StoredValue<A>
+    where
+        F: FnOnce() -> Vec<u8>,
+    {
+        let (value, _) = self.get_or_insert_entry_with(key, f);
+        value
+    }
+
+    /// Returns the value stored under the key together with its expiry,
+    /// inserting the result of `f` without expiry if the key does not exist yet or its value is expired.
+    fn get_or_insert_entry_with<F>(
+        &mut self,
+        key: &[u8],
+        f: F,
+    ) -> (&mut StoredValue<A>, &mut Option<Expiry>)
+    where
+        F: FnOnce() -> Vec<u8>,
+    {
+        let mut key = key;
+        let mut node = self.root;
+
+        loop {
+            if self.arena[node].routing && !self.arena[node].is_full() {
+                // Split full children while there is still space here, so the tree grows in width instead of depth.
+                let ResolvedChild::InDomainOf(segment) = self.arena[node].resolve_child(key) else {
+                    panic!("routing nodes only resolve to ResolvedChild::InDomainOf(..)")
+                };
+                let TSIMTreeNodeChild::Node(child) = *self.arena[node].child(segment) else {
+                    panic!("children of routing nodes must be TSIMTreeNodeChild::Node(..)")
+                };
+                if self.arena[child].is_full() {
+                    let (pivot, upper_half) = self.arena[child].split_off_for(key);
+                    let upper_half = self.arena.insert(upper_half);
+                    self.arena[node].insert_child(
+                        segment + 1,
+                        &pivot,
+                        TSIMTreeNodeChild::Node(upper_half),
+                    );
+                }
+            }
+
+            // The key is inserted under an empty value first, which is replaced once the rest of the key is known.
+            let placeholder = TSIMTreeNodeChild::Value(StoredValue::new(), None);
+            let resolved = match self.arena[node].put_sorted(key, placeholder) {
+                Ok((segment, remaining_key)) => {
+                    let child = self.with_mapping(remaining_key, f());
+                    *self.arena[node].child_mut(segment) = child;
+
+                    let mut segment = segment;
+                    while let TSIMTreeNodeChild::Node(chain_node) = *self.arena[node].child(segment)
+                    {
+                        node = chain_node;
+                        segment = 0;
+                    }
+                    let TSIMTreeNodeChild::Value(value, expiry) =
+                        self.arena[node].child_mut(segment)
+                    else {
+                        panic!(
+                            "chains created by with_mapping end in a TSIMTreeNodeChild::Value(..)"
+                        )
+                    };
+                    return (value, expiry);
+                }
+                Err(resolved) => resolved,
+            };
+
+            match resolved {
+                // Only a full node does not take a key whose first byte is vacant.
+                ResolvedChild::Vacant(_) => {
+                    self.split(node, key);
+                    continue;
+                }
+
+                ResolvedChild::ExactMatch(segment, remaining_key) => {
+                    let new_node = match *self.arena[node].child(segment) {
+                        TSIMTreeNodeChild::Node(new_node) => {
+                            self.arena.prefetch(new_node);
+                            new_node
+                        }
+                        TSIMTreeNodeChild::Value(..) if remaining_key.is_empty() => {
+                            let TSIMTreeNodeChild::Value(old_val, expiry) =
+                                self.arena[node].child_mut(segment)
+                            else {
+                                panic!("child was just checked to be TSIMTreeNodeChild::Value(..)")
+                            };
+                            if is_expired(*expiry) {
+                                *old_val = self.values.store(f());
+                                *expiry = None;
+                            }
+                            return (old_val, expiry);
+                        }
+                        // The existing value is stored under a prefix of the new key.
+                        // The value is moved under the empty segment of a new node, which then also receives the new key.
+                        TSIMTreeNodeChild::Value(..) => {
+                            let len = self.arena[node].get_segment(segment).len();
+                            self.pushdown_child(node, segment, len)
+                        }
+                    };
+                    node = new_node;
+                    key = remaining_key;
+                }
+
+                ResolvedChild::PartialMatch {
+                    idx: segment,
+                    common,
+                } => {
+                    // The key diverges in the middle of the stored segment.
+                    // The segment is shortened to the common part and the rest of it is pushed down into a new node.
+                    node = self.pushdown_child(node, segment, common);
+                    key = &key[common..];
+                }
+
+                ResolvedChild::InDomainOf(segment) => {
+                    let TSIMTreeNodeChild::Node(new_node) = *self.arena[node].child(segment) else {
+                        panic!("children of routing nodes must be TSIMTreeNodeChild::Node(..)")
+                    };
+                    self.arena.prefetch(new_node);
+                    node = new_node;
+                }
+            };
+        }
+    }
+
+    /// Moves the child at the index one layer down into a new node and returns the new node.
+    /// The segment is shortened to its first `len` bytes, the rest of it now leads from the new node to the child.
+    ///
+    /// The child is moved and the segment is copied straight from the node, so neither values nor keys are cloned.
+    fn pushdown_child(&mut self, node: NodeId, idx: usize, len: usize) -> NodeId {
+        let child = core::mem::replace(
+            self.arena[node].child_mut(idx),
+            TSIMTreeNodeChild::Value(StoredValue::new(), None),
+        );
+        let mut new_node = TSIMTreeNode::empty();
+        new_node.insert_child(0, &self.arena[node].get_segment(idx)[len..], child);
+        let new_node = self.arena.insert(new_node);
+
+        let parent = &mut self.arena[node];
+        *parent.child_mut(idx) = TSIMTreeNodeChild::Node(new_node);
+        parent.truncate_segment(idx, len);
+        new_node
+    }
+
+    /// Creates a subtree to store the value at the given key.
+    fn with_mapping(&mut self, key: &[u8], value: Vec<u8>) -> TSIMTreeNodeChild<RADIX, LINE, A> {
+        self.with_path(
+            key,
+            TSIMTreeNodeChild::Value(self.values.store(value), None),
+        )
+    }
+
+    /// Creates a chain of nodes that leads to the child under the given key.
+    ///
+    /// Every node of the chain stores a full segment except the last one,
+    /// which is the least number of nodes that can store the key, as the segments of a node are alternatives.
+    fn with_path(
+        &mut self,
+        key: &[u8],
+        child: TSIMTreeNodeChild<RADIX, LINE, A>,
+    ) -> TSIMTreeNodeChild<RADIX, LINE, A> {
+        key.chunks(Self::MAX_STORED_KEY_SEGMENT_SIZE)
+            .rev()
+            .fold(child, |child, key_fragment| {
+                let mut node = TSIMTreeNode::empty();
+                node.insert_child(0, key_fragment, child);
+                TSIMTreeNodeChild::Node(self.arena.insert(node))
+            })
+    }
+
+    /// Removes the key from the tree and returns the value that was stored under it.
+    /// Expired values are removed as well, but they are not returned.
+    fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.remove_entry(key)
+            .filter(|(_, expiry)| !is_expired(*expiry))
+            .map(|(value, _)| value)
+    }
+
+    /// Removes the key from the tree and returns the value that was stored under it together with its expiry.
+    fn remove_entry(&mut self, key: &[u8]) -> Option<(Vec<u8>, Option<Expiry>)> {
+        // First find the path to the value without modifying anything.
+        let mut path = Vec::new();
+        let mut key = key;
+        let mut node = &self.arena[self.root];
+        loop {
+            match node.resolve_child(key) {
+                ResolvedChild::Vacant(_) | ResolvedChild::PartialMatch { .. } => return None,
+                ResolvedChild::ExactMatch(segment, remaining_key) => {
+                    path.push(segment);
+                    match node.child(segment) {
+                        TSIMTreeNodeChild::Value(..) if remaining_key.is_empty() => break,
+                        TSIMTreeNodeChild::Value(..) => return None,
+                        TSIMTreeNodeChild::Node(new_node) => {
+                            node = &self.arena[*new_node];
+                            key = remaining_key;
+                        }
+                    }
+                }
+                ResolvedChild::InDomainOf(segment) => {
+                    path.push(segment);
+                    let TSIMTreeNodeChild::Node(new_node) = node.child(segment) else {
+                        panic!("children of routing nodes must be TSIMTreeNodeChild::Node(..)")
+                    };
+                    node = &self.arena[*new_node];
+                }
+            }
+        }
+
+        Some(self.detach_value(&path))
+    }
+
+    /// Removes the value at the end of the path of child indices and returns it together with its expiry.
+    fn detach_value(&mut self, path: &[usize]) -> (Vec<u8>, Option<Expiry>) {
+        let TSIMTreeNodeChild::Value(value, expiry) = self.detach(path) else {
+            panic!("the path was resolved to a TSIMTreeNodeChild::Value(..)")
+        };
+        (self.values.release(value), expiry)
+    }
+
+    /// Returns the smallest or, if `last` is set, the largest unexpired entry
+    /// together with the path of child indices that leads to its value.
+    ///
+    /// Only the leftmost or rightmost children are visited, unless the subtree they lead to holds only expired entries.
+    fn extreme_entry(&self, last: bool) -> Option<(Vec<usize>, Vec<u8>, &[u8])> {
+        self.scan_entries(vec![(self.root, 0, 0)], Vec::new(), Vec::new(), last)
+    }
+
+    /// Returns the smallest unexpired entry whose key is greater than or equal to the key or,
+    /// if `last` is set, the largest unexpired entry whose key is less than or equal to the key.
+    /// Unless `inclusive` is set, the entry of the key itself is skipped.
+    ///
+    /// The key is looked up like in `get`. Where the lookup stops, the scan continues
+    /// with the neighbouring children, so only the entries next to the key are visited.
+    fn neighbour_entry(&self, key: &[u8], last: bool, inclusive: bool) -> Option<(Vec<u8>, &[u8])> {
+        // The frame of a node resumes the scan at the given child, as if the children before it were visited.
+        let resume_at = |child_idx: usize, children_count: usize| match last {
+            true => children_count - 1 - child_idx,
+            false => child_idx,
+        };
+        let resume_after = |child_idx: usize, children_count: usize| match last {
+            true => children_count - child_idx,
+            false => child_idx + 1,
+        };
+
+        let mut remaining_key = key;
+        let mut key = Vec::new();
+        let mut path = Vec::new();
+        let mut stack = Vec::new();
+        let mut node_id = self.root;
+        loop {
+            let node = &self.arena[node_id];
+            let children_count = node.children_count as usize;
+            let key_len = key.len();
+            let (child_idx, child) = match node.resolve_child(remaining_key) {
+                ResolvedChild::Vacant(idx) => {
+                    // The child at the index is the first one that is greater than the key.
+                    let visited = match last {
+                        true => children_count - idx,
+                        false => idx,
+                    };
+                    stack.push((node_id, visited, key_len));
+                    break;
+                }
+                ResolvedChild::PartialMatch { idx, common } => {
+                    // All keys below the child are on the same side of the key.
+                    let segment = node.get_segment(idx);
+                    let child_is_greater = remaining_key
+                        .get(common)
+                        .is_none_or(|&key_byte| segment[common] > key_byte);
+                    let visited = match child_is_greater == last {
+                        true => resume_after(idx, children_count),
+                        false => resume_at(idx, children_count),
+                    };
+                    stack.push((node_id, visited, key_len));
+                    break;
+                }
+                ResolvedChild::ExactMatch(idx, rest) => match node.child(idx) {
+                    TSIMTreeNodeChild::Value(..) => {
+                        // The value is stored under the key itself or under a prefix of the key, which is less.
+                        let is_neighbour = match rest.is_empty() {
+                            true => inclusive,
+                            false => last,
+                        };
+                        let visited = match is_neighbour {
+                            true => resume_at(idx, children_count),
+                            false => resume_after(idx, children_count),
+                        };
+                        stack.push((node_id, visited, key_len));
+                        break;
+                    }
+                    TSIMTreeNodeChild::Node(child) => {
+                        key.extend_from_slice(node.get_segment(idx));
+                        remaining_key = rest;
+                        (idx, *child)
+                    }
+                },
+                ResolvedChild::InDomainOf(idx) => {
+                    let TSIMTreeNodeChild::Node(child) = node.child(idx) else {
+                        panic!("children of routing nodes must be TSIMTreeNodeChild::Node(..)")
+                    };
+                    (idx, *child)
+                }
+            };
+
+            // Once the subtree of the child holds no matching entry, the scan continues with its siblings.
+            stack.push((node_id, resume_after(child_idx, children_count), key_len));
+            path.push(child_idx);
+            node_id = child;
+        }
+
+        let (_, key, value) = self.scan_entries(stack, path, key, last)?;
+        Some((key, value))
+    }
+
+    /// Continues a scan over the entries in ascending or, if `last` is set, descending key order
+    /// and returns the first unexpired entry together with the path of child indices that leads to its value.
+    ///
+    /// Each frame of the stack holds a node, the number of its children that were visited
+    /// and the length of the key at that node. The path and the key belong to the last visited child.
+    fn scan_entries(
+        &self,
+        mut stack: Vec<(NodeId, usize, usize)>,
+        mut path: Vec<usize>,
+        mut key: Vec<u8>,
+        last: bool,
+    ) -> Option<(Vec<usize>, Vec<u8>, &[u8])> {
+        while let Some((node_id, visited, key_len)) = stack.pop() {
+            let node = &self.arena[node_id];
+            let children_count = node.children_count as usize;
+            if visited >= children_count {
+                continue;
+            }
+            stack.push((node_id, visited + 1, key_len));
+
+            let child_idx = if last {
+                children_count - 1 - visited
+            } else {
+                visited
+            };
+            path.truncate(stack.len() - 1);
+            path.push(child_idx);
+            key.truncate(key_len);
+            if !node.routing {
+                key.extend_from_slice(node.get_segment(child_idx));
+            }
+
+            match node.child(child_idx) {
+                TSIMTreeNodeChild::Value(_, expiry) if is_expired(*expiry) => {}
+                TSIMTreeNodeChild::Value(value, _) => return Some((path, key, value)),
+                TSIMTreeNodeChild::Node(child) => stack.push((*child, 0, key.len())),
+            }
+        }
+        None
+    }
+
+    /// Removes the smallest or, if `last` is set, the largest unexpired entry and returns it.
+    fn pop_extreme_entry(&mut self, last: bool) -> Option<(Vec<u8>, Vec<u8>)> {
+        let (path, key, _) = self.extreme_entry(last)?;
+        let (value, _) = self.detach_value(&path);
+        Some((key, value))
+    }
+
+    /// Returns the keys of all expired entries in ascending order.
+    fn expired_keys(&self) -> Vec<Vec<u8>> {
+        let mut expired_keys = Vec::new();
+        let mut walker = TSIMTreeWalker::new(self.root);
+        while let Some(key) = walker.next_expired_key(self) {
+            expired_keys.push(key.to_vec());
+        }
+        expired_keys
+    }
+
+    /// Removes the child at the end of the path of child indices and returns it.
+    ///
+    /// Nodes that only lead to the removed child are removed as a whole,
+    /// so the child is detached at the deepest node that has other children as well.
+    /// If that node is left with a single child, it is merged into its parent where possible.
+    fn detach(&mut self, path: &[usize]) -> TSIMTreeNodeChild<RADIX, LINE, A> {
+        let mut detach_depth = 0;
+        let mut node = self.root;
+        for (depth, &segment) in path.iter().enumerate() {
+            if self.arena[node].children_count > 1 {
+                detach_depth = depth;
+            }
+            if let TSIMTreeNodeChild::Node(new_node) = *self.arena[node].child(segment) {
+                node = new_node;
+            }
+        }
+
+        let mut parent = self.root;
+        let mut node = self.root;
+        for &segment in &path[..detach_depth] {
+            let TSIMTreeNodeChild::Node(new_node) = *self.arena[node].child(segment) else {
+                panic!("the path must lead through TSIMTreeNodeChild::Node(..)")
+            };
+            parent = node;
+            node = new_node;
+        }
+
+        let segment = path[detach_depth];
+        let mut detached = self.arena[node].remove_child(segment);
+
+        if self.arena[node].routing {
+            if self.arena[node].children_count == 1 {
+                // A routing node with a single child is redundant, the child takes its place.
+                let TSIMTreeNodeChild::Node(only_child) = self.arena[node].remove_child(0) else {
+                    panic!("children of routing nodes must be TSIMTreeNodeChild::Node(..)")
+                };
+                let only_child = self.arena.remove(only_child);
+                self.arena[node] = only_child;
+            } else if segment == 0 {
+                self.arena[node].set_segment(0, &[]);
+            }
+        }
+        if detach_depth > 0 {
+            self.merge_single_child(parent, path[detach_depth - 1]);
+        }
+
+        // Everything below the detached child only leads to the child at the end of the path, those nodes are freed.
+        for &segment in &path[detach_depth + 1..] {
+            let TSIMTreeNodeChild::Node(detached_node) = detached else {
+                panic!("the path must lead through TSIMTreeNodeChild::Node(..)")
+            };
+            detached = self.arena.remove(detached_node).remove_child(segment);
+        }
+        detached
+    }
+
+    /// Replaces the child node at the index by its only child, if both segments fit into a single segment.
+    ///
+    /// Removing keys can leave nodes behind that only hold a short segment,
+    /// merging them keeps chains of nodes as short as if the remaining keys had been inserted alone.
+    ///
+    /// Returns whether the child was merged.
+    fn merge_single_child(&mut self, node: NodeId, idx: usize) -> bool {
+        if self.arena[node].routing {
+            return false;
+        }
+        let TSIMTreeNodeChild::Node(child) = *self.arena[node].child(idx) else {
+            return false;
+        };
+        if self.arena[child].routing || self.arena[child].children_count != 1 {
+            return false;
+        }
+
+        let mut merged_segment = self.arena[node].get_segment(idx).to_vec();
+        merged_segment.extend_from_slice(self.arena[child].get_segment(0));
+        if merged_segment.len() > Self::MAX_STORED_KEY_SEGMENT_SIZE {
+            return false;
+        }
+
+        let grandchild = self.arena.remove(child).remove_child(0);
+        let node = &mut self.arena[node];
+        *node.child_mut(idx) = grandchild;
+        node.set_segment(idx, &merged_segment);
+        true
+    }
+
+    /// Merges the nodes that removals left sparse and moves the nodes into a new arena without vacant slots.
+    ///
+    /// Returns the number of bytes the nodes shrank by.
+    fn shrink_to_fit(&mut self) -> usize {
+        let allocated = self.allocated_bytes();
+
+        // The children are shrunk before their parents, so a parent sees how few children they have left.
+        let mut nodes = Vec::new();
+        let mut unvisited = vec![self.root];
+        while let Some(node) = unvisited.pop() {
+            nodes.push(node);
+            for idx in 0..self.arena[node].children_count as usize {
+                if let TSIMTreeNodeChild::Node(child) = *self.arena[node].child(idx) {
+                    unvisited.push(child);
+                }
+            }
+        }
+        for &node in nodes.iter().rev() {
+            while self.shrink_node(node) {}
+            self.arena[node].children.shrink_to_fit();
+        }
+
+        let mut compacted = TSIMTreeNodes {
+            arena: Arena::new_in(self.values.alloc.clone()),
+            root: self.root,
+            values: self.values.clone(),
+            version: self.version,
+        };
+        let root = self.arena.remove(self.root);
+        compacted.root = self.move_node(root, &mut compacted);
+        compacted.arena.shrink_to_fit();
+        *self = compacted;
+        allocated.saturating_sub(self.allocated_bytes())
+    }
+
+    /// Returns the number of bytes allocated for the arena and for the children that did not fit into their nodes.
+    /// The values are not included.
+    fn allocated_bytes(&self) -> usize {
+        let mut allocated = self.arena.allocated_bytes();
+        let mut nodes = vec![self.root];
+        while let Some(node) = nodes.pop() {
+            let node = &self.arena[node];
+            allocated += node.heap_bytes();
+            for idx in 0..node.children_count as usize {
+                if let TSIMTreeNodeChild::Node(child) = *node.child(idx) {
+                    nodes.push(child);
+                }
+            }
+        }
+        allocated
+    }
+
+    /// Returns the number of bytes allocated for the nodes, like [`TSIMTreeNodes::allocated_bytes`],
+    /// plus the capacity of the values that are too long to be stored inline.
+    fn memory_bytes(&self) -> usize {
+        let mut allocated = self.arena.allocated_bytes();
+        let mut nodes = vec![self.root];
+        while let Some(node) = nodes.pop() {
+            let node = &self.arena[node];
+            allocated += node.heap_bytes();
+            for idx in 0..node.children_count as usize {
+                match node.child(idx) {
+                    TSIMTreeNodeChild::Node(child) => nodes.push(*child),
+                    TSIMTreeNodeChild::Value(value, _) => {
+                        allocated += value.heap_capacity().unwrap_or(0);
+                    }
+                }
+            }
+        }
+        allocated
+    }
+
+    /// Merges one child of the node into it, or the node into its only child.
+    ///
+    /// Returns whether the node changed, the node is shrunk until it does not change anymore.
+    fn shrink_node(&mut self, node: NodeId) -> bool {
+        let routing = self.arena[node].routing;
+        let children_count = self.arena[node].children_count as usize;
+        if routing && children_count == 1 {
+            // A routing node with a single child is redundant, the child takes its place.
+            let TSIMTreeNodeChild::Node(only_child) = self.arena[node].remove_child(0) else {
+                panic!("children of routing nodes must be TSIMTreeNodeChild::Node(..)")
+            };
+            self.arena[node] = self.arena.remove(only_child);
+            return true;
+        }
+
+        for idx in 0..children_count {
+            let TSIMTreeNodeChild::Node(child) = *self.arena[node].child(idx) else {
+                continue;
+            };
+            if self.arena[child].children_count == 0 {
+                // Only the root may be empty, any other empty node leads to no entry.
+                self.arena[node].remove_child(idx);
+                self.arena.remove(child);
+                if routing && idx == 0 && self.arena[node].children_count > 0 {
+                    self.arena[node].set_segment(0, &[]);
+                }
+                return true;
+            }
+            let merged = match (routing, self.arena[child].routing) {
+                (false, _) => self.merge_single_child(node, idx),
+                (true, true) => self.merge_routing_child(node, idx),
+                (true, false) => false,
+            };
+            if merged {
+                return true;
+            }
+        }
+        routing && self.merge_regular_children(node)
+    }
+
+    /// Replaces the routing child at the index by its children, if they fit into the routing node.
+    ///
+    /// Both nodes route the same part of the key, so the lower bounds of the child stay valid in the node.
+    fn merge_routing_child(&mut self, node: NodeId, idx: usize) -> bool {
+        let TSIMTreeNodeChild::Node(child) = *self.arena[node].child(idx) else {
+            return false;
+        };
+        let children_count = self.arena[node].children_count as usize;
+        if children_count - 1 + self.arena[child].children_count as usize > RADIX {
+            return false;
+        }
+
+        // The first lower bound of the child is empty, its children are bounded by the segment of the node instead.
+        let lower_bound = self.arena[node].get_segment(idx).to_vec();
+        let mut child = self.arena.remove(child);
+        let node = &mut self.arena[node];
+        node.remove_child(idx);
+        for child_idx in 0..child.children_count as usize {
+            let grandchild = child.children[child_idx]
+                .take()
+                .expect("children[child_idx] must be Some(..)");
+            let segment = match child_idx {
+                0 => lower_bound.as_slice(),
+                _ => child.get_segment(child_idx),
+            };
+            node.insert_child(idx + child_idx, segment, grandchild);
+        }
+        true
+    }
+
+    /// Turns the routing node into a regular node holding the children of its children,
+    /// if they fit into one node and all start with different bytes.
+    fn merge_regular_children(&mut self, node: NodeId) -> bool {
+        let children: Vec<NodeId> = (0..self.arena[node].children_count as usize)
+            .map(|idx| match *self.arena[node].child(idx) {
+                TSIMTreeNodeChild::Node(child) => child,
+                TSIMTreeNodeChild::Value(..) => {
+                    panic!("children of routing nodes must be TSIMTreeNodeChild::Node(..)")
+                }
+            })
+            .collect();
+        if children.iter().any(|&child| self.arena[child].routing) {
+            return false;
+        }
+        let first_bytes: Vec<Option<&u8>> = children
+            .iter()
+            .flat_map(|&child| {
+                let child = &self.arena[child];
+                (0..child.children_count as usize).map(|idx| child.get_segment(idx).first())
+            })
+            .collect();
+        if first_bytes.len() > RADIX || !first_bytes.windows(2).all(|pair| pair[0] < pair[1]) {
+            return false;
+        }
+
+        let mut merged = TSIMTreeNode::empty();
+        for child in children {
+            let mut child = self.arena.remove(child);
+            for idx in 0..child.children_count as usize {
+                let grandchild = child.children[idx]
+                    .take()
+                    .expect("children[idx] must be Some(..)");
+                merged.insert_child(
+                    merged.children_count as usize,
+                    child.get_segment(idx),
+                    grandchild,
+                );
+            }
+        }
+        self.arena[node] = merged;
+        true
+    }
+
+    /// Moves the child and all nodes below it into the arena of `target`.
+    fn move_child(
+        &mut self,
+        child: TSIMTreeNodeChild<RADIX, LINE, A>,
+        target: &mut TSIMTreeNodes<RADIX, LINE, A>,
+    ) -> TSIMTreeNodeChild<RADIX, LINE, A> {
+        match child {
+            TSIMTreeNodeChild::Node(node) => {
+                let node = self.arena.remove(node);
+                TSIMTreeNodeChild::Node(self.move_node(node, target))
+            }
+            value => value,
+        }
+    }
+
+    /// Inserts a node that was taken out of this arena into the arena of `target`,
+    /// together with all nodes below it.
+    fn move_node(
+        &mut self,
+        node: TSIMTreeNode<RADIX, LINE, A>,
+        target: &mut TSIMTreeNodes<RADIX, LINE, A>,
+    ) -> NodeId {
+        let moved_node = target.arena.insert(node);
+        let mut nodes = vec![moved_node];
+        while let Some(node) = nodes.pop() {
+            for idx in 0..target.arena[node].children_count as usize {
+                if let TSIMTreeNodeChild::Node(child) = *target.arena[node].child(idx) {
+                    let moved_child = target.arena.insert(self.arena.remove(child));
+                    *target.arena[node].child_mut(idx) = TSIMTreeNodeChild::Node(moved_child);
+                    nodes.push(moved_child);
+                }
+            }
+        }
+        moved_node
+    }
+
+    /// Removes the child and all nodes below it from the arena.
+    fn free_child(&mut self, child: TSIMTreeNodeChild<RADIX, LINE, A>) {
+        let mut children = vec![child];
+        while let Some(child) = children.pop() {
+            if let TSIMTreeNodeChild::Node(node) = child {
+                let mut node = self.arena.remove(node);
+                children.extend(node.children.iter_mut().filter_map(Option::take));
+            }
+        }
+    }
+
+    /// Removes all keys starting with the prefix and returns them as the nodes of a new tree.
+    fn split_off_prefix(&mut self, prefix: &[u8]) -> TSIMTreeNodes<RADIX, LINE, A> {
+        if prefix.is_empty() {
+            return self.take();
+        }
+
+        // Find the child that holds all keys starting with the prefix, as well as the key that leads to it.
+        let mut path = Vec::new();
+        let mut child_key = Vec::new();
+        let mut key = prefix;
+        let mut node = &self.arena[self.root];
+        loop {
+            match node.resolve_child(key) {
+                ResolvedChild::Vacant(_) => return self.empty_like(),
+                ResolvedChild::PartialMatch { idx: segment, common } if common == key.len() => {
+                    // The prefix ends in the middle of the segment, so the whole child starts with the prefix.
+                    path.push(segment);
+                    child_key.extend_from_slice(node.get_segment(segment));
+                    break;
+                }
+                ResolvedChild::PartialMatch { .. } => return self.empty_like(),
+                ResolvedChild::ExactMatch(segment, remaining_key) => {
+                    path.push(segment);
+                    child_key.extend_from_slice(node.get_segment(segment));
+                    match node.child(segment) {
+                        _ if remaining_key.is_empty() => break,
+                        TSIMTreeNodeChild::Value(..) => return self.empty_like(),
+                        TSIMTreeNodeChild::Node(new_node) => {
+                            node = &self.arena[*new_node];
+                            key = remaining_key;
+                        }
+                    }
+                }
+                ResolvedChild::InDomainOf(segment) => {
+                    let next_segment = segment + 1;
+                    if next_segment < node.children_count as usize
+                        && node.get_segment(next_segment).starts_with(key)
+                    {
+                        // The keys starting with the prefix are spread over multiple children, they are moved one by one.
+                        return self.split_off_prefix_by_entries(prefix);
+                    }
+
+                    path.push(segment);
+                    let TSIMTreeNodeChild::Node(new_node) = node.child(segment) else {
+                        panic!("children of routing nodes must be TSIMTreeNodeChild::Node(..)")
+                    };
+                    node = &self.arena[*new_node];
+                }
+            }
+        }
+
+        let detached = self.detach(&path);
+
+        let mut split_off = self.empty_like();
+        let detached = self.move_child(detached, &mut split_off);
+        let (key_fragment, remaining_key) =
+            child_key.split_at(child_key.len().min(Self::MAX_STORED_KEY_SEGMENT_SIZE));
+        let child = split_off.with_path(remaining_key, detached);
+        let root = split_off.root;
+        split_off.arena[root].insert_child(0, key_fragment, child);
+        split_off
+    }
+
+    /// Moves all entries starting with the prefix into a new tree one by one.
+    fn split_off_prefix_by_entries(&mut self, prefix: &[u8]) -> TSIMTreeNodes<RADIX, LINE, A> {
+        let mut keys = Vec::new();
+        self.for_each_entry(|key, _| {
+            if key.starts_with(prefix) {
+                keys.push(key.to_vec());
+            }
+        });
+
+        let mut split_off = self.empty_like();
+        for key in keys {
+            // The value may have expired since the key was found, so the expiry is moved along with it.
+            let (value, expiry) = self
+                .remove_entry(&key)
+                .expect("the key was just found in the tree");
+            split_off.put_expiring(&key, value, expiry);
+        }
+        split_off
+    }
+
+    /// Calls `f` for every unexpired entry whose key is a prefix of the key, in ascending key order.
+    ///
+    /// A routing node may spread keys starting with the same byte over several children, so the shorter prefixes
+    /// can be stored in the children before the one responsible for the key. Lower bounds are at most a segment long,
+    /// so only the prefixes up to that length can be routed to another child.
+    fn for_each_prefix<F>(&self, key: &[u8], mut f: F)
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        // Each frame holds a node and the length of the key at that node, the frames on top come first in key order.
+        let mut stack = vec![(self.root, 0)];
+        while let Some((node_id, consumed)) = stack.pop() {
+            let node = &self.arena[node_id];
+            let remaining_key = &key[consumed..];
+            if node.routing {
+                let max_bound_len = TSIMTreeNode::<RADIX, LINE, A>::MAX_STORED_KEY_SEGMENT_SIZE;
+                let mut children: Vec<usize> = (0..=remaining_key.len().min(max_bound_len))
+                    .map(|len| &remaining_key[..len])
+                    .chain([remaining_key])
+                    .map(|prefix| match node.resolve_child(prefix) {
+                        ResolvedChild::InDomainOf(idx) => idx,
+                        _ => panic!("routing nodes only resolve to ResolvedChild::InDomainOf(..)"),
+                    })
+                    .collect();
+                children.dedup();
+                for idx in children.into_iter().rev() {
+                    let TSIMTreeNodeChild::Node(child) = node.child(idx) else {
+                        panic!("children of routing nodes must be TSIMTreeNodeChild::Node(..)")
+                    };
+                    stack.push((*child, consumed));
+                }
+                continue;
+            }
+
+            // The empty segment holds the key that ends at this node.
+            if !remaining_key.is_empty() && node.has_empty_segment() {
+                match node.child(0) {
+                    TSIMTreeNodeChild::Value(value, expiry) if !is_expired(*expiry) => {
+                        f(&key[..consumed], value)
+                    }
+                    _ => {}
+                }
+            }
+            if let ResolvedChild::ExactMatch(idx, rest) = node.resolve_child(remaining_key) {
+                let consumed = key.len() - rest.len();
+                match node.child(idx) {
+                    TSIMTreeNodeChild::Value(value, expiry) if !is_expired(*expiry) => {
+                        f(&key[..consumed], value)
+                    }
+                    TSIMTreeNodeChild::Value(..) => {}
+                    TSIMTreeNodeChild::Node(child) => stack.push((*child, consumed)),
+                }
+            }
+        }
+    }
+
+    /// Counts the unexpired keys that start with the prefix.
+    ///
+    /// The descent follows the prefix like in `split_off_prefix`, once a child only holds keys starting with the prefix,
+    /// its values are counted without reconstructing their keys.
+    fn count_prefix(&self, prefix: &[u8]) -> usize {
+        // Each frame holds a node and the remaining prefix, or `None` if all keys of the node start with the prefix.
+        let mut nodes = vec![(self.root, (!prefix.is_empty()).then_some(prefix))];
+        let mut count = 0;
+        while let Some((node_id, key)) = nodes.pop() {
+            let node = &self.arena[node_id];
+            // The children whose keys all start with the prefix.
+            let counted = match key {
+                None => 0..node.children_count as usize,
+                Some(key) => {
+                    match node.resolve_child(key) {
+                        ResolvedChild::Vacant(_) => 0..0,
+                        ResolvedChild::PartialMatch { idx, common } if common == key.len() => {
+                            idx..idx + 1
+                        }
+                        ResolvedChild::PartialMatch { .. } => 0..0,
+                        ResolvedChild::ExactMatch(idx, remaining_key) => match node.child(idx) {
+                            _ if remaining_key.is_empty() => idx..idx + 1,
+                            TSIMTreeNodeChild::Value(..) => 0..0,
+                            TSIMTreeNodeChild::Node(child) => {
+                                nodes.push((*child, Some(remaining_key)));
+                                0..0
+                            }
+                        },
+                        ResolvedChild::InDomainOf(segment) => {
+                            // The keys starting with the prefix may be spread over the following children as well.
+                            let next_segments = segment + 1..node.children_count as usize;
+                            let spread = next_segments
+                                .take_while(|&idx| node.get_segment(idx).starts_with(key))
+                                .count();
+                            for idx in segment..=segment + spread {
+                                let TSIMTreeNodeChild::Node(child) = node.child(idx) else {
+                                    panic!("children of routing nodes must be TSIMTreeNodeChild::Node(..)")
+                                };
+                                nodes.push((*child, Some(key)));
+                            }
+                            0..0
+                        }
+                    }
+                }
+            };
+            for idx in counted {
+                match node.child(idx) {
+                    TSIMTreeNodeChild::Node(child) => nodes.push((*child, None)),
+                    TSIMTreeNodeChild::Value(_, expiry) => count += !is_expired(*expiry) as usize,
+                }
+            }
+        }
+        count
+    }
+
+    /// Removes all entries for which `f(key, value)` returns `false` and all expired entries in a single traversal.
+    ///
+    /// Rejected values are taken out of their nodes while the nodes are visited.
+    /// Once all children of a node were visited, the gaps are closed and the node is removed if it is empty,
+    /// or merged with its parent like in `detach`.
+    fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        let mut key = Vec::new();
+        // Each frame holds a node, the index of the next child to visit and the key length at that node.
+        let mut stack = vec![(self.root, 0, 0)];
+        while let Some(&mut (node_id, ref mut child_idx, key_len)) = stack.last_mut() {
+            let node = &mut self.arena[node_id];
+            if *child_idx >= node.children_count as usize {
+                stack.pop();
+                let parent = stack
+                    .last()
+                    .map(|&(parent, next_idx, _)| (parent, next_idx - 1));
+                self.close_gaps(node_id, parent);
+                continue;
+            }
+            let idx = *child_idx;
+            *child_idx += 1;
+
+            key.truncate(key_len);
+            if !node.routing {
+                key.extend_from_slice(node.get_segment(idx));
+            }
+            match node.child(idx) {
+                TSIMTreeNodeChild::Node(child) => stack.push((*child, 0, key.len())),
+                TSIMTreeNodeChild::Value(value, expiry) => {
+                    if is_expired(*expiry) || !f(&key, value) {
+                        node.children[idx] = None;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Applies `f` to every unexpired value in ascending order of their keys.
+    fn transform_values<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut Vec<u8>),
+    {
+        // Each frame holds a node and the index of the next child to visit.
+        let mut stack = vec![(self.root, 0)];
+        while let Some((node_id, child_idx)) = stack.pop() {
+            let node = &mut self.arena[node_id];
+            if child_idx >= node.children_count as usize {
+                continue;
+            }
+            stack.push((node_id, child_idx + 1));
+
+            match node.child_mut(child_idx) {
+                TSIMTreeNodeChild::Node(child) => stack.push((*child, 0)),
+                TSIMTreeNodeChild::Value(value, expiry) if !is_expired(*expiry) => {
+                    let mut transformed = self.values.release(core::mem::take(value));
+                    f(&mut transformed);
+                    *value = self.values.store(transformed);
+                }
+                TSIMTreeNodeChild::Value(..) => {}
+            }
+        }
+    }
+
+    /// Closes the gaps in the node after `retain` took children out of it.
+    /// `parent` is the parent of the node and the index of the node in it, the root has no parent.
+    fn close_gaps(&mut self, node: NodeId, parent: Option<(NodeId, usize)>) {
+        self.arena[node].compact_children();
+
+        if self.arena[node].routing {
+            match self.arena[node].children_count {
+                0 => self.arena[node] = TSIMTreeNode::empty(),
+                1 => {
+                    // A routing node with a single child is redundant, the child takes its place.
+                    let TSIMTreeNodeChild::Node(only_child) = self.arena[node].remove_child(0)
+                    else {
+                        panic!("children of routing nodes must be TSIMTreeNodeChild::Node(..)")
+                    };
+                    let only_child = self.arena.remove(only_child);
+                    self.arena[node] = only_child;
+                }
+                _ => self.arena[node].set_segment(0, &[]),
+            }
+        }
+
+        let Some((parent, idx)) = parent else {
+            return;
+        };
+        if self.arena[node].children_count == 0 {
+            self.arena.remove(node);
+            self.arena[parent].children[idx] = None;
+        } else {
+            self.merge_single_child(parent, idx);
+        }
+    }
+
+    /// Removes all keys that are greater than or equal to the key and returns them as the nodes of a new tree.
+    ///
+    /// Only the nodes on the path to the key are divided, all other children are moved as a whole.
+    fn split_off_from(&mut self, key: &[u8]) -> TSIMTreeNodes<RADIX, LINE, A> {
+        if key.is_empty() {
+            return self.take();
+        }
+
+        // For every node on the path to the key: the upper part of the node and the segment of the
+        // child that straddles the key, whose upper part still has to be inserted in front of it.
+        let mut upper_parts = Vec::new();
+        // The index of the straddling child and the number of children left in each node on the path.
+        let mut path = Vec::new();
+        let mut children_left = Vec::new();
+
+        let mut key = key;
+        let mut node = self.root;
+        loop {
+            let node_ref = &mut self.arena[node];
+            let (split_idx, straddling) = match node_ref.resolve_child(key) {
+                ResolvedChild::Vacant(idx) => (idx, None),
+                ResolvedChild::PartialMatch { idx, common } => {
+                    // Either the key is a prefix of the segment or they differ at the first uncommon byte.
+                    match key.get(common) < node_ref.get_segment(idx).get(common) {
+                        true => (idx, None),
+                        false => (idx + 1, None),
+                    }
+                }
+                ResolvedChild::ExactMatch(idx, remaining_key) => match node_ref.child(idx) {
+                    _ if remaining_key.is_empty() => (idx, None),
+                    TSIMTreeNodeChild::Value(..) => (idx + 1, None),
+                    TSIMTreeNodeChild::Node(_) => (idx + 1, Some((idx, remaining_key))),
+                },
+                ResolvedChild::InDomainOf(idx) => (idx + 1, Some((idx, key))),
+            };
+
+            let upper_part = node_ref.split_off_children(split_idx);
+            children_left.push(node_ref.children_count as usize);
+
+            let Some((idx, remaining_key)) = straddling else {
+                upper_parts.push((upper_part, Vec::new()));
+                break;
+            };
+            let segment = match node_ref.routing {
+                true => Vec::new(),
+                false => node_ref.get_segment(idx).to_vec(),
+            };
+            upper_parts.push((upper_part, segment));
+            path.push(idx);
+
+            key = remaining_key;
+            let TSIMTreeNodeChild::Node(child) = *node_ref.child(idx) else {
+                panic!("straddling children are TSIMTreeNodeChild::Node(..)")
+            };
+            node = child;
+        }
+
+        // Assemble the upper tree bottom up, dropping empty nodes and redundant routing nodes.
+        let mut upper = self.empty_like();
+        let mut upper_child = None;
+        for (upper_part, segment) in upper_parts.into_iter().rev() {
+            let upper_part = self.move_node(upper_part, &mut upper);
+            let upper_part_node = &mut upper.arena[upper_part];
+            match upper_child.take() {
+                Some(child) => {
+                    upper_part_node.insert_child(0, &segment, TSIMTreeNodeChild::Node(child))
+                }
+                None if upper_part_node.routing && upper_part_node.children_count > 0 => {
+                    upper_part_node.set_segment(0, &[])
+                }
+                None => {}
+            }
+            upper_child = match upper_part_node.children_count {
+                0 => {
+                    upper.arena.remove(upper_part);
+                    None
+                }
+                1 if upper_part_node.routing => {
+                    let TSIMTreeNodeChild::Node(only_child) =
+                        upper.arena.remove(upper_part).remove_child(0)
+                    else {
+                        panic!("children of routing nodes must be TSIMTreeNodeChild::Node(..)")
+                    };
+                    Some(only_child)
+                }
+                _ => Some(upper_part),
+            };
+        }
+        if let Some(upper_root) = upper_child {
+            let empty_root = core::mem::replace(&mut upper.root, upper_root);
+            upper.arena.remove(empty_root);
+        }
+
+        // The nodes at the end of the path may have lost all their entries, they are removed from the lower tree.
+        // The deepest node is empty if it has no children left, the nodes above if their only child is empty.
+        let mut kept_nodes = children_left.len();
+        if children_left[kept_nodes - 1] == 0 {
+            kept_nodes -= 1;
+            while kept_nodes > 0 && children_left[kept_nodes - 1] == 1 {
+                kept_nodes -= 1;
+            }
+        }
+        if kept_nodes == 0 {
+            *self = self.empty_like();
+        } else if kept_nodes < children_left.len() {
+            let detached = self.detach(&path[..kept_nodes]);
+            self.free_child(detached);
+        }
+
+        // Routing nodes that are left with a single child are replaced by the child.
+        let mut node = self.root;
+        let mut path = &path[..kept_nodes.saturating_sub(1)];
+        loop {
+            if self.arena[node].routing && self.arena[node].children_count == 1 {
+                let TSIMTreeNodeChild::Node(only_child) = self.arena[node].remove_child(0) else {
+                    panic!("children of routing nodes must be TSIMTreeNodeChild::Node(..)")
+                };
+                let only_child = self.arena.remove(only_child);
+                self.arena[node] = only_child;
+                // The child took the place of the routing node, so the next step of the path is already taken.
+                path = path.get(1..).unwrap_or_default();
+                continue;
+            }
+            let Some((&idx, remaining_path)) = path.split_first() else {
+                break;
+            };
+            path = remaining_path;
+            let TSIMTreeNodeChild::Node(child) = *self.arena[node].child(idx) else {
+                // The last node of the path was merged into its parent by detach.
+                break;
+            };
+            node = child;
+        }
+
+        upper
+    }
+
+    /// Calls `f` for every entry of the tree in ascending key order.
+    fn for_each_entry<F>(&self, mut f: F)
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        let mut entries = self.entries();
+        while let Some((key, value)) = entries.next_entry() {
+            f(key, value);
+        }
+    }
+
+    /// Returns an iterator over the entries of the tree in ascending key order.
+    fn entries(&self) -> TSIMTreeEntries<'_, RADIX, LINE, A> {
+        TSIMTreeEntries {
+            nodes: self,
+            walker: TSIMTreeWalker::new(self.root),
+        }
+    }
+
+    fn stats(&self) -> TSIMTreeStats {
+        let mut stats = TSIMTreeStats::default();
+        // Each frame holds a node and the number of nodes on the path to it, including the node.
+        let mut nodes = vec![(self.root, 1)];
+        while let Some((node_id, depth)) = nodes.pop() {
+            let node = &self.arena[node_id];
+            stats.nodes += 1;
+            stats.routing_nodes += node.routing as usize;
+            for idx in 0..node.children_count as usize {
+                match node.child(idx) {
+                    TSIMTreeNodeChild::Node(child) => nodes.push((*child, depth + 1)),
+                    TSIMTreeNodeChild::Value(..) => {
+                        stats.entries += 1;
+                        stats.depth = stats.depth.max(depth);
+                    }
+                }
+            }
+        }
+        stats
+    }
+
+    /// Checks the invariants of all nodes of the tree.
+    fn check_integrity(&self) -> Result<(), TSIMTreeFault> {
+        let mut nodes = vec![self.root];
+        while let Some(node_id) = nodes.pop() {
+            let node = self.check_node(node_id)?;
+            if node.children_count == 0 && node_id != self.root {
+                return Err(TSIMTreeFault::EmptyNode);
+            }
+            for child_idx in 0..node.children_count as usize {
+                if let TSIMTreeNodeChild::Node(child) = node.child(child_idx) {
+                    nodes.push(*child);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Verifies the invariants of a single node and of the references to its children.
+    fn check_node(&self, node_id: NodeId) -> Result<&TSIMTreeNode<RADIX, LINE, A>, TSIMTreeFault> {
+        let node = &self.arena[node_id];
+        let children_count = node.children_count as usize;
+        let children = node.children.iter().filter(|child| child.is_some()).count();
+        if children != children_count {
+            return Err(TSIMTreeFault::CountMismatch {
+                children_count: node.children_count,
+                children,
+            });
+        }
+
+        for child_idx in 0..children_count {
+            TSIMTreeNode::<RADIX, LINE, A>::stored_segment(node.segment_buffer(child_idx))?;
+            match &node.children[child_idx] {
+                None => {
+                    return Err(TSIMTreeFault::ChildIsNone {
+                        child_idx,
+                        children_count: node.children_count,
+                    })
+                }
+                Some(TSIMTreeNodeChild::Node(child)) if self.arena.get(*child).is_none() => {
+                    return Err(TSIMTreeFault::MissingNode { child_idx })
+                }
+                Some(TSIMTreeNodeChild::Node(_)) => {}
+                Some(TSIMTreeNodeChild::Value(..)) if node.routing => {
+                    return Err(TSIMTreeFault::ValueInRoutingNode { child_idx })
+                }
+                Some(TSIMTreeNodeChild::Value(..)) => {}
+            }
+        }
+        if let Some(segment_idx) = (1..children_count).find(|&idx| !node.is_sorted_at(idx)) {
+            return Err(TSIMTreeFault::UnsortedSegments { segment_idx });
+        }
+        if node.routing && children_count > 0 && !node.has_empty_segment() {
+            return Err(TSIMTreeFault::UncoveredKeys);
+        }
+        if !node.routing && node.has_empty_segment() {
+            if let TSIMTreeNodeChild::Node(_) = node.child(0) {
+                return Err(TSIMTreeFault::NodeUnderEmptySegment);
+            }
+        }
+        Ok(node)
+    }
+
+    /// Like `find_value`, but verifies every node on the path to the key first
+    /// and returns the first violated invariant instead of panicking.
+    fn verify_path(&self, key: &[u8]) -> Result<Option<(NodeId, usize)>, TSIMTreeFault> {
+        let mut key = key;
+        let mut node_id = self.root;
+        loop {
+            let node = self.check_node(node_id)?;
+            let (child_idx, remaining_key) = match node.resolve_child(key) {
+                ResolvedChild::Vacant(_) | ResolvedChild::PartialMatch { .. } => return Ok(None),
+                ResolvedChild::ExactMatch(child_idx, remaining_key) => (child_idx, remaining_key),
+                ResolvedChild::InDomainOf(child_idx) => (child_idx, key),
+            };
+            match node.child(child_idx) {
+                TSIMTreeNodeChild::Value(..) if remaining_key.is_empty() => {
+                    return Ok(Some((node_id, child_idx)))
+                }
+                TSIMTreeNodeChild::Value(..) => return Ok(None),
+                TSIMTreeNodeChild::Node(child) => {
+                    node_id = *child;
+                    key = remaining_key;
+                }
+            }
+        }
+    }
+
+    fn fmt_outline(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // Each frame holds a node, the index of the next child to print and the depth of the node.
+        let mut stack = vec![(&self.arena[self.root], 0, 0)];
+
+        while let Some((node, child_idx, depth)) = stack.pop() {
+            if child_idx >= node.children_count as usize {
+                continue;
+            }
+            stack.push((node, child_idx + 1, depth));
+
+            let pivot = if node.routing { ">=" } else { "" };
+            let segment = node.get_segment(child_idx).escape_ascii();
+            write!(f, "{:indent$}{pivot}\"{segment}\"", "", indent = 2 * depth)?;
+
+            match node.child(child_idx) {
+                TSIMTreeNodeChild::Value(value, _) => {
+                    writeln!(f, " = \"{}\"", value.escape_ascii())?
+                }
+                TSIMTreeNodeChild::Node(child) => {
+                    writeln!(f)?;
+                    stack.push((&self.arena[*child], 0, depth + 1));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The position of a traversal over the entries of a tree in ascending key order, without recursion.
+///
+/// The walker refers to the nodes by their id, so it does not borrow them
+/// and can be stored next to the guard that protects the nodes.
+struct TSIMTreeWalker {
+    /// The key of the last visited child.
+    key: Vec<u8>,
+    /// Each frame holds a node, the index of the next child to visit and the key length at that node.
+    stack: Vec<(NodeId, usize, usize)>,
+}
+
+impl TSIMTreeWalker {
+    fn new(root: NodeId) -> TSIMTreeWalker {
+        TSIMTreeWalker {
+            key: Vec::new(),
+            stack: vec![(root, 0, 0)],
+        }
+    }
+
+    /// Returns a walker that first advances to the smallest key that is greater than or equal to the key.
+    ///
+    /// The key is looked up like in `get`, the frame of each node on the way points to the first child
+    /// that only holds greater keys. The walker descends into the child that may hold both.
+    fn seek<const RADIX: usize, const LINE: usize, A: Allocator + Clone>(
+        nodes: &TSIMTreeNodes<RADIX, LINE, A>,
+        key: &[u8],
+    ) -> TSIMTreeWalker {
+        TSIMTreeWalker::seek_from(nodes, nodes.root, key)
+    }
+
+    /// Seeks like `seek`, but only walks the entries below the node.
+    /// The key, like the keys the walker yields, does not contain the segments on the path to the node.
+    fn seek_from<const RADIX: usize, const LINE: usize, A: Allocator + Clone>(
+        nodes: &TSIMTreeNodes<RADIX, LINE, A>,
+        node_id: NodeId,
+        key: &[u8],
+    ) -> TSIMTreeWalker {
+        let mut walker = TSIMTreeWalker {
+            key: Vec::new(),
+            stack: Vec::new(),
+        };
+        let mut remaining_key = key;
+        let mut node_id = node_id;
+        loop {
+            let node = &nodes.arena[node_id];
+            let (next_idx, descend) = match node.resolve_child(remaining_key) {
+                ResolvedChild::InDomainOf(idx) => (idx + 1, Some((idx, remaining_key))),
+                ResolvedChild::Vacant(idx) => (idx, None),
+                // All keys below the child start with the key.
+                ResolvedChild::ExactMatch(idx, []) => (idx, None),
+                ResolvedChild::ExactMatch(idx, rest) => match node.child(idx) {
+                    TSIMTreeNodeChild::Value(..) => (idx + 1, None),
+                    TSIMTreeNodeChild::Node(_) => (idx + 1, Some((idx, rest))),
+                },
+                // The byte where the segment and the key diverge decides, a key ending first sorts first.
+                ResolvedChild::PartialMatch { idx, common } => {
+                    match node.get_segment(idx).get(common) > remaining_key.get(common) {
+                        true => (idx, None),
+                        false => (idx + 1, None),
+                    }
+                }
+            };
+            walker.stack.push((node_id, next_idx, walker.key.len()));
+
+            let Some((idx, rest)) = descend else {
+                return walker;
+            };
+            let TSIMTreeNodeChild::Node(child) = node.child(idx) else {
+                panic!("the walker only descends into TSIMTreeNodeChild::Node(..)")
+            };
+            if !node.routing {
+                walker.key.extend_from_slice(node.get_segment(idx));
+            }
+            remaining_key = rest;
+            node_id = *child;
+        }
+    }
+
+    /// Advances to the next entry, the key is borrowed from the walker so it does not have to be copied.
+    /// Expired entries are skipped.
+    fn next_entry<'n, const RADIX: usize, const LINE: usize, A: Allocator + Clone>(
+        &mut self,
+        nodes: &'n TSIMTreeNodes<RADIX, LINE, A>,
+    ) -> Option<(&[u8], &'n [u8])> {
+        self.advance(nodes, true)
+            .map(|(key, value, _)| (key, value))
+    }
+
+    /// Returns the value of the entry the walker advanced to last, as long as it did not advance past it.
+    fn current_value<'n, const RADIX: usize, const LINE: usize, A: Allocator + Clone>(
+        &self,
+        nodes: &'n TSIMTreeNodes<RADIX, LINE, A>,
+    ) -> Option<&'n [u8]> {
+        // The frame of the node holding the entry is on top and points behind the entry.
+        let &(node_id, next_idx, _) = self.stack.last()?;
+        match nodes.arena[node_id].child(next_idx.checked_sub(1)?) {
+            TSIMTreeNodeChild::Value(value, _) => Some(value),
+            TSIMTreeNodeChild::Node(_) => None,
+        }
+    }
+
+    /// Advances to the next expired entry.
+    fn next_expired_key<const RADIX: usize, const LINE: usize, A: Allocator + Clone>(
+        &mut self,
+        nodes: &TSIMTreeNodes<RADIX, LINE, A>,
+    ) -> Option<&[u8]> {
+        while let Some((_, _, expiry)) = self.advance(nodes, false) {
+            if is_expired(expiry) {
+                return Some(&self.key);
+            }
+        }
+        None
+    }
+
+    /// Advances to the next entry and returns it together with its expiry.
+    ///
+    /// The expiry is checked within the loop, returning a borrowed key from a loop that continues otherwise
+    /// is not accepted by the borrow checker.
+    fn advance<'n, const RADIX: usize, const LINE: usize, A: Allocator + Clone>(
+        &mut self,
+        nodes: &'n TSIMTreeNodes<RADIX, LINE, A>,
+        skip_expired: bool,
+    ) -> Option<(&[u8], &'n [u8], Option<Expiry>)> {
+        while let Some((node_id, child_idx, key_len)) = self.stack.pop() {
+            let node = &nodes.arena[node_id];
+            if child_idx >= node.children_count as usize {
+                continue;
+            }
+            self.stack.push((node_id, child_idx + 1, key_len));
+
+            self.key.truncate(key_len);
+            if !node.routing {
+                self.key.extend_from_slice(node.get_segment(child_idx));
+            }
+
+            match node.child(child_idx) {
+                TSIMTreeNodeChild::Value(_, expiry) if skip_expired && is_expired(*expiry) => {}
+                TSIMTreeNodeChild::Value(value, expiry) => {
+                    return Some((&self.key, value, *expiry))
+                }
+                TSIMTreeNodeChild::Node(child) => self.stack.push((*child, 0, self.key.len())),
+            }
+        }
+        None
+    }
+
+    /// Advances to the next unexpired entry like `next_entry`, but skips reconstructing the key.
+    fn next_value<'n, const RADIX: usize, const LINE: usize, A: Allocator + Clone>(
+        &mut self,
+        nodes: &'n TSIMTreeNodes<RADIX, LINE, A>,
+    ) -> Option<&'n [u8]> {
+        while let Some((node_id, child_idx, key_len)) = self.stack.pop() {
+            let node = &nodes.arena[node_id];
+            if child_idx >= node.children_count as usize {
+                continue;
+            }
+            self.stack.push((node_id, child_idx + 1, key_len));
+
+            match node.child(child_idx) {
+                TSIMTreeNodeChild::Value(_, expiry) if is_expired(*expiry) => {}
+                TSIMTreeNodeChild::Value(value, _) => return Some(value),
+                TSIMTreeNodeChild::Node(child) => self.stack.push((*child, 0, 0)),
+            }
+        }
+        None
+    }
+
+    /// Advances to the next unexpired entry like `next_entry`, but moves the value out of the nodes.
+    fn take_next_entry<const RADIX: usize, const LINE: usize, A: Allocator + Clone>(
+        &mut self,
+        nodes: &mut TSIMTreeNodes<RADIX, LINE, A>,
+    ) -> Option<(&[u8], Vec<u8>)> {
+        let (key, value, _) = self.take_next_value(nodes)?;
+        Some((key, nodes.values.release(value)))
+    }
+
+    /// Like `take_next_entry`, but returns the value as it is stored together with its expiry.
+    ///
+    /// Every child is taken out of its node when it is visited,
+    /// and a node is removed from the arena as soon as all of its children were visited.
+    fn take_next_value<const RADIX: usize, const LINE: usize, A: Allocator + Clone>(
+        &mut self,
+        nodes: &mut TSIMTreeNodes<RADIX, LINE, A>,
+    ) -> Option<(&[u8], StoredValue<A>, Option<Expiry>)> {
+        while let Some((node_id, child_idx, key_len)) = self.stack.pop() {
+            let node = &mut nodes.arena[node_id];
+            if child_idx >= node.children_count as usize {
+                nodes.arena.remove(node_id);
+                continue;
+            }
+            self.stack.push((node_id, child_idx + 1, key_len));
+
+            self.key.truncate(key_len);
+            if !node.routing {
+                self.key.extend_from_slice(node.get_segment(child_idx));
+            }
+
+            match node.children[child_idx]
+                .take()
+                .expect("children[child_idx] must be Some(..)")
+            {
+                TSIMTreeNodeChild::Value(_, expiry) if is_expired(expiry) => {}
+                TSIMTreeNodeChild::Value(value, expiry) => return Some((&self.key, value, expiry)),
+                TSIMTreeNodeChild::Node(child) => self.stack.push((child, 0, self.key.len())),
+            }
+        }
+        None
+    }
+}
+
+/// Iterates over the entries of a tree in ascending key order, borrowing the values.
+struct TSIMTreeEntries<'n, const RADIX: usize, const LINE: usize, A: Allocator + Clone> {
+    nodes: &'n TSIMTreeNodes<RADIX, LINE, A>,
+    walker: TSIMTreeWalker,
+}
+
+impl<'n, const RADIX: usize, const LINE: usize, A: Allocator + Clone>
+    TSIMTreeEntries<'n, RADIX, LINE, A>
+{
+    /// Advances to the next entry, the key is borrowed from the iterator so it does not have to be copied.
+    fn next_entry(&mut self) -> Option<(&[u8], &'n [u8])> {
+        self.walker.next_entry(self.nodes)
+    }
+}
+
+impl<'n, const RADIX: usize, const LINE: usize, A: Allocator + Clone> Iterator
+    for TSIMTreeEntries<'n, RADIX, LINE, A>
+{
+    type Item = (Vec<u8>, &'n [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().map(|(key, value)| (key.to_vec(), value))
+    }
+}
+
+/// A mutable reference to a stored value, created by [`TSIMTree::get_mut`].
+///
+/// The guard holds the write lock of the tree until it is dropped.
+/// The value is taken out of its node while the guard exists and stored back when the guard is dropped.
+pub struct TSIMTreeValueGuard<
+    't,
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    tree: &'t TSIMTree<RADIX, LINE, A>,
+    /// The key of the value, only kept if the tree has a capacity, so it can be touched with the new length.
+    key: Option<Vec<u8>>,
+    node_guard: RootWriteGuard<'t, TSIMTreeNodes<RADIX, LINE, A>>,
+    /// The node holding the value and the index of the value in the node.
+    node: NodeId,
+    idx: usize,
+    /// The value as a `Vec`, so it can grow past or shrink below the length of values stored within their node.
+    value: Vec<u8>,
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Deref
+    for TSIMTreeValueGuard<'_, RADIX, LINE, A>
+{
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.value
+    }
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> DerefMut
+    for TSIMTreeValueGuard<'_, RADIX, LINE, A>
+{
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.value
+    }
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Drop
+    for TSIMTreeValueGuard<'_, RADIX, LINE, A>
+{
+    fn drop(&mut self) {
+        let value = self
+            .node_guard
+            .values
+            .store(core::mem::take(&mut self.value));
+        match self.node_guard.arena[self.node].child_mut(self.idx) {
+            TSIMTreeNodeChild::Value(stored, _) => *stored = value,
+            TSIMTreeNodeChild::Node(_) => {
+                panic!("the guard refers to a TSIMTreeNodeChild::Value(..)")
+            }
+        }
+        // Touched under the write lock, so the length is counted before another thread can modify the value.
+        if let Some(key) = &self.key {
+            self.tree.touch_and_evict(&mut self.node_guard, key);
+        }
+    }
+}
+
+/// A read-locked view of a tree, created by [`TSIMTree::read`].
+///
+/// The values are borrowed from the tree, the borrows are valid until the guard is dropped.
+/// Expired values are hidden, but not removed, as that needs the write lock.
+pub struct TSIMReadGuard<
+    't,
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    tree: &'t TSIMTree<RADIX, LINE, A>,
+    node_guard: RootReadGuard<'t, TSIMTreeNodes<RADIX, LINE, A>>,
+}
+
+impl<'t, const RADIX: usize, const LINE: usize, A: Allocator + Clone> IntoIterator
+    for &'t TSIMReadGuard<'_, RADIX, LINE, A>
+{
+    type Item = (Vec<u8>, &'t [u8]);
+    type IntoIter = TSIMTreeLocalIter<'t, RADIX, LINE, A>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone>
+    TSIMReadGuard<'_, RADIX, LINE, A>
+{
+    /// Returns the value stored under the key without cloning it.
+    pub fn get<K>(&self, k: K) -> Option<&[u8]>
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = k.as_ref();
+        match self.node_guard.get_entry(key) {
+            Some((value, expiry)) if !is_expired(expiry) => {
+                self.tree.refresh(key);
+                Some(value)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns an iterator over the entries in ascending key order, the values are not cloned.
+    pub fn iter(&self) -> TSIMTreeLocalIter<'_, RADIX, LINE, A> {
+        TSIMTreeLocalIter {
+            entries: self.node_guard.entries(),
+        }
+    }
+}
+
+/// The read-locked root node of a tree, created by [`TSIMTree::root_node`].
+pub struct TSIMTreeRootGuard<
+    't,
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    node_guard: RootReadGuard<'t, TSIMTreeNodes<RADIX, LINE, A>>,
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Deref
+    for TSIMTreeRootGuard<'_, RADIX, LINE, A>
+{
+    type Target = TSIMTreeNode<RADIX, LINE, A>;
+
+    fn deref(&self) -> &TSIMTreeNode<RADIX, LINE, A> {
+        &self.node_guard.arena[self.node_guard.root]
+    }
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone>
+    TSIMTreeRootGuard<'_, RADIX, LINE, A>
+{
+    /// Returns the root node together with the arena its children are looked up in.
+    pub fn node(&self) -> TSIMTreeNodeRef<'_, RADIX, LINE, A> {
+        TSIMTreeNodeRef {
+            nodes: &self.node_guard,
+            node: self.node_guard.root,
+        }
+    }
+
+    /// Returns the child of the root node at the given index, see [`TSIMTreeNodeRef::child`].
+    pub fn child(&self, idx: usize) -> TSIMTreeChildRef<'_, RADIX, LINE, A> {
+        self.node().child(idx)
+    }
+}
+
+/// A node of a read-locked tree, which can reach its children.
+#[derive(Clone, Copy)]
+pub struct TSIMTreeNodeRef<
+    'n,
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    nodes: &'n TSIMTreeNodes<RADIX, LINE, A>,
+    node: NodeId,
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Deref
+    for TSIMTreeNodeRef<'_, RADIX, LINE, A>
+{
+    type Target = TSIMTreeNode<RADIX, LINE, A>;
+
+    fn deref(&self) -> &TSIMTreeNode<RADIX, LINE, A> {
+        &self.nodes.arena[self.node]
+    }
+}
+
+impl<'n, const RADIX: usize, const LINE: usize, A: Allocator + Clone>
+    TSIMTreeNodeRef<'n, RADIX, LINE, A>
+{
+    /// Returns the child at the given index. Values are returned even if they are expired.
+    ///
+    /// # Panics
+    /// If the index is not below [`TSIMTreeNode::children_count`].
+    pub fn child(&self, idx: usize) -> TSIMTreeChildRef<'n, RADIX, LINE, A> {
+        match self.nodes.arena[self.node].child(idx) {
+            TSIMTreeNodeChild::Node(node) => TSIMTreeChildRef::Node(TSIMTreeNodeRef {
+                nodes: self.nodes,
+                node: *node,
+            }),
+            TSIMTreeNodeChild::Value(value, _) => TSIMTreeChildRef::Value(value),
+        }
+    }
+}
+
+/// A child of a node, as returned by [`TSIMTreeNodeRef::child`].
+pub enum TSIMTreeChildRef<
+    'n,
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    Node(TSIMTreeNodeRef<'n, RADIX, LINE, A>),
+    Value(&'n [u8]),
+}
+
+/// An iterator over the entries of a tree in ascending key order, created by [`TSIMTree::iter`].
+///
+/// The iterator holds the read lock of the tree until it is dropped, so writers have to wait for it.
+/// The entries are cloned, as the tree may be modified once the lock is released.
+pub struct TSIMTreeIter<
+    't,
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    node_guard: RootReadGuard<'t, TSIMTreeNodes<RADIX, LINE, A>>,
+    walker: TSIMTreeWalker,
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Iterator
+    for TSIMTreeIter<'_, RADIX, LINE, A>
+{
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.walker
+            .next_entry(&self.node_guard)
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+    }
+}
+
+/// A position between two entries of a tree, created by [`TSIMTree::cursor_at`].
+///
+/// [`Iterator::next`] returns the entry behind the position and moves the position behind that entry,
+/// [`TSIMTreeCursor::prev`] does the same in the other direction. So stepping forward and then backward
+/// returns the same entry twice. Each step looks up the neighbour of the last returned key,
+/// the cursor can be moved past the first or last entry and back again.
+///
+/// Like [`TSIMTreeIter`], it holds the read lock of the tree until it is dropped.
+pub struct TSIMTreeCursor<
+    't,
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    node_guard: RootReadGuard<'t, TSIMTreeNodes<RADIX, LINE, A>>,
+    /// The position is right in front of the key, or right behind it if `behind_key` is set.
+    key: Vec<u8>,
+    behind_key: bool,
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone>
+    TSIMTreeCursor<'_, RADIX, LINE, A>
+{
+    /// Returns the entry in front of the position and moves the position in front of that entry.
+    pub fn prev(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let (key, value) = self
+            .node_guard
+            .neighbour_entry(&self.key, true, self.behind_key)?;
+        let value = value.to_vec();
+        self.key.clone_from(&key);
+        self.behind_key = false;
+        Some((key, value))
+    }
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Iterator
+    for TSIMTreeCursor<'_, RADIX, LINE, A>
+{
+    type Item = (Vec<u8>, Vec<u8>);
+
+    /// Returns the entry behind the position and moves the position behind that entry.
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = self
+            .node_guard
+            .neighbour_entry(&self.key, false, !self.behind_key)?;
+        let value = value.to_vec();
+        self.key.clone_from(&key);
+        self.behind_key = true;
+        Some((key, value))
+    }
+}
+
+/// An iterator over the keys of a tree in ascending order, created by [`TSIMTree::keys`].
+///
+/// Like [`TSIMTreeIter`], it holds the read lock of the tree until it is dropped.
+pub struct TSIMTreeKeys<
+    't,
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    node_guard: RootReadGuard<'t, TSIMTreeNodes<RADIX, LINE, A>>,
+    walker: TSIMTreeWalker,
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Iterator
+    for TSIMTreeKeys<'_, RADIX, LINE, A>
+{
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.walker
+            .next_entry(&self.node_guard)
+            .map(|(key, _)| key.to_vec())
+    }
+}
+
+/// An iterator over the entries whose keys start with a prefix in ascending key order,
+/// created by [`TSIMTree::prefix_iter`].
+///
+/// Like [`TSIMTreeIter`], it holds the read lock of the tree until it is dropped.
+pub struct TSIMTreePrefixIter<
+    't,
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    node_guard: RootReadGuard<'t, TSIMTreeNodes<RADIX, LINE, A>>,
+    walker: TSIMTreeWalker,
+    prefix: Vec<u8>,
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Iterator
+    for TSIMTreePrefixIter<'_, RADIX, LINE, A>
+{
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = self.walker.next_entry(&self.node_guard)?;
+        if !key.starts_with(&self.prefix) {
+            // The walker started at the prefix, so all keys starting with it were visited.
+            self.walker.stack.clear();
+            return None;
+        }
+        Some((key.to_vec(), value.to_vec()))
+    }
+}
+
+/// An iterator over the keys starting with a prefix in ascending order, created by [`TSIMTree::prefix_keys`].
+///
+/// Like [`TSIMTreeIter`], it holds the read lock of the tree until it is dropped.
+pub struct TSIMTreePrefixKeys<
+    't,
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    node_guard: RootReadGuard<'t, TSIMTreeNodes<RADIX, LINE, A>>,
+    walker: TSIMTreeWalker,
+    prefix: Vec<u8>,
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Iterator
+    for TSIMTreePrefixKeys<'_, RADIX, LINE, A>
+{
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, _) = self.walker.next_entry(&self.node_guard)?;
+        if !key.starts_with(&self.prefix) {
+            self.walker.stack.clear();
+            return None;
+        }
+        Some(key.to_vec())
+    }
+}
+
+/// The state of the key fed to a [`TSIMTreeLookup`] so far.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LookupState {
+    /// The key is not stored, but stored keys start with it.
+    Pending,
+    /// The key is stored with this value. Longer stored keys may start with it as well.
+    Found(Vec<u8>),
+    /// No stored key starts with the key, feeding more bytes does not change that.
+    NotFound,
+}
+
+/// Looks up a key whose bytes are fed in chunks, created by [`TSIMTree::lookup`].
+///
+/// The fed bytes are consumed by the nodes on the path to the key right away,
+/// only the bytes that did not lead to a node yet are buffered.
+///
+/// Like [`TSIMTreeIter`], it holds the read lock of the tree until it is dropped.
+pub struct TSIMTreeLookup<
+    't,
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    node_guard: RootReadGuard<'t, TSIMTreeNodes<RADIX, LINE, A>>,
+    /// The node the consumed bytes lead to, or `None` once no stored key starts with the fed bytes.
+    node: Option<NodeId>,
+    /// The bytes that were fed after the path to the node.
+    rest: Vec<u8>,
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone>
+    TSIMTreeLookup<'_, RADIX, LINE, A>
+{
+    /// Appends the bytes to the key and returns the state of the key fed so far.
+    pub fn feed(&mut self, bytes: &[u8]) -> LookupState {
+        let Some(mut node_id) = self.node else {
+            return LookupState::NotFound;
+        };
+        self.rest.extend_from_slice(bytes);
+
+        // Follow the children that every key starting with the fed bytes is stored under.
+        let nodes = &*self.node_guard;
+        let mut consumed = 0;
+        loop {
+            let node = &nodes.arena[node_id];
+            let rest = &self.rest[consumed..];
+            match node.resolve_child(rest) {
+                // Lower bounds are at most a segment long, once as many bytes are fed the child cannot change.
+                ResolvedChild::InDomainOf(idx)
+                    if rest.len()
+                        >= TSIMTreeNode::<RADIX, LINE, A>::MAX_STORED_KEY_SEGMENT_SIZE =>
+                {
+                    let TSIMTreeNodeChild::Node(child) = node.child(idx) else {
+                        panic!("children of routing nodes must be TSIMTreeNodeChild::Node(..)")
+                    };
+                    node_id = *child;
+                }
+                ResolvedChild::ExactMatch(idx, remaining_key) => match node.child(idx) {
+                    TSIMTreeNodeChild::Node(child) => {
+                        consumed = self.rest.len() - remaining_key.len();
+                        node_id = *child;
+                    }
+                    TSIMTreeNodeChild::Value(..) => break,
+                },
+                _ => break,
+            }
+        }
+        self.rest.drain(..consumed);
+        self.node = Some(node_id);
+
+        if let Some((node, idx)) = nodes.find_value_from(node_id, &self.rest) {
+            match nodes.entry_at(node, idx) {
+                (value, expiry) if !is_expired(expiry) => {
+                    return LookupState::Found(value.to_vec())
+                }
+                _ => {}
+            }
+        }
+        // The first key that is not smaller tells whether any key starts with the fed bytes.
+        let mut walker = TSIMTreeWalker::seek_from(nodes, node_id, &self.rest);
+        match walker.next_entry(nodes) {
+            Some((key, _)) if key.starts_with(&self.rest) => LookupState::Pending,
+            _ => {
+                self.node = None;
+                LookupState::NotFound
+            }
+        }
+    }
+}
+
+/// An iterator over the values of a tree in ascending order of their keys, created by [`TSIMTree::values`].
+///
+/// Like [`TSIMTreeIter`], it holds the read lock of the tree until it is dropped.
+pub struct TSIMTreeValues<
+    't,
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    node_guard: RootReadGuard<'t, TSIMTreeNodes<RADIX, LINE, A>>,
+    walker: TSIMTreeWalker,
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Iterator
+    for TSIMTreeValues<'_, RADIX, LINE, A>
+{
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.walker.next_value(&self.node_guard).map(<[u8]>::to_vec)
+    }
+}
+
+/// An iterator that moves the entries out of a tree, created by [`TSIMTree::into_iter`].
+///
+/// The nodes are dismantled while iterating, so the values are moved out without cloning.
+/// Dropping the iterator early drops the remaining nodes together with the arena, without recursion.
+pub struct TSIMTreeIntoIter<
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    nodes: TSIMTreeNodes<RADIX, LINE, A>,
+    walker: TSIMTreeWalker,
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Iterator
+    for TSIMTreeIntoIter<RADIX, LINE, A>
+{
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.walker
+            .take_next_entry(&mut self.nodes)
+            .map(|(key, value)| (key.to_vec(), value))
+    }
+}
+
+/// An iterator that removes the entries from a tree, created by [`TSIMTree::drain`].
+///
+/// The tree is emptied as soon as the iterator is created, the entries are moved out of the detached nodes.
+pub struct TSIMTreeDrain<
+    't,
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    // The guard is declared first, so the entries that were not yielded are dropped after the write lock is released.
+    _node_guard: RootWriteGuard<'t, TSIMTreeNodes<RADIX, LINE, A>>,
+    entries: TSIMTreeIntoIter<RADIX, LINE, A>,
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Iterator
+    for TSIMTreeDrain<'_, RADIX, LINE, A>
+{
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next()
+    }
+}
+
+/// An iterator over the entries of a snapshot in ascending key order,
+/// created by [`TSIMTree::snapshot_iter`] and by consuming a [`TSIMTreeSnapshot`].
+///
+/// It keeps the nodes of the snapshot alive and clones the entries, as it does not borrow from anything.
+pub struct TSIMTreeSnapshotIter<
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    nodes: Arc<TSIMTreeNodes<RADIX, LINE, A>>,
+    walker: TSIMTreeWalker,
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Iterator
+    for TSIMTreeSnapshotIter<RADIX, LINE, A>
+{
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.walker
+            .next_entry(&self.nodes)
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+    }
+}
+
+/// An iterator over the entries of a tree that is read without a lock in ascending key order,
+/// created by [`TSIMTreeLocal::iter`], [`TSIMTreeSnapshot::iter`] and [`TSIMReadGuard::iter`].
+pub struct TSIMTreeLocalIter<
+    't,
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    entries: TSIMTreeEntries<'t, RADIX, LINE, A>,
+}
+
+impl<'t, const RADIX: usize, const LINE: usize, A: Allocator + Clone> Iterator
+    for TSIMTreeLocalIter<'t, RADIX, LINE, A>
+{
+    type Item = (Vec<u8>, &'t [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next()
+    }
+}
+
+/// A key that differs between two trees, yielded by [`TSIMTreeDiff`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffEntry {
+    /// The key is only stored in the new tree.
+    Added(Vec<u8>, Vec<u8>),
+    /// The key is only stored in the old tree.
+    Removed(Vec<u8>, Vec<u8>),
+    /// The key is stored in both trees with different values, the old value comes first.
+    Modified(Vec<u8>, Vec<u8>, Vec<u8>),
+}
+
+/// Compares the entries of an old and a new tree and returns the keys that differ between them.
+///
+/// Both trees are walked side by side in ascending key order, so the entries are yielded in that order
+/// without collecting them first. Expired entries count as absent.
+pub fn diff<'a, const RADIX: usize, const LINE: usize, A: Allocator + Clone>(
+    a: &'a TSIMTree<RADIX, LINE, A>,
+    b: &'a TSIMTree<RADIX, LINE, A>,
+) -> TSIMTreeDiff<'a, RADIX, LINE, A> {
+    if core::ptr::eq(a, b) {
+        // A tree does not differ from itself, reading it twice could deadlock with a waiting writer.
+        return TSIMTreeDiff { sides: None };
+    }
+
+    let (old_guard, new_guard) = a.read_both(b);
+    let mut old = TSIMTreeDiffSide::new(old_guard);
+    let mut new = TSIMTreeDiffSide::new(new_guard);
+    old.advance();
+    new.advance();
+    TSIMTreeDiff {
+        sides: Some((old, new)),
+    }
+}
+
+/// An iterator over the keys that differ between two trees in ascending key order, created by [`diff`].
+///
+/// The iterator holds the read locks of both trees until it is dropped.
+pub struct TSIMTreeDiff<
+    'a,
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+    A: Allocator + Clone = Global,
+> {
+    /// The old and the new tree, or nothing if a tree is compared with itself.
+    sides: Option<(
+        TSIMTreeDiffSide<'a, RADIX, LINE, A>,
+        TSIMTreeDiffSide<'a, RADIX, LINE, A>,
+    )>,
+}
+
+/// One of the trees compared by [`TSIMTreeDiff`], positioned at its next entry that was not compared yet.
+struct TSIMTreeDiffSide<'a, const RADIX: usize, const LINE: usize, A: Allocator + Clone> {
+    node_guard: RootReadGuard<'a, TSIMTreeNodes<RADIX, LINE, A>>,
+    walker: TSIMTreeWalker,
+    /// Whether the walker stands on an entry, the key of the entry is the key of the walker.
+    has_entry: bool,
+}
+
+impl<'a, const RADIX: usize, const LINE: usize, A: Allocator + Clone>
+    TSIMTreeDiffSide<'a, RADIX, LINE, A>
+{
+    fn new(node_guard: RootReadGuard<'a, TSIMTreeNodes<RADIX, LINE, A>>) -> Self {
+        let walker = TSIMTreeWalker::new(node_guard.root);
+        TSIMTreeDiffSide {
+            node_guard,
+            walker,
+            has_entry: false,
+        }
+    }
+
+    fn advance(&mut self) {
+        self.has_entry = self.walker.next_entry(&self.node_guard).is_some();
+    }
+
+    fn value(&self) -> &[u8] {
+        self.walker
+            .current_value(&self.node_guard)
+            .expect("the walker stands on an entry")
+    }
+
+    /// Returns the current entry and advances to the next one.
+    fn take_entry(&mut self) -> (Vec<u8>, Vec<u8>) {
+        let entry = (self.walker.key.clone(), self.value().to_vec());
+        self.advance();
+        entry
+    }
+}
+
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Iterator
+    for TSIMTreeDiff<'_, RADIX, LINE, A>
+{
+    type Item = DiffEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (old, new) = self.sides.as_mut()?;
+        loop {
+            let order = match (old.has_entry, new.has_entry) {
+                (false, false) => return None,
+                (true, false) => Ordering::Less,
+                (false, true) => Ordering::Greater,
+                (true, true) => old.walker.key.cmp(&new.walker.key),
+            };
+            match order {
+                Ordering::Less => {
+                    let (key, value) = old.take_entry();
+                    return Some(DiffEntry::Removed(key, value));
+                }
+                Ordering::Greater => {
+                    let (key, value) = new.take_entry();
+                    return Some(DiffEntry::Added(key, value));
+                }
+                Ordering::Equal if old.value() == new.value() => {
+                    old.advance();
+                    new.advance();
+                }
+                Ordering::Equal => {
+                    let (key, old_value) = old.take_entry();
+                    let (_, new_value) = new.take_entry();
+                    return Some(DiffEntry::Modified(key, old_value, new_value));
+                }
+            }
+        }
+    }
+}
+
+/// Shows the child nodes by their id, as a node cannot reach them without its arena.
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Debug
+    for TSIMTreeNode<RADIX, LINE, A>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut builder = &mut f.debug_map();
+
+        for child_idx in 0..self.children_count as usize {
+            let key_builder = match TSIMTreeNode::<RADIX, LINE, A>::stored_segment(
+                self.segment_buffer(child_idx),
+            ) {
+                Ok(segment) if self.routing => builder.key(&format!(">={segment:X?}")),
+                Ok(segment) => builder.key(&format!("{segment:X?}")),
+                Err(e) => builder.key(&e),
+            };
+
+            builder = match &self.children[child_idx] {
+                Some(TSIMTreeNodeChild::Node(node)) => key_builder.value(node),
+                Some(TSIMTreeNodeChild::Value(value, _)) => {
+                    key_builder.value(&format!("{value:X?}"))
+                }
+                None => key_builder.value(&TSIMTreeFault::ChildIsNone {
+                    child_idx,
+                    children_count: self.children_count,
+                }),
+            };
+        }
+
+        builder.finish()
+    }
+}
+
+/// Shows the nodes as nested maps, starting at the root.
+///
+/// Nesting `debug_map` builders would recurse once per level of the tree,
+/// so the maps are written by hand in the same format, including the alternate `{:#?}` format.
+impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> Debug
+    for TSIMTreeNodes<RADIX, LINE, A>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let pretty = f.alternate();
+        // Each frame holds a node and the index of the next child to print, the depth is the size of the stack.
+        let mut stack = vec![(self.root, 0)];
+
+        f.write_str("{")?;
+        while let Some((node_id, child_idx)) = stack.pop() {
+            let node = &self.arena[node_id];
+            let depth = stack.len();
+            let children_count = node.children_count as usize;
+
+            if child_idx >= children_count {
+                if pretty && children_count > 0 {
+                    write!(f, "\n{:indent$}", "", indent = 4 * depth)?;
+                }
+                f.write_str("}")?;
+                if pretty && depth > 0 {
+                    f.write_str(",")?;
+                }
+                continue;
+            }
+            stack.push((node_id, child_idx + 1));
+
+            if pretty {
+                write!(f, "\n{:indent$}", "", indent = 4 * (depth + 1))?;
+            } else if child_idx > 0 {
+                f.write_str(", ")?;
+            }
+
+            match TSIMTreeNode::<RADIX, LINE, A>::stored_segment(node.segment_buffer(child_idx)) {
+                Ok(segment) if node.routing => write!(f, "{:?}: ", format!(">={segment:X?}"))?,
+                Ok(segment) => write!(f, "{:?}: ", format!("{segment:X?}"))?,
+                Err(e) => write!(f, "{e:?}: ")?,
+            }
+
+            match &node.children[child_idx] {
+                Some(TSIMTreeNodeChild::Node(child)) => {
+                    f.write_str("{")?;
+                    stack.push((*child, 0));
+                    continue;
+                }
+                Some(TSIMTreeNodeChild::Value(value, _)) => {
+                    write!(f, "{:?}", format!("{value:X?}"))?
+                }
+                None => write!(
+                    f,
+                    "{:?}",
+                    TSIMTreeFault::ChildIsNone {
+                        child_idx,
+                        children_count: node.children_count,
+                    }
+                )?,
+            }
+            if pretty {
+                f.write_str(",")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(not(feature = "std"))]
+    use std::{boxed::Box, dbg, println, string::String, string::ToString};
+
+    // The trees are shared between threads, a field that is not thread-safe must not slip into them.
+    static_assertions::assert_impl_all!(TSIMTree: Send, Sync);
+    static_assertions::assert_impl_all!(TSIMTree<8, 64>: Send, Sync);
+    static_assertions::assert_impl_all!(TSIMTreeNode: Send, Sync);
+    static_assertions::assert_impl_all!(TSIMTreeNodeChild: Send, Sync);
+    static_assertions::assert_impl_all!(TSIMTreeNodes: Send, Sync);
+    static_assertions::assert_impl_all!(TSIMTreeLocal: Send, Sync);
+    static_assertions::assert_impl_all!(TSIMTreeSnapshot: Send, Sync);
+    static_assertions::assert_impl_all!(TSIMTreeSnapshotIter: Send, Sync);
+    static_assertions::assert_impl_all!(TSIMTreeIntoIter: Send, Sync);
+    static_assertions::assert_impl_all!(TSIMTreeBuilder: Send, Sync);
+    static_assertions::assert_impl_all!(TypedTSIMTree<u64>: Send, Sync);
+    static_assertions::assert_impl_all!(ShardedTSIMTree: Send, Sync);
+    static_assertions::assert_impl_all!(PersistentTSIMTree: Send, Sync);
+    #[cfg(feature = "thread-local-cache")]
+    static_assertions::assert_impl_all!(CachedTSIMTree: Send, Sync);
+
+    /// Runs the body once with the default layout and once with a narrow 8-way / 64-byte layout.
+    macro_rules! for_each_layout {
+        ($tree:ident => $body:block) => {{
+            {
+                type $tree = TSIMTree;
+                $body
+            }
+            {
+                type $tree = TSIMTree<8, 64>;
+                $body
+            }
+        }};
+    }
+
+    /// Runs the body for both layouts of [`TSIMTree`] and of [`TSIMTreeLocal`].
+    /// The body accesses the trees through [`SortedMap`].
+    macro_rules! for_each_tree_kind {
+        ($tree:ident => $body:block) => {{
+            for_each_layout!($tree => $body);
+            {
+                type $tree = TSIMTreeLocal;
+                $body
+            }
+            {
+                type $tree = TSIMTreeLocal<8, 64>;
+                $body
+            }
+        }};
+    }
+
+    /// The operations that the locking and the single-threaded tree have in common.
+    trait SortedMap: Default {
+        fn put(&mut self, k: &[u8], v: Vec<u8>);
+        fn get(&self, k: &[u8]) -> Option<Vec<u8>>;
+        fn remove(&mut self, k: &[u8]) -> Option<Vec<u8>>;
+        fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>)>;
+    }
+
+    impl<const RADIX: usize, const LINE: usize> SortedMap for TSIMTree<RADIX, LINE> {
+        fn put(&mut self, k: &[u8], v: Vec<u8>) {
+            TSIMTree::put(self, k, v);
+        }
+
+        fn get(&self, k: &[u8]) -> Option<Vec<u8>> {
+            TSIMTree::get(self, k)
+        }
+
+        fn remove(&mut self, k: &[u8]) -> Option<Vec<u8>> {
+            TSIMTree::remove(self, k)
+        }
+
+        fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+            self.iter().collect()
+        }
+    }
+
+    impl<const RADIX: usize, const LINE: usize> SortedMap for TSIMTreeLocal<RADIX, LINE> {
+        fn put(&mut self, k: &[u8], v: Vec<u8>) {
+            TSIMTreeLocal::put(self, k, v);
+        }
+
+        fn get(&self, k: &[u8]) -> Option<Vec<u8>> {
+            TSIMTreeLocal::get(self, k).map(<[u8]>::to_vec)
+        }
+
+        fn remove(&mut self, k: &[u8]) -> Option<Vec<u8>> {
+            TSIMTreeLocal::remove(self, k)
+        }
+
+        fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+            self.iter()
+                .map(|(key, value)| (key, value.to_vec()))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_comparison_behavior() {
+        assert_eq!(b"abc".as_slice().cmp(b"abc".as_slice()), Ordering::Equal);
+        assert_eq!(b"ab".as_slice().cmp(b"abc".as_slice()), Ordering::Less);
+        assert_eq!(b"abc".as_slice().cmp(b"ab".as_slice()), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_pretty_printing() {
+        let tree = TSIMTree::new();
+        tree.put(b"key1", b"val1".into());
+
+        dbg!(tree);
+    }
+
+    #[test]
+    fn test_display_outline() {
+        let tree = TSIMTree::new();
+        tree.put(b"a", b"1".into());
+        tree.put(b"abc", b"2".into());
+        tree.put(b"b", b"3".into());
+        tree.put(b"\xff\x00", b"\n".into());
+
+        let expected = concat!(
+            "\"a\"\n",
+            "  \"\" = \"1\"\n",
+            "  \"bc\" = \"2\"\n",
+            "\"b\" = \"3\"\n",
+            "\"\\xff\\x00\" = \"\\n\"\n",
+        );
+        assert_eq!(tree.to_string(), expected);
+    }
+
+    #[test]
+    fn test_node_resolving() {
+        println!("Initializing Node");
+        let mut node: TSIMTreeNode = TSIMTreeNode {
+            key_segments: [0; CACHE_LINE_SIZE],
+            children: (0..TREE_RADIX)
+                .map(|i| {
+                    Some(TSIMTreeNodeChild::Value(
+                        ValueAllocator::global().store(vec![i as u8]),
+                        None,
+                    ))
+                })
+                .collect(),
+            children_count: TREE_RADIX as u8,
+            routing: false,
+        };
+
+        let first_key = 1_u8;
+        let last_key = TREE_RADIX as u8 + 1;
+
+        assert_eq!((first_key..last_key).len(), TREE_RADIX);
+
+        println!("Initializing Segments");
+
+        for (segment, key) in (0..TREE_RADIX).zip(first_key..last_key) {
             let buf = vec![key];
             node.set_segment(segment, buf.as_slice());
         }
 
-        println!("Retrieving Children");
+        let v = vec![];
+        let empty_slice: &[u8] = v.as_slice();
+
+        // Since the keys are stored with +1 offset, if we search for 0, there is None, if we search for 1 we get the first element, at idx 0.
+        assert_eq!(
+            node.resolve_child(vec![first_key - 1].as_slice()),
+            ResolvedChild::Vacant(0)
+        );
+
+        assert_eq!(
+            node.resolve_child(vec![first_key].as_slice()),
+            ResolvedChild::ExactMatch(0, empty_slice)
+        );
+        assert_eq!(
+            node.resolve_child(vec![first_key, 42].as_slice()),
+            ResolvedChild::ExactMatch(0, [42].as_slice())
+        );
+        assert_eq!(
+            node.resolve_child(vec![last_key - 1].as_slice()),
+            ResolvedChild::ExactMatch(TREE_RADIX - 1, empty_slice)
+        );
+        // looking beyond the last key, we would have to insert after the last child
+        assert_eq!(
+            node.resolve_child(vec![last_key].as_slice()),
+            ResolvedChild::Vacant(TREE_RADIX)
+        );
+
+        // As a routing node, every key is in the domain of the child with the closest lower bound.
+        node.routing = true;
+        node.set_segment(0, &[]);
+        assert_eq!(
+            node.resolve_child(vec![first_key - 1].as_slice()),
+            ResolvedChild::InDomainOf(0)
+        );
+        assert_eq!(
+            node.resolve_child(vec![first_key + 1, 42].as_slice()),
+            ResolvedChild::InDomainOf(1)
+        );
+        assert_eq!(
+            node.resolve_child(vec![last_key].as_slice()),
+            ResolvedChild::InDomainOf(TREE_RADIX - 1)
+        );
+    }
+
+    /// Resolves the key by scanning all segments of the node, as a reference for the binary search.
+    fn resolve_child_linearly<'k>(node: &TSIMTreeNode, key: &'k [u8]) -> ResolvedChild<'k> {
+        let segments: Vec<&[u8]> = (0..node.children_count())
+            .map(|idx| node.get_segment(idx))
+            .collect();
+        if node.is_routing() {
+            let idx = segments
+                .iter()
+                .rposition(|segment| *segment <= key)
+                .unwrap();
+            return ResolvedChild::InDomainOf(idx);
+        }
+        for (idx, segment) in segments.iter().enumerate() {
+            if key.starts_with(segment) && (key.is_empty() || !segment.is_empty()) {
+                return ResolvedChild::ExactMatch(idx, &key[segment.len()..]);
+            }
+            if !segment.is_empty() && segment.first() == key.first() {
+                let common = segment.iter().zip(key).take_while(|(a, b)| a == b).count();
+                return ResolvedChild::PartialMatch { idx, common };
+            }
+        }
+        let vacant = segments
+            .iter()
+            .filter(|segment| segment.first() < key.first())
+            .count();
+        ResolvedChild::Vacant(vacant)
+    }
+
+    #[test]
+    fn test_resolve_child_for_every_node_size_and_key_position() {
+        // Keys before, on and after every segment, sharing none, some or all of its bytes.
+        let keys: Vec<Vec<u8>> = (0..=2 * TREE_RADIX as u8 + 2)
+            .flat_map(|first| {
+                [
+                    vec![],
+                    vec![4],
+                    vec![5],
+                    vec![5, 0],
+                    vec![5, 1],
+                    vec![6],
+                    vec![6, 6],
+                ]
+                .map(|rest| [vec![first], rest].concat())
+            })
+            .chain([vec![]])
+            .collect();
+
+        for children_count in 1..=TREE_RADIX {
+            for (routing, with_empty_segment) in [(false, false), (false, true), (true, true)] {
+                let mut node: TSIMTreeNode = TSIMTreeNode::empty();
+                node.routing = routing;
+                for idx in 0..children_count {
+                    let segment = match with_empty_segment {
+                        true if idx == 0 => vec![],
+                        _ => vec![2 * idx as u8 + 1, 5],
+                    };
+                    node.insert_child(
+                        idx,
+                        &segment,
+                        TSIMTreeNodeChild::Value(StoredValue::new(), None),
+                    );
+                }
+
+                for key in &keys {
+                    assert_eq!(
+                        node.resolve_child(key),
+                        resolve_child_linearly(&node, key),
+                        "{key:?} in a node with {children_count} children, routing: {routing}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_partial_segment_match() {
+        let mut node: TSIMTreeNode = TSIMTreeNode::empty();
+        node.insert_child(
+            0,
+            b"abc",
+            TSIMTreeNodeChild::Value(StoredValue::new(), None),
+        );
+
+        assert_eq!(
+            node.resolve_child(b"abd"),
+            ResolvedChild::PartialMatch { idx: 0, common: 2 }
+        );
+        assert_eq!(
+            node.resolve_child(b"ab"),
+            ResolvedChild::PartialMatch { idx: 0, common: 2 }
+        );
+        assert_eq!(node.resolve_child(b"b"), ResolvedChild::Vacant(1));
+    }
+
+    #[test]
+    fn test_node_layout() {
+        std::println!(
+            "A node takes {} bytes aligned to {} bytes, a child {} bytes",
+            size_of::<TSIMTreeNode>(),
+            align_of::<TSIMTreeNode>(),
+            size_of::<TSIMTreeNodeChild>(),
+        );
+        assert_eq!(align_of::<TSIMTreeNode>(), CACHE_LINE_SIZE);
+        // An inline value of 16 bytes fits into the space of a `Vec`.
+        assert_eq!(size_of::<StoredValue>(), size_of::<Vec<u8>>());
+        assert_eq!(size_of::<TSIMTreeNode>() % CACHE_LINE_SIZE, 0);
+        // The key segments of all children are read from the first cache line.
+        assert_eq!(core::mem::offset_of!(TSIMTreeNode, key_segments), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "sparse-nodes")]
+    fn test_sparse_node_moves_children_to_the_heap() {
+        let mut node: TSIMTreeNode = TSIMTreeNode::empty();
+        for i in 0..TREE_RADIX {
+            assert_eq!(node.heap_bytes() > 0, i > INLINE_CHILDREN);
+            node.insert_child(
+                i,
+                &[i as u8],
+                TSIMTreeNodeChild::Value(ValueAllocator::global().store(vec![i as u8]), None),
+            );
+        }
+        // The spilled children grow up to the radix, but not beyond.
+        let child_size = size_of::<Option<TSIMTreeNodeChild>>();
+        assert_eq!(node.heap_bytes(), TREE_RADIX * child_size);
+
+        while node.children_count() > INLINE_CHILDREN {
+            node.remove_child(0);
+        }
+        node.children.shrink_to_fit();
+        assert_eq!(node.heap_bytes(), 0);
+        assert_eq!(
+            node.resolve_child(&[TREE_RADIX as u8 - 1]),
+            ResolvedChild::ExactMatch(INLINE_CHILDREN - 1, &[])
+        );
+    }
+
+    #[test]
+    fn test_routing_segment_longer_than_the_key() {
+        let mut node: TSIMTreeNode = TSIMTreeNode::empty();
+        node.routing = true;
+        for (idx, segment) in [&b""[..], b"abcdefg", b"abd"].into_iter().enumerate() {
+            node.insert_child(
+                idx,
+                segment,
+                TSIMTreeNodeChild::Value(StoredValue::new(), None),
+            );
+        }
+
+        // A key that is a strict prefix of a lower bound sorts before it, a key extending it sorts after it.
+        assert_eq!(compare::compare(b"abcdefg", b"abc"), Ordering::Greater);
+        assert_eq!(compare::compare(b"abc", b"abcdefg"), Ordering::Less);
+        assert_eq!(node.resolve_child(b"abc"), ResolvedChild::InDomainOf(0));
+        assert_eq!(node.resolve_child(b"abcdef"), ResolvedChild::InDomainOf(0));
+        assert_eq!(node.resolve_child(b"abcdefg"), ResolvedChild::InDomainOf(1));
+        assert_eq!(
+            node.resolve_child(b"abcdefgh"),
+            ResolvedChild::InDomainOf(1)
+        );
+        assert_eq!(node.resolve_child(b"abc\xff"), ResolvedChild::InDomainOf(1));
+        assert_eq!(node.resolve_child(b"abd"), ResolvedChild::InDomainOf(2));
+    }
+
+    #[test]
+    fn test_kilobyte_keys() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            let prefix: Vec<u8> = (0..2000_u32).map(|i| (i * 7) as u8).collect();
+            // Enough keys behind the shared prefix for routing nodes to appear at the end of the chain.
+            let keys: Vec<Vec<u8>> = (0..500_u32)
+                .map(|i| {
+                    [&prefix[..], &i.to_be_bytes(), &prefix[..i as usize]].concat()
+                })
+                .collect();
+            for key in &keys {
+                tree.put(key, key[..8].to_vec());
+            }
+            assert_eq!(tree.check_integrity(), Ok(()));
+
+            for key in &keys {
+                assert_eq!(tree.get(key), Some(key[..8].to_vec()));
+            }
+            // Keys ending inside the chain of a stored key, on a segment boundary or in the middle of one.
+            for len in [1, 2, 1000, 1001, 1999, 2000, 2001, 2003] {
+                assert_eq!(tree.get(&keys[499][..len]), None);
+            }
+            for key in &keys {
+                let mut longer = key.clone();
+                longer.push(0);
+                assert_eq!(tree.get(&longer), None);
+            }
+            assert!(tree.iter().map(|(key, _)| key).eq(keys.iter().cloned()));
+
+            // The strict prefixes then sort before the keys they are a prefix of.
+            for len in [1000, 1001, 2003] {
+                tree.put(&keys[499][..len], vec![]);
+                assert_eq!(tree.get(&keys[499][..len]), Some(vec![]));
+            }
+            assert_eq!(tree.check_integrity(), Ok(()));
+            let stored: Vec<Vec<u8>> = tree.iter().map(|(key, _)| key).collect();
+            assert_eq!(stored.len(), keys.len() + 3);
+            assert!(stored.windows(2).all(|pair| pair[0] < pair[1]));
+            assert_eq!(stored[0], keys[499][..1000]);
+        });
+    }
+
+    #[test]
+    fn test_basic_insert_and_get() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            dbg!(&tree).put(b"key1", b"val1".into());
+            dbg!(&tree).put(b"key2", b"val2".into());
+
+            assert_eq!(dbg!(&tree).get(b"key1"), Some(b"val1".to_vec()));
+            assert_eq!(tree.get(b"key2"), Some(b"val2".to_vec()));
+        });
+    }
+
+    #[test]
+    fn test_overwrite_value() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            tree.put(b"key", b"first".into());
+            tree.put(b"key", b"second".into());
+
+            assert_eq!(tree.get(b"key"), Some(b"second".to_vec()));
+        });
+    }
+
+    #[test]
+    fn test_missing_key() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            tree.put(b"key", b"value".into());
+
+            assert_eq!(tree.get(b"other"), None);
+        });
+    }
+
+    #[test]
+    fn test_key_that_is_a_prefix_or_extension_of_a_stored_key() {
+        for_each_layout!(Tree => {
+            let orders: [[&[u8]; 3]; 3] = [
+                [b"abc", b"ab", b"abcd"],
+                [b"ab", b"abc", b"abcd"],
+                [b"abcd", b"abc", b"ab"],
+            ];
+            for keys in orders {
+                let tree = Tree::default();
+                tree.put(keys[0], keys[0].to_vec());
+                // The query ends inside the stored segment, or continues after it.
+                for key in &keys[1..] {
+                    assert_eq!(tree.get(key), None);
+                }
+
+                for (stored, key) in keys.iter().enumerate() {
+                    tree.put(key, key.to_vec());
+                    for (idx, key) in keys.iter().enumerate() {
+                        let expected = (idx <= stored).then(|| key.to_vec());
+                        assert_eq!(tree.get(key), expected, "{keys:?}");
+                    }
+                }
+                assert_eq!(tree.check_integrity(), Ok(()));
+            }
+        });
+    }
+
+    #[test]
+    fn test_multiple_sizes() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            tree.put(b"k", b"1".into());
+            tree.put(b"key", b"v".into());
+            tree.put(b"", b"empty".into());
+            tree.put(b"a", b"A".into());
+
+            assert_eq!(tree.get(b""), Some(b"empty".to_vec()));
+            assert_eq!(tree.get(b"k"), Some(b"1".to_vec()));
+            assert_eq!(tree.get(b"a"), Some(b"A".to_vec()));
+            assert_eq!(tree.get(b"key"), Some(b"v".to_vec()));
+        });
+    }
+
+    #[test]
+    fn test_empty_key_before_and_after_other_keys() {
+        for_each_tree_kind!(Tree => {
+            let mut empty_first = Tree::default();
+            SortedMap::put(&mut empty_first, b"", b"empty".to_vec());
+            SortedMap::put(&mut empty_first, b"key", b"v".to_vec());
+
+            let mut empty_last = Tree::default();
+            SortedMap::put(&mut empty_last, b"key", b"v".to_vec());
+            SortedMap::put(&mut empty_last, b"", b"empty".to_vec());
+
+            for tree in [&mut empty_first, &mut empty_last] {
+                assert_eq!(SortedMap::get(tree, b""), Some(b"empty".to_vec()));
+                assert_eq!(SortedMap::get(tree, b"key"), Some(b"v".to_vec()));
+                assert_eq!(
+                    tree.entries(),
+                    vec![(vec![], b"empty".to_vec()), (b"key".to_vec(), b"v".to_vec())]
+                );
+
+                SortedMap::put(tree, b"", b"overwritten".to_vec());
+                assert_eq!(SortedMap::get(tree, b""), Some(b"overwritten".to_vec()));
+                assert_eq!(SortedMap::remove(tree, b""), Some(b"overwritten".to_vec()));
+                assert_eq!(SortedMap::get(tree, b""), None);
+                assert_eq!(SortedMap::remove(tree, b""), None);
+                assert_eq!(SortedMap::get(tree, b"key"), Some(b"v".to_vec()));
+            }
+        });
+    }
+
+    #[test]
+    fn test_keys_ending_at_every_level() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            // Each key ends at a node that the next key descends through, so its value moves under an empty segment.
+            let chain: Vec<Vec<u8>> = (0..=40).map(|len| b"ab".repeat(len)).collect();
+            let siblings: Vec<Vec<u8>> = chain
+                .iter()
+                .map(|key| [key.as_slice(), b"z"].concat())
+                .collect();
+
+            // Every other key is stored before the longer keys, the others after them.
+            for key in chain.iter().step_by(2) {
+                tree.put(key, key.clone());
+            }
+            for key in chain.iter().skip(1).step_by(2).rev().chain(&siblings) {
+                tree.put(key, key.clone());
+            }
+            assert_eq!(tree.check_integrity(), Ok(()));
+            for key in chain.iter().chain(&siblings) {
+                assert_eq!(tree.get(key).as_ref(), Some(key));
+            }
+
+            for key in &chain {
+                tree.put(key, b"overwritten".to_vec());
+            }
+            for (key, sibling) in chain.iter().zip(&siblings) {
+                assert_eq!(tree.remove(key), Some(b"overwritten".to_vec()));
+                assert_eq!(tree.get(key), None);
+                assert_eq!(tree.get(sibling).as_ref(), Some(sibling));
+                assert_eq!(tree.check_integrity(), Ok(()));
+            }
+            let mut remaining_keys = siblings.clone();
+            remaining_keys.sort();
+            assert_eq!(tree.keys().collect::<Vec<_>>(), remaining_keys);
+        });
+    }
+
+    #[test]
+    fn test_empty_key_in_split_root() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            tree.put(b"", b"empty".to_vec());
+            for i in 0..500_u32 {
+                tree.put(i.to_be_bytes(), vec![]);
+                assert_eq!(tree.get(b""), Some(b"empty".to_vec()));
+            }
+            assert!(tree.stats().routing_nodes > 0);
+            assert_eq!(tree.first_key_value(), Some((vec![], b"empty".to_vec())));
+
+            assert_eq!(tree.remove(b""), Some(b"empty".to_vec()));
+            assert_eq!(tree.first_key_value(), Some((vec![0; 4], vec![])));
+            tree.put(b"", b"again".to_vec());
+            assert_eq!(tree.get(b""), Some(b"again".to_vec()));
+            assert_eq!(tree.check_integrity(), Ok(()));
+            assert_eq!(tree.keys().count(), 501);
+        });
+    }
+
+    #[test]
+    fn test_node_under_empty_segment_is_a_fault() {
+        let tree = TSIMTree::new();
+        tree.put(b"a", b"1".into());
+        tree.put(b"ab", b"2".into());
+        {
+            let mut nodes = tree.root.write();
+            let root = nodes.root;
+            let TSIMTreeNodeChild::Node(child) = *nodes.arena[root].child(0) else {
+                panic!("the keys share the node below the segment a");
+            };
+            let replaced = nodes.arena.insert(TSIMTreeNode::empty());
+            *nodes.arena[child].child_mut(0) = TSIMTreeNodeChild::Node(replaced);
+        }
+        assert_eq!(
+            tree.check_integrity(),
+            Err(TSIMTreeFault::NodeUnderEmptySegment)
+        );
+    }
+
+    #[test]
+    fn test_key_byte_equality() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+
+            // Two keys with same content but different Vec allocations
+            let k1: Vec<u8> = b"identical".into();
+            let k2: Vec<u8> = b"identical".into();
+            let v: Vec<u8> = b"value".into();
+
+            tree.put(&k1, v.clone());
+            assert_eq!(tree.get(&k2), Some(v));
+        });
+    }
+
+    #[test]
+    fn test_full_node_is_split() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for byte in 0..=u8::MAX {
+                tree.put([byte], vec![byte]);
+            }
+
+            for byte in 0..=u8::MAX {
+                assert_eq!(tree.get([byte]), Some(vec![byte]));
+                assert_eq!(tree.get([byte, 0]), None);
+            }
+        });
+    }
+
+    // #[test]
+    // fn test_concurrent_inserts_and_gets() {
+    //     let tree = Arc::new(TSIMTree::new());
+    //     let num_threads = 8;
+    //     let num_keys = 100;
+
+    //     // Spawn threads for concurrent puts
+    //     let mut handles = vec![];
+    //     for tid in 0..num_threads {
+    //         let t_clone = Arc::clone(&tree);
+    //         handles.push(thread::spawn(move || {
+    //             for i in 0..num_keys {
+    //                 let k = format!("k{}_{}", tid, i).into_bytes();
+    //                 let v = format!("v{}_{}", tid, i).into_bytes();
+    //                 t_clone.put(k, v);
+    //             }
+    //         }));
+    //     }
+    //     // Wait for all insertions
+    //     for h in handles {
+    //         h.join().expect("thread panicked");
+    //     }
+
+    //     // Concurrent gets
+    //     let mut handles = vec![];
+    //     for tid in 0..num_threads {
+    //         let t_clone = Arc::clone(&tree);
+    //         handles.push(thread::spawn(move || {
+    //             for i in 0..num_keys {
+    //                 let k = format!("k{}_{}", tid, i).into_bytes();
+    //                 let expected = format!("v{}_{}", tid, i).into_bytes();
+    //                 assert_eq!(t_clone.get(&k), Some(expected.as_slice()));
+    //             }
+    //         }));
+    //     }
+    //     for h in handles {
+    //         h.join().expect("thread panicked");
+    //     }
+    // }
+
+    #[test]
+    fn test_keys_with_null_bytes() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            tree.put(&b"key\0with\0nulls"[..], b"value".into());
+            assert_eq!(tree.get(&b"key\0with\0nulls"[..]), Some(b"value".to_vec()));
+        });
+    }
+
+    #[test]
+    fn test_remove() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            tree.put(b"key", b"value".into());
+            tree.put(b"keys", b"values".into());
+
+            assert_eq!(tree.remove(b"ke"), None);
+            assert_eq!(tree.remove(b"key"), Some(b"value".to_vec()));
+            assert_eq!(tree.remove(b"key"), None);
+
+            assert_eq!(tree.get(b"key"), None);
+            assert_eq!(tree.get(b"keys"), Some(b"values".to_vec()));
+        });
+    }
+
+    /// Counts the nodes of the tree, including the root.
+    fn count_nodes<const RADIX: usize, const LINE: usize>(tree: &TSIMTree<RADIX, LINE>) -> usize {
+        let node_guard = tree.root.read();
+        let mut nodes = vec![node_guard.root];
+        let mut count = 0;
+        while let Some(node) = nodes.pop() {
+            count += 1;
+            let node = &node_guard.arena[node];
+            for idx in 0..node.children_count as usize {
+                if let TSIMTreeNodeChild::Node(child) = node.child(idx) {
+                    nodes.push(*child);
+                }
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn test_long_key_node_count() {
+        const SEGMENT: usize = <TSIMTreeNode>::MAX_STORED_KEY_SEGMENT_SIZE;
+
+        // Every node on the path to the value stores a full segment of the key, the root included.
+        for key_len in [1, SEGMENT, SEGMENT + 1, 10 * SEGMENT, 10 * SEGMENT + 3] {
+            let tree = TSIMTree::new();
+            tree.put(vec![7; key_len], b"value".into());
+            assert_eq!(count_nodes(&tree), key_len.div_ceil(SEGMENT));
+        }
+    }
+
+    #[test]
+    fn test_remove_merges_chains() {
+        const SEGMENT: usize = <TSIMTreeNode>::MAX_STORED_KEY_SEGMENT_SIZE;
+
+        let tree = TSIMTree::new();
+        let long_key: Vec<u8> = (0..10 * SEGMENT as u8).collect();
+        tree.put(&long_key, b"value".into());
+        let minimal_nodes = count_nodes(&tree);
+
+        // Keys diverging in the middle of segments split the segments of the chain.
+        let diverging_keys: Vec<Vec<u8>> = (1..10 * SEGMENT)
+            .step_by(3)
+            .map(|len| [&long_key[..len], b"!"].concat())
+            .collect();
+        for key in &diverging_keys {
+            tree.put(key, vec![]);
+        }
+        assert!(count_nodes(&tree) > minimal_nodes);
+
+        for key in &diverging_keys {
+            assert_eq!(tree.remove(key), Some(vec![]));
+            assert_eq!(tree.check_integrity(), Ok(()));
+        }
+        assert_eq!(count_nodes(&tree), minimal_nodes);
+        assert_eq!(tree.get(&long_key), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_shrink_to_fit_merges_sparse_nodes() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            let keys: Vec<Vec<u8>> = (0..4000_u32)
+                .map(|i| {
+                    let suffix = &b"-suffix"[..i as usize % 8];
+                    [&(i * 7919 % 4000).to_be_bytes()[..], suffix].concat()
+                })
+                .collect();
+            for key in &keys {
+                tree.put(key, key.clone());
+            }
+            let (kept, removed): (Vec<_>, Vec<_>) = keys.iter().partition(|key| key[3] % 16 == 0);
+            for key in removed {
+                tree.remove(key);
+            }
+            let before = tree.stats();
+
+            assert!(tree.shrink_to_fit() > 0);
+            let after = tree.stats();
+            assert!(after.nodes < before.nodes, "{after:?} is not smaller than {before:?}");
+            assert_eq!(after.entries, kept.len());
+            assert_eq!(tree.check_integrity(), Ok(()));
+            for key in &kept {
+                assert_eq!(tree.get(key).as_ref(), Some(*key));
+            }
+
+            // Shrinking again finds nothing left to merge.
+            tree.shrink_to_fit();
+            assert_eq!(tree.stats(), after);
+        });
+    }
+
+    #[test]
+    fn test_compact() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            // Visits every number below 10_000 exactly once, as 7919 and 10_000 are coprime.
+            let keys: Vec<[u8; 4]> = (0..10_000_u32)
+                .map(|i| (i * 7919 % 10_000).to_be_bytes())
+                .collect();
+            for key in &keys {
+                tree.put(key, key.to_vec());
+            }
+            // Expired entries are dropped.
+            #[cfg(feature = "std")]
+            tree.put_with_ttl(b"expired", vec![], Duration::ZERO);
+            let (kept, removed) = keys.split_at(1000);
+            for key in removed {
+                tree.remove(key);
+            }
+
+            let (before, after) = tree.compact();
+            assert!(after.nodes < before.nodes, "{after:?} is not smaller than {before:?}");
+            assert_eq!(after, tree.stats());
+            assert_eq!(after.entries, kept.len());
+            assert_eq!(tree.check_integrity(), Ok(()));
+            assert_eq!(tree.root.read().arena.len(), count_nodes(&tree));
+            let mut sorted = kept.to_vec();
+            sorted.sort();
+            let entries: Vec<_> = sorted.iter().map(|key| (key.to_vec(), key.to_vec())).collect();
+            assert_eq!(tree.iter().collect::<Vec<_>>(), entries);
+
+            // The tree keeps working like any other tree.
+            tree.put(removed[0], b"again".to_vec());
+            assert_eq!(tree.remove(kept[0]), Some(kept[0].to_vec()));
+            assert_eq!(tree.get(removed[0]), Some(b"again".to_vec()));
+            assert_eq!(tree.check_integrity(), Ok(()));
+        });
+    }
+
+    #[test]
+    fn test_approximate_memory_bytes() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            let empty = tree.approximate_memory_bytes();
+            // Inline values cost nothing beyond the slots of their nodes.
+            for i in 0..1000_u32 {
+                tree.put(i.to_be_bytes(), vec![1; 4]);
+            }
+            let with_short_values = tree.approximate_memory_bytes();
+            let node_size = {
+                let node_guard = tree.root.read();
+                size_of_val(&node_guard.arena[node_guard.root])
+            };
+            let nodes = count_nodes(&tree) * node_size;
+            assert!(with_short_values >= nodes, "{with_short_values} is below {nodes} bytes");
+            assert!(with_short_values < 4 * nodes, "{with_short_values} is far above {nodes} bytes");
+
+            // Long values are counted with their capacity.
+            for i in 0..1000_u32 {
+                tree.put(i.to_be_bytes(), vec![1; 1000]);
+            }
+            let with_long_values = tree.approximate_memory_bytes();
+            assert!(with_long_values >= with_short_values + 1000 * 1000);
+            assert!(with_long_values < with_short_values + 1100 * 1000);
+
+            tree.clear();
+            tree.shrink_to_fit();
+            assert!(tree.approximate_memory_bytes() <= empty);
+        });
+
+        // The nodes that track the recency of the entries are counted as well.
+        let tree = TSIMTree::<16, 128>::new();
+        let bounded = TSIMTree::<16, 128>::with_capacity(1000);
+        for i in 0..1000_u32 {
+            tree.put(i.to_be_bytes(), vec![]);
+            bounded.put(i.to_be_bytes(), vec![]);
+        }
+        assert!(bounded.approximate_memory_bytes() > 2 * tree.approximate_memory_bytes());
+    }
+
+    #[test]
+    fn test_removed_nodes_are_freed() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            let keys: Vec<String> = (0..500_u32)
+                .map(|i| format!("key-{i}-{}", "x".repeat(i as usize % 40)))
+                .collect();
+            for key in &keys {
+                tree.put(key, vec![]);
+            }
+            let stored_nodes = tree.root.read().arena.len();
+            assert_eq!(stored_nodes, count_nodes(&tree));
+
+            for key in keys.iter().step_by(2) {
+                tree.remove(key);
+            }
+            let stored_nodes = tree.root.read().arena.len();
+            assert_eq!(stored_nodes, count_nodes(&tree));
+
+            for key in &keys {
+                tree.remove(key);
+            }
+            // Only the root is left.
+            assert_eq!(tree.root.read().arena.len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_arena_reuses_slots_of_removed_nodes() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            let keys: Vec<String> = (0..500_u32)
+                .map(|i| format!("key-{i}-{}", "x".repeat(i as usize % 40)))
+                .collect();
+            for key in &keys {
+                tree.put(key, vec![]);
+            }
+            let slots = tree.root.read().arena.slots();
+
+            // Refilling the tree only fills the vacant slots, so the arena does not grow.
+            for _ in 0..3 {
+                for key in &keys {
+                    tree.remove(key);
+                }
+                for key in &keys {
+                    tree.put(key, vec![]);
+                }
+                // A slab reports its capacity, which does not grow while it has vacant entries either.
+                assert!(tree.root.read().arena.slots() <= slots);
+                assert_eq!(tree.root.read().arena.len(), count_nodes(&tree));
+            }
+        });
+    }
+
+    #[test]
+    fn test_into_iter() {
+        use std::collections::BTreeMap;
+
+        for_each_layout!(Tree => {
+            let map: BTreeMap<Vec<u8>, Vec<u8>> = (0..300_u32)
+                .map(|i| {
+                    let key = i.to_string().repeat(i as usize % 7);
+                    (key.into_bytes(), i.to_le_bytes().to_vec())
+                })
+                .collect();
+            let tree: Tree = map.clone().into_iter().collect();
+            let entries: Vec<_> = tree.into_iter().collect();
+            assert_eq!(entries, map.into_iter().collect::<Vec<_>>());
+
+            let tree: Tree = (0..300_u32).map(|i| (i.to_be_bytes(), vec![])).collect();
+            let stored_nodes = tree.root.read().arena.len();
+            let mut entries = tree.into_iter();
+            for i in 0..150_u32 {
+                assert_eq!(entries.next(), Some((i.to_be_bytes().to_vec(), vec![])));
+            }
+            // The visited nodes were already removed, the rest is dropped with the iterator.
+            assert!(entries.nodes.arena.len() < stored_nodes);
+            drop(entries);
+        });
+    }
+
+    #[test]
+    fn test_for_loops_over_borrowed_and_owned_trees() {
+        for_each_layout!(Tree => {
+            let tree: Tree = (0..300_u32)
+                .map(|i| (i * 7919 % 300).to_be_bytes())
+                .map(|key| (key, key.to_vec()))
+                .collect();
+            let expected: Vec<_> = tree.iter().collect();
+
+            let mut borrowed = Vec::new();
+            for (k, v) in &tree {
+                borrowed.push((k, v));
+            }
+            assert_eq!(borrowed, expected);
+
+            let mut owned = Vec::new();
+            for (k, v) in tree {
+                owned.push((k, v));
+            }
+            assert_eq!(owned, expected);
+            assert!(owned.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        });
+    }
+
+    #[test]
+    fn test_drop_partially_consumed_deep_into_iter() {
+        let tree = TSIMTree::new();
+        let long_key = vec![b'x'; 100_000];
+        tree.put(&long_key, b"long".into());
+        tree.put(&long_key[..50_000], b"half".into());
+        tree.put(b"a", b"short".into());
+
+        let mut entries = tree.into_iter();
+        assert_eq!(entries.next(), Some((b"a".to_vec(), b"short".to_vec())));
+        assert_eq!(
+            entries.next(),
+            Some((long_key[..50_000].to_vec(), b"half".to_vec()))
+        );
+        drop(entries);
+    }
+
+    #[test]
+    fn test_builder() {
+        for_each_layout!(Tree => {
+            // Visits every number below 10_000 exactly once, as 7919 and 10_000 are coprime.
+            let shuffled: Vec<u32> = (0..10_000_u32).map(|i| i * 7919 % 10_000).collect();
+
+            let mut builder = TSIMTreeBuilder::default();
+            let tree = Tree::default();
+            for &i in &shuffled {
+                builder.push(i.to_be_bytes(), i.to_le_bytes().to_vec());
+                tree.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+            }
+            builder.push(0_u32.to_be_bytes(), b"pushed last".into());
+            tree.put(0_u32.to_be_bytes(), b"pushed last".into());
+
+            let built: Tree = builder.build();
+            assert_eq!(built, tree);
+            assert_eq!(built.check_integrity(), Ok(()));
+            assert!(count_nodes(&built) < count_nodes(&tree));
+        });
+    }
+
+    #[test]
+    fn test_from_sorted_iter() {
+        for_each_layout!(Tree => {
+            // Short keys, keys that are prefixes of others and keys that span several segments.
+            let mut keys: Vec<Vec<u8>> = (0..10_000_u32).map(|i| i.to_be_bytes().to_vec()).collect();
+            keys.extend((0..40).map(|len| vec![0xff; len]));
+            keys.sort();
+
+            let tree = Tree::default();
+            for key in &keys {
+                tree.put(key, key.clone());
+            }
+            let entries = keys.iter().map(|key| (key.clone(), key.clone()));
+            let loaded = Tree::from_sorted_iter(entries.clone()).unwrap();
+            assert_eq!(loaded, tree);
+            assert_eq!(loaded.check_integrity(), Ok(()));
+            assert!(count_nodes(&loaded) <= count_nodes(&tree));
+            assert_eq!(loaded.root.read().arena.len(), count_nodes(&loaded));
+
+            // Nodes that are only half full leave room for later puts.
+            let half_full = Tree::from_sorted_iter_with_fill_factor(entries, 0.5).unwrap();
+            assert_eq!(half_full, tree);
+            assert_eq!(half_full.check_integrity(), Ok(()));
+            assert!(count_nodes(&half_full) > count_nodes(&loaded));
+
+            // The tree keeps working like any other tree.
+            loaded.put(b"between", b"new".into());
+            assert_eq!(loaded.remove(&keys[1]), Some(keys[1].clone()));
+            assert_eq!(loaded.get(b"between"), Some(b"new".to_vec()));
+            assert_eq!(loaded.get(&keys[1]), None);
+            assert_eq!(loaded.check_integrity(), Ok(()));
+
+            assert_eq!(Tree::from_sorted_iter(core::iter::empty()).unwrap(), Tree::default());
+        });
+    }
+
+    #[test]
+    fn test_from_sorted_iter_rejects_unsorted_keys() {
+        let load = |keys: &[&[u8]]| -> Result<TSIMTree, BulkLoadError> {
+            TSIMTree::from_sorted_iter(keys.iter().map(|key| (key.to_vec(), Vec::new())))
+        };
+        assert_eq!(
+            load(&[b"a", b"c", b"b"]),
+            Err(BulkLoadError::UnsortedKeys { index: 2 })
+        );
+        assert_eq!(
+            load(&[b"", b"a", b"a"]),
+            Err(BulkLoadError::UnsortedKeys { index: 2 })
+        );
+        assert_eq!(
+            load(&[b"ab", b"a"]),
+            Err(BulkLoadError::UnsortedKeys { index: 1 })
+        );
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_parallel_builder() {
+        for_each_layout!(Tree => {
+            // Keys sharing a prefix longer than a segment can not start a range.
+            let long_prefix = [7_u8; 20];
+            let keys: Vec<Vec<u8>> = (0..40_000_u32)
+                .map(|i| i * 7919 % 40_000)
+                .map(|i| match i % 4 {
+                    0 => [long_prefix.as_slice(), &i.to_be_bytes()].concat(),
+                    _ => i.to_be_bytes().to_vec(),
+                })
+                .collect();
+
+            let mut builder = TSIMTreeBuilder::default();
+            let mut parallel_builder = TSIMTreeBuilder::default();
+            for key in &keys {
+                builder.push(key, key.clone());
+                parallel_builder.push(key, key.clone());
+            }
+            builder.push(&keys[0], b"pushed last".into());
+            parallel_builder.push(&keys[0], b"pushed last".into());
+
+            let built: Tree = builder.build();
+            let parallel_built: Tree = parallel_builder.build_parallel();
+            assert_eq!(parallel_built, built);
+            assert_eq!(parallel_built.check_integrity(), Ok(()));
+            assert_eq!(parallel_built.get(&keys[0]), Some(b"pushed last".to_vec()));
+            assert!(parallel_built.stats().routing_nodes > built.stats().routing_nodes);
+
+            // The tree keeps working like any other tree.
+            parallel_built.put(b"", b"empty".into());
+            parallel_built.put(&keys[1], b"overwritten".into());
+            assert_eq!(parallel_built.remove(&keys[2]), Some(keys[2].clone()));
+            assert_eq!(parallel_built.get(b""), Some(b"empty".to_vec()));
+            assert_eq!(parallel_built.get(&keys[1]), Some(b"overwritten".to_vec()));
+            assert_eq!(parallel_built.check_integrity(), Ok(()));
+            let stored_nodes = parallel_built.root.read().arena.len();
+            assert_eq!(stored_nodes, count_nodes(&parallel_built));
+        });
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_parallel_builder_with_few_entries() {
+        let mut builder = TSIMTreeBuilder::new();
+        builder.push(b"b", b"2".into());
+        builder.push(b"a", b"1".into());
+        let tree = builder.build_parallel();
+        assert_eq!(tree.stats().routing_nodes, 0);
+        assert_eq!(
+            tree.iter().collect::<Vec<_>>(),
+            vec![(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), b"2".to_vec())]
+        );
+        assert_eq!(TSIMTreeBuilder::new().build_parallel(), TSIMTree::new());
+    }
+
+    #[test]
+    fn test_persistent_tree() {
+        let empty = PersistentTSIMTree::new();
+        let v1 = empty.put(b"apple", b"1".to_vec());
+        let v2 = v1.put(b"apricot", b"2".to_vec());
+        let v3 = v2.put(b"ap", b"3".to_vec()).put(b"", b"4".to_vec());
+        let v4 = v3.put(b"apple", b"overwritten".to_vec());
+        let v5 = v4.remove(b"apricot").remove(b"missing");
+
+        assert!(empty.is_empty());
+        assert_eq!(empty.get(b"apple"), None);
+        assert_eq!(v1.get(b"apple"), Some(&b"1"[..]));
+        assert_eq!(v1.get(b"apricot"), None);
+        assert_eq!(v2.get(b"apricot"), Some(&b"2"[..]));
+        assert_eq!(v2.get(b"ap"), None);
+        assert_eq!(v3.get(b"ap"), Some(&b"3"[..]));
+        assert_eq!(v3.get(b""), Some(&b"4"[..]));
+        assert_eq!(v3.get(b"apple"), Some(&b"1"[..]));
+        assert_eq!(v4.get(b"apple"), Some(&b"overwritten"[..]));
+        assert_eq!(v5.get(b"apricot"), None);
+        assert_eq!(v4.get(b"apricot"), Some(&b"2"[..]));
+
+        let versions = [&empty, &v1, &v2, &v3, &v4, &v5];
+        let lens: Vec<_> = versions.iter().map(|v| v.len()).collect();
+        assert_eq!(lens, [0, 1, 2, 4, 4, 3]);
+        let keys: Vec<_> = v4.iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, [&b""[..], b"ap", b"apple", b"apricot"]);
+        let keys: Vec<_> = (&v5).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, [&b""[..], b"ap", b"apple"]);
+
+        // Removing every key leads back to an empty tree, while the old versions still hold them.
+        let emptied = v5.remove(b"ap").remove(b"").remove(b"apple");
+        assert!(emptied.is_empty());
+        assert_eq!(emptied.iter().next(), None);
+        assert_eq!(v5.iter().count(), 3);
+    }
+
+    #[test]
+    fn test_local_tree() {
+        let mut tree = TSIMTreeLocal::new();
+        for key in ["a", "abc", "b", ""] {
+            tree.put(key, key.as_bytes().to_vec());
+        }
+        assert_eq!(tree.get("abc"), Some(b"abc".as_slice()));
+        assert_eq!(tree.get("ab"), None);
+        assert_eq!(tree.remove("a"), Some(b"a".to_vec()));
+        assert_eq!(tree.remove("a"), None);
+
+        let entries: Vec<_> = tree.iter().collect();
+        assert_eq!(
+            entries,
+            [
+                (b"".to_vec(), b"".as_slice()),
+                (b"abc".to_vec(), b"abc"),
+                (b"b".to_vec(), b"b")
+            ]
+        );
+
+        // The nodes are moved between both kinds of trees.
+        let shared = TSIMTree::from(tree);
+        shared.put("c", b"c".into());
+        let tree = TSIMTreeLocal::from(shared);
+        assert_eq!(tree.get("c"), Some(b"c".as_slice()));
+        assert_eq!(tree.into_iter().count(), 4);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_local_tree_skips_expired_entries() {
+        let shared = TSIMTree::new();
+        shared.put_with_ttl("expired", b"value".into(), Duration::ZERO);
+        shared.put("kept", b"value".into());
+
+        let mut tree = TSIMTreeLocal::from(shared);
+        assert_eq!(tree.get("expired"), None);
+        assert_eq!(tree.iter().count(), 1);
+        assert_eq!(tree.remove("expired"), None);
+    }
+
+    #[test]
+    fn test_iter_and_stats() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            assert_eq!(tree.iter().next(), None);
+            assert_eq!(
+                tree.stats(),
+                TSIMTreeStats {
+                    nodes: 1,
+                    ..Default::default()
+                }
+            );
+
+            for key in ["a", "abc", "b", ""] {
+                tree.put(key, key.as_bytes().to_vec());
+            }
+            let entries: Vec<_> = tree.iter().collect();
+            let expected: Vec<_> = ["", "a", "abc", "b"]
+                .map(|key| (key.as_bytes().to_vec(), key.as_bytes().to_vec()))
+                .into();
+            assert_eq!(entries, expected);
+            assert_eq!(
+                tree.stats(),
+                TSIMTreeStats {
+                    nodes: 2,
+                    routing_nodes: 0,
+                    entries: 4,
+                    depth: 2
+                }
+            );
+
+            for i in 0..1000_u32 {
+                tree.put(i.to_be_bytes(), vec![]);
+            }
+            let stats = tree.stats();
+            assert_eq!(stats.nodes, count_nodes(&tree));
+            assert!(stats.routing_nodes > 0);
+            assert_eq!(stats.entries, (&tree).into_iter().count());
+        });
+    }
+
+    #[test]
+    fn test_overwriting_keeps_the_structure() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in 0..1000_u32 {
+                tree.put(i.to_be_bytes(), vec![0]);
+            }
+            let stats = tree.stats();
+
+            for i in 0..1000_u32 {
+                tree.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+            }
+            assert_eq!(tree.stats(), stats);
+            assert_eq!(tree.check_integrity(), Ok(()));
+            assert!((0..1000_u32).all(|i| tree.get(i.to_be_bytes()) == Some(i.to_le_bytes().to_vec())));
+        });
+    }
+
+    #[test]
+    fn test_put_at_the_position_found_under_the_read_lock_builds_the_same_tree() {
+        for_each_layout!(Tree => {
+            let keys = bench_utils::shuffled_keys(2000, 7);
+            let long_keys = bench_utils::long_keys(200, 64, 7);
+            let tree = Tree::default();
+            let reference = Tree::default();
+            for key in keys.iter().map(|key| &key[..]).chain(long_keys.iter().map(Vec::as_slice)) {
+                tree.put(key, key.to_vec());
+                reference.root.write().put(key, key.to_vec());
+            }
+            assert_eq!(tree.stats(), reference.stats());
+            assert_eq!(tree.check_integrity(), Ok(()));
+            assert!(tree.iter().eq(reference.iter()));
+        });
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_concurrent_puts_do_not_reuse_stale_positions() {
+        let tree = TSIMTree::new();
+        std::thread::scope(|s| {
+            for thread in 0..4_u32 {
+                let tree = &tree;
+                s.spawn(move || {
+                    for i in 0..2000_u32 {
+                        tree.put((i * 4 + thread).to_be_bytes(), vec![thread as u8]);
+                    }
+                });
+            }
+        });
+
+        assert_eq!(tree.check_integrity(), Ok(()));
+        assert!((0..8000_u32).all(|i| tree.get(i.to_be_bytes()) == Some(vec![(i % 4) as u8])));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_overwriting_clears_the_expiry() {
+        let tree = TSIMTree::new();
+        tree.put_with_ttl(b"key", vec![1], Duration::ZERO);
+        // The expired value is still stored, so it is overwritten in place.
+        tree.put(b"key", vec![2]);
+        assert_eq!(tree.get(b"key"), Some(vec![2]));
+    }
+
+    #[test]
+    fn test_for_each_borrows_every_entry() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in 0..1000_u32 {
+                tree.put(i.to_be_bytes(), vec![0; i as usize % 7]);
+            }
+            tree.put(b"", vec![0; 100]);
+
+            let mut sum = 0;
+            let mut keys = Vec::new();
+            tree.for_each(|key, value| {
+                sum += value.len();
+                keys.push(key.to_vec());
+            });
+            assert_eq!(sum, tree.iter().map(|(_, value)| value.len()).sum::<usize>());
+            assert_eq!(keys, tree.keys().collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn test_debug_format() {
+        let tree = TSIMTree::new();
+        assert_eq!(format!("{:?}", *tree.root.read()), "{}");
+        assert_eq!(format!("{:#?}", *tree.root.read()), "{}");
+
+        for key in ["a", "abc", "b", "ab"] {
+            tree.put(key, b"v".into());
+        }
+        assert_eq!(
+            format!("{:?}", *tree.root.read()),
+            r#"{"[61]": {"[]": "[76]", "[62]": {"[]": "[76]", "[63]": "[76]"}}, "[62]": "[76]"}"#
+        );
+        let expected = r#"{
+    "[61]": {
+        "[]": "[76]",
+        "[62]": {
+            "[]": "[76]",
+            "[63]": "[76]",
+        },
+    },
+    "[62]": "[76]",
+}"#;
+        assert_eq!(format!("{:#?}", *tree.root.read()), expected);
+
+        let tree = TSIMTree::<2, 16>::default();
+        for key in ["a", "b", "c"] {
+            tree.put(key, b"v".into());
+        }
+        assert_eq!(
+            format!("{:?}", *tree.root.read()),
+            r#"{">=[]": {"[61]": "[76]", "[62]": "[76]"}, ">=[63]": {"[63]": "[76]"}}"#
+        );
+    }
+
+    #[test]
+    fn test_traversals_of_deep_trees() {
+        // Every node stores only a few bytes of the key, so a 1 MB key creates a chain of more than 100_000 nodes.
+        let long_key: Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+        let tree = TSIMTree::new();
+        tree.put(&long_key, b"long".into());
+        tree.put(b"short", b"short".into());
+
+        let stats = tree.stats();
+        assert_eq!(stats.entries, 2);
+        assert!(stats.depth > 100_000);
+        assert_eq!(tree.check_integrity(), Ok(()));
+
+        let keys: Vec<_> = tree.iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, [long_key.clone(), b"short".to_vec()]);
+
+        let snapshot = tree.clone();
+        assert_eq!(snapshot, tree);
+        assert_eq!(snapshot.get(&long_key), Some(b"long".to_vec()));
+        assert!(format!("{tree:?}").len() > stats.depth);
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            tree.put(b"existing", b"old".into());
+
+            let mut calls = 0;
+            let value = tree.get_or_insert_with(b"new", || {
+                calls += 1;
+                b"inserted".to_vec()
+            });
+            assert_eq!(value, b"inserted");
+            assert_eq!(calls, 1);
+
+            let value = tree.get_or_insert_with(b"existing", || {
+                calls += 1;
+                b"replaced".to_vec()
+            });
+            assert_eq!(value, b"old");
+            assert_eq!(calls, 1);
+
+            assert_eq!(tree.get(b"new"), Some(b"inserted".to_vec()));
+            assert_eq!(tree.get(b"existing"), Some(b"old".to_vec()));
+
+            // The inserted value is returned when the key is stored in a chain of nodes as well.
+            let long_key = [7; 100];
+            assert_eq!(tree.get_or_insert_with(long_key, || vec![1]), vec![1]);
+            assert_eq!(tree.get_or_insert_with(long_key, || vec![2]), vec![1]);
+        });
+    }
+
+    #[test]
+    fn test_str_keys() {
+        use std::borrow::Cow;
+        use std::sync::Arc;
+
+        let tree = TSIMTree::new();
+        tree.put("hello", b"ascii".into());
+        tree.put(String::from("grüße"), b"umlaut".into());
+        tree.put(Box::<str>::from("🦀🌳").tree_key(), b"emoji".into());
+        tree.put(Arc::<str>::from("日本語").tree_key(), b"cjk".into());
+
+        assert_eq!(tree.get("hello"), Some(b"ascii".to_vec()));
+        assert_eq!(tree.get(Cow::from("grüße").tree_key()), Some(b"umlaut".to_vec()));
+        assert_eq!(tree.get("🦀🌳"), Some(b"emoji".to_vec()));
+        assert_eq!(tree.get("日本語".tree_key()), Some(b"cjk".to_vec()));
+
+        // Keys are compared by their bytes, so the decomposed spelling is a different key.
+        assert_eq!(tree.get("gru\u{308}ße"), None);
+        // The emoji shares its first bytes with the stored key, but is not stored itself.
+        assert_eq!(tree.get("🦀"), None);
+    }
+
+    #[test]
+    fn test_get_entry() {
+        for_each_layout!(Tree => {
+            let tree: Tree = (0..300_u32)
+                .map(|i| ((i * 3).to_be_bytes(), i.to_le_bytes().to_vec()))
+                .collect();
+            for i in 0..900_u32 {
+                let key = i.to_be_bytes();
+                let expected = (i % 3 == 0).then(|| (key.to_vec(), (i / 3).to_le_bytes().to_vec()));
+                assert_eq!(tree.get_entry(key), expected);
+                // An exact match is the neighbor of the key that includes the key itself.
+                if let Some(entry) = tree.get_entry(key) {
+                    assert_eq!(tree.get_next(key), Some(entry.clone()));
+                    assert_eq!(tree.get_prev(key), Some(entry));
+                }
+            }
+            assert_eq!(tree.get_entry(b""), None);
+            tree.put(b"", b"empty".to_vec());
+            assert_eq!(tree.get_entry(b""), Some((vec![], b"empty".to_vec())));
+        });
+    }
+
+    #[test]
+    fn test_value_len() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            tree.put(b"empty", vec![]);
+            tree.put(b"short", vec![1; 3]);
+            tree.put(b"long", vec![2; 1000]);
+
+            assert_eq!(tree.value_len(b"empty"), Some(0));
+            assert_eq!(tree.value_len(b"short"), Some(3));
+            assert_eq!(tree.value_len(b"long"), Some(1000));
+            assert_eq!(tree.value_len(b"absent"), None);
+            assert_eq!(tree.value_len(b"shor"), None);
+            assert_eq!(tree.value_len(b""), None);
+
+            tree.put(b"long", vec![]);
+            assert_eq!(tree.value_len(b"long"), Some(0));
+            tree.remove(b"long");
+            assert_eq!(tree.value_len(b"long"), None);
+        });
+    }
+
+    /// Returns whether the value of the key is stored within its node.
+    fn is_inline<const RADIX: usize, const LINE: usize>(
+        tree: &TSIMTree<RADIX, LINE>,
+        key: &[u8],
+    ) -> bool {
+        let node_guard = tree.root.read();
+        let (node, idx) = node_guard.find_value(key).unwrap();
+        match node_guard.arena[node].child(idx) {
+            TSIMTreeNodeChild::Value(value, _) => value.is_inline(),
+            TSIMTreeNodeChild::Node(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_values_move_between_node_and_heap() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in 0..200_u32 {
+                tree.put(i.to_be_bytes(), vec![i as u8; i as usize % 40]);
+            }
+            let key = 100_u32.to_be_bytes();
+
+            // Overwrites cross the longest inline length in both directions.
+            for len in [INLINE_VALUE_LEN, INLINE_VALUE_LEN + 1, 0, 64, INLINE_VALUE_LEN, 1] {
+                let value: Vec<u8> = (0..len as u8).collect();
+                tree.put(key, value.clone());
+                assert_eq!(tree.get(key), Some(value.clone()));
+                assert_eq!(tree.get_with(key, |stored| stored == value), Some(true));
+                assert_eq!(is_inline(&tree, &key), len <= INLINE_VALUE_LEN);
+            }
+
+            // A value modified in place moves as well once the guard is dropped.
+            tree.get_mut(key).unwrap().extend_from_slice(&[7; INLINE_VALUE_LEN]);
+            assert!(!is_inline(&tree, &key));
+            tree.get_mut(key).unwrap().truncate(2);
+            assert!(is_inline(&tree, &key));
+            assert_eq!(tree.remove(key), Some(vec![0, 7]));
+
+            // A UUID or a u128 fits within the node.
+            tree.put(key, u128::MAX.to_le_bytes().to_vec());
+            assert!(is_inline(&tree, &key));
+            assert_eq!(tree.remove(key), Some(u128::MAX.to_le_bytes().to_vec()));
+
+            // The other values keep their contents, wherever they are stored.
+            let expected = |i: u32| vec![i as u8; i as usize % 40];
+            assert!(tree.iter().eq((0..200_u32).filter(|&i| i != 100).map(|i| (i.to_be_bytes().to_vec(), expected(i)))));
+            tree.transform_values(|value| value.resize(INLINE_VALUE_LEN + 1, 0));
+            assert!(!is_inline(&tree, &0_u32.to_be_bytes()));
+            tree.transform_values(|value| value.truncate(1));
+            assert!(is_inline(&tree, &39_u32.to_be_bytes()));
+            assert_eq!(tree.get(39_u32.to_be_bytes()), Some(vec![39]));
+            assert_eq!(tree.check_integrity(), Ok(()));
+        });
+    }
+
+    #[test]
+    fn test_str_values() {
+        let tree = TSIMTree::new();
+        let entries = [
+            ("z", "ascii"),
+            ("ü", "umlaut"),
+            ("🦀", "crab"),
+            ("日本", "cjk"),
+            ("", "empty"),
+        ];
+        for (key, value) in entries {
+            tree.put_str(key, value);
+        }
+        for (key, value) in entries {
+            assert_eq!(tree.get_str(key).as_deref(), Some(value));
+        }
+        assert_eq!(tree.get_str("🦀🌳"), None);
+
+        // The keys are ordered by their UTF-8 encoding, which sorts like their code points.
+        let keys: Vec<String> = tree
+            .keys()
+            .map(|key| String::from_utf8(key).unwrap())
+            .collect();
+        assert_eq!(keys, ["", "z", "ü", "日本", "🦀"]);
+
+        // Keys ending inside a multi-byte character are not stored, the bytes are still looked up.
+        assert_eq!(tree.get(&"🦀".as_bytes()[..2]), None);
+        tree.put(&"🦀".as_bytes()[..2], vec![0xf0, 0x9f]);
+        assert_eq!(tree.get_str("🦀").as_deref(), Some("crab"));
+
+        // A value that is not valid UTF-8 cannot be read as a string.
+        tree.put("bytes", vec![0xff]);
+        assert_eq!(tree.get_str("bytes"), None);
+        assert_eq!(tree.get("bytes"), Some(vec![0xff]));
+    }
+
+    #[test]
+    fn test_merge_with_conflicts() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            let other = Tree::default();
+            for i in 0..100_u32 {
+                tree.put(i.to_be_bytes(), b"self".into());
+            }
+            // Half of the keys of other are also stored in tree.
+            for i in 50..150_u32 {
+                other.put(i.to_be_bytes(), b"other".into());
+            }
+
+            tree.merge(&other, |key, self_value, other_value| {
+                assert!((50..100).contains(&u32::from_be_bytes(key.try_into().unwrap())));
+                [self_value, other_value].concat()
+            });
+
+            for i in 0..150_u32 {
+                let expected: &[u8] = match i {
+                    0..50 => b"self",
+                    50..100 => b"selfother",
+                    _ => b"other",
+                };
+                assert_eq!(tree.get(i.to_be_bytes()), Some(expected.to_vec()));
+            }
+            // other is left untouched
+            assert_eq!(other.get(0_u32.to_be_bytes()), None);
+            assert_eq!(other.get(50_u32.to_be_bytes()), Some(b"other".to_vec()));
+        });
+    }
+
+    #[test]
+    fn test_merge_owned() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            let other = Tree::default();
+            for i in 0..100_u32 {
+                tree.put(i.to_be_bytes(), b"self".into());
+            }
+            // Half of the keys of other are also stored in tree.
+            for i in 50..150_u32 {
+                other.put(i.to_be_bytes(), b"other".into());
+            }
+
+            let mut conflicts = 0;
+            tree.merge_owned(other, |key, mut self_value, other_value| {
+                assert!((50..100).contains(&u32::from_be_bytes(key.try_into().unwrap())));
+                conflicts += 1;
+                self_value.extend_from_slice(&other_value);
+                self_value
+            });
+
+            assert_eq!(conflicts, 50);
+            for i in 0..150_u32 {
+                let expected: &[u8] = match i {
+                    0..50 => b"self",
+                    50..100 => b"selfother",
+                    _ => b"other",
+                };
+                assert_eq!(tree.get(i.to_be_bytes()), Some(expected.to_vec()));
+            }
+            assert_eq!(tree.check_integrity(), Ok(()));
+            assert_sorted(&tree);
+        });
+    }
+
+    #[test]
+    fn test_merge_owned_disjoint_trees() {
+        for_each_layout!(Tree => {
+            let even: Tree = (0..200_u32).step_by(2).map(|i| (i.to_be_bytes(), vec![0])).collect();
+            let odd: Tree = (1..200_u32).step_by(2).map(|i| (i.to_be_bytes(), vec![1])).collect();
+
+            let tree = Tree::default();
+            // The nodes of the first tree are taken over as a whole.
+            tree.merge_owned(even, |_, _, _| panic!("the keys are disjoint"));
+            tree.merge_owned(odd, |_, _, _| panic!("the keys are disjoint"));
+
+            for i in 0..200_u32 {
+                assert_eq!(tree.get(i.to_be_bytes()), Some(vec![(i % 2) as u8]));
+            }
+            assert_eq!(tree.check_integrity(), Ok(()));
+            assert_sorted(&tree);
+        });
+    }
+
+    #[test]
+    fn test_merge_with_itself() {
+        let tree = TSIMTree::new();
+        tree.put(b"a", vec![1]);
+        tree.put(b"b", vec![2]);
+
+        tree.merge(&tree, |_, self_value, other_value| {
+            vec![self_value[0] + other_value[0]]
+        });
+
+        assert_eq!(tree.get(b"a"), Some(vec![2]));
+        assert_eq!(tree.get(b"b"), Some(vec![4]));
+    }
+
+    #[test]
+    fn test_split_off_prefix() {
+        for_each_layout!(Tree => {
+            // Few keys are moved as a single subtree, many keys are spread over the children of routing nodes.
+            for user_count in [3_u32, 500] {
+                let tree = Tree::default();
+                for i in 0..user_count {
+                    tree.put(format!("user:{i}"), i.to_be_bytes().to_vec());
+                    tree.put(format!("item:{i}"), i.to_be_bytes().to_vec());
+                }
+                tree.put("user", b"no separator".into());
+                tree.put("users", b"other namespace".into());
+
+                let users = tree.split_off_prefix("user:");
+
+                for i in 0..user_count {
+                    let user = format!("user:{i}");
+                    let item = format!("item:{i}");
+                    assert_eq!(tree.get(&user), None);
+                    assert_eq!(users.get(&user), Some(i.to_be_bytes().to_vec()));
+                    assert_eq!(tree.get(&item), Some(i.to_be_bytes().to_vec()));
+                    assert_eq!(users.get(&item), None);
+                }
+                assert_eq!(tree.get("user"), Some(b"no separator".to_vec()));
+                assert_eq!(tree.get("users"), Some(b"other namespace".to_vec()));
+                assert_eq!(users.get("user"), None);
+
+                // Both trees keep working after the split.
+                users.put("user:new", b"new".into());
+                tree.put("user:other", b"other".into());
+                assert_eq!(users.get("user:new"), Some(b"new".to_vec()));
+                assert_eq!(tree.get("user:other"), Some(b"other".to_vec()));
+                assert_eq!(users.get("user:other"), None);
+            }
+        });
+    }
+
+    #[test]
+    fn test_split_off_prefix_across_routing_children() {
+        let tree = TSIMTree::new();
+        // Fill the node at "p" and split it, so [8, 5] becomes the lower bound of the upper half.
+        for byte in 0..8 {
+            tree.put([b'p', byte], vec![byte]);
+        }
+        tree.put([b'p', 8, 5], vec![85]);
+        for byte in 9..=16 {
+            tree.put([b'p', byte], vec![byte]);
+        }
+        // This key is stored in the lower half, the keys starting with [p, 8] are now spread over both halves.
+        tree.put([b'p', 8, 1], vec![81]);
+
+        let split_off = tree.split_off_prefix([b'p', 8]);
+
+        assert_eq!(split_off.get([b'p', 8, 1]), Some(vec![81]));
+        assert_eq!(split_off.get([b'p', 8, 5]), Some(vec![85]));
+        assert_eq!(tree.get([b'p', 8, 1]), None);
+        assert_eq!(tree.get([b'p', 8, 5]), None);
+        for byte in (0..8).chain(9..=16) {
+            assert_eq!(tree.get([b'p', byte]), Some(vec![byte]));
+            assert_eq!(split_off.get([b'p', byte]), None);
+        }
+    }
+
+    #[test]
+    fn test_split_off_missing_and_empty_prefix() {
+        let tree = TSIMTree::new();
+        tree.put("a", b"a".into());
+        tree.put("ab", b"ab".into());
+
+        let none = tree.split_off_prefix("b");
+        assert_eq!(none.get("a"), None);
+        assert_eq!(tree.get("a"), Some(b"a".to_vec()));
+
+        // The prefix may end in the middle of a stored key.
+        let value = tree.split_off_prefix("ab");
+        assert_eq!(value.get("ab"), Some(b"ab".to_vec()));
+        assert_eq!(tree.get("ab"), None);
+
+        let everything = tree.split_off_prefix("");
+        assert_eq!(everything.get("a"), Some(b"a".to_vec()));
+        assert_eq!(tree.get("a"), None);
+    }
+
+    #[test]
+    fn test_prefix_keys_lists_a_directory() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for key in ["dir", "dir/sub/c", "dir/b", "dir/a", "dir0", "dip", "dir.txt", "e"] {
+                tree.put(key, key.repeat(100).into_bytes());
+            }
+
+            let listed: Vec<Vec<u8>> = tree.prefix_keys("dir/").collect();
+            assert_eq!(listed, [&b"dir/a"[..], b"dir/b", b"dir/sub/c"]);
+            assert_eq!(
+                tree.prefix_iter("dir/sub/").collect::<Vec<_>>(),
+                [(b"dir/sub/c".to_vec(), "dir/sub/c".repeat(100).into_bytes())]
+            );
+            assert_eq!(tree.prefix_keys("dir").count(), 6);
+            assert_eq!(tree.prefix_keys("").count(), 8);
+            assert_eq!(tree.prefix_keys("dir/x").next(), None);
+            assert_eq!(tree.prefix_keys("f").next(), None);
+        });
+    }
+
+    #[test]
+    fn test_lookup_fed_in_chunks() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            tree.put("/usr/lib/libc.so", b"link".into());
+            tree.put("/usr/lib/libc.so.6", b"library".into());
+            tree.put("/usr/local/bin", b"dir".into());
+
+            let mut lookup = tree.lookup();
+            assert_eq!(lookup.feed(b""), LookupState::Pending);
+            assert_eq!(lookup.feed(b"/us"), LookupState::Pending);
+            assert_eq!(lookup.feed(b"r/lib/lib"), LookupState::Pending);
+            assert_eq!(lookup.feed(b"c.so"), LookupState::Found(b"link".into()));
+            assert_eq!(lookup.feed(b"."), LookupState::Pending);
+            assert_eq!(lookup.feed(b"6"), LookupState::Found(b"library".into()));
+            assert_eq!(lookup.feed(b"x"), LookupState::NotFound);
+            assert_eq!(lookup.feed(b""), LookupState::NotFound);
+            drop(lookup);
+
+            let mut lookup = tree.lookup();
+            assert_eq!(lookup.feed(b"/usr/lo"), LookupState::Pending);
+            assert_eq!(lookup.feed(b"g"), LookupState::NotFound);
+            drop(lookup);
+
+            // Keys that go through routing nodes, fed one byte at a time.
+            for i in 0..1000_u32 {
+                tree.put([&i.to_be_bytes()[..], &[7; 100]].concat(), i.to_be_bytes().to_vec());
+            }
+            let mut lookup = tree.lookup();
+            for byte in 789_u32.to_be_bytes().into_iter().chain([7; 99]) {
+                assert_eq!(lookup.feed(&[byte]), LookupState::Pending);
+            }
+            assert_eq!(lookup.feed(&[7]), LookupState::Found(789_u32.to_be_bytes().to_vec()));
+        });
+    }
+
+    #[test]
+    fn test_count_prefix() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in 0..500_u32 {
+                tree.put(format!("user:{i}"), i.to_be_bytes().to_vec());
+                tree.put(format!("item:{i}"), i.to_be_bytes().to_vec());
+            }
+            tree.put("user", b"no separator".into());
+            tree.put("users", b"other namespace".into());
+
+            assert_eq!(tree.count_prefix("user:"), 500);
+            assert_eq!(tree.count_prefix("user"), 502);
+            assert_eq!(tree.count_prefix("user:1"), 111);
+            assert_eq!(tree.count_prefix("user:499"), 1);
+            assert_eq!(tree.count_prefix("user:4999"), 0);
+            assert_eq!(tree.count_prefix("use"), 502);
+            assert_eq!(tree.count_prefix("usa"), 0);
+            assert_eq!(tree.count_prefix("z"), 0);
+            assert_eq!(tree.count_prefix(""), 1002);
+
+            // Expired entries are not counted.
+            #[cfg(feature = "std")]
+            {
+                tree.put_with_ttl("user:expired", vec![], Duration::ZERO);
+                assert_eq!(tree.count_prefix("user:"), 500);
+            }
+        });
+    }
+
+    #[test]
+    fn test_count_prefix_across_routing_children() {
+        let tree = TSIMTree::new();
+        // The same layout as in `test_split_off_prefix_across_routing_children`.
+        for byte in 0..8 {
+            tree.put([b'p', byte], vec![byte]);
+        }
+        tree.put([b'p', 8, 5], vec![85]);
+        for byte in 9..=16 {
+            tree.put([b'p', byte], vec![byte]);
+        }
+        tree.put([b'p', 8, 1], vec![81]);
+
+        assert_eq!(tree.count_prefix([b'p', 8]), 2);
+        assert_eq!(tree.count_prefix([b'p', 8, 5]), 1);
+        assert_eq!(tree.count_prefix([b'p']), 18);
+    }
+
+    #[test]
+    fn test_for_each_prefix() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for path in ["/", "/home", "/home/alice/", "/home/alice/docs/notes.txt", "/home/bob/", "/var/"] {
+                tree.put(path, path.len().to_be_bytes().to_vec());
+            }
+            let prefixes_of = |key: &str| {
+                let mut prefixes = Vec::new();
+                tree.for_each_prefix(key, |prefix, value| {
+                    assert_eq!(value, prefix.len().to_be_bytes());
+                    prefixes.push(String::from_utf8(prefix.to_vec()).unwrap());
+                });
+                prefixes
+            };
+
+            assert_eq!(prefixes_of("/home/alice/docs/notes.txt"), ["/", "/home", "/home/alice/", "/home/alice/docs/notes.txt"]);
+            assert_eq!(prefixes_of("/home/alice/docs"), ["/", "/home", "/home/alice/"]);
+            assert_eq!(prefixes_of("/home/bo"), ["/", "/home"]);
+            assert_eq!(prefixes_of("/var/log"), ["/", "/var/"]);
+            assert!(prefixes_of("").is_empty());
+            tree.put("", vec![0; 8]);
+            assert_eq!(prefixes_of(""), [""]);
+            assert_eq!(prefixes_of("/ho"), ["", "/"]);
+        });
+    }
+
+    #[test]
+    fn test_for_each_prefix_across_routing_children() {
+        let tree = TSIMTree::new();
+        // The same layout as in `test_split_off_prefix_across_routing_children`.
+        for byte in 0..8 {
+            tree.put([b'p', byte], vec![byte]);
+        }
+        tree.put([b'p', 8, 5], vec![85]);
+        for byte in 9..=16 {
+            tree.put([b'p', byte], vec![byte]);
+        }
+        tree.put([b'p', 8, 1], vec![81]);
+        tree.put([b'p', 8], vec![8]);
+        tree.put([b'p'], vec![]);
+
+        let mut prefixes = Vec::new();
+        tree.for_each_prefix([b'p', 8, 5, 0], |prefix, value| {
+            prefixes.push((prefix.to_vec(), value.to_vec()))
+        });
+        assert_eq!(
+            prefixes,
+            [
+                (vec![b'p'], vec![]),
+                (vec![b'p', 8], vec![8]),
+                (vec![b'p', 8, 5], vec![85])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_at_prefix() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in 0..300_u32 {
+                tree.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+            }
+            tree.put(b"", b"empty".into());
+
+            let (lower, upper) = tree.split_at_prefix(150_u32.to_be_bytes());
+            assert_eq!(tree, Tree::default());
+            assert_eq!(lower.get(b""), Some(b"empty".to_vec()));
+            assert_eq!(upper.get(b""), None);
+            for i in 0..300_u32 {
+                let (contained, missing) = match i < 150 {
+                    true => (&lower, &upper),
+                    false => (&upper, &lower),
+                };
+                assert_eq!(contained.get(i.to_be_bytes()), Some(i.to_le_bytes().to_vec()));
+                assert_eq!(missing.get(i.to_be_bytes()), None);
+            }
+            assert_sorted(&lower);
+            assert_sorted(&upper);
+        });
+    }
+
+    #[test]
+    fn test_split_at_prefix_inside_keys() {
+        let tree = TSIMTree::new();
+        for key in ["a", "ab", "abc", "abd", "b", "ba"] {
+            tree.put(key, key.into());
+        }
+
+        // The prefix ends in the middle of a segment and between stored keys.
+        let (lower, upper) = tree.split_at_prefix("abcc");
+        let keys = |tree: &TSIMTree| -> Vec<String> {
+            let node_guard = tree.root.read();
+            node_guard
+                .entries()
+                .map(|(key, _)| String::from_utf8(key).unwrap())
+                .collect()
+        };
+        assert_eq!(keys(&lower), ["a", "ab", "abc"]);
+        assert_eq!(keys(&upper), ["abd", "b", "ba"]);
+
+        let (lower, upper) = upper.split_at_prefix("abd");
+        assert_eq!(keys(&lower), Vec::<String>::new());
+        assert_eq!(keys(&upper), ["abd", "b", "ba"]);
+
+        let (lower, upper) = upper.split_at_prefix("c");
+        assert_eq!(keys(&lower), ["abd", "b", "ba"]);
+        assert_eq!(keys(&upper), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_split_off() {
+        for_each_layout!(Tree => {
+            let filled = || {
+                let tree = Tree::default();
+                for i in (0..600_u32).step_by(2) {
+                    tree.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+                }
+                tree
+            };
+
+            // Existing keys, absent keys and keys below or above all stored keys.
+            for pivot in [300_u32, 301, 0, 599, 1000] {
+                let lower = filled();
+                let upper = lower.split_off(pivot.to_be_bytes());
+                for i in (0..600_u32).step_by(2) {
+                    let (contained, missing) = match i < pivot {
+                        true => (&lower, &upper),
+                        false => (&upper, &lower),
+                    };
+                    assert_eq!(contained.get(i.to_be_bytes()), Some(i.to_le_bytes().to_vec()));
+                    assert_eq!(missing.get(i.to_be_bytes()), None);
+                }
+                assert_eq!(lower.iter().count() + upper.iter().count(), 300);
+                assert_eq!(lower.check_integrity(), Ok(()));
+                assert_eq!(upper.check_integrity(), Ok(()));
+                assert_sorted(&lower);
+                assert_sorted(&upper);
+            }
+
+            let lower = filled();
+            let upper = lower.split_off(b"");
+            assert_eq!(lower, Tree::default());
+            assert_eq!(upper, filled());
+        });
+    }
+
+    #[test]
+    fn test_clear() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in 0..100_u32 {
+                tree.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+            }
+
+            tree.clear();
+            for i in 0..100_u32 {
+                assert_eq!(tree.get(i.to_be_bytes()), None);
+            }
+
+            tree.put(b"key", b"value".into());
+            assert_eq!(tree.get(b"key"), Some(b"value".to_vec()));
+            assert_eq!(tree.get(0_u32.to_be_bytes()), None);
+        });
+    }
+
+    #[test]
+    fn test_clear_deep_tree() {
+        let tree = TSIMTree::new();
+        // The key is stored in a chain of tens of thousands of nodes, which must not be dropped recursively.
+        let long_key = vec![42; 256 * 1024];
+        tree.put(&long_key, b"value".into());
+        assert_eq!(tree.get(&long_key), Some(b"value".to_vec()));
+
+        tree.clear();
+        assert_eq!(tree.get(&long_key), None);
+    }
+
+    #[test]
+    fn test_drain() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            let mut expected = Vec::new();
+            for i in 0..1000_u32 {
+                tree.put((i * 7919 % 1000).to_be_bytes(), vec![]);
+                expected.push((i.to_be_bytes().to_vec(), vec![]));
+            }
+            tree.put(b"", b"empty".into());
+            expected.insert(0, (vec![], b"empty".to_vec()));
+
+            assert_eq!(tree.drain().collect::<Vec<_>>(), expected);
+            assert_eq!(tree.iter().count(), 0);
+            assert_eq!(count_nodes(&tree), 1);
+            assert_eq!(tree.check_integrity(), Ok(()));
+
+            tree.put(b"key", b"value".into());
+            assert_eq!(tree.get(b"key"), Some(b"value".to_vec()));
+        });
+    }
+
+    #[test]
+    fn test_drop_partially_consumed_drain() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in 0..100_u32 {
+                tree.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+            }
+
+            let mut drain = tree.drain();
+            assert_eq!(
+                drain.next(),
+                Some((0_u32.to_be_bytes().to_vec(), 0_u32.to_le_bytes().to_vec()))
+            );
+            drop(drain);
+
+            assert_eq!(tree.stats().entries, 0);
+            assert_eq!(tree.check_integrity(), Ok(()));
+            assert_eq!(tree.drain().next(), None);
+        });
+    }
+
+    #[test]
+    fn test_clone_is_a_snapshot() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in 0..100_u32 {
+                tree.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+            }
+
+            let snapshot = tree.clone();
+            for i in 0..50_u32 {
+                tree.put(i.to_be_bytes(), b"modified".into());
+                tree.remove((i + 50).to_be_bytes());
+            }
+            tree.put(b"new", b"value".into());
+
+            for i in 0..100_u32 {
+                assert_eq!(snapshot.get(i.to_be_bytes()), Some(i.to_le_bytes().to_vec()));
+            }
+            assert_eq!(snapshot.get(b"new"), None);
+            assert_eq!(tree.get(0_u32.to_be_bytes()), Some(b"modified".to_vec()));
+        });
+    }
+
+    #[test]
+    fn test_modifying_a_clone_leaves_the_original() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in 0..100_u32 {
+                tree.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+            }
+
+            let clone = tree.clone();
+            for i in 0..50_u32 {
+                clone.put(i.to_be_bytes(), b"modified".into());
+                clone.remove((i + 50).to_be_bytes());
+            }
+            clone.put(b"new", b"value".into());
+            assert_eq!(clone.stats().entries, 51);
+
+            assert_eq!(tree.stats().entries, 100);
+            for i in 0..100_u32 {
+                assert_eq!(tree.get(i.to_be_bytes()), Some(i.to_le_bytes().to_vec()));
+            }
+            assert_eq!(tree.get(b"new"), None);
+            assert_eq!(tree.check_integrity(), Ok(()));
+        });
+    }
+
+    #[test]
+    fn test_clone_deep_tree() {
+        let tree = TSIMTree::new();
+        let long_key = vec![42; 256 * 1024];
+        tree.put(&long_key, b"value".into());
+
+        let snapshot = tree.clone();
+        tree.remove(&long_key);
+        assert_eq!(snapshot.get(&long_key), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_equality_ignores_structure() {
+        for_each_layout!(Tree => {
+            let ascending = Tree::default();
+            let descending = Tree::default();
+            for i in 0..200_u32 {
+                ascending.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+                descending.put((199 - i).to_be_bytes(), (199 - i).to_le_bytes().to_vec());
+            }
+            assert_ne!(ascending.to_string(), descending.to_string());
+            assert_eq!(ascending, descending);
+            assert_eq!(ascending, ascending);
+
+            descending.put(0_u32.to_be_bytes(), b"modified".into());
+            assert_ne!(ascending, descending);
+
+            descending.put(0_u32.to_be_bytes(), 0_u32.to_le_bytes().to_vec());
+            descending.put(b"extra", vec![]);
+            assert_ne!(ascending, descending);
+            assert_ne!(descending, ascending);
+        });
+    }
+
+    #[test]
+    #[allow(clippy::mutable_key_type)]
+    fn test_ordering_matches_btreemap() {
+        use std::collections::BTreeSet;
+
+        for_each_layout!(Tree => {
+            let tree = |entries: &[(&str, &str)]| -> Tree {
+                entries
+                    .iter()
+                    .map(|(k, v)| (k.as_bytes(), v.as_bytes().to_vec()))
+                    .collect()
+            };
+            let empty = tree(&[]);
+            let a = tree(&[("a", "1")]);
+            let a_with_larger_value = tree(&[("a", "2")]);
+            let a_and_b = tree(&[("a", "1"), ("b", "1")]);
+            let b = tree(&[("b", "0")]);
+
+            // Keys are compared before values, a tree that is a prefix of another one is less.
+            assert!(empty < a);
+            assert!(a < a_with_larger_value);
+            assert!(a < a_and_b);
+            assert!(a_and_b < a_with_larger_value);
+            assert!(a_with_larger_value < b);
+            assert_eq!(a.cmp(&a), Ordering::Equal);
+            assert_eq!(a.cmp(&a.clone()), Ordering::Equal);
+
+            let sorted: Vec<_> = BTreeSet::from([b, a_and_b, a_with_larger_value, empty, a])
+                .into_iter()
+                .map(|tree| tree.into_iter().collect::<BTreeMap<_, _>>())
+                .collect();
+            let mut expected = sorted.clone();
+            expected.sort();
+            assert_eq!(sorted, expected);
+        });
+    }
+
+    #[test]
+    fn test_diff_yields_changed_keys_in_order() {
+        for_each_layout!(Tree => {
+            let old: Tree = [("a", "1"), ("ab", "2"), ("b", "3"), ("c", "4")]
+                .iter()
+                .map(|(k, v)| (k.as_bytes(), v.as_bytes().to_vec()))
+                .collect();
+            let new: Tree = [("", "0"), ("a", "1"), ("b", "5"), ("d", "6")]
+                .iter()
+                .map(|(k, v)| (k.as_bytes(), v.as_bytes().to_vec()))
+                .collect();
+
+            let entry = |s: &str| s.as_bytes().to_vec();
+            assert_eq!(
+                diff(&old, &new).collect::<Vec<_>>(),
+                vec![
+                    DiffEntry::Added(entry(""), entry("0")),
+                    DiffEntry::Removed(entry("ab"), entry("2")),
+                    DiffEntry::Modified(entry("b"), entry("3"), entry("5")),
+                    DiffEntry::Removed(entry("c"), entry("4")),
+                    DiffEntry::Added(entry("d"), entry("6")),
+                ]
+            );
+            assert_eq!(diff(&old, &old).count(), 0);
+            assert_eq!(diff(&old, &old.clone()).count(), 0);
+            assert_eq!(diff(&Tree::default(), &old).count(), 4);
+        });
+    }
+
+    #[test]
+    #[allow(clippy::mutable_key_type)]
+    fn test_hash_matches_equality() {
+        use std::collections::HashSet;
+        use std::hash::{BuildHasher, RandomState};
+
+        for_each_layout!(Tree => {
+            let ascending = Tree::default();
+            let descending = Tree::default();
+            for i in 0..200_u32 {
+                ascending.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+                descending.put((199 - i).to_be_bytes(), (199 - i).to_le_bytes().to_vec());
+            }
+            let hasher = RandomState::new();
+            assert_eq!(hasher.hash_one(&ascending), hasher.hash_one(&descending));
+
+            // Moving a byte from the key into the value must change the hash.
+            let moved_byte = Tree::default();
+            moved_byte.put(b"ab", b"c".into());
+            let unmoved_byte = Tree::default();
+            unmoved_byte.put(b"a", b"bc".into());
+            assert_ne!(hasher.hash_one(&moved_byte), hasher.hash_one(&unmoved_byte));
+
+            let trees: HashSet<Tree> = [ascending, descending, moved_byte, unmoved_byte]
+                .into_iter()
+                .collect();
+            assert_eq!(trees.len(), 3);
+        });
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let ascending = TSIMTree::new();
+        let descending = TSIMTree::<8, 64>::default();
+        for i in 0..200_u32 {
+            ascending.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+            descending.put((199 - i).to_be_bytes(), (199 - i).to_le_bytes().to_vec());
+        }
+        // Neither the insertion order nor the layout of the nodes affects the hash.
+        assert_eq!(ascending.content_hash(), descending.content_hash());
+        assert_ne!(ascending.content_hash(), TSIMTree::new().content_hash());
+
+        let hash = ascending.content_hash();
+        ascending.put(100_u32.to_be_bytes(), vec![0; 4]);
+        assert_ne!(ascending.content_hash(), hash);
+        ascending.put(100_u32.to_be_bytes(), 100_u32.to_le_bytes().to_vec());
+        assert_eq!(ascending.content_hash(), hash);
+
+        // Moving a byte from the key into the value must change the hash.
+        let moved_byte = TSIMTree::new();
+        moved_byte.put(b"ab", b"c".into());
+        let unmoved_byte = TSIMTree::new();
+        unmoved_byte.put(b"a", b"bc".into());
+        assert_ne!(moved_byte.content_hash(), unmoved_byte.content_hash());
+    }
+
+    #[test]
+    fn test_check_integrity_detects_faults() {
+        let tree = TSIMTree::new();
+        for key in ["a", "ab", "b", "c"] {
+            tree.put(key, vec![]);
+        }
+        assert_eq!(tree.check_integrity(), Ok(()));
+        assert_eq!(TSIMTree::new().check_integrity(), Ok(()));
+
+        let mut nodes = tree.root.read().clone();
+        let root = nodes.root;
+        nodes.arena[root].set_segment(2, b"0");
+        assert_eq!(
+            nodes.check_integrity(),
+            Err(TSIMTreeFault::UnsortedSegments { segment_idx: 2 })
+        );
+        nodes.arena[root].set_segment(2, b"c");
+
+        nodes.arena[root].children_count = 2;
+        assert_eq!(
+            nodes.check_integrity(),
+            Err(TSIMTreeFault::CountMismatch {
+                children_count: 2,
+                children: 3
+            })
+        );
+        nodes.arena[root].children_count = 3;
+        assert_eq!(nodes.check_integrity(), Ok(()));
+
+        let TSIMTreeNodeChild::Node(node) = *nodes.arena[root].child(0) else {
+            panic!("a and ab share a node");
+        };
+        nodes.arena[node].key_segments[0] = u8::MAX;
+        assert_eq!(
+            nodes.check_integrity(),
+            Err(TSIMTreeFault::InvalidSegment { len: u8::MAX })
+        );
+
+        nodes.arena[node].key_segments[0] = 0;
+        nodes.arena[node].remove_child(1);
+        nodes.arena[node].remove_child(0);
+        assert_eq!(nodes.check_integrity(), Err(TSIMTreeFault::EmptyNode));
+    }
+
+    #[test]
+    fn test_collect_and_extend() {
+        use std::collections::BTreeMap;
+
+        for_each_layout!(Tree => {
+            let map: BTreeMap<Vec<u8>, Vec<u8>> = (0..200_u32)
+                .map(|i| (i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec()))
+                .collect();
+
+            let tree: Tree = map.clone().into_iter().collect();
+            for (k, v) in &map {
+                assert_eq!(tree.get(k).as_ref(), Some(v));
+            }
+
+            // Extending through a shared reference overwrites existing keys like put.
+            (&tree).extend([
+                (b"extra".to_vec(), b"value".to_vec()),
+                (0_u32.to_be_bytes().to_vec(), vec![]),
+            ]);
+            assert_eq!(tree.get(b"extra"), Some(b"value".to_vec()));
+            assert_eq!(tree.get(0_u32.to_be_bytes()), Some(vec![]));
+
+            let mut tree = tree;
+            tree.extend(map.iter().map(|(k, v)| (k, v.clone())));
+            assert_eq!(tree.get(0_u32.to_be_bytes()), Some(0_u32.to_le_bytes().to_vec()));
+        });
+    }
+
+    #[test]
+    fn test_get_many() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in (0..200_u32).step_by(2) {
+                tree.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+            }
+
+            // Unsorted keys with duplicates and keys that are not stored.
+            let keys: Vec<[u8; 4]> = [7_u32, 4, 199, 0, 4, 150, 1000, 3]
+                .iter()
+                .map(|i| i.to_be_bytes())
+                .collect();
+
+            let individually: Vec<_> = keys.iter().map(|k| tree.get(k)).collect();
+            assert_eq!(tree.get_many(&keys), individually);
+            assert_eq!(individually[1], Some(4_u32.to_le_bytes().to_vec()));
+            assert_eq!(individually[0], None);
+
+            assert_eq!(tree.get_many(Vec::<Vec<u8>>::new()), vec![]);
+        });
+    }
+
+    /// Asserts that the segments of every node in the tree are sorted.
+    fn assert_sorted<const RADIX: usize, const LINE: usize>(tree: &TSIMTree<RADIX, LINE>) {
+        let node_guard = tree.root.read();
+        let mut nodes = vec![node_guard.root];
+        while let Some(node) = nodes.pop() {
+            let node = &node_guard.arena[node];
+            assert!(node.segments_are_sorted(), "Unsorted node: {node:?}");
+            for idx in 0..node.children_count as usize {
+                if let TSIMTreeNodeChild::Node(child) = node.child(idx) {
+                    nodes.push(*child);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_segments_stay_sorted() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            // Descending insertion always inserts in front of the existing segments.
+            for byte in (0..=u8::MAX).rev() {
+                tree.put([byte], vec![]);
+                tree.put([byte, byte], vec![]);
+                assert_sorted(&tree);
+            }
+            tree.put([], vec![]);
+            assert_sorted(&tree);
+        });
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "breaks the order of the segments")]
+    fn test_unsorted_insertion_is_detected() {
+        let mut node: TSIMTreeNode = TSIMTreeNode::empty();
+        node.insert_child(0, b"b", TSIMTreeNodeChild::Value(StoredValue::new(), None));
+        node.insert_child(1, b"a", TSIMTreeNodeChild::Value(StoredValue::new(), None));
+    }
+
+    #[test]
+    fn test_put_sorted() {
+        let value = || TSIMTreeNodeChild::Value(StoredValue::new(), None);
+        let mut node: TSIMTreeNode = TSIMTreeNode::empty();
+        // The first bytes are neither ascending nor descending, as 97 is odd they are all different.
+        for i in 0..TREE_RADIX as u8 {
+            let key = [i.wrapping_mul(97), i];
+            let (idx, remaining_key) = node.put_sorted(&key, value()).unwrap();
+            assert_eq!((node.get_segment(idx), remaining_key), (&key[..], &[][..]));
+            assert!(node.segments_are_sorted(), "Unsorted node: {node:?}");
+        }
+        // A full node rejects the key instead of panicking.
+        assert!(node.is_full());
+        assert_eq!(
+            node.put_sorted(&[1], value()),
+            Err(ResolvedChild::Vacant(1))
+        );
+
+        let mut node: TSIMTreeNode = TSIMTreeNode::empty();
+        for byte in [9, 3, 7, 1] {
+            assert!(node.put_sorted(&[byte], value()).is_ok());
+            assert!(node.segments_are_sorted(), "Unsorted node: {node:?}");
+        }
+        assert_eq!(node.put_sorted(&[], value()), Ok((0, &[][..])));
+        assert!(node.segments_are_sorted(), "Unsorted node: {node:?}");
+        let segments: Vec<&[u8]> = (0..node.children_count())
+            .map(|idx| node.get_segment(idx))
+            .collect();
+        assert_eq!(segments, [&[][..], &[1], &[3], &[7], &[9]]);
+
+        // A key longer than a segment is inserted with its start, the rest is returned.
+        let long_key = [20; 10];
+        let (idx, remaining_key) = node.put_sorted(&long_key, value()).unwrap();
+        assert_eq!(idx, 5);
+        assert_eq!(
+            node.get_segment(idx).len() + remaining_key.len(),
+            long_key.len()
+        );
+        assert!(node.segments_are_sorted(), "Unsorted node: {node:?}");
+
+        // Keys that share their first byte with a segment are rejected with their resolution.
+        assert_eq!(
+            node.put_sorted(&[7], value()),
+            Err(ResolvedChild::ExactMatch(3, &[]))
+        );
+        assert_eq!(
+            node.put_sorted(&[7, 1], value()),
+            Err(ResolvedChild::ExactMatch(3, &[1]))
+        );
+        assert_eq!(
+            node.put_sorted(&[20, 2], value()),
+            Err(ResolvedChild::PartialMatch { idx: 5, common: 1 })
+        );
+        assert_eq!(node.children_count(), 6);
+        assert!(node.segments_are_sorted(), "Unsorted node: {node:?}");
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot insert at 2 behind the 1 children of the node")]
+    fn test_insertion_behind_the_children_is_rejected() {
+        let mut node: TSIMTreeNode = TSIMTreeNode::empty();
+        node.insert_child(0, b"a", TSIMTreeNodeChild::Value(StoredValue::new(), None));
+        node.insert_child(2, b"b", TSIMTreeNodeChild::Value(StoredValue::new(), None));
+    }
+
+    /// Returns the nodes on the path to the key, starting with the root.
+    fn nodes_on_path(nodes: &TSIMTreeNodes, key: &[u8]) -> Vec<NodeId> {
+        let mut key = key;
+        let mut path = vec![nodes.root];
+        loop {
+            let node = &nodes.arena[*path.last().unwrap()];
+            let child_idx = match node.resolve_child(key) {
+                ResolvedChild::ExactMatch(child_idx, remaining_key) => {
+                    key = remaining_key;
+                    child_idx
+                }
+                ResolvedChild::InDomainOf(child_idx) => child_idx,
+                _ => return path,
+            };
+            match node.child(child_idx) {
+                TSIMTreeNodeChild::Node(child) => path.push(*child),
+                TSIMTreeNodeChild::Value(..) => return path,
+            }
+        }
+    }
+
+    #[test]
+    fn test_fallible_api() {
+        let tree = TSIMTree::new();
+        for i in 0..300_u32 {
+            tree.put(i.to_be_bytes(), vec![i as u8]);
+        }
+
+        for i in 0..310_u32 {
+            assert_eq!(tree.try_get(i.to_be_bytes()), Ok(tree.get(i.to_be_bytes())));
+            assert_eq!(
+                tree.get_checked(i.to_be_bytes()),
+                Ok(tree.get(i.to_be_bytes()))
+            );
+        }
+        assert_eq!(tree.try_put(b"key", vec![1]), Ok(()));
+        assert_eq!(tree.try_get(b"key"), Ok(Some(vec![1])));
+        assert_eq!(tree.try_remove(b"key"), Ok(Some(vec![1])));
+        assert_eq!(tree.try_remove(b"key"), Ok(None));
+        assert_eq!(tree.put_checked(b"key", vec![2]), Ok(()));
+        assert_eq!(tree.get_checked(b"key"), Ok(Some(vec![2])));
+        assert_eq!(tree.remove_checked(b"key"), Ok(Some(vec![2])));
+        assert_eq!(tree.remove_checked(b"key"), Ok(None));
+        assert_eq!(tree.check_integrity(), Ok(()));
+    }
+
+    #[test]
+    fn test_fallible_api_reports_corrupted_nodes() {
+        type Corruption = fn(&mut TSIMTreeNodes, &[NodeId]);
+        let key = 150_u32.to_be_bytes();
+        let corruptions: [(Corruption, TSIMTreeFault); 6] = [
+            (
+                |nodes, path| nodes.arena[path[0]].key_segments[0] = u8::MAX,
+                TSIMTreeFault::InvalidSegment { len: u8::MAX },
+            ),
+            (
+                |nodes, path| nodes.arena[path[0]].children_count += 1,
+                TSIMTreeFault::CountMismatch {
+                    children_count: 2,
+                    children: 1,
+                },
+            ),
+            (
+                |nodes, path| {
+                    let root = &mut nodes.arena[path[0]];
+                    let child = root.children[0].take();
+                    root.children.push(child);
+                },
+                TSIMTreeFault::ChildIsNone {
+                    child_idx: 0,
+                    children_count: 1,
+                },
+            ),
+            (
+                |nodes, path| {
+                    nodes.arena.remove(path[1]);
+                },
+                TSIMTreeFault::MissingNode { child_idx: 0 },
+            ),
+            (
+                |nodes, path| {
+                    let routing = path.iter().position(|&id| nodes.arena[id].routing).unwrap();
+                    let next = path[routing + 1];
+                    let node = &mut nodes.arena[path[routing]];
+                    let child_idx = node
+                        .children
+                        .iter()
+                        .position(|child| {
+                            matches!(child, Some(TSIMTreeNodeChild::Node(id)) if *id == next)
+                        })
+                        .unwrap();
+                    node.children[child_idx] =
+                        Some(TSIMTreeNodeChild::Value(StoredValue::new(), None));
+                },
+                // The routing node spreads the keys over leaves of 16 keys, 150 is in the tenth one.
+                TSIMTreeFault::ValueInRoutingNode { child_idx: 9 },
+            ),
+            (
+                |nodes, path| {
+                    let routing = path.iter().position(|&id| nodes.arena[id].routing).unwrap();
+                    nodes.arena[path[routing]].set_segment(0, &[0]);
+                },
+                TSIMTreeFault::UncoveredKeys,
+            ),
+        ];
+
+        for (corrupt, fault) in corruptions {
+            let tree = TSIMTree::new();
+            for i in 0..300_u32 {
+                tree.put(i.to_be_bytes(), vec![i as u8]);
+            }
+            {
+                let mut nodes = tree.root.write();
+                let path = nodes_on_path(&nodes, &key);
+                corrupt(&mut nodes, &path);
+            }
+
+            let error = TSIMTreeError::Fault(fault);
+            assert_eq!(tree.try_get(key), Err(error));
+            assert_eq!(tree.try_put(key, vec![]), Err(error));
+            assert_eq!(tree.try_remove(key), Err(error));
+            assert_eq!(tree.get_checked(key), Err(error));
+            assert_eq!(tree.put_checked(key, vec![]), Err(error));
+            assert_eq!(tree.remove_checked(key), Err(error));
+        }
+    }
+
+    #[cfg(all(feature = "std", not(feature = "parking_lot")))]
+    #[test]
+    fn test_fallible_api_reports_poisoned_lock() {
+        let tree = TSIMTree::new();
+        tree.put(b"key", vec![1]);
+        std::thread::scope(|s| {
+            let poisoner = s.spawn(|| {
+                let _value = tree.get_mut(b"key");
+                panic!("the write lock is poisoned");
+            });
+            assert!(poisoner.join().is_err());
+        });
+
+        assert_eq!(tree.try_put(b"key", vec![2]), Err(TSIMTreeError::Poisoned));
+        assert_eq!(tree.try_remove(b"key"), Err(TSIMTreeError::Poisoned));
+        assert_eq!(
+            tree.put_checked(b"key", vec![2]),
+            Err(TSIMTreeError::Poisoned)
+        );
+        assert_eq!(tree.remove_checked(b"key"), Err(TSIMTreeError::Poisoned));
+        // With the lockfree feature, readers do not lock and keep seeing the published root.
+        #[cfg(not(feature = "lockfree"))]
+        {
+            assert_eq!(tree.try_get(b"key"), Err(TSIMTreeError::Poisoned));
+            assert_eq!(tree.get_checked(b"key"), Err(TSIMTreeError::Poisoned));
+        }
+        #[cfg(feature = "lockfree")]
+        {
+            assert_eq!(tree.try_get(b"key"), Ok(Some(vec![1])));
+            assert_eq!(tree.get_checked(b"key"), Ok(Some(vec![1])));
+        }
+    }
+
+    #[cfg(feature = "parking_lot")]
+    #[test]
+    fn test_panicking_writer_does_not_poison_the_tree() {
+        let tree = TSIMTree::new();
+        tree.put(b"key", vec![1]);
+        std::thread::scope(|s| {
+            let panicking_writer = s.spawn(|| {
+                let mut value = tree.get_mut(b"key").unwrap();
+                value.push(2);
+                panic!("the writer panics while holding the write lock");
+            });
+            assert!(panicking_writer.join().is_err());
+        });
+
+        assert_eq!(tree.try_get(b"key"), Ok(Some(vec![1, 2])));
+        assert_eq!(tree.try_put(b"key", vec![3]), Ok(()));
+        assert_eq!(tree.get(b"key"), Some(vec![3]));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_checked_api_waits_for_a_writer() {
+        use std::sync::Barrier;
+
+        let tree = TSIMTree::new();
+        tree.put(b"key", vec![1]);
+        let locked = Barrier::new(2);
+        let released = Instant::now() + Duration::from_millis(50);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let mut value = tree.get_mut(b"key").unwrap();
+                locked.wait();
+                while Instant::now() < released {
+                    std::thread::yield_now();
+                }
+                value[0] = 2;
+            });
+            locked.wait();
+
+            assert_eq!(tree.put_checked(b"other", vec![3]), Ok(()));
+            assert!(Instant::now() >= released);
+            assert_eq!(tree.get_checked(b"key"), Ok(Some(vec![2])));
+            assert_eq!(tree.remove_checked(b"other"), Ok(Some(vec![3])));
+        });
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_fallible_api_does_not_wait_for_a_writer() {
+        use std::sync::Barrier;
+
+        let tree = TSIMTree::new();
+        tree.put(b"key", vec![1]);
+        let locked = Barrier::new(2);
+        let checked = Barrier::new(2);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let _value = tree.get_mut(b"key");
+                locked.wait();
+                checked.wait();
+            });
+            locked.wait();
+
+            let start = Instant::now();
+            // With the lockfree feature, readers do not lock and keep seeing the published root.
+            #[cfg(not(feature = "lockfree"))]
+            assert_eq!(tree.try_get(b"key"), Err(TSIMTreeError::WouldBlock));
+            #[cfg(feature = "lockfree")]
+            assert_eq!(tree.try_get(b"key"), Ok(Some(vec![1])));
+            assert_eq!(
+                tree.try_put(b"key", vec![2]),
+                Err(TSIMTreeError::WouldBlock)
+            );
+            assert_eq!(tree.try_remove(b"key"), Err(TSIMTreeError::WouldBlock));
+            #[cfg(feature = "parking_lot")]
+            {
+                let timeout = Duration::from_millis(20);
+                assert_eq!(
+                    tree.try_put_for(b"key", vec![2], timeout),
+                    Err(TSIMTreeError::WouldBlock)
+                );
+                #[cfg(not(feature = "lockfree"))]
+                assert_eq!(
+                    tree.try_get_for(b"key", timeout),
+                    Err(TSIMTreeError::WouldBlock)
+                );
+            }
+            assert!(start.elapsed() < Duration::from_secs(1));
+            checked.wait();
+        });
+
+        assert_eq!(tree.try_put(b"key", vec![2]), Ok(()));
+        assert_eq!(tree.try_get(b"key"), Ok(Some(vec![2])));
+        #[cfg(feature = "parking_lot")]
+        assert_eq!(
+            tree.try_get_for(b"key", Duration::from_millis(20)),
+            Ok(Some(vec![2]))
+        );
+    }
+
+    #[test]
+    fn test_capacity_evicts_least_recently_used() {
+        let tree = TSIMTree::with_capacity(3);
+        tree.put(b"a", vec![1]);
+        tree.put(b"b", vec![2]);
+        tree.put(b"c", vec![3]);
+        assert_eq!(tree.get(b"a"), Some(vec![1]));
+
+        // b is the least recently used key, as a was read after c was stored.
+        tree.put(b"d", vec![4]);
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [b"a", b"c", b"d"]);
+
+        // Removing a key frees its slot, storing an existing key again does not need one.
+        tree.remove(b"c");
+        tree.put(b"e", vec![5]);
+        tree.put(b"a", vec![6]);
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [b"a", b"d", b"e"]);
+
+        // The key that is read after every new key survives, the other keys are evicted.
+        for i in 0..100_u32 {
+            tree.put(i.to_be_bytes(), vec![]);
+            assert_eq!(tree.get(b"a"), Some(vec![6]));
+        }
+        let survivors = [
+            98_u32.to_be_bytes().to_vec(),
+            99_u32.to_be_bytes().to_vec(),
+            b"a".to_vec(),
+        ];
+        assert_eq!(tree.keys().collect::<Vec<_>>(), survivors);
+        assert_eq!(tree.check_integrity(), Ok(()));
+        assert_eq!(count_nodes(&tree), tree.root.read().arena.len());
+    }
+
+    #[test]
+    fn test_capacity_after_clear() {
+        let tree = TSIMTree::with_capacity(2);
+        tree.put(b"a", vec![1]);
+        tree.put(b"b", vec![2]);
+        tree.clear();
+
+        tree.put(b"c", vec![3]);
+        tree.put(b"d", vec![4]);
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [b"c", b"d"]);
+        assert_eq!(tree.pop_first(), Some((b"c".to_vec(), vec![3])));
+        tree.put(b"e", vec![5]);
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [b"d", b"e"]);
+
+        // Clones keep the capacity and the recency of their entries.
+        let clone = tree.clone();
+        clone.get(b"d");
+        clone.put(b"f", vec![6]);
+        assert_eq!(clone.keys().collect::<Vec<_>>(), [b"d", b"f"]);
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [b"d", b"e"]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_clear_forgets_keys_under_the_write_lock() {
+        let tree = TSIMTree::with_capacity(10_000);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..2000_u32 {
+                    tree.put(i.to_be_bytes(), vec![]);
+                }
+            });
+            for _ in 0..200 {
+                tree.clear();
+            }
+        });
+
+        // Every tracked key is stored, so no key that was cleared counts towards the capacity.
+        let tracked = tree.recency.as_ref().unwrap().lock().len();
+        assert_eq!(tracked, tree.iter().count());
+    }
+
+    #[test]
+    fn test_reads_do_not_track_removed_keys() {
+        let tree = TSIMTree::with_capacity(2);
+        tree.put([1], vec![1]);
+        tree.put([2], vec![2]);
+
+        // A read that raced with the removal refreshes the key after the writer forgot it.
+        tree.remove([1]);
+        tree.refresh(&[1]);
+        tree.refresh(&[2]);
+        let recency = tree.recency.as_ref().unwrap().lock();
+        assert_eq!((recency.len(), recency.value_bytes()), (1, 1));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_concurrent_reads_do_not_track_removed_keys() {
+        let tree = TSIMTree::with_capacity(10_000);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..200 {
+                    for i in 0..100_u32 {
+                        tree.put(i.to_be_bytes(), vec![0; 4]);
+                    }
+                    for i in 0..100_u32 {
+                        tree.remove(i.to_be_bytes());
+                    }
+                }
+            });
+            for _ in 0..200 {
+                for i in 0..100_u32 {
+                    tree.get(i.to_be_bytes());
+                }
+            }
+        });
+
+        let recency = tree.recency.as_ref().unwrap().lock();
+        assert_eq!((recency.len(), recency.value_bytes()), (0, 0));
+    }
+
+    #[test]
+    fn test_capacity_keeps_live_keys_after_bulk_removals() {
+        let filled_tree = || {
+            let tree = TSIMTree::with_capacity(3);
+            for i in 0..3_u8 {
+                tree.put([i], vec![i]);
+            }
+            tree
+        };
+
+        // The keys moved out of the tree no longer count towards the capacity, so refilling it evicts nothing.
+        let tree = filled_tree();
+        tree.split_off([1]);
+        tree.put([9], vec![9]);
+        tree.put([8], vec![8]);
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [[0], [8], [9]]);
+
+        let tree = filled_tree();
+        tree.split_off_prefix([2]);
+        tree.put([9], vec![9]);
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [[0], [1], [9]]);
+
+        let tree = filled_tree();
+        tree.split_at_prefix([1]);
+        for i in 5..8_u8 {
+            tree.put([i], vec![i]);
+        }
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [[5], [6], [7]]);
+
+        let tree = filled_tree();
+        tree.retain(|key, _| key == [1]);
+        tree.put([9], vec![9]);
+        tree.put([8], vec![8]);
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [[1], [8], [9]]);
+
+        // Expired entries that a bulk operation drops are forgotten as well.
+        #[cfg(feature = "std")]
+        {
+            let tree = filled_tree();
+            tree.put_with_ttl([3], vec![3], Duration::ZERO);
+            tree.retain(|key, _| key != [0]);
+            tree.put([9], vec![9]);
+            assert_eq!(tree.keys().collect::<Vec<_>>(), [[1], [2], [9]]);
+        }
+    }
+
+    #[test]
+    fn test_capacity_bounds_merged_and_extended_entries() {
+        let tree = TSIMTree::with_capacity(3);
+        (&tree).extend((0..100_u8).map(|i| ([i], vec![i])));
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [[97], [98], [99]]);
+
+        let other = TSIMTree::new();
+        for i in 0..10_u8 {
+            other.put([i], vec![i]);
+        }
+        tree.merge(&other, |_, _, other_value| other_value.to_vec());
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [[7], [8], [9]]);
+
+        // The merged keys are tracked, so a new key evicts the least recently merged one.
+        tree.put([50], vec![]);
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [[8], [9], [50]]);
+
+        let empty = TSIMTree::with_capacity(3);
+        empty.merge_owned(other.clone(), |_, value, _| value);
+        assert_eq!(empty.keys().collect::<Vec<_>>(), [[7], [8], [9]]);
+        tree.merge_owned(other, |_, value, _| value);
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [[7], [8], [9]]);
+        assert_eq!(tree.check_integrity(), Ok(()));
+    }
+
+    #[test]
+    fn test_capacity_limit_by_value_bytes() {
+        let tree = TSIMTree::with_capacity_limit(CacheLimit::ValueBytes(100));
+        tree.put(b"a", vec![1; 40]);
+        tree.put(b"b", vec![2; 40]);
+        assert_eq!(tree.get(b"a").map(|value| value.len()), Some(40));
+
+        // b is the least recently used key and makes room for c.
+        tree.put(b"c", vec![3; 40]);
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [b"a", b"c"]);
+
+        // Overwriting a value counts its new length, shrinking it makes room for d.
+        tree.put(b"a", vec![1; 10]);
+        tree.put(b"d", vec![4; 50]);
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [b"a", b"c", b"d"]);
+
+        // A value longer than the limit does not fit next to any other value, nor on its own.
+        tree.put(b"e", vec![5; 101]);
+        assert_eq!(tree.iter().count(), 0);
+
+        for i in 0..1000_u32 {
+            tree.put(i.to_be_bytes(), vec![0; i as usize % 30]);
+            let value_bytes: usize = tree.iter().map(|(_, value)| value.len()).sum();
+            assert!(value_bytes <= 100, "{value_bytes} bytes are stored");
+        }
+        assert_eq!(tree.check_integrity(), Ok(()));
+    }
+
+    #[test]
+    fn test_capacity_limit_by_value_bytes_counts_modified_values() {
+        let stored_bytes = |tree: &TSIMTree| -> usize {
+            let value_bytes: usize = tree.iter().map(|(_, value)| value.len()).sum();
+            let recency = tree.recency.as_ref().unwrap().lock();
+            assert_eq!(recency.value_bytes(), value_bytes);
+            value_bytes
+        };
+        let tree = TSIMTree::with_capacity_limit(CacheLimit::ValueBytes(100));
+        for i in 0..5_u8 {
+            tree.put([i], vec![i; 10]);
+        }
+
+        // Growing a value in place counts its new length once the guard is dropped.
+        tree.get_mut([4]).unwrap().resize(80, 4);
+        assert_eq!(stored_bytes(&tree), 100);
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [[2], [3], [4]]);
+        // A value that outgrows the limit evicts itself as well.
+        tree.get_mut([4]).unwrap().extend([4; 50]);
+        assert_eq!(stored_bytes(&tree), 0);
+
+        for i in 0..5_u8 {
+            tree.put([i], vec![i; 10]);
+        }
+        tree.get_mut([0]).unwrap().truncate(1);
+        assert_eq!(stored_bytes(&tree), 41);
+
+        // Transforming the values touches them in key order, so the largest keys remain.
+        tree.transform_values(|value| value.resize(40, 0));
+        assert_eq!(stored_bytes(&tree), 80);
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [[3], [4]]);
+        tree.transform_values(|value| value.resize(400, 0));
+        assert_eq!(stored_bytes(&tree), 0);
+        assert_eq!(tree.check_integrity(), Ok(()));
+    }
+
+    #[test]
+    fn test_on_evict_receives_evicted_entries() {
+        use std::sync::Mutex;
+
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let tree = TSIMTree::with_capacity(2).on_evict({
+            let evicted = evicted.clone();
+            move |key, value| evicted.lock().unwrap().push((key, value))
+        });
+        tree.put(b"a", vec![1]);
+        tree.put(b"b", vec![2]);
+        tree.get(b"a");
+        tree.put(b"c", vec![3]);
+        tree.put(b"d", vec![4]);
+        // Removed entries are not evicted.
+        tree.remove(b"d");
+        tree.put(b"e", vec![5]);
+        assert_eq!(
+            *evicted.lock().unwrap(),
+            [(b"b".to_vec(), vec![2]), (b"a".to_vec(), vec![1])]
+        );
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [b"c", b"e"]);
+
+        // Clones share the hook.
+        let clone = tree.clone();
+        clone.put(b"f", vec![6]);
+        assert_eq!(evicted.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_get_with() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in 0..300_u32 {
+                tree.put(i.to_be_bytes(), vec![i as u8; i as usize]);
+            }
+
+            let checksum = |value: &[u8]| {
+                value
+                    .iter()
+                    .fold(0_u32, |sum, &byte| sum.wrapping_mul(31).wrapping_add(u32::from(byte)))
+            };
+            for i in 0..310_u32 {
+                let key = i.to_be_bytes();
+                assert_eq!(tree.get_with(key, <[u8]>::len), tree.get(key).map(|v| v.len()));
+                assert_eq!(tree.get_with(key, checksum), tree.get(key).map(|v| checksum(&v)));
+            }
+        });
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_get_with_removes_expired_entries() {
+        let tree = TSIMTree::new();
+        tree.put_with_ttl(b"expired", vec![1], Duration::ZERO);
+
+        assert_eq!(
+            tree.get_with(b"expired", |_| panic!("the value is expired")),
+            None::<()>
+        );
+        assert_eq!(count_nodes(&tree), 1);
+    }
+
+    #[test]
+    fn test_get_mut() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in 0..300_u32 {
+                tree.put(i.to_be_bytes(), vec![i as u8]);
+            }
+
+            assert!(tree.get_mut(300_u32.to_be_bytes()).is_none());
+            {
+                let mut value = tree.get_mut(150_u32.to_be_bytes()).unwrap();
+                assert_eq!(*value, vec![150]);
+                value.push(1);
+                value.extend_from_slice(b"patch");
+            }
+            assert_eq!(tree.get(150_u32.to_be_bytes()), Some(b"\x96\x01patch".to_vec()));
+            assert_eq!(tree.get(149_u32.to_be_bytes()), Some(vec![149]));
+            assert_sorted(&tree);
+        });
+    }
+
+    /// Every mutator takes `&self`, so two closures can write through the same shared reference at once
+    /// and the tree can be shared behind an `Arc` without a `Mutex`.
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_mutators_take_a_shared_reference() {
+        let tree = TSIMTree::new();
+        let shared: &TSIMTree = &tree;
+        let write_evens = || {
+            for i in (0..1000_u32).step_by(2) {
+                shared.put(i.to_be_bytes(), vec![0]);
+                shared.get_mut(i.to_be_bytes()).unwrap()[0] = 2;
+                assert!(!shared.insert_if_absent(i.to_be_bytes(), vec![1]));
+            }
+        };
+        let write_odds = || {
+            for i in (1..1000_u32).step_by(2) {
+                shared.get_or_insert_with(i.to_be_bytes(), || vec![0]);
+                assert_eq!(
+                    shared.compare_and_swap(i.to_be_bytes(), Some(&[0]), vec![1]),
+                    Ok(())
+                );
+                shared.put_with_ttl(i.to_be_bytes(), vec![1], Duration::from_secs(60));
+            }
+        };
+        std::thread::scope(|s| {
+            s.spawn(write_evens);
+            s.spawn(write_odds);
+        });
+
+        shared.transform_values(|value| value[0] += 1);
+        shared.retain(|key, _| key[3] % 4 < 2);
+        assert_eq!(shared.remove(0_u32.to_be_bytes()), Some(vec![3]));
+        assert_eq!(
+            shared.pop_last(),
+            Some((997_u32.to_be_bytes().to_vec(), vec![2]))
+        );
+        assert_eq!(shared.iter().count(), 498);
+        shared.clear();
+        assert_eq!(tree.iter().count(), 0);
+
+        let tree = Arc::new(tree);
+        let writers: Vec<_> = (0..2_u8)
+            .map(|writer| {
+                let tree = Arc::clone(&tree);
+                std::thread::spawn(move || tree.put([writer], vec![writer]))
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(tree.get([1]), Some(vec![1]));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_get_mut_blocks_writers() {
+        let tree = TSIMTree::new();
+        tree.put(b"counter", vec![0]);
+
+        std::thread::scope(|s| {
+            let mut value = tree.get_mut(b"counter").unwrap();
+            let writer = s.spawn(|| tree.put(b"counter", vec![2]));
+            std::thread::sleep(Duration::from_millis(50));
+            // The writer has to wait until the guard is dropped, so it overwrites the modified value.
+            value[0] = 1;
+            drop(value);
+            writer.join().unwrap();
+        });
+        assert_eq!(tree.get(b"counter"), Some(vec![2]));
+
+        tree.put_with_ttl(b"expired", vec![1], Duration::ZERO);
+        assert!(tree.get_mut(b"expired").is_none());
+        assert_eq!(count_nodes(&tree), tree.root.read().arena.len());
+    }
+
+    #[test]
+    fn test_transform_values() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in 0..1000_u32 {
+                tree.put(i.to_be_bytes(), vec![(i % 100) as u8, 42]);
+            }
+
+            tree.transform_values(|value| value[0] *= 2);
+
+            for i in 0..1000_u32 {
+                assert_eq!(
+                    tree.get(i.to_be_bytes()),
+                    Some(vec![(i % 100) as u8 * 2, 42])
+                );
+            }
+
+            // The values are visited in ascending key order.
+            let mut visited = Vec::new();
+            tree.transform_values(|value| visited.push(value.clone()));
+            assert_eq!(visited, tree.values().collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn test_map_values() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in 0..1000_u32 {
+                tree.put(format!("key{i}"), vec![42; i as usize % 7]);
+            }
+            let old = tree.clone();
+
+            // Prefixes every value with its length.
+            let mapped = tree.map_values(|_, value| [&[value.len() as u8][..], value].concat());
+
+            assert!(mapped.keys().eq(tree.keys()));
+            for (key, value) in tree.iter() {
+                assert_eq!(mapped.get(&key), Some([&[value.len() as u8][..], &value].concat()));
+            }
+            assert_eq!(tree, old);
+            assert_eq!(mapped.check_integrity(), Ok(()));
+
+            let mut visited = Vec::new();
+            let keys = tree.map_values(|key, _| {
+                visited.push(key.to_vec());
+                key.to_vec()
+            });
+            assert!(keys.values().eq(tree.keys()));
+            assert!(visited.into_iter().eq(tree.keys()));
+        });
+    }
+
+    #[test]
+    fn test_encode_format() {
+        type Tree = TSIMTree;
+        let tree = Tree::new();
+        tree.put(b"ab", vec![1]);
+        tree.put(b"abc", vec![2, 3]);
+        tree.put(b"b", vec![]);
+
+        #[rustfmt::skip]
+        let expected = vec![
+            b'T', b'S', b'I', b'M', 1,
+            0, 2, b'a', b'b', 1, 1,
+            2, 1, b'c', 2, 2, 3,
+            0, 1, b'b', 0,
+        ];
+        assert_eq!(tree.encode(), expected);
+        assert_eq!(Tree::decode(&expected), Ok(tree));
+        assert_eq!(Tree::new().encode(), b"TSIM\x01");
+    }
+
+    #[test]
+    fn test_decode_rejects_invalid_buffers() {
+        type Tree = TSIMTree;
+        type Error = TSIMTreeDecodeError;
+        assert_eq!(Tree::decode(b""), Err(Error::InvalidMagic));
+        assert_eq!(Tree::decode(b"TSIX\x01"), Err(Error::InvalidMagic));
+        assert_eq!(Tree::decode(b"TSIM"), Err(Error::UnexpectedEnd));
+        assert_eq!(
+            Tree::decode(b"TSIM\x02"),
+            Err(Error::UnsupportedVersion { version: 2 })
+        );
+        assert_eq!(
+            Tree::decode(b"TSIM\x01\x00\x02a"),
+            Err(Error::UnexpectedEnd)
+        );
+        assert_eq!(
+            Tree::decode(b"TSIM\x01\x00\x01a\x05ab"),
+            Err(Error::UnexpectedEnd)
+        );
+        assert_eq!(
+            Tree::decode(b"TSIM\x01\x01\x01a\x00"),
+            Err(Error::InvalidSharedPrefix { shared: 1 })
+        );
+        assert_eq!(
+            Tree::decode(b"TSIM\x01\x00\x01b\x00\x00\x01a\x00"),
+            Err(Error::UnsortedKeys)
+        );
+        assert_eq!(
+            Tree::decode(b"TSIM\x01\x00\x01a\x00\x01\x00\x00"),
+            Err(Error::UnsortedKeys)
+        );
+        let mut too_long = b"TSIM\x01\x00".to_vec();
+        too_long.extend([0xff; 10]);
+        too_long.push(0x7f);
+        assert_eq!(Tree::decode(&too_long), Err(Error::InvalidVarint));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_encode_skips_expired_entries() {
+        type Tree = TSIMTree;
+        let tree = Tree::new();
+        tree.put(b"kept", vec![1]);
+        tree.put_with_ttl(b"expired", vec![2], Duration::ZERO);
+        tree.put_with_ttl(b"expiring", vec![3], Duration::from_secs(3600));
+
+        let decoded = Tree::decode(&tree.encode()).unwrap();
+        assert_eq!(
+            decoded.iter().collect::<Vec<_>>(),
+            vec![(b"expiring".to_vec(), vec![3]), (b"kept".to_vec(), vec![1])]
+        );
+    }
+
+    #[test]
+    fn test_retain_even_length_keys() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for len in 0..20 {
+                tree.put(vec![b'k'; len], vec![len as u8]);
+            }
+
+            tree.retain(|key, _| key.len() % 2 == 0);
+
+            for len in 0..20 {
+                let expected = (len % 2 == 0).then(|| vec![len as u8]);
+                assert_eq!(tree.get(vec![b'k'; len]), expected);
+            }
+        });
+    }
+
+    #[test]
+    fn test_retain_values_above_threshold() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for byte in 0..=u8::MAX {
+                tree.put([byte, byte], vec![byte]);
+            }
+
+            tree.retain(|_, value| value[0] > 100);
+
+            for byte in 0..=u8::MAX {
+                let expected = (byte > 100).then(|| vec![byte]);
+                assert_eq!(tree.get([byte, byte]), expected);
+            }
+        });
+    }
+
+    #[test]
+    fn test_first_and_last_entries() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            assert_eq!(tree.first_key_value(), None);
+            assert_eq!(tree.last_key_value(), None);
+            assert_eq!(tree.pop_first(), None);
+            assert_eq!(tree.pop_last(), None);
+
+            // "b" and "ba" are stored below an interior node, "" is stored at the root.
+            for key in ["a", "b", "ba", ""] {
+                tree.put(key, key.into());
+            }
+            let entry = |key: &str| Some((key.as_bytes().to_vec(), key.as_bytes().to_vec()));
+            assert_eq!(tree.first_key_value(), entry(""));
+            assert_eq!(tree.last_key_value(), entry("ba"));
+
+            assert_eq!(tree.pop_first(), entry(""));
+            assert_eq!(tree.pop_last(), entry("ba"));
+            assert_eq!(tree.first_key_value(), entry("a"));
+            assert_eq!(tree.last_key_value(), entry("b"));
+            assert_eq!(tree.check_integrity(), Ok(()));
+
+            assert_eq!(tree.pop_last(), entry("b"));
+            assert_eq!(tree.pop_last(), entry("a"));
+            assert_eq!(tree.pop_last(), None);
+            assert_eq!(count_nodes(&tree), 1);
+        });
+    }
+
+    #[test]
+    fn test_shortest_and_longest_key() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            assert_eq!(tree.shortest_key(), None);
+            assert_eq!(tree.longest_key(), None);
+
+            tree.put(b"only", vec![]);
+            assert_eq!(tree.shortest_key(), Some(b"only".to_vec()));
+            assert_eq!(tree.longest_key(), Some(b"only".to_vec()));
+
+            // The keys are ordered like slices, so a long key can be the smallest and a short one the largest.
+            for key in [&b"aaaaaaaaaaaaaaaaaaaa"[..], b"z", b"m", b"zz\x00", b"b"] {
+                tree.put(key, vec![]);
+            }
+            assert_eq!(tree.shortest_key(), Some(b"aaaaaaaaaaaaaaaaaaaa".to_vec()));
+            assert_eq!(tree.longest_key(), Some(b"zz\x00".to_vec()));
+
+            tree.remove(b"zz\x00");
+            assert_eq!(tree.longest_key(), Some(b"z".to_vec()));
+        });
+    }
+
+    #[test]
+    fn test_successor_and_predecessor() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            assert_eq!(tree.successor(b"a"), None);
+            assert_eq!(tree.predecessor(b"a"), None);
+
+            for key in ["", "b", "ba", "bab", "bc", "d"] {
+                tree.put(key, key.into());
+            }
+            let entry = |key: &str| Some((key.as_bytes().to_vec(), key.as_bytes().to_vec()));
+
+            // Stored keys are skipped, unlike by get_next and get_prev.
+            assert_eq!(tree.successor(b"ba"), entry("bab"));
+            assert_eq!(tree.predecessor(b"ba"), entry("b"));
+            assert_eq!(tree.successor(b""), entry("b"));
+            assert_eq!(tree.predecessor(b""), None);
+            assert_eq!(tree.predecessor(b"b"), entry(""));
+            assert_eq!(tree.successor(b"d"), None);
+            assert_eq!(tree.predecessor(b"d"), entry("bc"));
+
+            // Keys that are not stored behave like for get_next and get_prev.
+            assert_eq!(tree.successor(b"baa"), entry("bab"));
+            assert_eq!(tree.predecessor(b"babz"), entry("bab"));
+
+            // A range is walked by passing the returned keys back in, in both directions.
+            let mut forward = Vec::new();
+            let mut key = vec![];
+            while let Some((next, _)) = tree.successor(&key) {
+                forward.push(next.clone());
+                key = next;
+            }
+            let mut backward = Vec::new();
+            let mut key = b"z".to_vec();
+            while let Some((prev, _)) = tree.predecessor(&key) {
+                backward.push(prev.clone());
+                key = prev;
+            }
+            backward.reverse();
+            assert_eq!(forward, tree.keys().skip(1).collect::<Vec<_>>());
+            assert_eq!(backward, tree.keys().collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn test_get_next_and_prev() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            assert_eq!(tree.get_next(b"a"), None);
+            assert_eq!(tree.get_prev(b"a"), None);
+
+            for key in ["b", "ba", "bab", "bc", "d"] {
+                tree.put(key, key.into());
+            }
+            let entry = |key: &str| Some((key.as_bytes().to_vec(), key.as_bytes().to_vec()));
+
+            // Below the smallest and above the largest key.
+            assert_eq!(tree.get_next(b""), entry("b"));
+            assert_eq!(tree.get_prev(b""), None);
+            assert_eq!(tree.get_next(b"e"), None);
+            assert_eq!(tree.get_prev(b"e"), entry("d"));
+
+            // Stored keys are their own neighbours.
+            assert_eq!(tree.get_next(b"ba"), entry("ba"));
+            assert_eq!(tree.get_prev(b"ba"), entry("ba"));
+
+            // The query ends inside a segment or continues below a stored key.
+            assert_eq!(tree.get_next(b"baa"), entry("bab"));
+            assert_eq!(tree.get_prev(b"baa"), entry("ba"));
+            assert_eq!(tree.get_next(b"babz"), entry("bc"));
+            assert_eq!(tree.get_prev(b"babz"), entry("bab"));
+            assert_eq!(tree.get_next(b"bb"), entry("bc"));
+            assert_eq!(tree.get_prev(b"bb"), entry("bab"));
+            assert_eq!(tree.get_next(b"c"), entry("d"));
+            assert_eq!(tree.get_prev(b"c"), entry("bc"));
+
+            // Expired entries are skipped.
+            #[cfg(feature = "std")]
+            {
+                tree.put_with_ttl(b"bc", b"expired".into(), Duration::ZERO);
+                assert_eq!(tree.get_next(b"bc"), entry("d"));
+                assert_eq!(tree.get_prev(b"c"), entry("bab"));
+            }
+        });
+    }
+
+    #[test]
+    fn test_cursor() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            // Enough keys for the narrow layout to spread them over several nodes.
+            for i in 0..100_u32 {
+                tree.put(format!("{i:03}"), i.to_be_bytes().to_vec());
+            }
+            let entry = |i: u32| Some((format!("{i:03}").into_bytes(), i.to_be_bytes().to_vec()));
+
+            // The cursor starts in front of the smallest key that is not less than the key.
+            let mut cursor = tree.cursor_at("0495");
+            assert_eq!(cursor.next(), entry(50));
+            assert_eq!(cursor.prev(), entry(50));
+            assert_eq!(cursor.prev(), entry(49));
+            assert_eq!(cursor.next(), entry(49));
+            assert_eq!(cursor.next(), entry(50));
+
+            // Stepping forward and then backward visits the same entries in reverse order.
+            let mut cursor = tree.cursor_at("010");
+            let forward: Vec<_> = (0..30).map_while(|_| cursor.next()).collect();
+            let mut backward: Vec<_> = (0..30).map_while(|_| cursor.prev()).collect();
+            backward.reverse();
+            assert_eq!(forward, backward);
+            assert_eq!(forward, (10..40).map_while(entry).collect::<Vec<_>>());
+
+            // The cursor can step past both ends and come back.
+            let mut cursor = tree.cursor_at("");
+            assert_eq!(cursor.prev(), None);
+            assert_eq!(cursor.next(), entry(0));
+            let mut cursor = tree.cursor_at("1");
+            assert_eq!(cursor.next(), None);
+            assert_eq!(cursor.prev(), entry(99));
+        });
+    }
+
+    #[test]
+    fn test_cursor_visits_prefixes_of_keys() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for key in ["b", "ba", "bab", "bc", "d"] {
+                tree.put(key, key.into());
+            }
+            let entry = |key: &str| Some((key.as_bytes().to_vec(), key.as_bytes().to_vec()));
+
+            let mut cursor = tree.cursor_at("ba");
+            assert_eq!(cursor.prev(), entry("b"));
+            assert_eq!(cursor.next(), entry("b"));
+            assert_eq!(cursor.next(), entry("ba"));
+            assert_eq!(cursor.next(), entry("bab"));
+            assert_eq!(cursor.next(), entry("bc"));
+            assert_eq!(cursor.prev(), entry("bc"));
+            assert_eq!(cursor.prev(), entry("bab"));
+            assert_eq!(cursor.prev(), entry("ba"));
+            assert_eq!(cursor.prev(), entry("b"));
+            assert_eq!(cursor.prev(), None);
+
+            // Expired entries are skipped.
+            #[cfg(feature = "std")]
+            {
+                drop(cursor);
+                tree.put_with_ttl(b"bab", b"expired".into(), Duration::ZERO);
+                let mut cursor = tree.cursor_at("bab");
+                assert_eq!(cursor.next(), entry("bc"));
+                assert_eq!(cursor.prev(), entry("bc"));
+                assert_eq!(cursor.prev(), entry("ba"));
+            }
+        });
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_first_and_last_skip_expired_entries() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            tree.put_with_ttl(b"a", b"expired".into(), Duration::ZERO);
+            tree.put(b"b", b"kept".into());
+            tree.put_with_ttl(b"c", b"expired".into(), Duration::ZERO);
+
+            assert_eq!(tree.first_key_value(), Some((b"b".to_vec(), b"kept".to_vec())));
+            assert_eq!(tree.last_key_value(), Some((b"b".to_vec(), b"kept".to_vec())));
+            assert_eq!(tree.pop_first(), Some((b"b".to_vec(), b"kept".to_vec())));
+            assert_eq!(tree.pop_last(), None);
+        });
+    }
+
+    #[test]
+    fn test_pop_in_key_order() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in 0..1000_u32 {
+                tree.put((i * 7919 % 1000).to_be_bytes(), i.to_le_bytes().to_vec());
+            }
+
+            let mut keys = Vec::new();
+            while let Some((key, _)) = tree.pop_first() {
+                keys.push(key);
+                if let Some((key, _)) = tree.pop_last() {
+                    keys.push(key);
+                }
+            }
+
+            let mut expected = Vec::new();
+            for i in 0..500_u32 {
+                expected.push(i.to_be_bytes().to_vec());
+                expected.push((999 - i).to_be_bytes().to_vec());
+            }
+            assert_eq!(keys, expected);
+            assert_eq!(tree.check_integrity(), Ok(()));
+            assert_eq!(count_nodes(&tree), 1);
+        });
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_ttl_expires_at_the_deadline() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            tree.put_with_ttl(b"expired", b"value".into(), Duration::ZERO);
+            tree.put_with_ttl(b"short", b"value".into(), Duration::from_millis(200));
+            tree.put_with_ttl(b"long", b"value".into(), Duration::from_secs(3600));
+            tree.put(b"forever", b"value".into());
+
+            assert_eq!(tree.get(b"expired"), None);
+            assert_eq!(tree.get(b"short"), Some(b"value".to_vec()));
+
+            std::thread::sleep(Duration::from_millis(250));
+            assert_eq!(tree.get(b"short"), None);
+            assert_eq!(tree.get(b"long"), Some(b"value".to_vec()));
+            assert_eq!(tree.get(b"forever"), Some(b"value".to_vec()));
+        });
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_expired_keys_are_removed_lazily() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in 0..100_u8 {
+                tree.put_with_ttl([i], vec![i], Duration::ZERO);
+            }
+            tree.put(b"kept", b"value".into());
+            assert_eq!(tree.stats().entries, 101);
+
+            // Expired entries are hidden from every read, but they stay in the tree until they are accessed.
+            assert_eq!(tree.iter().count(), 1);
+            assert_eq!(tree.keys().collect::<Vec<_>>(), vec![b"kept".to_vec()]);
+            assert_eq!(tree.values().count(), 1);
+            assert_eq!(tree.stats().entries, 101);
+
+            assert_eq!(tree.get([0]), None);
+            assert_eq!(tree.remove([1]), None);
+            assert_eq!(tree.stats().entries, 99);
+
+            assert_eq!(tree.tick(), 98);
+            assert_eq!(tree.tick(), 0);
+            tree.put_with_ttl(b"late", vec![], Duration::ZERO);
+            assert_eq!(tree.purge_expired(), 1);
+            assert_eq!(tree.stats().entries, 1);
+            assert_eq!(tree.check_integrity(), Ok(()));
+            assert_eq!(
+                tree.into_iter().collect::<Vec<_>>(),
+                vec![(b"kept".to_vec(), b"value".to_vec())]
+            );
+        });
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_storing_an_expired_key_again() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            tree.put_with_ttl(b"put", b"old".into(), Duration::ZERO);
+            tree.put_with_ttl(b"insert", b"old".into(), Duration::ZERO);
+            tree.put_with_ttl(b"kept", b"old".into(), Duration::from_secs(3600));
+
+            // A plain put removes the time to live.
+            tree.put(b"put", b"new".into());
+            tree.put(b"kept", b"new".into());
+            assert_eq!(tree.tick(), 1);
+            assert_eq!(tree.get(b"put"), Some(b"new".to_vec()));
+            assert_eq!(tree.get(b"kept"), Some(b"new".to_vec()));
+
+            // Expired values are replaced like missing ones.
+            tree.put_with_ttl(b"insert", b"old".into(), Duration::ZERO);
+            assert_eq!(tree.get_or_insert_with(b"insert", || b"new".into()), b"new");
+            assert_eq!(tree.tick(), 0);
+            assert_eq!(tree.get(b"insert"), Some(b"new".to_vec()));
+        });
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_expiry_thread() {
+        let tree = Arc::new(TSIMTree::new());
+        for i in 0..10_u8 {
+            tree.put_with_ttl([i], vec![i], Duration::from_millis(10));
+        }
+        tree.put(b"kept", b"value".into());
+
+        let expiry_thread = TSIMTree::spawn_expiry_thread(&tree, Duration::from_millis(20));
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(tree.stats().entries, 1);
 
-        let first_child = node.children[0].clone().expect("All children are Some");
-        let last_child = node.children[TREE_RADIX - 1]
-            .clone()
-            .expect("All children are Some");
+        // The thread stops once the tree is dropped.
+        drop(tree);
+        expiry_thread.join().expect("expiry thread panicked");
+    }
 
-        let v = vec![];
-        let empty_slice: &[u8] = v.as_slice();
+    #[test]
+    fn test_retain_prunes_empty_nodes() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in 0..1000_u32 {
+                tree.put(i.to_be_bytes(), vec![]);
+            }
+            assert!(tree.stats().routing_nodes > 0);
 
-        // Since the keys are stored with +1 offset, if we search for 0, there is None, if we search for 1 we get the first element, at idx 0.
-        assert_eq!(
-            node.resolve_child(vec![first_key - 1].as_slice()),
-            ResolvedChild::Smallest
-        );
+            tree.retain(|key, _| key[3] == 0);
+            assert_eq!(tree.stats().entries, 4);
+            assert_eq!(tree.root.read().arena.len(), count_nodes(&tree));
+            assert_eq!(tree.check_integrity(), Ok(()));
 
+            tree.retain(|_, _| false);
+            assert_eq!(tree.iter().next(), None);
+            assert_eq!(count_nodes(&tree), 1);
+            assert_eq!(tree.root.read().arena.len(), 1);
+
+            tree.put(b"key", b"value".into());
+            assert_eq!(tree.get(b"key"), Some(b"value".to_vec()));
+        });
+    }
+
+    #[cfg(feature = "lockfree")]
+    #[test]
+    fn test_reads_do_not_block_during_writes() {
+        let tree = TSIMTree::new();
+        tree.put(b"key", b"old".into());
+
+        let mut node_guard = tree.root.write();
+        node_guard.put(b"key", b"new".into());
+
+        // Readers keep seeing the published root until the writer is done.
+        std::thread::scope(|s| {
+            let reader = s.spawn(|| tree.get(b"key"));
+            assert_eq!(
+                reader.join().expect("reader panicked"),
+                Some(b"old".to_vec())
+            );
+        });
+
+        drop(node_guard);
+        assert_eq!(tree.get(b"key"), Some(b"new".to_vec()));
+    }
+
+    #[cfg(feature = "lockfree")]
+    #[test]
+    fn test_concurrent_reads_during_writes() {
+        let tree = TSIMTree::new();
+
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for i in 0..200_u32 {
+                        // Every published root either stores the key with its value or not at all.
+                        if let Some(v) = tree.get(i.to_be_bytes()) {
+                            assert_eq!(v, i.to_le_bytes());
+                        }
+                    }
+                });
+            }
+            for i in 0..200_u32 {
+                tree.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+            }
+        });
+
+        for i in 0..200_u32 {
+            assert_eq!(tree.get(i.to_be_bytes()), Some(i.to_le_bytes().to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_snapshots_stay_frozen_during_writes() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+
+            std::thread::scope(|s| {
+                for _ in 0..4 {
+                    s.spawn(|| {
+                        for _ in 0..50 {
+                            let snapshot = tree.snapshot();
+                            let entries: Vec<_> = snapshot.iter().collect();
+                            // The writer stores the keys in ascending order, so every snapshot holds a prefix of them.
+                            for (i, (key, value)) in (0_u32..).zip(&entries) {
+                                assert_eq!(key, &i.to_be_bytes());
+                                assert_eq!(*value, i.to_le_bytes());
+                            }
+
+                            let copy = snapshot.clone();
+                            std::thread::yield_now();
+                            assert_eq!(copy.iter().count(), entries.len());
+                            assert_eq!(snapshot.get((entries.len() as u32).to_be_bytes()), None);
+                        }
+                    });
+                }
+                for i in 0..500_u32 {
+                    tree.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+                }
+            });
+
+            let snapshot = tree.snapshot();
+            tree.clear();
+            assert_eq!(snapshot.iter().count(), 500);
+            let key = 42_u32.to_be_bytes();
+            assert_eq!(snapshot.get(key), Some(42_u32.to_le_bytes().as_slice()));
+            assert_eq!(tree.get(key), None);
+        });
+    }
+
+    #[test]
+    fn test_snapshot_iter_ignores_concurrent_writes() {
+        fn assert_send_static<T: Send + 'static>(_: &T) {}
+
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in 0..1000_u32 {
+                tree.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+            }
+            let expected: Vec<_> = tree.iter().collect();
+
+            let mut entries = tree.snapshot_iter();
+            assert_send_static(&entries);
+            let mut yielded: Vec<_> = entries.by_ref().take(10).collect();
+
+            std::thread::scope(|s| {
+                s.spawn(|| {
+                    for i in 0..1000_u32 {
+                        if i % 2 == 0 {
+                            tree.remove(i.to_be_bytes());
+                        } else {
+                            tree.put(i.to_be_bytes(), b"new".to_vec());
+                        }
+                        tree.put((1000 + i).to_be_bytes(), b"added".to_vec());
+                    }
+                });
+                // The iterator holds no lock, so the writer is not blocked while it is consumed.
+                for entry in entries.by_ref() {
+                    yielded.push(entry);
+                    std::thread::yield_now();
+                }
+            });
+
+            assert_eq!(yielded, expected);
+            assert_eq!(tree.snapshot_iter().count(), 1500);
+        });
+    }
+
+    #[test]
+    fn test_custom_traversal_from_root_node() {
+        fn lookup(tree: &TSIMTree, mut key: &[u8]) -> Option<Vec<u8>> {
+            let root = tree.root_node();
+            let mut node = root.node();
+            loop {
+                let (idx, remaining_key) = match node.resolve_child(key) {
+                    ResolvedChild::ExactMatch(idx, remaining_key) => (idx, remaining_key),
+                    ResolvedChild::InDomainOf(idx) => (idx, key),
+                    ResolvedChild::Vacant(_) | ResolvedChild::PartialMatch { .. } => return None,
+                };
+                match node.child(idx) {
+                    TSIMTreeChildRef::Node(child) => node = child,
+                    TSIMTreeChildRef::Value(value) if remaining_key.is_empty() => {
+                        return Some(value.to_vec())
+                    }
+                    TSIMTreeChildRef::Value(_) => return None,
+                }
+                key = remaining_key;
+            }
+        }
+
+        let tree = TSIMTree::new();
+        for i in 0..2000_u32 {
+            tree.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+        }
+        tree.put(b"", b"empty".to_vec());
+        assert!(tree.stats().routing_nodes > 0);
+
+        for i in 0..2000_u32 {
+            assert_eq!(
+                lookup(&tree, &i.to_be_bytes()),
+                Some(i.to_le_bytes().to_vec())
+            );
+        }
+        assert_eq!(lookup(&tree, b""), Some(b"empty".to_vec()));
+        assert_eq!(lookup(&tree, &2000_u32.to_be_bytes()), None);
+        assert_eq!(lookup(&tree, &[0, 0]), None);
+        assert_eq!(lookup(&tree, b"x"), None);
+    }
+
+    #[test]
+    fn test_transactional_batch_is_all_or_nothing() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            tree.put(b"b", b"old".to_vec());
+
+            let batch = |keys: &[&str]| -> Vec<_> {
+                keys.iter()
+                    .map(|k| (k.to_string(), k.as_bytes().to_vec()))
+                    .collect()
+            };
+            assert_eq!(
+                tree.put_batch_transactional(batch(&["a", "b", "c"])),
+                Err(TransactionError::KeyExists { key: b"b".to_vec() })
+            );
+            assert_eq!(
+                tree.put_batch_transactional(batch(&["c", "", "a", ""])),
+                Err(TransactionError::DuplicateKey { key: b"".to_vec() })
+            );
+            assert_eq!(tree.iter().collect::<Vec<_>>(), vec![(b"b".to_vec(), b"old".to_vec())]);
+            assert_eq!(tree.check_integrity(), Ok(()));
+
+            let keys: Vec<String> = (0..500).map(|i| format!("key{i}")).collect();
+            let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+            assert_eq!(tree.put_batch_transactional(batch(&keys)), Ok(()));
+            assert_eq!(tree.put_batch_transactional(batch(&["a", "", "c"])), Ok(()));
+            assert_eq!(tree.iter().count(), 504);
+            assert_eq!(tree.get(b"b"), Some(b"old".to_vec()));
+            assert_eq!(tree.get(b"key499"), Some(b"key499".to_vec()));
+            assert_eq!(tree.get(b""), Some(b"".to_vec()));
+            assert_eq!(tree.put_batch_transactional(Vec::<(&[u8], Vec<u8>)>::new()), Ok(()));
+        });
+    }
+
+    #[test]
+    fn test_compare_and_swap_only_replaces_the_expected_value() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            assert_eq!(tree.compare_and_swap(b"", None, b"1".to_vec()), Ok(()));
+            assert_eq!(tree.compare_and_swap(b"", Some(b"1"), b"2".to_vec()), Ok(()));
+            assert_eq!(tree.get(b""), Some(b"2".to_vec()));
+
+            // The key is expected to be absent, but is present.
+            assert_eq!(tree.compare_and_swap(b"", None, b"3".to_vec()), Err(Some(b"2".to_vec())));
+            // The key is expected to hold another value.
+            assert_eq!(tree.compare_and_swap(b"", Some(b"1"), b"3".to_vec()), Err(Some(b"2".to_vec())));
+            // The key is expected to be present, but is absent.
+            assert_eq!(tree.compare_and_swap(b"a", Some(b"2"), b"3".to_vec()), Err(None));
+            assert_eq!(tree.get(b""), Some(b"2".to_vec()));
+            assert_eq!(tree.get(b"a"), None);
+        });
+    }
+
+    #[test]
+    fn test_compare_and_swap_retry_loop_loses_no_increment() {
+        let tree = TSIMTree::new();
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        loop {
+                            let current = tree.get(b"counter");
+                            let count = match &current {
+                                Some(value) => u32::from_le_bytes(value[..].try_into().unwrap()),
+                                None => 0,
+                            };
+                            let new = (count + 1).to_le_bytes().to_vec();
+                            let swapped = tree.compare_and_swap(b"counter", current.as_deref(), new);
+                            if swapped.is_ok() {
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(tree.get(b"counter"), Some(400_u32.to_le_bytes().to_vec()));
+    }
+
+    #[test]
+    fn test_put_if_version() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            assert_eq!(tree.get_with_version(b"key"), None);
+            tree.put(b"key", b"1".to_vec());
+            let (value, version) = tree.get_with_version(b"key").unwrap();
+            assert_eq!(value, b"1");
+
+            assert!(tree.put_if_version(b"key", b"2".to_vec(), version));
+            // The successful write moved the version on.
+            assert!(!tree.put_if_version(b"key", b"3".to_vec(), version));
+            assert_eq!(tree.get(b"key"), Some(b"2".to_vec()));
+
+            // Writes to other keys and writes that change nothing count as well.
+            let (_, version) = tree.get_with_version(b"key").unwrap();
+            assert_eq!(tree.remove(b"missing"), None);
+            assert!(!tree.put_if_version(b"key", b"3".to_vec(), version));
+            let (_, version) = tree.get_with_version(b"key").unwrap();
+            tree.put(b"other", b"1".to_vec());
+            assert!(!tree.put_if_version(b"key", b"3".to_vec(), version));
+
+            // Reads leave the version alone, and a new key can be stored at the current version.
+            let (_, version) = tree.get_with_version(b"key").unwrap();
+            assert_eq!(tree.get(b"other"), Some(b"1".to_vec()));
+            assert!(tree.put_if_version(b"new", b"1".to_vec(), version));
+            assert_eq!(tree.get(b"new"), Some(b"1".to_vec()));
+        });
+    }
+
+    #[test]
+    fn test_version_never_returns_to_an_earlier_value() {
+        let tree = TSIMTree::new();
+        tree.put(b"key", b"1".to_vec());
+        let (_, version) = tree.get_with_version(b"key").unwrap();
+
+        // Replacing the nodes as a whole keeps counting.
+        tree.clear();
+        tree.merge_owned(TSIMTree::new(), |_, value, _| value);
+        let _ = tree.split_off_prefix(b"");
+        tree.put(b"key", b"1".to_vec());
+        let (_, new_version) = tree.get_with_version(b"key").unwrap();
+        assert!(new_version > version + 1);
+        assert!(!tree.put_if_version(b"key", b"2".to_vec(), version));
+        assert!(!tree.put_if_version(b"key", b"2".to_vec(), version + 1));
+    }
+
+    #[test]
+    fn test_put_if_version_retry_loop_loses_no_increment() {
+        let tree = TSIMTree::new();
+        tree.put(b"counter", 0_u32.to_le_bytes().to_vec());
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        loop {
+                            let (value, version) = tree.get_with_version(b"counter").unwrap();
+                            let count = u32::from_le_bytes(value[..].try_into().unwrap());
+                            let new = (count + 1).to_le_bytes().to_vec();
+                            if tree.put_if_version(b"counter", new, version) {
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(tree.get(b"counter"), Some(400_u32.to_le_bytes().to_vec()));
+    }
+
+    #[test]
+    fn test_increment() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            assert_eq!(tree.increment(b"fresh", 5), 5);
+            assert_eq!(tree.get(b"fresh"), Some(5_i64.to_le_bytes().to_vec()));
+            assert_eq!(tree.increment(b"fresh", 5), 10);
+            assert_eq!(tree.increment(b"fresh", -15), -5);
+            assert_eq!(tree.increment(b"fresh", 0), -5);
+            assert_eq!(tree.increment(b"negative", -1), -1);
+            assert_eq!(tree.increment(b"negative", i64::MIN), i64::MAX);
+
+            // A value of another length is not a counter, and the tree is not poisoned by the panic.
+            tree.put(b"text", b"not a counter".to_vec());
+            let increment = std::panic::AssertUnwindSafe(|| tree.increment(b"text", 1));
+            assert!(std::panic::catch_unwind(increment).is_err());
+            assert_eq!(tree.get(b"text"), Some(b"not a counter".to_vec()));
+            assert_eq!(tree.increment(b"fresh", 1), -4);
+        });
+    }
+
+    #[test]
+    fn test_concurrent_increments_are_not_lost() {
+        let tree = TSIMTree::new();
+        std::thread::scope(|s| {
+            for delta in [1, 2, 3, -1] {
+                let tree = &tree;
+                s.spawn(move || {
+                    for _ in 0..1000 {
+                        tree.increment(b"counter", delta);
+                    }
+                });
+            }
+        });
+        assert_eq!(tree.increment(b"counter", 0), 5000);
+    }
+
+    #[test]
+    fn test_insert_if_absent_keeps_existing_values() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            assert!(tree.insert_if_absent(b"", b"empty".to_vec()));
+            assert!(!tree.insert_if_absent(b"", b"other".to_vec()));
+            assert!(tree.insert_if_absent(b"ab", b"1".to_vec()));
+            assert!(!tree.insert_if_absent(b"ab", b"2".to_vec()));
+            // Keys that extend or are a prefix of an existing key are absent.
+            assert!(tree.insert_if_absent(b"abc", b"3".to_vec()));
+            assert!(tree.insert_if_absent(b"a", b"4".to_vec()));
+
+            assert_eq!(tree.get(b""), Some(b"empty".to_vec()));
+            assert_eq!(tree.get(b"ab"), Some(b"1".to_vec()));
+            assert_eq!(tree.get(b"abc"), Some(b"3".to_vec()));
+            assert_eq!(tree.get(b"a"), Some(b"4".to_vec()));
+
+            tree.remove(b"");
+            assert!(tree.insert_if_absent(b"", b"again".to_vec()));
+            assert_eq!(tree.get(b""), Some(b"again".to_vec()));
+        });
+    }
+
+    #[test]
+    fn test_insert_if_absent_lets_one_thread_claim_a_key() {
+        let tree = TSIMTree::new();
+        let claims: usize = std::thread::scope(|s| {
+            let threads: Vec<_> = (0..8_u8)
+                .map(|i| {
+                    let tree = &tree;
+                    s.spawn(move || {
+                        (0..100_u32)
+                            .filter(|key| tree.insert_if_absent(key.to_be_bytes(), vec![i]))
+                            .count()
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).sum()
+        });
+        assert_eq!(claims, 100);
+        assert_eq!(tree.iter().count(), 100);
+    }
+
+    #[test]
+    fn test_read_guard_borrows_values_until_dropped() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in 0..200_u32 {
+                tree.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+            }
+
+            std::thread::scope(|s| {
+                let guard = tree.read();
+                let first = guard.get(0_u32.to_be_bytes()).unwrap();
+                let last = guard.get(199_u32.to_be_bytes()).unwrap();
+                assert_eq!(guard.get(200_u32.to_be_bytes()), None);
+
+                // The writer waits for the guard, or with lockfree modifies a copy the guard does not see.
+                let writer = s.spawn(|| {
+                    tree.put(0_u32.to_be_bytes(), b"new".to_vec());
+                    tree.remove(199_u32.to_be_bytes());
+                });
+                std::thread::yield_now();
+
+                assert_eq!(first, 0_u32.to_le_bytes());
+                assert_eq!(last, 199_u32.to_le_bytes());
+                assert_eq!(guard.iter().count(), 200);
+                assert!((&guard).into_iter().all(|(key, value)| guard.get(key) == Some(value)));
+                drop(guard);
+
+                writer.join().unwrap();
+            });
+
+            assert_eq!(tree.get(0_u32.to_be_bytes()), Some(b"new".to_vec()));
+            assert_eq!(tree.read().get(199_u32.to_be_bytes()), None);
+        });
+    }
+
+    #[cfg(feature = "lockfree")]
+    #[test]
+    fn test_writes_only_copy_the_modified_nodes() {
+        let tree = TSIMTree::new();
+        for i in 0..1000_u32 {
+            tree.put(i.to_be_bytes(), vec![]);
+        }
+
+        let snapshot = tree.snapshot();
+        tree.put(1000_u32.to_be_bytes(), vec![]);
+        let node_guard = tree.root.read();
+        let mut nodes = vec![node_guard.root];
+        let mut copied_nodes = 0;
+        while let Some(id) = nodes.pop() {
+            let node = &node_guard.arena[id];
+            let old_node = snapshot.nodes.arena.get(id);
+            if !old_node.is_some_and(|old_node| core::ptr::eq(node, old_node)) {
+                copied_nodes += 1;
+            }
+            for idx in 0..node.children_count as usize {
+                if let TSIMTreeNodeChild::Node(child) = node.child(idx) {
+                    nodes.push(*child);
+                }
+            }
+        }
+        assert!(copied_nodes >= 1);
+        assert!(copied_nodes <= node_guard.stats().depth);
+
+        // The slots of the copied nodes are found through a chunk per level, the other chunks stay shared.
+        let levels = node_guard.arena.slots().ilog(32) as usize + 1;
+        let copied_chunks = node_guard.arena.unshared_chunks(&snapshot.nodes.arena);
+        assert!(copied_chunks <= (copied_nodes + 1) * levels);
+    }
+
+    #[test]
+    fn test_integer_key_methods() {
+        for_each_layout!(Tree => {
+            let tree = Tree::default();
+            for i in [0_u32, 1, 255, 256, 65_536, u32::MAX] {
+                tree.put_u32(i, i.to_le_bytes().to_vec());
+            }
+            assert_eq!(tree.get_u32(256), Some(256_u32.to_le_bytes().to_vec()));
+            assert_eq!(tree.get_u32(257), None);
+            // The key is stored in big-endian order, so it can be read through the byte interface as well.
+            assert_eq!(tree.get(42_u64.to_be_bytes()), None);
+            let value = 65_536_u32.to_le_bytes().to_vec();
+            assert_eq!(tree.get(65_536_u32.to_be_bytes()), Some(value));
+            let values: Vec<_> = tree.values().collect();
+            let sorted = [0_u32, 1, 255, 256, 65_536, u32::MAX];
+            assert_eq!(values, sorted.map(|i| i.to_le_bytes().to_vec()));
+
+            // Negative numbers sort before positive ones.
+            let tree = Tree::default();
+            for i in [i64::MAX, 1, 0, -1, -256, i64::MIN] {
+                tree.put_i64(i, i.to_le_bytes().to_vec());
+            }
+            assert_eq!(tree.get_i64(-256), Some((-256_i64).to_le_bytes().to_vec()));
+            let keys: Vec<_> = tree.keys().map(|key| i64::decode_key(&key)).collect();
+            assert_eq!(keys, [i64::MIN, -256, -1, 0, 1, i64::MAX]);
+
+            let tree = Tree::default();
+            tree.put_u8(7, b"u8".to_vec());
+            tree.put_u16(7, b"u16".to_vec());
+            tree.put_u64(7, b"u64".to_vec());
+            tree.put_i32(-7, b"i32".to_vec());
+            assert_eq!(tree.get_u8(7), Some(b"u8".to_vec()));
+            assert_eq!(tree.get_u16(7), Some(b"u16".to_vec()));
+            assert_eq!(tree.get_u64(7), Some(b"u64".to_vec()));
+            assert_eq!(tree.get_i32(-7), Some(b"i32".to_vec()));
+            assert_eq!(tree.get_i32(7), None);
+        });
+    }
+
+    #[test]
+    fn test_typed_keys_round_trip_and_keep_their_order() {
+        fn assert_encoding_keeps_order<K: TSIMTreeKey + Ord + Debug + Clone>(mut keys: Vec<K>) {
+            keys.sort();
+            let tree = TypedTSIMTree::<K>::new();
+            for (i, key) in keys.iter().enumerate() {
+                assert_eq!(K::decode_key(&key.encode_key()), *key);
+                tree.put(key.clone(), vec![i as u8]);
+            }
+            keys.dedup();
+            assert_eq!(tree.iter().map(|(key, _)| key).collect::<Vec<_>>(), keys);
+        }
+
+        assert_encoding_keeps_order(vec![0_u64, 1, 255, 256, u64::MAX]);
+        assert_encoding_keeps_order(vec![0_u32, 7, u32::MAX]);
+        assert_encoding_keeps_order(vec![i64::MIN, -256, -1, 0, 1, 255, i64::MAX]);
+        assert_encoding_keeps_order(vec![[0_u8, 1], [1, 0], [255, 255]]);
+        assert_encoding_keeps_order(vec![
+            String::new(),
+            "a".to_string(),
+            "a\0".to_string(),
+            "ab".to_string(),
+            "grüße".to_string(),
+        ]);
+        assert_encoding_keeps_order(vec![(1_u32, -1_i64), (1, 1), (0, i64::MAX)]);
+        // A shorter string field sorts before a longer one starting with it, even if more fields follow.
+        assert_encoding_keeps_order(vec![
+            ("a".to_string(), 9_u8),
+            ("a\0".to_string(), 0),
+            ("ab".to_string(), 0),
+            (String::new(), 255),
+        ]);
+        assert_encoding_keeps_order(vec![
+            (1_u8, "x".to_string(), -5_i32),
+            (1, "x".to_string(), 5),
+            (0, "y".to_string(), 0),
+        ]);
+        assert_encoding_keeps_order(vec![
+            (1_u16, vec![0_u8, 0], [7_u8; 3], "z".to_string()),
+            (1, vec![0], [7; 3], "z".to_string()),
+            (1, vec![], [8; 3], "a".to_string()),
+        ]);
+
+        let tree = TypedTSIMTree::new();
+        tree.put(42_u64, b"answer".to_vec());
+        assert_eq!(tree.get(42_u64), Some(b"answer".to_vec()));
         assert_eq!(
-            node.resolve_child(vec![first_key].as_slice()),
-            ResolvedChild::ExactMatch(0, empty_slice)
-        );
-        // looking for the last key and beyond, we return the last child
-        assert_eq!(
-            node.resolve_child(vec![last_key - 1].as_slice()),
-            ResolvedChild::ExactMatch(TREE_RADIX - 1, empty_slice)
-        );
-        assert_eq!(
-            node.resolve_child(vec![last_key].as_slice()),
-            ResolvedChild::InDomainOf(TREE_RADIX - 1)
+            tree.as_bytes_tree().get(42_u64.to_be_bytes()),
+            Some(b"answer".to_vec())
         );
+        assert_eq!(tree.remove(42_u64), Some(b"answer".to_vec()));
+        assert_eq!(tree.get(42_u64), None);
     }
 
     #[test]
-    fn test_basic_insert_and_get() {
-        let tree = TSIMTree::new();
-        dbg!(&tree).put(b"key1", b"val1".into());
-        dbg!(&tree).put(b"key2", b"val2".into());
+    #[cfg(feature = "thread-local-cache")]
+    fn test_cached_tree_sees_writes_of_other_threads() {
+        use std::sync::Barrier;
 
-        assert_eq!(dbg!(&tree).get(b"key1"), Some(b"val1".to_vec()));
-        assert_eq!(tree.get(b"key2"), Some(b"val2".to_vec()));
-    }
+        let tree = CachedTSIMTree::new(16);
+        tree.put(b"key", b"old".into());
+        let cached = Barrier::new(2);
+        let written = Barrier::new(2);
 
-    #[test]
-    fn test_overwrite_value() {
-        let tree = TSIMTree::new();
-        tree.put(b"key", b"first".into());
-        tree.put(b"key", b"second".into());
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..2 {
+                    assert_eq!(tree.get(b"key"), Some(b"old".to_vec()));
+                    assert_eq!(tree.get(b"missing"), None);
+                }
+                cached.wait();
+                written.wait();
+                // Both results are cached by this thread, but the writes outdated them.
+                assert_eq!(tree.get(b"key"), Some(b"new".to_vec()));
+                assert_eq!(tree.get(b"missing"), Some(b"added".to_vec()));
+            });
 
-        assert_eq!(tree.get(b"key"), Some(b"second".to_vec()));
+            cached.wait();
+            tree.put(b"key", b"new".into());
+            tree.put(b"missing", b"added".into());
+            written.wait();
+        });
+
+        assert_eq!(tree.get(b"key"), Some(b"new".to_vec()));
+        assert_eq!(tree.remove(b"key"), Some(b"new".to_vec()));
+        assert_eq!(tree.get(b"key"), None);
     }
 
     #[test]
-    fn test_missing_key() {
-        let tree = TSIMTree::new();
-        tree.put(b"key", b"value".into());
+    #[cfg(feature = "thread-local-cache")]
+    fn test_cached_trees_do_not_share_values() {
+        let first = CachedTSIMTree::new(16);
+        let second = CachedTSIMTree::new(16);
+        first.put(b"key", b"first".into());
+        second.put(b"key", b"second".into());
+        for _ in 0..2 {
+            assert_eq!(first.get(b"key"), Some(b"first".to_vec()));
+            assert_eq!(second.get(b"key"), Some(b"second".to_vec()));
+        }
 
-        assert_eq!(tree.get(b"other"), None);
+        let uncached = CachedTSIMTree::new(0);
+        uncached.put(b"key", b"uncached".into());
+        assert_eq!(uncached.get(b"key"), Some(b"uncached".to_vec()));
+        assert_eq!(
+            uncached.into_inner().get(b"key"),
+            Some(b"uncached".to_vec())
+        );
     }
 
     #[test]
-    fn test_multiple_sizes() {
-        let tree = TSIMTree::new();
-        tree.put(b"k", b"1".into());
-        tree.put(b"key", b"v".into());
-        tree.put(b"", b"empty".into());
-        tree.put(b"a", b"A".into());
+    #[cfg(feature = "thread-local-cache")]
+    fn test_thread_cache_evicts_the_least_recently_used_key() {
+        let mut cache = cached::ThreadCache::new();
+        cache.claim(0, 0, 2);
+        cache.insert(b"a", Some(b"1".to_vec()));
+        cache.insert(b"b", None);
+        assert_eq!(cache.get(b"a"), Some(&Some(b"1".to_vec())));
 
-        assert_eq!(tree.get(b""), Some(b"empty".to_vec()));
-        assert_eq!(tree.get(b"k"), Some(b"1".to_vec()));
-        assert_eq!(tree.get(b"a"), Some(b"A".to_vec()));
-        assert_eq!(tree.get(b"key"), Some(b"v".to_vec()));
+        cache.insert(b"c", Some(b"3".to_vec()));
+        assert_eq!(cache.get(b"b"), None);
+        assert_eq!(cache.get(b"a"), Some(&Some(b"1".to_vec())));
+        cache.insert(b"b", None);
+        assert_eq!(cache.get(b"c"), None);
+        assert_eq!(cache.get(b"b"), Some(&None));
+
+        // The results of another generation of the tree are dropped.
+        cache.claim(0, 1, 2);
+        assert_eq!(cache.get(b"a"), None);
+        assert_eq!(cache.get(b"b"), None);
     }
 
     #[test]
-    fn test_key_byte_equality() {
-        let tree = TSIMTree::new();
-
-        // Two keys with same content but different Vec allocations
-        let k1: Vec<u8> = b"identical".into();
-        let k2: Vec<u8> = b"identical".into();
-        let v: Vec<u8> = b"value".into();
-
-        tree.put(&k1, v.clone());
-        assert_eq!(tree.get(&k2), Some(v));
+    fn test_sharded_tree_rounds_shards_to_power_of_two() {
+        assert_eq!(ShardedTSIMTree::with_shards(0).shards(), 1);
+        assert_eq!(ShardedTSIMTree::with_shards(5).shards(), 8);
+        assert_eq!(ShardedTSIMTree::with_shards(8).shards(), 8);
     }
 
-    // #[test]
-    // fn test_concurrent_inserts_and_gets() {
-    //     let tree = Arc::new(TSIMTree::new());
-    //     let num_threads = 8;
-    //     let num_keys = 100;
+    #[test]
+    fn test_sharded_tree_merges_shards_in_key_order() {
+        let tree = ShardedTSIMTree::with_shards(4);
+        let mut ref_map = BTreeMap::new();
+        assert!(tree.is_empty());
 
-    //     // Spawn threads for concurrent puts
-    //     let mut handles = vec![];
-    //     for tid in 0..num_threads {
-    //         let t_clone = Arc::clone(&tree);
-    //         handles.push(thread::spawn(move || {
-    //             for i in 0..num_keys {
-    //                 let k = format!("k{}_{}", tid, i).into_bytes();
-    //                 let v = format!("v{}_{}", tid, i).into_bytes();
-    //                 t_clone.put(k, v);
-    //             }
-    //         }));
-    //     }
-    //     // Wait for all insertions
-    //     for h in handles {
-    //         h.join().expect("thread panicked");
-    //     }
+        std::thread::scope(|s| {
+            for writer in 0..4_u32 {
+                let tree = &tree;
+                s.spawn(move || {
+                    for i in (writer..400).step_by(4) {
+                        tree.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+                    }
+                });
+            }
+        });
+        for i in 0..400_u32 {
+            ref_map.insert(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
+        }
 
-    //     // Concurrent gets
-    //     let mut handles = vec![];
-    //     for tid in 0..num_threads {
-    //         let t_clone = Arc::clone(&tree);
-    //         handles.push(thread::spawn(move || {
-    //             for i in 0..num_keys {
-    //                 let k = format!("k{}_{}", tid, i).into_bytes();
-    //                 let expected = format!("v{}_{}", tid, i).into_bytes();
-    //                 assert_eq!(t_clone.get(&k), Some(expected.as_slice()));
-    //             }
-    //         }));
-    //     }
-    //     for h in handles {
-    //         h.join().expect("thread panicked");
-    //     }
-    // }
+        assert_eq!(tree.len(), 400);
+        assert_eq!(tree.iter().collect::<BTreeMap<_, _>>(), ref_map);
 
-    #[test]
-    fn test_keys_with_null_bytes() {
-        let tree = TSIMTree::new();
-        tree.put(&b"key\0with\0nulls"[..], b"value".into());
-        assert_eq!(tree.get(&b"key\0with\0nulls"[..]), Some(b"value".to_vec()));
+        for i in (0..400_u32).step_by(2) {
+            assert_eq!(
+                tree.remove(i.to_be_bytes()),
+                ref_map.remove(&i.to_be_bytes()[..])
+            );
+        }
+        assert_eq!(
+            tree.get(1_u32.to_be_bytes()),
+            Some(1_u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(tree.get(2_u32.to_be_bytes()), None);
+        assert_eq!(tree.len(), 200);
+        assert_eq!(
+            tree.iter().collect::<Vec<_>>(),
+            ref_map.into_iter().collect::<Vec<_>>()
+        );
     }
 
     use proptest::prelude::*;
-    use std::collections::HashMap;
+    use std::collections::{BTreeMap, HashMap};
+
+    #[derive(Debug, Clone)]
+    enum Operation {
+        Put(Vec<u8>, Vec<u8>),
+        Remove(Vec<u8>),
+        SplitOffPrefix(Vec<u8>),
+        SplitAtPrefix(Vec<u8>),
+    }
+
+    /// Keys are drawn from a small alphabet, so they share prefixes and operations hit stored keys.
+    fn operations() -> impl Strategy<Value = Vec<Operation>> {
+        let key = || proptest::collection::vec(0..4_u8, 0..12);
+        let operation = prop_oneof![
+            4 => (key(), proptest::collection::vec(any::<u8>(), 0..4))
+                .prop_map(|(k, v)| Operation::Put(k, v)),
+            2 => key().prop_map(Operation::Remove),
+            1 => key().prop_map(Operation::SplitOffPrefix),
+            1 => key().prop_map(Operation::SplitAtPrefix),
+        ];
+        proptest::collection::vec(operation, 1..128)
+    }
 
     proptest! {
 
+        #[test]
+        fn segment_comparison_matches_slices(
+            segment in proptest::collection::vec(0..4_u8, 0..40),
+            key in proptest::collection::vec(0..4_u8, 0..40),
+        ) {
+            let common = compare::common_prefix_len(&segment, &key);
+            prop_assert_eq!(common, compare::scalar::common_prefix_len(&segment, &key));
+            prop_assert_eq!(compare::compare(&segment, &key), segment.cmp(&key));
+        }
+
+        #[test]
+        fn segment_comparison_matches_slices_with_shared_prefix(
+            prefix in proptest::collection::vec(any::<u8>(), 0..40),
+            segment_suffix in proptest::collection::vec(any::<u8>(), 0..20),
+            key_suffix in proptest::collection::vec(any::<u8>(), 0..20),
+        ) {
+            let segment = [prefix.as_slice(), &segment_suffix].concat();
+            let key = [prefix.as_slice(), &key_suffix].concat();
+            let common = compare::common_prefix_len(&segment, &key);
+            prop_assert_eq!(common, compare::scalar::common_prefix_len(&segment, &key));
+            prop_assert_eq!(compare::compare(&segment, &key), segment.cmp(&key));
+        }
+
         #[test]
         fn tsimtree_behaves_like_hashmap(
             insertions in proptest::collection::vec((proptest::collection::vec(any::<u8>(), 0..32), proptest::collection::vec(any::<u8>(), 0..32)), 1..32)
         ) {
-            let mut ref_map = HashMap::new();
-            let tree = TSIMTree::new();
+            for_each_layout!(Tree => {
+                let mut ref_map = HashMap::new();
+                let tree = Tree::default();
 
-            for (k, v) in &insertions {
-                ref_map.insert(k.clone(), v.clone());
-                tree.put(k.clone(), v.clone());
+                for (k, v) in &insertions {
+                    ref_map.insert(k.clone(), v.clone());
+                    tree.put(k.clone(), v.clone());
+                    assert_sorted(&tree);
 
-                for (k, v) in &ref_map {
-                    let tree_value = tree.get(k.clone());
-                    prop_assert!(tree_value.is_some(),"Tree does not store {:?}: {:?}: \n {:?}",k,v,tree);
-                    prop_assert_eq!(tree_value.unwrap(), v.as_slice());
+                    for (k, v) in &ref_map {
+                        let tree_value = tree.get(k.clone());
+                        prop_assert!(tree_value.is_some(),"Tree does not store {:?}: {:?}: \n {:?}",k,v,tree);
+                        prop_assert_eq!(tree_value.unwrap(), v.as_slice());
+                    }
+                }
+
+                dbg!(&tree);
+            });
+        }
+
+        #[test]
+        fn tree_kinds_behave_like_btreemap(
+            insertions in proptest::collection::vec((proptest::collection::vec(0..4_u8, 0..12), proptest::collection::vec(any::<u8>(), 0..4)), 0..128),
+            removals in proptest::collection::vec(proptest::collection::vec(0..4_u8, 0..12), 0..64),
+        ) {
+            for_each_tree_kind!(Tree => {
+                let mut ref_map = BTreeMap::new();
+                let mut tree = Tree::default();
+                for (k, v) in &insertions {
+                    ref_map.insert(k.clone(), v.clone());
+                    SortedMap::put(&mut tree, k, v.clone());
                 }
+                for k in &removals {
+                    prop_assert_eq!(SortedMap::remove(&mut tree, k), ref_map.remove(k));
+                }
+
+                for (k, _) in &insertions {
+                    prop_assert_eq!(SortedMap::get(&tree, k), ref_map.get(k).cloned());
+                }
+                prop_assert_eq!(tree.entries(), ref_map.into_iter().collect::<Vec<_>>());
+            });
+        }
+
+        #[test]
+        fn persistent_tree_versions_behave_like_btreemap_snapshots(operations in operations()) {
+            let mut ref_map = BTreeMap::new();
+            let mut versions = vec![(PersistentTSIMTree::new(), ref_map.clone())];
+            for operation in operations {
+                let tree = &versions.last().unwrap().0;
+                let tree = match operation {
+                    Operation::Put(k, v) => {
+                        ref_map.insert(k.clone(), v.clone());
+                        tree.put(k, v)
+                    }
+                    Operation::Remove(k) | Operation::SplitOffPrefix(k) | Operation::SplitAtPrefix(k) => {
+                        ref_map.remove(&k);
+                        tree.remove(k)
+                    }
+                };
+                versions.push((tree, ref_map.clone()));
             }
 
-            dbg!(&tree);
+            // Every version still holds the entries it was created with.
+            for (tree, ref_map) in &versions {
+                prop_assert_eq!(tree.len(), ref_map.len());
+                let entries: Vec<_> = tree.iter().map(|(k, v)| (k, v.to_vec())).collect();
+                prop_assert_eq!(entries, ref_map.clone().into_iter().collect::<Vec<_>>());
+                for k in ref_map.keys() {
+                    prop_assert_eq!(tree.get(k), ref_map.get(k).map(Vec::as_slice));
+                    let mut missing = k.clone();
+                    missing.push(4);
+                    prop_assert_eq!(tree.get(&missing), None);
+                }
+            }
         }
 
+        /// Runs on the keys of the baselines benchmark, so the distributions it measures are known to be correct.
+        #[test]
+        fn tree_kinds_behave_like_btreemap_on_bench_keys(
+            seed in any::<u64>(),
+            count in 0..300_usize,
+            long_key_len in 8..64_usize,
+        ) {
+            let keys: Vec<Vec<u8>> = bench_utils::shuffled_keys(count, seed)
+                .iter()
+                .map(|key| key.to_vec())
+                .chain(bench_utils::long_keys(count, long_key_len, seed))
+                .collect();
+            for_each_tree_kind!(Tree => {
+                let mut ref_map = BTreeMap::new();
+                let mut tree = Tree::default();
+                for key in &keys {
+                    ref_map.insert(key.clone(), bench_utils::value_of(key));
+                    SortedMap::put(&mut tree, key, bench_utils::value_of(key));
+                }
 
-    }
+                for key in bench_utils::missing_keys(count, seed) {
+                    prop_assert_eq!(SortedMap::get(&tree, &key), ref_map.get(&key[..]).cloned());
+                }
+                prop_assert_eq!(tree.entries(), ref_map.into_iter().collect::<Vec<_>>());
+            });
+        }
+
+        #[test]
+        fn prefix_iter_matches_btreemap_range(
+            keys in proptest::collection::vec(proptest::collection::vec(0..4_u8, 0..20), 0..200),
+            prefix in proptest::collection::vec(0..4_u8, 0..6),
+        ) {
+            for_each_layout!(Tree => {
+                let tree = Tree::default();
+                let mut ref_map = BTreeMap::new();
+                for key in &keys {
+                    tree.put(key, key.clone());
+                    ref_map.insert(key.clone(), key.clone());
+                }
+
+                let expected: Vec<_> = ref_map
+                    .range(prefix.clone()..)
+                    .take_while(|(key, _)| key.starts_with(&prefix))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                prop_assert_eq!(tree.prefix_iter(&prefix).collect::<Vec<_>>(), expected.clone());
+                prop_assert!(tree.prefix_keys(&prefix).eq(expected.into_iter().map(|(key, _)| key)));
+            });
+        }
+
+        #[test]
+        fn for_each_prefix_matches_btreemap(
+            keys in proptest::collection::vec(proptest::collection::vec(0..4_u8, 0..20), 0..200),
+            key in proptest::collection::vec(0..4_u8, 0..20),
+        ) {
+            for_each_layout!(Tree => {
+                let tree = Tree::default();
+                let mut ref_map = BTreeMap::new();
+                for key in &keys {
+                    tree.put(key, key.clone());
+                    ref_map.insert(key.clone(), key.clone());
+                }
+
+                let expected: Vec<_> = (0..=key.len())
+                    .filter_map(|len| ref_map.get_key_value(&key[..len]))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                let mut prefixes = Vec::new();
+                tree.for_each_prefix(&key, |prefix, value| prefixes.push((prefix.to_vec(), value.to_vec())));
+                prop_assert_eq!(prefixes, expected);
+            });
+        }
+
+        #[test]
+        fn lookup_matches_btreemap(
+            keys in proptest::collection::vec(proptest::collection::vec(0..4_u8, 0..20), 0..200),
+            chunks in proptest::collection::vec(proptest::collection::vec(0..4_u8, 0..4), 0..8),
+        ) {
+            for_each_layout!(Tree => {
+                let tree = Tree::default();
+                let mut ref_map = BTreeMap::new();
+                for key in &keys {
+                    tree.put(key, key.clone());
+                    ref_map.insert(key.clone(), key.clone());
+                }
+
+                let mut lookup = tree.lookup();
+                let mut fed = Vec::new();
+                for chunk in &chunks {
+                    fed.extend_from_slice(chunk);
+                    let expected = match ref_map.get(&fed) {
+                        Some(value) => LookupState::Found(value.clone()),
+                        None if ref_map.range(fed.clone()..).next().is_some_and(|(key, _)| key.starts_with(&fed)) => {
+                            LookupState::Pending
+                        }
+                        None => LookupState::NotFound,
+                    };
+                    prop_assert_eq!(lookup.feed(chunk), expected);
+                }
+            });
+        }
+
+        #[test]
+        fn from_sorted_iter_matches_puts(
+            keys in proptest::collection::btree_set(proptest::collection::vec(0..4_u8, 0..20), 0..300),
+            fill_factor in 0.01..=1.0_f64,
+        ) {
+            for_each_layout!(Tree => {
+                let tree = Tree::default();
+                for key in &keys {
+                    tree.put(key, key.clone());
+                }
+                let entries = keys.iter().map(|key| (key.clone(), key.clone()));
+                let loaded = Tree::from_sorted_iter_with_fill_factor(entries, fill_factor).unwrap();
+                prop_assert_eq!(loaded.check_integrity(), Ok(()));
+                prop_assert_eq!(loaded.root.read().arena.len(), count_nodes(&loaded), "nodes leaked in the arena");
+                prop_assert_eq!(loaded.iter().collect::<Vec<_>>(), tree.iter().collect::<Vec<_>>());
+                for key in &keys {
+                    prop_assert_eq!(loaded.get(key), Some(key.clone()));
+                    let mut missing = key.clone();
+                    missing.push(4);
+                    prop_assert_eq!(loaded.get(&missing), None);
+                    prop_assert_eq!(loaded.get_next(&missing), tree.get_next(&missing));
+                    prop_assert_eq!(loaded.get_prev(key), tree.get_prev(key));
+                    prop_assert_eq!(loaded.count_prefix(key), tree.count_prefix(key));
+                }
+            });
+        }
+
+        #[test]
+        fn keys_match_the_keys_of_iter(
+            insertions in proptest::collection::vec((proptest::collection::vec(0..4_u8, 0..20), proptest::collection::vec(any::<u8>(), 0..40)), 0..200),
+            removals in proptest::collection::vec(proptest::collection::vec(0..4_u8, 0..20), 0..100)
+        ) {
+            for_each_layout!(Tree => {
+                let tree = Tree::default();
+                for (k, v) in &insertions {
+                    tree.put(k, v.clone());
+                }
+                for k in &removals {
+                    tree.remove(k);
+                }
+                let keys: Vec<Vec<u8>> = tree.iter().map(|(k, _)| k).collect();
+                prop_assert_eq!(tree.keys().collect::<Vec<_>>(), keys);
+            });
+        }
+
+        #[test]
+        fn tsimtree_removes_like_hashmap(
+            insertions in proptest::collection::vec((proptest::collection::vec(any::<u8>(), 0..16), proptest::collection::vec(any::<u8>(), 0..4)), 1..64),
+            removals in proptest::collection::vec(proptest::collection::vec(any::<u8>(), 0..16), 0..64)
+        ) {
+            for_each_layout!(Tree => {
+                let mut ref_map = HashMap::new();
+                let tree = Tree::default();
+
+                for (k, v) in &insertions {
+                    ref_map.insert(k.clone(), v.clone());
+                    tree.put(k, v.clone());
+                }
+
+                // Also remove keys that are actually stored, random keys rarely hit.
+                for k in removals.iter().chain(insertions.iter().step_by(2).map(|(k, _)| k)) {
+                    prop_assert_eq!(tree.remove(k), ref_map.remove(k));
+                }
+
+                for (k, _) in &insertions {
+                    prop_assert_eq!(tree.get(k), ref_map.get(k).cloned());
+                }
+
+                let mut keys: Vec<_> = ref_map.keys().cloned().collect();
+                keys.sort();
+                let values: Vec<_> = keys.iter().map(|k| ref_map[k].clone()).collect();
+                let first = keys.first().map(|k| (k.clone(), ref_map[k].clone()));
+                let last = keys.last().map(|k| (k.clone(), ref_map[k].clone()));
+                let has_several_keys = keys.len() > 1;
+                prop_assert_eq!(tree.keys().collect::<Vec<_>>(), keys);
+                prop_assert_eq!(tree.values().collect::<Vec<_>>(), values);
+
+                prop_assert_eq!(tree.first_key_value(), first.clone());
+                prop_assert_eq!(tree.last_key_value(), last.clone());
+                prop_assert_eq!(tree.pop_first(), first);
+                if has_several_keys {
+                    prop_assert_eq!(tree.pop_last(), last);
+                }
+                prop_assert_eq!(tree.check_integrity(), Ok(()));
+            });
+        }
+
+        #[test]
+        fn tsimtree_finds_neighbours_like_btreemap(
+            keys in proptest::collection::vec(proptest::collection::vec(0..4_u8, 0..8), 0..256),
+            queries in proptest::collection::vec(proptest::collection::vec(0..5_u8, 0..9), 1..64),
+        ) {
+            for_each_layout!(Tree => {
+                let mut ref_map = BTreeMap::new();
+                let tree = Tree::default();
+                for (i, k) in keys.iter().enumerate() {
+                    ref_map.insert(k.clone(), vec![i as u8]);
+                    tree.put(k, vec![i as u8]);
+                }
+
+                for query in queries.iter().chain(&keys) {
+                    let next = ref_map.range(query.clone()..).next();
+                    let prev = ref_map.range(..=query.clone()).next_back();
+                    prop_assert_eq!(tree.get_next(query), next.map(|(k, v)| (k.clone(), v.clone())));
+                    prop_assert_eq!(tree.get_prev(query), prev.map(|(k, v)| (k.clone(), v.clone())));
+                }
+            });
+        }
+
+        #[test]
+        fn tsimtree_diff_matches_btreemaps(
+            old_entries in proptest::collection::vec((proptest::collection::vec(0..4_u8, 0..6), 0..3_u8), 0..128),
+            new_entries in proptest::collection::vec((proptest::collection::vec(0..4_u8, 0..6), 0..3_u8), 0..128),
+        ) {
+            let old_map: BTreeMap<_, _> = old_entries.iter().map(|(k, v)| (k.clone(), vec![*v])).collect();
+            let new_map: BTreeMap<_, _> = new_entries.iter().map(|(k, v)| (k.clone(), vec![*v])).collect();
+            let mut expected = Vec::new();
+            for key in old_map.keys().chain(new_map.keys()).collect::<std::collections::BTreeSet<_>>() {
+                match (old_map.get(key), new_map.get(key)) {
+                    (Some(old), None) => expected.push(DiffEntry::Removed(key.clone(), old.clone())),
+                    (None, Some(new)) => expected.push(DiffEntry::Added(key.clone(), new.clone())),
+                    (Some(old), Some(new)) if old != new => {
+                        expected.push(DiffEntry::Modified(key.clone(), old.clone(), new.clone()))
+                    }
+                    _ => {}
+                }
+            }
+
+            for_each_layout!(Tree => {
+                let old: Tree = old_map.clone().into_iter().collect();
+                let new: Tree = new_map.clone().into_iter().collect();
+                prop_assert_eq!(diff(&old, &new).collect::<Vec<_>>(), expected.clone());
+            });
+        }
+
+        #[test]
+        fn tsimtree_cursor_steps_like_btreemap(
+            keys in proptest::collection::vec(proptest::collection::vec(0..4_u8, 0..8), 0..256),
+            start in proptest::collection::vec(0..5_u8, 0..9),
+            steps in proptest::collection::vec(any::<bool>(), 0..64),
+        ) {
+            for_each_layout!(Tree => {
+                let mut ref_map = BTreeMap::new();
+                let tree = Tree::default();
+                for (i, k) in keys.iter().enumerate() {
+                    ref_map.insert(k.clone(), vec![i as u8]);
+                    tree.put(k, vec![i as u8]);
+                }
+                let entries: Vec<_> = ref_map.into_iter().collect();
+
+                // The cursor is in front of the entry at this index.
+                let mut position = entries.partition_point(|(k, _)| *k < start);
+                let mut cursor = tree.cursor_at(&start);
+                for &forward in &steps {
+                    if forward {
+                        prop_assert_eq!(cursor.next(), entries.get(position).cloned());
+                        position = (position + 1).min(entries.len());
+                    } else {
+                        let expected = position.checked_sub(1).map(|idx| entries[idx].clone());
+                        prop_assert_eq!(cursor.prev(), expected);
+                        position = position.saturating_sub(1);
+                    }
+                }
+            });
+        }
+
+        #[test]
+        fn tsimtree_retains_like_hashmap(
+            insertions in proptest::collection::vec((proptest::collection::vec(0..4_u8, 0..12), proptest::collection::vec(any::<u8>(), 1..4)), 0..256),
+            divisor in 1..5_u8,
+        ) {
+            for_each_layout!(Tree => {
+                let mut ref_map = HashMap::new();
+                let tree = Tree::default();
+                for (k, v) in &insertions {
+                    ref_map.insert(k.clone(), v.clone());
+                    tree.put(k, v.clone());
+                }
+
+                let keep = |key: &[u8], value: &[u8]| (key.len() + usize::from(value[0])).is_multiple_of(usize::from(divisor));
+                ref_map.retain(|key, value| keep(key, value));
+                tree.retain(keep);
+
+                let mut entries: Vec<_> = ref_map.into_iter().collect();
+                entries.sort();
+                prop_assert_eq!(tree.iter().collect::<Vec<_>>(), entries);
+                prop_assert_eq!(tree.check_integrity(), Ok(()));
+                prop_assert_eq!(tree.root.read().arena.len(), count_nodes(&tree));
+                assert_sorted(&tree);
+            });
+        }
+
+        #[test]
+        fn tsimtree_round_trips_through_encoding(
+            insertions in proptest::collection::vec((proptest::collection::vec(0..4_u8, 0..12), proptest::collection::vec(any::<u8>(), 0..200)), 0..256),
+        ) {
+            for_each_layout!(Tree => {
+                let tree = Tree::default();
+                for (k, v) in &insertions {
+                    tree.put(k, v.clone());
+                }
+
+                let buf = tree.encode();
+                let decoded = Tree::decode(&buf).unwrap();
+                prop_assert_eq!(decoded.iter().collect::<Vec<_>>(), tree.iter().collect::<Vec<_>>());
+                prop_assert_eq!(decoded.check_integrity(), Ok(()));
+                // The encoding only depends on the entries, not on the layout of the nodes.
+                prop_assert_eq!(TSIMTree::<4, 32>::decode(&buf).unwrap().encode(), buf);
+            });
+        }
+
+        #[test]
+        fn tsimtree_decodes_arbitrary_buffers_without_panicking(
+            entries in proptest::collection::vec(any::<u8>(), 0..64),
+        ) {
+            let buf = [b"TSIM\x01".as_slice(), &entries].concat();
+            if let Ok(tree) = TSIMTree::<TREE_RADIX, CACHE_LINE_SIZE>::decode(&buf) {
+                prop_assert_eq!(tree.check_integrity(), Ok(()));
+                // Shared prefixes and varints may be longer than necessary, so only the decoded entries are compared.
+                prop_assert_eq!(TSIMTree::decode(&tree.encode()), Ok(tree));
+            }
+        }
+
+        #[test]
+        fn tsimtree_splits_off_like_btreemap(
+            insertions in proptest::collection::vec((proptest::collection::vec(0..4_u8, 0..12), proptest::collection::vec(any::<u8>(), 0..4)), 0..256),
+            pivot in proptest::collection::vec(0..4_u8, 0..12),
+        ) {
+            for_each_layout!(Tree => {
+                let mut ref_map = BTreeMap::new();
+                let tree = Tree::default();
+                for (k, v) in &insertions {
+                    ref_map.insert(k.clone(), v.clone());
+                    tree.put(k, v.clone());
+                }
+
+                let ref_upper = ref_map.split_off(&pivot);
+                let upper = tree.split_off(&pivot);
+                prop_assert_eq!(tree.iter().collect::<Vec<_>>(), ref_map.into_iter().collect::<Vec<_>>());
+                prop_assert_eq!(upper.iter().collect::<Vec<_>>(), ref_upper.into_iter().collect::<Vec<_>>());
+                prop_assert_eq!(tree.check_integrity(), Ok(()));
+                prop_assert_eq!(upper.check_integrity(), Ok(()));
+            });
+        }
+
+        #[test]
+        fn tsimtree_counts_prefixes_like_btreemap(
+            insertions in proptest::collection::vec((proptest::collection::vec(0..4_u8, 0..12), proptest::collection::vec(any::<u8>(), 0..4)), 0..256),
+            prefix in proptest::collection::vec(0..4_u8, 0..6),
+        ) {
+            for_each_layout!(Tree => {
+                let mut ref_map = BTreeMap::new();
+                let tree = Tree::default();
+                for (k, v) in &insertions {
+                    ref_map.insert(k.clone(), v.clone());
+                    tree.put(k, v.clone());
+                }
 
+                let ref_count = ref_map.keys().filter(|key| key.starts_with(&prefix)).count();
+                prop_assert_eq!(tree.count_prefix(&prefix), ref_count);
+            });
+        }
+
+        #[test]
+        fn tsimtree_evicts_like_lru(
+            capacity in 0..8_usize,
+            operations in proptest::collection::vec((0..3_u8, proptest::collection::vec(0..3_u8, 0..3), any::<u8>()), 1..128),
+        ) {
+            use std::sync::Mutex;
+
+            let evicted = Arc::new(Mutex::new(Vec::new()));
+            let tree = TSIMTree::with_capacity(capacity).on_evict({
+                let evicted = evicted.clone();
+                move |key, value| evicted.lock().unwrap().push((key, value))
+            });
+            // The entries ordered from the least to the most recently used one.
+            let mut ref_lru: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+            let mut ref_evicted = Vec::new();
+            for (kind, key, value) in operations {
+                let position = ref_lru.iter().position(|(k, _)| *k == key);
+                match kind {
+                    0 => {
+                        tree.put(&key, vec![value]);
+                        if let Some(position) = position {
+                            ref_lru.remove(position);
+                        }
+                        ref_lru.push((key, vec![value]));
+                        if ref_lru.len() > capacity {
+                            ref_evicted.push(ref_lru.remove(0));
+                        }
+                        prop_assert!(tree.iter().count() <= capacity);
+                    }
+                    1 => {
+                        let ref_value = position.map(|position| {
+                            let entry = ref_lru.remove(position);
+                            ref_lru.push(entry);
+                            ref_lru.last().unwrap().1.clone()
+                        });
+                        prop_assert_eq!(tree.get(&key), ref_value);
+                    }
+                    _ => {
+                        let ref_value = position.map(|position| ref_lru.remove(position).1);
+                        prop_assert_eq!(tree.remove(&key), ref_value);
+                    }
+                }
+            }
+
+            ref_lru.sort();
+            prop_assert_eq!(tree.iter().collect::<Vec<_>>(), ref_lru);
+            prop_assert_eq!(tree.check_integrity(), Ok(()));
+            prop_assert_eq!(&*evicted.lock().unwrap(), &ref_evicted);
+        }
+
+        #[test]
+        fn tsimtree_keeps_integrity(operations in operations()) {
+            for_each_layout!(Tree => {
+                let mut ref_map = HashMap::new();
+                let tree = Tree::default();
+                let keep_own_value = |_: &[u8], value: &[u8], _: &[u8]| value.to_vec();
+
+                for operation in &operations {
+                    match operation {
+                        Operation::Put(k, v) => {
+                            tree.put(k, v.clone());
+                            ref_map.insert(k.clone(), v.clone());
+                        }
+                        Operation::Remove(k) => {
+                            prop_assert_eq!(tree.remove(k), ref_map.remove(k));
+                        }
+                        // The split off entries are merged back, so the tree keeps growing.
+                        Operation::SplitOffPrefix(prefix) => {
+                            let split_off = tree.split_off_prefix(prefix);
+                            prop_assert_eq!(split_off.check_integrity(), Ok(()));
+                            prop_assert_eq!(tree.check_integrity(), Ok(()));
+                            tree.merge(&split_off, keep_own_value);
+                        }
+                        Operation::SplitAtPrefix(prefix) => {
+                            let (lower, upper) = tree.split_at_prefix(prefix);
+                            prop_assert_eq!(lower.check_integrity(), Ok(()));
+                            prop_assert_eq!(upper.check_integrity(), Ok(()));
+                            tree.merge(&lower, keep_own_value);
+                            tree.merge(&upper, keep_own_value);
+                        }
+                    }
+                    prop_assert_eq!(tree.check_integrity(), Ok(()));
+                    let stored_nodes = tree.root.read().arena.len();
+                    prop_assert_eq!(stored_nodes, count_nodes(&tree), "nodes leaked in the arena");
+                }
+
+                for (k, v) in &ref_map {
+                    prop_assert_eq!(tree.get(k), Some(v.clone()));
+                }
+
+                let mut keys: Vec<_> = ref_map.keys().cloned().collect();
+                keys.sort();
+                let values: Vec<_> = keys.iter().map(|k| ref_map[k].clone()).collect();
+                prop_assert_eq!(tree.keys().collect::<Vec<_>>(), keys);
+                prop_assert_eq!(tree.values().collect::<Vec<_>>(), values);
+            });
+        }
+    }
 }
@@ -0,0 +1,295 @@
+//! An immutable sorted tree, whose modifications return a new version and leave the old one intact.
+//!
+//! The nodes are shared between versions behind an [`Arc`]. A modification copies the nodes on the path to the key
+//! and links the copies to the untouched children of the originals, so it copies as many nodes as the key is deep.
+//!
+//! The nodes of [`TSIMTree`](crate::TSIMTree) live in an arena and are packed into cache lines, a copy of them
+//! would have to copy the arena. The nodes here are allocated one by one instead, and a node stores the fragments
+//! of the keys below it at their full length, so it holds as many children as there are first bytes.
+//! As in `TSIMTree`, the fragments of a node start with different bytes, except for an empty fragment
+//! that comes first and holds the value of the key ending at the node.
+//! Every node but the root has at least two children, otherwise it is merged into its parent.
+
+use crate::compare;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// A sorted tree that is never modified, `put` and `remove` return a new version of the tree.
+///
+/// Versions share all nodes that are not on the path to the modified key, so keeping old versions around
+/// for undo or for readers that work on a consistent state only costs the nodes that were copied.
+/// Cloning a version just clones the reference to its root.
+#[derive(Debug, Clone, Default)]
+pub struct PersistentTSIMTree {
+    root: Arc<PersistentNode>,
+    len: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+struct PersistentNode {
+    /// The children sorted by their fragment of the key.
+    /// The fragments are shared as well, so copying a node does not copy them.
+    children: Vec<(Arc<[u8]>, PersistentChild)>,
+}
+
+#[derive(Debug, Clone)]
+enum PersistentChild {
+    Node(Arc<PersistentNode>),
+    /// Keeps the allocation passed to `put`, every version that contains the key shares it.
+    Value(Arc<Vec<u8>>),
+}
+
+/// Which child of a node is responsible for a key.
+enum Position<'k> {
+    /// The fragment of the child is a prefix of the key, the rest of the key continues below the child.
+    Exact(usize, &'k [u8]),
+    /// The fragment of the child shares its first `common` bytes with the key, but not all of them.
+    Partial(usize, usize),
+    /// No child shares the first byte of the key, a child for it would be inserted at the index.
+    Vacant(usize),
+}
+
+impl PersistentNode {
+    fn position<'k>(&self, key: &'k [u8]) -> Position<'k> {
+        let Some(first_byte) = key.first() else {
+            return match self.children.first() {
+                Some((fragment, _)) if fragment.is_empty() => Position::Exact(0, key),
+                _ => Position::Vacant(0),
+            };
+        };
+        let found = self
+            .children
+            .binary_search_by(|(fragment, _)| fragment.first().cmp(&Some(first_byte)));
+        match found {
+            Err(idx) => Position::Vacant(idx),
+            Ok(idx) => {
+                let fragment = &self.children[idx].0;
+                let common = compare::common_prefix_len(fragment, key);
+                match common == fragment.len() {
+                    true => Position::Exact(idx, &key[common..]),
+                    false => Position::Partial(idx, common),
+                }
+            }
+        }
+    }
+
+    /// Creates a node from two children whose fragments start with different bytes.
+    fn with_children(
+        a: (Arc<[u8]>, PersistentChild),
+        b: (Arc<[u8]>, PersistentChild),
+    ) -> PersistentNode {
+        let children = match a.0.first() < b.0.first() {
+            true => vec![a, b],
+            false => vec![b, a],
+        };
+        PersistentNode { children }
+    }
+}
+
+/// Drops the nodes that are not shared with another version without recursion, so deep paths do not overflow the stack.
+impl Drop for PersistentNode {
+    fn drop(&mut self) {
+        let mut children = core::mem::take(&mut self.children);
+        while let Some((_, child)) = children.pop() {
+            if let PersistentChild::Node(node) = child {
+                if let Some(mut node) = Arc::into_inner(node) {
+                    children.append(&mut node.children);
+                }
+            }
+        }
+    }
+}
+
+impl PersistentTSIMTree {
+    pub fn new() -> PersistentTSIMTree {
+        PersistentTSIMTree::default()
+    }
+
+    /// Returns the number of entries in this version.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the value stored under the key in this version.
+    pub fn get<K>(&self, k: K) -> Option<&[u8]>
+    where
+        K: AsRef<[u8]>,
+    {
+        let mut key = k.as_ref();
+        let mut node = &*self.root;
+        loop {
+            let Position::Exact(idx, remaining_key) = node.position(key) else {
+                return None;
+            };
+            match &node.children[idx].1 {
+                PersistentChild::Node(child) => node = child,
+                PersistentChild::Value(value) if remaining_key.is_empty() => return Some(value),
+                PersistentChild::Value(_) => return None,
+            }
+            key = remaining_key;
+        }
+    }
+
+    /// Returns a new version in which the key maps to the value, this version is left unchanged.
+    pub fn put<K>(&self, k: K, v: Vec<u8>) -> PersistentTSIMTree
+    where
+        K: AsRef<[u8]>,
+    {
+        let mut key = k.as_ref();
+        let value = PersistentChild::Value(Arc::new(v));
+        // The copies of the nodes on the path, each with the index of the child that leads to the key.
+        let mut path: Vec<(PersistentNode, usize)> = Vec::new();
+        let mut node = &*self.root;
+        let mut inserted = true;
+
+        let modified = loop {
+            let mut copy = node.clone();
+            match node.position(key) {
+                Position::Exact(idx, remaining_key) => match &node.children[idx].1 {
+                    PersistentChild::Node(child) => {
+                        path.push((copy, idx));
+                        node = child;
+                        key = remaining_key;
+                        continue;
+                    }
+                    PersistentChild::Value(_) if remaining_key.is_empty() => {
+                        copy.children[idx].1 = value;
+                        inserted = false;
+                    }
+                    // The stored key is a prefix of the key, its value moves to the empty fragment of a new node.
+                    PersistentChild::Value(stored) => {
+                        let stored = PersistentChild::Value(stored.clone());
+                        let below = PersistentNode::with_children(
+                            (Arc::default(), stored),
+                            (remaining_key.into(), value),
+                        );
+                        copy.children[idx].1 = PersistentChild::Node(Arc::new(below));
+                    }
+                },
+                Position::Partial(idx, common) => {
+                    let (fragment, child) = copy.children[idx].clone();
+                    let below = PersistentNode::with_children(
+                        (fragment[common..].into(), child),
+                        (key[common..].into(), value),
+                    );
+                    copy.children[idx] = (
+                        fragment[..common].into(),
+                        PersistentChild::Node(Arc::new(below)),
+                    );
+                }
+                Position::Vacant(idx) => copy.children.insert(idx, (key.into(), value)),
+            }
+            break copy;
+        };
+
+        let mut modified = modified;
+        while let Some((mut parent, idx)) = path.pop() {
+            parent.children[idx].1 = PersistentChild::Node(Arc::new(modified));
+            modified = parent;
+        }
+        PersistentTSIMTree {
+            root: Arc::new(modified),
+            len: self.len + usize::from(inserted),
+        }
+    }
+
+    /// Returns a new version without the key, this version is left unchanged.
+    ///
+    /// If the key is not stored, the new version shares the root with this one.
+    pub fn remove<K>(&self, k: K) -> PersistentTSIMTree
+    where
+        K: AsRef<[u8]>,
+    {
+        let mut key = k.as_ref();
+        let mut path: Vec<(&PersistentNode, usize)> = Vec::new();
+        let mut node = &*self.root;
+        loop {
+            let Position::Exact(idx, remaining_key) = node.position(key) else {
+                return self.clone();
+            };
+            path.push((node, idx));
+            match &node.children[idx].1 {
+                PersistentChild::Node(child) => node = child,
+                PersistentChild::Value(_) if remaining_key.is_empty() => break,
+                PersistentChild::Value(_) => return self.clone(),
+            }
+            key = remaining_key;
+        }
+
+        let (node, idx) = path.pop().expect("the path leads to the value");
+        let mut modified = node.clone();
+        modified.children.remove(idx);
+        // A node that is left with a single child is merged into its parent, an empty node is removed from it.
+        while let Some((parent, idx)) = path.pop() {
+            let mut parent = parent.clone();
+            match modified.children.len() {
+                0 => {
+                    parent.children.remove(idx);
+                }
+                1 => {
+                    let (fragment, child) = modified.children.pop().expect("the node has a child");
+                    let merged = [&*parent.children[idx].0, &fragment].concat();
+                    parent.children[idx] = (merged.into(), child);
+                }
+                _ => parent.children[idx].1 = PersistentChild::Node(Arc::new(modified)),
+            }
+            modified = parent;
+        }
+        PersistentTSIMTree {
+            root: Arc::new(modified),
+            len: self.len - 1,
+        }
+    }
+
+    /// Returns an iterator over the entries of this version in ascending key order, the values are not cloned.
+    pub fn iter(&self) -> PersistentTSIMTreeIter<'_> {
+        PersistentTSIMTreeIter {
+            key: Vec::new(),
+            stack: vec![(&*self.root, 0, 0)],
+        }
+    }
+}
+
+impl<'t> IntoIterator for &'t PersistentTSIMTree {
+    type Item = (Vec<u8>, &'t [u8]);
+    type IntoIter = PersistentTSIMTreeIter<'t>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Iterates over the entries of a [`PersistentTSIMTree`] in ascending key order.
+pub struct PersistentTSIMTreeIter<'t> {
+    /// The key of the last visited child.
+    key: Vec<u8>,
+    /// Each frame holds a node, the index of the next child to visit and the key length at that node.
+    stack: Vec<(&'t PersistentNode, usize, usize)>,
+}
+
+impl<'t> Iterator for PersistentTSIMTreeIter<'t> {
+    type Item = (Vec<u8>, &'t [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let frame = self.stack.last_mut()?;
+            let (node, idx, key_len) = *frame;
+            let Some((fragment, child)) = node.children.get(idx) else {
+                self.stack.pop();
+                continue;
+            };
+            frame.1 += 1;
+            self.key.truncate(key_len);
+            self.key.extend_from_slice(fragment);
+            match child {
+                PersistentChild::Node(child) => self.stack.push((child, 0, self.key.len())),
+                PersistentChild::Value(value) => return Some((self.key.clone(), value)),
+            }
+        }
+    }
+}
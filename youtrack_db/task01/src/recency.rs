@@ -0,0 +1,138 @@
+//! Tracks the order in which the entries of a bounded tree were accessed, so the least recently used entry can be evicted.
+//!
+//! Every access stamps the key with the next value of a counter. Two trees map the keys to their stamps
+//! and the stamps, encoded in big endian so they sort numerically, back to their keys.
+//! The entry with the smallest stamp is the least recently used one.
+//! The length of the value is stored behind the stamp without its high zero bytes, so a tree limited by its value
+//! bytes knows how many bytes an eviction frees, and the entry still fits inline for lengths below 2^56.
+//!
+//! The tracker only learns about the keys it is told about. Keys that are removed from the tree without
+//! calling [`Recency::forget`] stay tracked until they are evicted, evicting them leaves the tree unchanged.
+
+use crate::{CacheLimit, TSIMTreeNodes};
+use alloc::vec::Vec;
+
+const STAMP_LEN: usize = size_of::<u64>();
+
+#[derive(Debug, Clone)]
+pub(crate) struct Recency<const RADIX: usize, const LINE: usize> {
+    limit: CacheLimit,
+    /// The number of tracked keys.
+    len: usize,
+    /// The sum of the value lengths of the tracked keys.
+    value_bytes: usize,
+    /// The stamp of the next access.
+    clock: u64,
+    stamps: TSIMTreeNodes<RADIX, LINE>,
+    keys: TSIMTreeNodes<RADIX, LINE>,
+}
+
+impl<const RADIX: usize, const LINE: usize> Recency<RADIX, LINE> {
+    pub(crate) fn new(limit: CacheLimit) -> Recency<RADIX, LINE> {
+        Recency {
+            limit,
+            len: 0,
+            value_bytes: 0,
+            clock: 0,
+            stamps: TSIMTreeNodes::empty(),
+            keys: TSIMTreeNodes::empty(),
+        }
+    }
+
+    /// Marks the key, whose value has the given length, as the most recently used one.
+    pub(crate) fn touch(&mut self, key: &[u8], value_len: usize) {
+        let stamp = self.clock.to_be_bytes();
+        self.clock += 1;
+        let value_len = value_len as u64;
+        let value_len_bytes = value_len.to_le_bytes();
+        let significant = value_len_bytes.len() - value_len.leading_zeros() as usize / 8;
+        let entry = [&stamp[..], &value_len_bytes[..significant]].concat();
+        if let Some(old_entry) = self.stamps.put(key, entry) {
+            self.untrack(&old_entry);
+        }
+        self.len += 1;
+        self.value_bytes += value_len as usize;
+        self.keys.put(&stamp, key.to_vec());
+    }
+
+    /// Marks a tracked key as the most recently used one and keeps the length of its value.
+    /// Keys that are not tracked are left alone, as they may have been removed since they were read.
+    pub(crate) fn refresh(&mut self, key: &[u8]) {
+        if let Some(entry) = self.stamps.get(key) {
+            let value_len = value_len(entry);
+            self.touch(key, value_len);
+        }
+    }
+
+    /// Stops tracking the key, because it was removed from the tree.
+    pub(crate) fn forget(&mut self, key: &[u8]) {
+        if let Some((old_entry, _)) = self.stamps.remove_entry(key) {
+            self.untrack(&old_entry);
+        }
+    }
+
+    /// Removes the stamp of an entry of `stamps` and subtracts the entry from the counts.
+    fn untrack(&mut self, entry: &[u8]) {
+        self.keys.remove_entry(&entry[..STAMP_LEN]);
+        self.len -= 1;
+        self.value_bytes -= value_len(entry);
+    }
+
+    fn exceeds_limit(&self) -> bool {
+        match self.limit {
+            CacheLimit::Entries(max_entries) => self.len > max_entries,
+            CacheLimit::ValueBytes(max_value_bytes) => self.value_bytes > max_value_bytes,
+        }
+    }
+
+    /// Stops tracking all keys, because the tree was cleared.
+    pub(crate) fn forget_all(&mut self) {
+        self.len = 0;
+        self.value_bytes = 0;
+        self.stamps = TSIMTreeNodes::empty();
+        self.keys = TSIMTreeNodes::empty();
+    }
+
+    /// Returns the number of tracked keys.
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the sum of the value lengths of the tracked keys.
+    #[cfg(test)]
+    pub(crate) fn value_bytes(&self) -> usize {
+        self.value_bytes
+    }
+
+    /// Returns the number of bytes allocated for the nodes and values of both trees.
+    pub(crate) fn memory_bytes(&self) -> usize {
+        self.stamps.memory_bytes() + self.keys.memory_bytes()
+    }
+
+    /// Stops tracking the least recently used keys until the tracked keys are within the limit
+    /// and returns them, so they can be removed from the tree.
+    pub(crate) fn evict(&mut self) -> Vec<Vec<u8>> {
+        let mut evicted = Vec::new();
+        while self.exceeds_limit() {
+            // A thread that panicked while holding the lock may have left the counts out of sync.
+            let Some((_, key)) = self.keys.pop_extreme_entry(false) else {
+                self.len = 0;
+                self.value_bytes = 0;
+                break;
+            };
+            // The stamp was popped already, forgetting the key only removes it from `stamps`.
+            self.forget(&key);
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+/// Returns the length of the value stored behind the stamp of an entry of `stamps`.
+fn value_len(entry: &[u8]) -> usize {
+    let value_len_bytes = &entry[STAMP_LEN..];
+    let mut value_len = [0; size_of::<u64>()];
+    value_len[..value_len_bytes.len()].copy_from_slice(value_len_bytes);
+    u64::from_le_bytes(value_len) as usize
+}
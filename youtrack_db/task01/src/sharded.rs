@@ -0,0 +1,180 @@
+//! Spreads the keys over several trees, so writers of different keys do not wait for the same root lock.
+//!
+//! Each key is routed to a shard by the FNV-1a hash of the whole key. Hashing the whole key instead of a prefix
+//! keeps keys with a common prefix from piling up in one shard. As a key is always stored in the same shard,
+//! the shards hold disjoint keys and iterating the tree merges their sorted entries.
+
+use crate::{fnv1a, TSIMTree, TSIMTreeIter, CACHE_LINE_SIZE, FNV_OFFSET_BASIS, TREE_RADIX};
+use alloc::collections::BinaryHeap;
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+
+/// A thread-safe sorted in-memory tree that is split into shards, each behind its own lock.
+///
+/// Operations on a single key only lock the shard of the key, so writers of different keys mostly run in parallel.
+/// Operations on the whole tree lock the shards one after another, so they do not observe a consistent
+/// state of the tree while it is modified concurrently.
+#[derive(Debug, Clone)]
+pub struct ShardedTSIMTree<const RADIX: usize = TREE_RADIX, const LINE: usize = CACHE_LINE_SIZE> {
+    /// Holds a power of two of shards, so a hash is mapped to a shard by masking it.
+    shards: Vec<TSIMTree<RADIX, LINE>>,
+}
+
+#[cfg(feature = "std")]
+impl<const RADIX: usize, const LINE: usize> Default for ShardedTSIMTree<RADIX, LINE> {
+    fn default() -> Self {
+        let parallelism = std::thread::available_parallelism().map_or(1, usize::from);
+        ShardedTSIMTree::from_shard_count(parallelism)
+    }
+}
+
+impl<'t, const RADIX: usize, const LINE: usize> IntoIterator for &'t ShardedTSIMTree<RADIX, LINE> {
+    type Item = (Vec<u8>, Vec<u8>);
+    type IntoIter = ShardedTSIMTreeIter<'t, RADIX, LINE>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl ShardedTSIMTree {
+    /// Creates a tree with one shard per available CPU, rounded up to a power of two. Requires the `std` feature.
+    #[cfg(feature = "std")]
+    pub fn new() -> ShardedTSIMTree {
+        ShardedTSIMTree::default()
+    }
+
+    /// Creates a tree with at least `shards` shards, the number is rounded up to a power of two.
+    pub fn with_shards(shards: usize) -> ShardedTSIMTree {
+        ShardedTSIMTree::from_shard_count(shards)
+    }
+}
+
+impl<const RADIX: usize, const LINE: usize> ShardedTSIMTree<RADIX, LINE> {
+    fn from_shard_count(shards: usize) -> ShardedTSIMTree<RADIX, LINE> {
+        let shards = shards.max(1).next_power_of_two();
+        ShardedTSIMTree {
+            shards: (0..shards).map(|_| TSIMTree::default()).collect(),
+        }
+    }
+
+    /// Returns the number of shards the keys are spread over.
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns the shard responsible for the key.
+    fn shard(&self, key: &[u8]) -> &TSIMTree<RADIX, LINE> {
+        let hash = fnv1a(FNV_OFFSET_BASIS, key);
+        // The high bits are mixed with more bytes of the key than the low bits.
+        let hash = (hash ^ (hash >> 32)) as usize;
+        &self.shards[hash & (self.shards.len() - 1)]
+    }
+
+    pub fn put<K>(&self, k: K, v: Vec<u8>)
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = k.as_ref();
+        self.shard(key).put(key, v);
+    }
+
+    pub fn get<K>(&self, k: K) -> Option<Vec<u8>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = k.as_ref();
+        self.shard(key).get(key)
+    }
+
+    /// Removes the key from the tree and returns the value that was stored under it.
+    pub fn remove<K>(&self, k: K) -> Option<Vec<u8>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = k.as_ref();
+        self.shard(key).remove(key)
+    }
+
+    /// Returns the number of entries, counted shard by shard.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.count_prefix([])).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards
+            .iter()
+            .all(|shard| shard.first_key_value().is_none())
+    }
+
+    /// Returns an iterator over the entries in ascending key order, merged from the entries of all shards.
+    ///
+    /// The read locks of all shards are acquired up front and held until the iterator is dropped.
+    pub fn iter(&self) -> ShardedTSIMTreeIter<'_, RADIX, LINE> {
+        let mut shards: Vec<_> = self.shards.iter().map(TSIMTree::iter).collect();
+        let mut heads = BinaryHeap::with_capacity(shards.len());
+        for (shard, entries) in shards.iter_mut().enumerate() {
+            if let Some((key, value)) = entries.next() {
+                heads.push(Reverse(ShardHead { key, shard, value }));
+            }
+        }
+        ShardedTSIMTreeIter { shards, heads }
+    }
+}
+
+/// The smallest entry of a shard that was not yielded yet.
+///
+/// The heads are ordered by their keys only, which are unique across the shards.
+struct ShardHead {
+    key: Vec<u8>,
+    shard: usize,
+    value: Vec<u8>,
+}
+
+impl PartialEq for ShardHead {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for ShardHead {}
+
+impl PartialOrd for ShardHead {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ShardHead {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+/// An iterator over the entries of a sharded tree in ascending key order, created by [`ShardedTSIMTree::iter`].
+///
+/// It merges the iterators of all shards, keeping the smallest entry of each shard in a heap.
+pub struct ShardedTSIMTreeIter<
+    't,
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+> {
+    shards: Vec<TSIMTreeIter<'t, RADIX, LINE>>,
+    heads: BinaryHeap<Reverse<ShardHead>>,
+}
+
+impl<const RADIX: usize, const LINE: usize> Iterator for ShardedTSIMTreeIter<'_, RADIX, LINE> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse(head) = self.heads.pop()?;
+        if let Some((key, value)) = self.shards[head.shard].next() {
+            self.heads.push(Reverse(ShardHead {
+                key,
+                shard: head.shard,
+                value,
+            }));
+        }
+        Some((head.key, head.value))
+    }
+}
@@ -0,0 +1,602 @@
+//! Synchronizes the access to the root node of the tree.
+//!
+//! By default the root is protected by a [`std::sync::RwLock`].
+//! Without the `std` feature, a [`spin::RwLock`] is used instead, whose waiting threads spin instead of sleeping.
+//! With the `parking_lot` feature, a [`parking_lot::RwLock`] is used, which is not poisoned by panicking threads
+//! and is fair to writers.
+//!
+//! With the `lockfree` feature, the root is published through an epoch-protected atomic pointer instead.
+//! Readers pin the current epoch and never block.
+//! Writers are serialized by a mutex, modify a private copy of the root and swap it in once they are done.
+//! With the `parking_lot` feature, the writer mutex is a [`parking_lot::Mutex`].
+//! The replaced root is freed by crossbeam-epoch after all readers that could still observe it are unpinned.
+//! The root is published as an `Arc`, so a snapshot of the tree only takes a reference to the current root.
+//! Every modification copies the root, whose arena shares the nodes and chunks of slots it does not modify,
+//! so a write copies as much as the paths to its keys. It is still several times slower than under a lock,
+//! so the feature only pays off for read-dominated workloads.
+//!
+//! Without the `lockfree` feature, taking a snapshot copies the root under the read lock.
+//!
+//! Bookkeeping next to the root, like the recency of the entries of a bounded tree, is protected by a [`Lock`].
+//! It is a [`std::sync::Mutex`], a [`parking_lot::Mutex`] with the `parking_lot` feature,
+//! or a write-locked [`spin::RwLock`] without the `std` feature.
+
+#[cfg(all(
+    feature = "std",
+    not(feature = "lockfree"),
+    not(feature = "parking_lot")
+))]
+pub(crate) use rwlock::{RootLock, RootReadGuard, RootWriteGuard};
+
+#[cfg(all(feature = "parking_lot", not(feature = "lockfree")))]
+pub(crate) use parking_lot_rwlock::{RootLock, RootReadGuard, RootWriteGuard};
+
+#[cfg(not(feature = "std"))]
+pub(crate) use spin_rwlock::{RootLock, RootReadGuard, RootWriteGuard};
+
+#[cfg(feature = "lockfree")]
+pub(crate) use lockfree::{RootLock, RootReadGuard, RootWriteGuard};
+
+#[cfg(all(feature = "std", not(feature = "parking_lot")))]
+pub(crate) use std_mutex::Lock;
+
+#[cfg(feature = "parking_lot")]
+pub(crate) use parking_lot_rwlock::Lock;
+
+#[cfg(not(feature = "std"))]
+pub(crate) use spin_rwlock::Lock;
+
+#[cfg(all(
+    feature = "std",
+    not(feature = "lockfree"),
+    not(feature = "parking_lot")
+))]
+mod rwlock {
+    use crate::TSIMTreeError;
+    use std::fmt::Debug;
+    use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+
+    pub(crate) type RootReadGuard<'l, T> = RwLockReadGuard<'l, T>;
+    pub(crate) type RootWriteGuard<'l, T> = RwLockWriteGuard<'l, T>;
+
+    pub(crate) struct RootLock<T>(RwLock<T>);
+
+    impl<T> RootLock<T> {
+        pub(crate) fn new(root: T) -> RootLock<T> {
+            RootLock(RwLock::new(root))
+        }
+
+        pub(crate) fn read(&self) -> RootReadGuard<'_, T> {
+            self.0.read().expect("Must be able to acquire read lock")
+        }
+
+        pub(crate) fn write(&self) -> RootWriteGuard<'_, T> {
+            self.0.write().expect("Must be able to acquire write lock")
+        }
+
+        /// Waits for the lock like `read`, but fails if a thread panicked while holding it.
+        pub(crate) fn checked_read(&self) -> Result<RootReadGuard<'_, T>, TSIMTreeError> {
+            self.0.read().map_err(|_| TSIMTreeError::Poisoned)
+        }
+
+        /// Waits for the lock like `write`, but fails if a thread panicked while holding it.
+        pub(crate) fn checked_write(&self) -> Result<RootWriteGuard<'_, T>, TSIMTreeError> {
+            self.0.write().map_err(|_| TSIMTreeError::Poisoned)
+        }
+
+        /// Fails instead of waiting if a writer holds the lock, or if a thread panicked while holding it.
+        pub(crate) fn try_read(&self) -> Result<RootReadGuard<'_, T>, TSIMTreeError> {
+            self.0.try_read().map_err(lock_error)
+        }
+
+        /// Fails instead of waiting if the lock is held, or if a thread panicked while holding it.
+        pub(crate) fn try_write(&self) -> Result<RootWriteGuard<'_, T>, TSIMTreeError> {
+            self.0.try_write().map_err(lock_error)
+        }
+
+        /// Copies the root under the read lock, so it can be read without holding the lock.
+        pub(crate) fn snapshot(&self) -> Arc<T>
+        where
+            T: Clone,
+        {
+            Arc::new(self.read().clone())
+        }
+
+        /// Replaces the root with the one built from it and drops the old root after the lock is released.
+        pub(crate) fn replace_with(&self, f: impl FnOnce(&T) -> T) {
+            let mut root_guard = self.write();
+            let root = f(&root_guard);
+            let old_root = std::mem::replace(&mut *root_guard, root);
+            drop(root_guard);
+            drop(old_root);
+        }
+
+        pub(crate) fn into_inner(self) -> T {
+            self.0
+                .into_inner()
+                .expect("Must be able to acquire write lock")
+        }
+    }
+
+    impl<T: Debug> Debug for RootLock<T> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    fn lock_error<G>(error: TryLockError<G>) -> TSIMTreeError {
+        match error {
+            TryLockError::Poisoned(_) => TSIMTreeError::Poisoned,
+            TryLockError::WouldBlock => TSIMTreeError::WouldBlock,
+        }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+mod spin_rwlock {
+    use crate::TSIMTreeError;
+    use alloc::sync::Arc;
+    use core::fmt::Debug;
+    use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+    pub(crate) type RootReadGuard<'l, T> = RwLockReadGuard<'l, T>;
+    pub(crate) type RootWriteGuard<'l, T> = RwLockWriteGuard<'l, T>;
+
+    pub(crate) struct RootLock<T>(RwLock<T>);
+
+    impl<T> RootLock<T> {
+        pub(crate) fn new(root: T) -> RootLock<T> {
+            RootLock(RwLock::new(root))
+        }
+
+        pub(crate) fn read(&self) -> RootReadGuard<'_, T> {
+            self.0.read()
+        }
+
+        pub(crate) fn write(&self) -> RootWriteGuard<'_, T> {
+            self.0.write()
+        }
+
+        /// Spin locks are not poisoned by panicking threads, so this never fails.
+        pub(crate) fn checked_read(&self) -> Result<RootReadGuard<'_, T>, TSIMTreeError> {
+            Ok(self.read())
+        }
+
+        /// Spin locks are not poisoned by panicking threads, so this never fails.
+        pub(crate) fn checked_write(&self) -> Result<RootWriteGuard<'_, T>, TSIMTreeError> {
+            Ok(self.write())
+        }
+
+        /// Fails instead of spinning if a writer holds the lock. Spin locks are not poisoned by panicking threads.
+        pub(crate) fn try_read(&self) -> Result<RootReadGuard<'_, T>, TSIMTreeError> {
+            self.0.try_read().ok_or(TSIMTreeError::WouldBlock)
+        }
+
+        /// Fails instead of spinning if the lock is held. Spin locks are not poisoned by panicking threads.
+        pub(crate) fn try_write(&self) -> Result<RootWriteGuard<'_, T>, TSIMTreeError> {
+            self.0.try_write().ok_or(TSIMTreeError::WouldBlock)
+        }
+
+        /// Copies the root under the read lock, so it can be read without holding the lock.
+        pub(crate) fn snapshot(&self) -> Arc<T>
+        where
+            T: Clone,
+        {
+            Arc::new(self.read().clone())
+        }
+
+        /// Replaces the root with the one built from it and drops the old root after the lock is released.
+        pub(crate) fn replace_with(&self, f: impl FnOnce(&T) -> T) {
+            let mut root_guard = self.write();
+            let root = f(&root_guard);
+            let old_root = core::mem::replace(&mut *root_guard, root);
+            drop(root_guard);
+            drop(old_root);
+        }
+
+        pub(crate) fn into_inner(self) -> T {
+            self.0.into_inner()
+        }
+    }
+
+    impl<T: Debug> Debug for RootLock<T> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    #[derive(Debug)]
+    pub(crate) struct Lock<T>(RwLock<T>);
+
+    impl<T> Lock<T> {
+        pub(crate) fn new(data: T) -> Lock<T> {
+            Lock(RwLock::new(data))
+        }
+
+        pub(crate) fn lock(&self) -> RwLockWriteGuard<'_, T> {
+            self.0.write()
+        }
+    }
+}
+
+#[cfg(feature = "parking_lot")]
+mod parking_lot_rwlock {
+    use crate::TSIMTreeError;
+    use parking_lot::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+    use std::fmt::Debug;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    pub(crate) type RootReadGuard<'l, T> = RwLockReadGuard<'l, T>;
+    pub(crate) type RootWriteGuard<'l, T> = RwLockWriteGuard<'l, T>;
+
+    // Only the bookkeeping lock is used together with the `lockfree` feature.
+    #[cfg_attr(feature = "lockfree", allow(dead_code))]
+    pub(crate) struct RootLock<T>(RwLock<T>);
+
+    #[cfg_attr(feature = "lockfree", allow(dead_code))]
+    impl<T> RootLock<T> {
+        pub(crate) fn new(root: T) -> RootLock<T> {
+            RootLock(RwLock::new(root))
+        }
+
+        pub(crate) fn read(&self) -> RootReadGuard<'_, T> {
+            self.0.read()
+        }
+
+        pub(crate) fn write(&self) -> RootWriteGuard<'_, T> {
+            self.0.write()
+        }
+
+        /// parking_lot locks are not poisoned, so this never fails.
+        pub(crate) fn checked_read(&self) -> Result<RootReadGuard<'_, T>, TSIMTreeError> {
+            Ok(self.read())
+        }
+
+        /// parking_lot locks are not poisoned, so this never fails.
+        pub(crate) fn checked_write(&self) -> Result<RootWriteGuard<'_, T>, TSIMTreeError> {
+            Ok(self.write())
+        }
+
+        /// Fails instead of waiting if a writer holds the lock. parking_lot locks are not poisoned.
+        pub(crate) fn try_read(&self) -> Result<RootReadGuard<'_, T>, TSIMTreeError> {
+            self.0.try_read().ok_or(TSIMTreeError::WouldBlock)
+        }
+
+        /// Fails instead of waiting if the lock is held. parking_lot locks are not poisoned.
+        pub(crate) fn try_write(&self) -> Result<RootWriteGuard<'_, T>, TSIMTreeError> {
+            self.0.try_write().ok_or(TSIMTreeError::WouldBlock)
+        }
+
+        /// Fails if a writer still holds the lock after the timeout.
+        pub(crate) fn try_read_for(
+            &self,
+            timeout: Duration,
+        ) -> Result<RootReadGuard<'_, T>, TSIMTreeError> {
+            self.0
+                .try_read_for(timeout)
+                .ok_or(TSIMTreeError::WouldBlock)
+        }
+
+        /// Fails if the lock is still held after the timeout.
+        pub(crate) fn try_write_for(
+            &self,
+            timeout: Duration,
+        ) -> Result<RootWriteGuard<'_, T>, TSIMTreeError> {
+            self.0
+                .try_write_for(timeout)
+                .ok_or(TSIMTreeError::WouldBlock)
+        }
+
+        /// Copies the root under the read lock, so it can be read without holding the lock.
+        pub(crate) fn snapshot(&self) -> Arc<T>
+        where
+            T: Clone,
+        {
+            Arc::new(self.read().clone())
+        }
+
+        /// Replaces the root with the one built from it and drops the old root after the lock is released.
+        pub(crate) fn replace_with(&self, f: impl FnOnce(&T) -> T) {
+            let mut root_guard = self.write();
+            let root = f(&root_guard);
+            let old_root = std::mem::replace(&mut *root_guard, root);
+            drop(root_guard);
+            drop(old_root);
+        }
+
+        pub(crate) fn into_inner(self) -> T {
+            self.0.into_inner()
+        }
+    }
+
+    impl<T: Debug> Debug for RootLock<T> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    #[derive(Debug)]
+    pub(crate) struct Lock<T>(Mutex<T>);
+
+    impl<T> Lock<T> {
+        pub(crate) fn new(data: T) -> Lock<T> {
+            Lock(Mutex::new(data))
+        }
+
+        pub(crate) fn lock(&self) -> MutexGuard<'_, T> {
+            self.0.lock()
+        }
+    }
+}
+
+#[cfg(all(feature = "std", not(feature = "parking_lot")))]
+mod std_mutex {
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    #[derive(Debug)]
+    pub(crate) struct Lock<T>(Mutex<T>);
+
+    impl<T> Lock<T> {
+        pub(crate) fn new(data: T) -> Lock<T> {
+            Lock(Mutex::new(data))
+        }
+
+        /// A thread that panicked while holding the lock only left bookkeeping behind, so the lock is recovered.
+        pub(crate) fn lock(&self) -> MutexGuard<'_, T> {
+            self.0.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+}
+
+#[cfg(feature = "lockfree")]
+mod lockfree {
+    use crate::TSIMTreeError;
+    use crossbeam_epoch::{self as epoch, Atomic, Guard, Owned};
+    #[cfg(feature = "parking_lot")]
+    use parking_lot::{Mutex, MutexGuard};
+    use std::fmt::Debug;
+    use std::marker::PhantomData;
+    use std::ops::{Deref, DerefMut};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    #[cfg(not(feature = "parking_lot"))]
+    use std::sync::{Mutex, MutexGuard, TryLockError};
+    #[cfg(feature = "parking_lot")]
+    use std::time::Duration;
+
+    pub(crate) struct RootLock<T> {
+        root: Atomic<Arc<T>>,
+        writer: Mutex<()>,
+    }
+
+    impl<T> RootLock<T> {
+        pub(crate) fn new(root: T) -> RootLock<T> {
+            RootLock {
+                root: Atomic::new(Arc::new(root)),
+                writer: Mutex::new(()),
+            }
+        }
+
+        pub(crate) fn read(&self) -> RootReadGuard<'_, T> {
+            let epoch_guard = epoch::pin();
+            let root = self.root.load(Ordering::Acquire, &epoch_guard).as_raw();
+            RootReadGuard {
+                _epoch_guard: epoch_guard,
+                root,
+                _lock: PhantomData,
+            }
+        }
+
+        pub(crate) fn write(&self) -> RootWriteGuard<'_, T>
+        where
+            T: Clone,
+        {
+            #[cfg(not(feature = "parking_lot"))]
+            let writer = self
+                .writer
+                .lock()
+                .expect("Must be able to acquire write lock");
+            #[cfg(feature = "parking_lot")]
+            let writer = self.writer.lock();
+            self.write_guard(writer)
+        }
+
+        /// Never fails, as readers do not lock.
+        pub(crate) fn checked_read(&self) -> Result<RootReadGuard<'_, T>, TSIMTreeError> {
+            Ok(self.read())
+        }
+
+        /// Waits for the writer mutex like `write`, but fails if a thread panicked while holding it.
+        pub(crate) fn checked_write(&self) -> Result<RootWriteGuard<'_, T>, TSIMTreeError>
+        where
+            T: Clone,
+        {
+            #[cfg(not(feature = "parking_lot"))]
+            let writer = self.writer.lock().map_err(|_| TSIMTreeError::Poisoned)?;
+            #[cfg(feature = "parking_lot")]
+            let writer = self.writer.lock();
+            Ok(self.write_guard(writer))
+        }
+
+        /// Never fails, as readers do not lock.
+        pub(crate) fn try_read(&self) -> Result<RootReadGuard<'_, T>, TSIMTreeError> {
+            Ok(self.read())
+        }
+
+        /// Fails instead of waiting if another writer holds the writer mutex,
+        /// or if a thread panicked while holding it. Its modifications were never published,
+        /// but a std mutex is poisoned nonetheless.
+        pub(crate) fn try_write(&self) -> Result<RootWriteGuard<'_, T>, TSIMTreeError>
+        where
+            T: Clone,
+        {
+            #[cfg(not(feature = "parking_lot"))]
+            let writer = self.writer.try_lock().map_err(|error| match error {
+                TryLockError::Poisoned(_) => TSIMTreeError::Poisoned,
+                TryLockError::WouldBlock => TSIMTreeError::WouldBlock,
+            })?;
+            #[cfg(feature = "parking_lot")]
+            let writer = self.writer.try_lock().ok_or(TSIMTreeError::WouldBlock)?;
+            Ok(self.write_guard(writer))
+        }
+
+        /// Never fails, as readers do not lock.
+        #[cfg(feature = "parking_lot")]
+        pub(crate) fn try_read_for(
+            &self,
+            _timeout: Duration,
+        ) -> Result<RootReadGuard<'_, T>, TSIMTreeError> {
+            Ok(self.read())
+        }
+
+        /// Fails if another writer still holds the writer mutex after the timeout.
+        #[cfg(feature = "parking_lot")]
+        pub(crate) fn try_write_for(
+            &self,
+            timeout: Duration,
+        ) -> Result<RootWriteGuard<'_, T>, TSIMTreeError>
+        where
+            T: Clone,
+        {
+            let writer = self
+                .writer
+                .try_lock_for(timeout)
+                .ok_or(TSIMTreeError::WouldBlock)?;
+            Ok(self.write_guard(writer))
+        }
+
+        fn write_guard<'l>(&'l self, writer: MutexGuard<'l, ()>) -> RootWriteGuard<'l, T>
+        where
+            T: Clone,
+        {
+            RootWriteGuard {
+                lock: self,
+                _writer: writer,
+                modified_root: None,
+            }
+        }
+
+        /// Shares the published root without copying it, it stays alive as long as the snapshot does.
+        pub(crate) fn snapshot(&self) -> Arc<T> {
+            let epoch_guard = epoch::pin();
+            let root = self.root.load(Ordering::Acquire, &epoch_guard);
+            // SAFETY: The root is never null and is only freed after the pinned epoch has ended.
+            Arc::clone(unsafe { root.deref() })
+        }
+
+        /// Replaces the root with the one built from it without copying the old root,
+        /// which is dropped once no reader observes it anymore.
+        pub(crate) fn replace_with(&self, f: impl FnOnce(&T) -> T)
+        where
+            T: Clone,
+        {
+            let mut node_guard = self.write();
+            node_guard.modified_root = Some(f(&node_guard));
+        }
+
+        /// Copies the root if a snapshot still shares it.
+        pub(crate) fn into_inner(mut self) -> T
+        where
+            T: Clone,
+        {
+            // SAFETY: Owning the lock guarantees that no reader or writer still accesses the root.
+            // The root is replaced by null, so it is not freed again when the lock is dropped.
+            let root = unsafe {
+                let root = std::mem::replace(&mut self.root, Atomic::null());
+                *root.into_owned().into_box()
+            };
+            Arc::unwrap_or_clone(root)
+        }
+
+        /// Only sound while no other writer can replace the root, i.e. while holding the writer mutex or `&mut self`.
+        unsafe fn current_root(&self) -> &T {
+            let root = self.root.load(Ordering::Acquire, epoch::unprotected());
+            root.deref()
+        }
+    }
+
+    impl<T> Drop for RootLock<T> {
+        fn drop(&mut self) {
+            // SAFETY: `&mut self` guarantees that no reader or writer still accesses the root.
+            unsafe {
+                let root = self.root.load(Ordering::Relaxed, epoch::unprotected());
+                // The root is null if it was moved out by into_inner.
+                if !root.is_null() {
+                    drop(root.into_owned());
+                }
+            }
+        }
+    }
+
+    impl<T: Debug> Debug for RootLock<T> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("RootLock")
+                .field("data", &&*self.read())
+                .finish_non_exhaustive()
+        }
+    }
+
+    /// Keeps the epoch pinned, so the root that was loaded is not freed while it is borrowed.
+    pub(crate) struct RootReadGuard<'l, T> {
+        _epoch_guard: Guard,
+        root: *const Arc<T>,
+        _lock: PhantomData<&'l RootLock<T>>,
+    }
+
+    impl<T> Deref for RootReadGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            // SAFETY: The root is never null and is only freed after the pinned epoch has ended.
+            unsafe { &*self.root }
+        }
+    }
+
+    /// Holds the writer mutex and a copy of the root, which is only created on the first mutable access.
+    /// The copy is published when the guard is dropped.
+    pub(crate) struct RootWriteGuard<'l, T: Clone> {
+        lock: &'l RootLock<T>,
+        _writer: MutexGuard<'l, ()>,
+        modified_root: Option<T>,
+    }
+
+    impl<T: Clone> Deref for RootWriteGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            match &self.modified_root {
+                Some(root) => root,
+                // SAFETY: The guard holds the writer mutex.
+                None => unsafe { self.lock.current_root() },
+            }
+        }
+    }
+
+    impl<T: Clone> DerefMut for RootWriteGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            let lock = self.lock;
+            self.modified_root.get_or_insert_with(|| {
+                // SAFETY: The guard holds the writer mutex.
+                unsafe { lock.current_root() }.clone()
+            })
+        }
+    }
+
+    impl<T: Clone> Drop for RootWriteGuard<'_, T> {
+        fn drop(&mut self) {
+            let Some(modified_root) = self.modified_root.take() else {
+                return;
+            };
+
+            let epoch_guard = epoch::pin();
+            let old_root = self.lock.root.swap(
+                Owned::new(Arc::new(modified_root)),
+                Ordering::AcqRel,
+                &epoch_guard,
+            );
+            // SAFETY: The old root is no longer reachable, readers that still hold it keep their epoch pinned.
+            unsafe { epoch_guard.defer_destroy(old_root) };
+        }
+    }
+}
@@ -0,0 +1,312 @@
+//! Stores typed keys in a tree by encoding them into bytes that sort like the keys themselves.
+//!
+//! Integers are encoded in big-endian order, signed integers additionally have their sign bit flipped,
+//! so negative numbers sort before positive ones. Tuples concatenate the encodings of their fields.
+//! Fields of a variable length are escaped and terminated, so a shorter field sorts before a longer one
+//! that starts with it, just like the tuple itself does.
+//!
+//! `TSIMTree` itself gets `put_u32`, `get_u32` and the like for the common integer types,
+//! which store the keys with the same encoding.
+
+use crate::{TSIMTree, TSIMTreeIter, CACHE_LINE_SIZE, TREE_RADIX};
+use alloc::string::String;
+use alloc::vec::Vec;
+use allocator_api2::alloc::Allocator;
+use core::marker::PhantomData;
+
+/// A key that is stored in a tree by its byte encoding.
+///
+/// The encoding must preserve the order of the keys, so the tree iterates them in their natural order.
+pub trait TSIMTreeKey: Sized {
+    /// Returns the bytes the key is stored under.
+    fn encode_key(&self) -> Vec<u8>;
+
+    /// Restores a key from the bytes returned by [`TSIMTreeKey::encode_key`].
+    ///
+    /// # Panics
+    /// If the bytes were not encoded by this type.
+    fn decode_key(bytes: &[u8]) -> Self;
+
+    /// Appends the key as a field of a tuple. The encoding must tell where the field ends.
+    ///
+    /// By default the encoding is escaped and terminated by two zero bytes, which preserves its order.
+    /// Keys of a fixed length append their encoding as it is.
+    fn encode_key_field(&self, out: &mut Vec<u8>) {
+        for byte in self.encode_key() {
+            out.push(byte);
+            if byte == 0 {
+                out.push(0xff);
+            }
+        }
+        out.extend_from_slice(&[0, 0]);
+    }
+
+    /// Restores a field appended by [`TSIMTreeKey::encode_key_field`] and advances the bytes past it.
+    ///
+    /// # Panics
+    /// If the bytes were not encoded by this type.
+    fn decode_key_field(bytes: &mut &[u8]) -> Self {
+        let mut encoded = Vec::new();
+        loop {
+            match bytes {
+                [0, 0, rest @ ..] => {
+                    *bytes = rest;
+                    return Self::decode_key(&encoded);
+                }
+                [0, 0xff, rest @ ..] => {
+                    encoded.push(0);
+                    *bytes = rest;
+                }
+                [byte, rest @ ..] if *byte != 0 => {
+                    encoded.push(*byte);
+                    *bytes = rest;
+                }
+                _ => panic!("The field is not terminated"),
+            }
+        }
+    }
+}
+
+/// Splits a field of a fixed length off the bytes.
+fn split_field<'b>(bytes: &mut &'b [u8], len: usize) -> &'b [u8] {
+    assert!(bytes.len() >= len, "The field is truncated");
+    let (field, rest) = bytes.split_at(len);
+    *bytes = rest;
+    field
+}
+
+macro_rules! impl_unsigned_key {
+    ($($int:ty),*) => {$(
+        impl TSIMTreeKey for $int {
+            fn encode_key(&self) -> Vec<u8> {
+                self.to_be_bytes().to_vec()
+            }
+
+            fn decode_key(bytes: &[u8]) -> Self {
+                <$int>::from_be_bytes(bytes.try_into().expect("The key has the size of the integer"))
+            }
+
+            fn encode_key_field(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn decode_key_field(bytes: &mut &[u8]) -> Self {
+                Self::decode_key(split_field(bytes, core::mem::size_of::<$int>()))
+            }
+        }
+    )*};
+}
+
+impl_unsigned_key!(u8, u16, u32, u64, u128);
+
+macro_rules! impl_signed_key {
+    ($($int:ty => $unsigned:ty),*) => {$(
+        /// The sign bit is flipped, so negative numbers sort before positive ones.
+        impl TSIMTreeKey for $int {
+            fn encode_key(&self) -> Vec<u8> {
+                ((*self as $unsigned) ^ (1 << (<$unsigned>::BITS - 1))).encode_key()
+            }
+
+            fn decode_key(bytes: &[u8]) -> Self {
+                (<$unsigned>::decode_key(bytes) ^ (1 << (<$unsigned>::BITS - 1))) as $int
+            }
+
+            fn encode_key_field(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.encode_key());
+            }
+
+            fn decode_key_field(bytes: &mut &[u8]) -> Self {
+                Self::decode_key(split_field(bytes, core::mem::size_of::<$int>()))
+            }
+        }
+    )*};
+}
+
+impl_signed_key!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+impl<const N: usize> TSIMTreeKey for [u8; N] {
+    fn encode_key(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+
+    fn decode_key(bytes: &[u8]) -> Self {
+        bytes.try_into().expect("The key has the size of the array")
+    }
+
+    fn encode_key_field(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self);
+    }
+
+    fn decode_key_field(bytes: &mut &[u8]) -> Self {
+        Self::decode_key(split_field(bytes, N))
+    }
+}
+
+impl TSIMTreeKey for Vec<u8> {
+    fn encode_key(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn decode_key(bytes: &[u8]) -> Self {
+        bytes.to_vec()
+    }
+}
+
+/// Strings are stored by their UTF-8 encoding, which sorts like the code points.
+impl TSIMTreeKey for String {
+    fn encode_key(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn decode_key(bytes: &[u8]) -> Self {
+        String::from_utf8(bytes.to_vec()).expect("The key is valid UTF-8")
+    }
+}
+
+macro_rules! impl_tuple_key {
+    ($($field:ident),*) => {
+        #[allow(non_snake_case)]
+        impl<$($field: TSIMTreeKey),*> TSIMTreeKey for ($($field,)*) {
+            fn encode_key(&self) -> Vec<u8> {
+                let mut out = Vec::new();
+                self.encode_key_field(&mut out);
+                out
+            }
+
+            fn decode_key(mut bytes: &[u8]) -> Self {
+                let key = Self::decode_key_field(&mut bytes);
+                assert!(bytes.is_empty(), "The key has trailing bytes");
+                key
+            }
+
+            fn encode_key_field(&self, out: &mut Vec<u8>) {
+                let ($($field,)*) = self;
+                $($field.encode_key_field(out);)*
+            }
+
+            fn decode_key_field(bytes: &mut &[u8]) -> Self {
+                ($($field::decode_key_field(bytes),)*)
+            }
+        }
+    };
+}
+
+impl_tuple_key!(A);
+impl_tuple_key!(A, B);
+impl_tuple_key!(A, B, C);
+impl_tuple_key!(A, B, C, D);
+
+macro_rules! impl_integer_key_methods {
+    ($($int:ty => $put:ident, $get:ident);*) => {
+        /// Stores integer keys without wrapping the tree into a [`TypedTSIMTree`].
+        ///
+        /// The keys are encoded like [`TSIMTreeKey`] encodes them, in big-endian order with the sign bit
+        /// of signed integers flipped, so the keys of one type sort like the numbers themselves.
+        /// Keys stored by these methods can be read by [`TSIMTree::get`] with the same encoding and vice versa.
+        impl<const RADIX: usize, const LINE: usize, A: Allocator + Clone> TSIMTree<RADIX, LINE, A> {$(
+            #[doc = concat!("Stores the value under the encoding of the `", stringify!($int), "` key.")]
+            pub fn $put(&self, k: $int, v: Vec<u8>) {
+                self.put(k.encode_key(), v);
+            }
+
+            #[doc = concat!("Returns the value stored under the encoding of the `", stringify!($int), "` key.")]
+            pub fn $get(&self, k: $int) -> Option<Vec<u8>> {
+                self.get(k.encode_key())
+            }
+        )*}
+    };
+}
+
+impl_integer_key_methods!(
+    u8 => put_u8, get_u8;
+    u16 => put_u16, get_u16;
+    u32 => put_u32, get_u32;
+    u64 => put_u64, get_u64;
+    i32 => put_i32, get_i32;
+    i64 => put_i64, get_i64
+);
+
+/// A thread-safe sorted in-memory tree with typed keys, which are stored by their [`TSIMTreeKey`] encoding.
+#[derive(Debug, Clone)]
+pub struct TypedTSIMTree<K, const RADIX: usize = TREE_RADIX, const LINE: usize = CACHE_LINE_SIZE> {
+    tree: TSIMTree<RADIX, LINE>,
+    keys: PhantomData<fn(K) -> K>,
+}
+
+impl<K, const RADIX: usize, const LINE: usize> Default for TypedTSIMTree<K, RADIX, LINE> {
+    fn default() -> Self {
+        TypedTSIMTree {
+            tree: TSIMTree::default(),
+            keys: PhantomData,
+        }
+    }
+}
+
+impl<'t, K: TSIMTreeKey, const RADIX: usize, const LINE: usize> IntoIterator
+    for &'t TypedTSIMTree<K, RADIX, LINE>
+{
+    type Item = (K, Vec<u8>);
+    type IntoIter = TypedTSIMTreeIter<'t, K, RADIX, LINE>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<K: TSIMTreeKey> TypedTSIMTree<K> {
+    pub fn new() -> TypedTSIMTree<K> {
+        TypedTSIMTree::default()
+    }
+}
+
+impl<K: TSIMTreeKey, const RADIX: usize, const LINE: usize> TypedTSIMTree<K, RADIX, LINE> {
+    pub fn put(&self, k: K, v: Vec<u8>) {
+        self.tree.put(k.encode_key(), v);
+    }
+
+    pub fn get(&self, k: K) -> Option<Vec<u8>> {
+        self.tree.get(k.encode_key())
+    }
+
+    /// Removes the key from the tree and returns the value that was stored under it.
+    pub fn remove(&self, k: K) -> Option<Vec<u8>> {
+        self.tree.remove(k.encode_key())
+    }
+
+    /// Returns an iterator over the entries in ascending key order, the keys are decoded again.
+    ///
+    /// The read lock is held until the iterator is dropped.
+    pub fn iter(&self) -> TypedTSIMTreeIter<'_, K, RADIX, LINE> {
+        TypedTSIMTreeIter {
+            entries: self.tree.iter(),
+            keys: PhantomData,
+        }
+    }
+
+    /// Returns the tree the encoded keys are stored in.
+    pub fn as_bytes_tree(&self) -> &TSIMTree<RADIX, LINE> {
+        &self.tree
+    }
+}
+
+/// An iterator over the entries of a typed tree in ascending key order, created by [`TypedTSIMTree::iter`].
+pub struct TypedTSIMTreeIter<
+    't,
+    K,
+    const RADIX: usize = TREE_RADIX,
+    const LINE: usize = CACHE_LINE_SIZE,
+> {
+    entries: TSIMTreeIter<'t, RADIX, LINE>,
+    keys: PhantomData<fn() -> K>,
+}
+
+impl<K: TSIMTreeKey, const RADIX: usize, const LINE: usize> Iterator
+    for TypedTSIMTreeIter<'_, K, RADIX, LINE>
+{
+    type Item = (K, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = self.entries.next()?;
+        Some((K::decode_key(&key), value))
+    }
+}
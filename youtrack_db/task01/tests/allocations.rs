@@ -0,0 +1,179 @@
+//! Counts the bytes allocated by inserts, which needs a global allocator and therefore its own test binary.
+//!
+//! With the `lockfree` feature, writers copy the nodes they modify, values included, as readers may still see the old ones.
+//!
+//! Trees created with `TSIMTree::new_in` are checked with an allocator that counts the bytes it hands out.
+
+#![cfg(not(feature = "lockfree"))]
+
+use allocator_api2::alloc::{AllocError, Allocator, Global};
+use quick_start::{PersistentTSIMTree, TSIMTree};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::ptr::NonNull;
+
+/// Counts the bytes allocated on each thread, so tests running in parallel do not disturb each other.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // The counter cannot be accessed while the thread is torn down, those allocations are not counted.
+        let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + layout.size()));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Returns the number of bytes the closure allocated.
+fn allocated_by(f: impl FnOnce()) -> usize {
+    let before = ALLOCATED.with(Cell::get);
+    f();
+    ALLOCATED.with(Cell::get) - before
+}
+
+const LARGE_VALUE_SIZE: usize = 4 << 20;
+
+#[test]
+fn restructuring_nodes_does_not_copy_values() {
+    // The longer key continues after the stored key, which moves the large value under the empty segment of a new node.
+    let tree = TSIMTree::new();
+    tree.put(b"key", vec![1; LARGE_VALUE_SIZE]);
+    let value = b"small".to_vec();
+    assert!(allocated_by(|| tree.put(b"key with a longer suffix", value)) < LARGE_VALUE_SIZE);
+
+    // The other key diverges inside the stored segment, which pushes the large value down with the rest of the segment.
+    let tree = TSIMTree::new();
+    tree.put(b"abcd", vec![1; LARGE_VALUE_SIZE]);
+    let value = b"small".to_vec();
+    assert!(allocated_by(|| tree.put(b"abxy", value)) < LARGE_VALUE_SIZE);
+
+    assert_eq!(
+        tree.get(b"abcd").map(|value| value.len()),
+        Some(LARGE_VALUE_SIZE)
+    );
+    assert_eq!(tree.get(b"abxy"), Some(b"small".to_vec()));
+    assert_eq!(tree.check_integrity(), Ok(()));
+}
+
+#[test]
+fn short_values_are_cloned_with_their_nodes() {
+    let filled_tree = |value_len: usize| {
+        let tree = TSIMTree::new();
+        for i in 0..1000_u32 {
+            tree.put(i.to_be_bytes(), vec![i as u8; value_len]);
+        }
+        tree
+    };
+    let (short, long) = (filled_tree(16), filled_tree(17));
+
+    // The trees have the same shape, only the longer values take an allocation of their own.
+    let allocated_for_short = allocated_by(|| drop(short.clone()));
+    let allocated_for_long = allocated_by(|| drop(long.clone()));
+    assert!(allocated_for_long - allocated_for_short >= 1000 * 17);
+}
+
+#[test]
+fn persistent_versions_only_copy_the_path_to_the_key() {
+    let mut tree = PersistentTSIMTree::new();
+    for i in 0..100_000_u32 {
+        tree = tree.put(i.to_be_bytes(), i.to_le_bytes().to_vec());
+    }
+    let key = 50_000_u32.to_be_bytes();
+
+    let mut versions = Vec::new();
+    let changed = b"changed".to_vec();
+    let allocated_for_versions = allocated_by(|| {
+        versions.push(tree.put(key, changed));
+        versions.push(tree.remove(key));
+    });
+    // A version copies the nodes on the path to the key, which hold up to 257 children each.
+    assert!(allocated_for_versions < 64 << 10);
+    assert_eq!(tree.get(key), Some(&50_000_u32.to_le_bytes()[..]));
+    assert_eq!(versions[0].get(key), Some(&b"changed"[..]));
+    assert_eq!(versions[1].get(key), None);
+}
+
+/// Hands out the allocations of the global allocator and counts their bytes, like a per-request arena would.
+#[derive(Default)]
+struct CountingArena {
+    allocated: Cell<usize>,
+    live: Cell<usize>,
+}
+
+unsafe impl Allocator for &CountingArena {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocated.set(self.allocated.get() + layout.size());
+        self.live.set(self.live.get() + layout.size());
+        Global.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.live.set(self.live.get() - layout.size());
+        Global.deallocate(ptr, layout)
+    }
+}
+
+/// The slab arena and the children spilled by sparse nodes are always allocated globally.
+#[cfg(not(any(feature = "slab", feature = "sparse-nodes")))]
+#[test]
+fn nodes_and_long_values_are_allocated_by_the_allocator_of_the_tree() {
+    const VALUE_LEN: usize = 64;
+    let arena = CountingArena::default();
+    let tree = TSIMTree::<16, 128, _>::new_in(&arena);
+    let values: Vec<Vec<u8>> = (0..1000_u32).map(|i| vec![i as u8; VALUE_LEN]).collect();
+
+    let allocated_before = arena.allocated.get();
+    let allocated = allocated_by(|| {
+        for (i, value) in values.into_iter().enumerate() {
+            tree.put((i as u32).to_be_bytes(), value);
+        }
+    });
+    // The arena takes its memory from the global allocator as well, only the pivots of split nodes bypass it.
+    assert!(allocated - (arena.allocated.get() - allocated_before) < 1000);
+    assert!(arena.live.get() >= 1000 * VALUE_LEN);
+
+    assert_eq!(tree.get(7_u32.to_be_bytes()), Some(vec![7; VALUE_LEN]));
+    assert_eq!(tree.remove(7_u32.to_be_bytes()), Some(vec![7; VALUE_LEN]));
+    tree.retain(|key, _| key[3] % 2 == 0);
+    tree.compact();
+    assert_eq!(tree.iter().count(), 500);
+    assert_eq!(tree.check_integrity(), Ok(()));
+
+    let snapshot = tree.clone();
+    drop(tree);
+    assert!(arena.live.get() > 0);
+    drop(snapshot);
+    assert_eq!(arena.live.get(), 0);
+    assert!(arena.allocated.get() >= 2 * 1000 * VALUE_LEN);
+}
+
+#[cfg(not(any(feature = "slab", feature = "sparse-nodes")))]
+#[test]
+fn approximate_memory_bytes_is_close_to_the_live_bytes() {
+    let arena = CountingArena::default();
+    let tree = TSIMTree::<16, 128, _>::new_in(&arena);
+    for i in 0..10_000_u32 {
+        tree.put(i.to_be_bytes(), vec![1; i as usize % 100]);
+    }
+    for i in (0..10_000_u32).step_by(3) {
+        tree.remove(i.to_be_bytes());
+    }
+
+    // Everything but the tree itself is allocated by the arena.
+    let live = arena.live.get() + size_of_val(&tree);
+    let approximate = tree.approximate_memory_bytes();
+    assert!(
+        approximate.abs_diff(live) < live / 20,
+        "{approximate} is not close to {live} bytes"
+    );
+}